@@ -141,7 +141,7 @@ def run_stress_test(commands):
 
     # Run cargo with the commands
     process = subprocess.Popen(
-        ["cargo", "run", "--release", "--bin", "client", USERNAME],
+        ["cargo", "run", "--release", "--bin", "client", USERNAME, "--anonymous"],
         stdin=subprocess.PIPE,
         stdout=subprocess.PIPE,
         stderr=subprocess.STDOUT,
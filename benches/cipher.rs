@@ -0,0 +1,56 @@
+//! Encrypting and decrypting a multi-megabyte image in each scheme
+//!
+//! `cargo bench --bench cipher`
+
+// The crate has no library, so the benchmarks build the modules they need;
+// their unit tests are left out, which leaves the tests' imports unused
+#[allow(dead_code, unused_imports)]
+#[path = "../src/blobformat.rs"]
+mod blobformat;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/compression.rs"]
+mod compression;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/encryption.rs"]
+mod encryption;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/framing.rs"]
+mod framing;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/protocol.rs"]
+mod protocol;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use encryption::EncryptionScheme;
+use std::hint::black_box;
+
+const IMAGE_LEN: usize = 4 << 20;
+
+fn cipher_throughput(c: &mut Criterion) {
+    let image: Vec<u8> = (0..IMAGE_LEN).map(|i| (i * 7919 % 251) as u8).collect();
+    let mut group = c.benchmark_group("cipher");
+    group.throughput(Throughput::Bytes(IMAGE_LEN as u64));
+    for scheme in [
+        EncryptionScheme::Aes128Ctr,
+        EncryptionScheme::Aes256Ctr,
+        EncryptionScheme::Aes256Gcm,
+        EncryptionScheme::ChaCha20Poly1305,
+    ] {
+        let cipher = scheme.cipher(None, "alice", true);
+        let sealed = cipher.encrypt(&image, b"alice/cat.png");
+        group.bench_function(BenchmarkId::new("encrypt", scheme.name()), |b| {
+            b.iter(|| cipher.encrypt(black_box(&image), b"alice/cat.png"))
+        });
+        group.bench_function(BenchmarkId::new("decrypt", scheme.name()), |b| {
+            b.iter(|| cipher.decrypt(black_box(&sealed), b"alice/cat.png").unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = cipher_throughput
+}
+criterion_main!(benches);
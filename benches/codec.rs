@@ -0,0 +1,59 @@
+//! Encoding and decoding a multi-megabyte upload as JSON and as bincode
+//!
+//! `cargo bench --bench codec`
+
+// The crate has no library, so the benchmarks build the modules they need;
+// their unit tests are left out, which leaves the tests' imports unused
+#[allow(dead_code, unused_imports)]
+#[path = "../src/framing.rs"]
+mod framing;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/protocol.rs"]
+mod protocol;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use framing::{decode, encode, Format};
+use protocol::{ClientRequest, RequestEnvelope, TaggedRequest, PROTOCOL_VERSION};
+use std::hint::black_box;
+
+const IMAGE_LEN: usize = 4 << 20;
+
+fn upload() -> TaggedRequest {
+    TaggedRequest::Client(RequestEnvelope {
+        protocol_version: PROTOCOL_VERSION,
+        request_id: "r1".to_string(),
+        body: ClientRequest::UploadImage {
+            username: "alice".to_string(),
+            token: "t".to_string(),
+            image_data: (0..IMAGE_LEN).map(|i| (i * 7919 % 251) as u8).collect(),
+            filename: "cat.png".to_string(),
+            sha256: None,
+            strip_metadata: None,
+            compress: None,
+        },
+        compression: None,
+    })
+}
+
+fn upload_codec(c: &mut Criterion) {
+    let request = upload();
+    let mut group = c.benchmark_group("upload_codec");
+    group.throughput(Throughput::Bytes(IMAGE_LEN as u64));
+    for (name, format) in [("json", Format::Json), ("bincode", Format::Bincode)] {
+        let payload = encode(format, &request).unwrap();
+        group.bench_function(BenchmarkId::new("encode", name), |b| {
+            b.iter(|| encode(format, black_box(&request)).unwrap())
+        });
+        group.bench_function(BenchmarkId::new("decode", name), |b| {
+            b.iter(|| decode::<TaggedRequest>(black_box(&payload)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = upload_codec
+}
+criterion_main!(benches);
@@ -0,0 +1,56 @@
+//! AES-128-CTR over large images in one pass and split across threads as
+//! `Cipher::encrypt` does past its parallel threshold
+//!
+//! `cargo bench --bench ctr`
+
+// The crate has no library, so the benchmarks build the modules they need;
+// their unit tests are left out, which leaves the tests' imports unused
+#[allow(dead_code, unused_imports)]
+#[path = "../src/blobformat.rs"]
+mod blobformat;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/compression.rs"]
+mod compression;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/encryption.rs"]
+mod encryption;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/framing.rs"]
+mod framing;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/protocol.rs"]
+mod protocol;
+
+use aes::Aes128;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use ctr::Ctr128BE;
+use encryption::EncryptionScheme;
+use std::hint::black_box;
+
+fn single_vs_parallel(c: &mut Criterion) {
+    let cipher = EncryptionScheme::Aes128Ctr.cipher(None, "alice", false);
+    let mut group = c.benchmark_group("ctr");
+    for len in [16 << 20, 64 << 20] {
+        let image: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_function(BenchmarkId::new("single", len >> 20), |b| {
+            b.iter(|| {
+                let mut data = black_box(&image).clone();
+                Ctr128BE::<Aes128>::new(&[0x42; 16].into(), &[7; 16].into()).apply_keystream(&mut data);
+                data
+            })
+        });
+        group.bench_function(BenchmarkId::new("parallel", len >> 20), |b| {
+            b.iter(|| cipher.encrypt(black_box(&image), b""))
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = single_vs_parallel
+}
+criterion_main!(benches);
@@ -0,0 +1,62 @@
+//! What a request pays for a user's image key: deriving it with Argon2,
+//! unwrapping it from the user registry, or finding it in the key cache
+//!
+//! `cargo bench --bench keycache`
+
+// The crate has no library, so the benchmarks build the modules they need;
+// their unit tests are left out, which leaves the tests' imports unused
+#[allow(dead_code, unused_imports)]
+#[path = "../src/blobformat.rs"]
+mod blobformat;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/compression.rs"]
+mod compression;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/encryption.rs"]
+mod encryption;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/framing.rs"]
+mod framing;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/keycache.rs"]
+mod keycache;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/protocol.rs"]
+mod protocol;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use encryption::{derive_user_key, key_wrapping_key, unwrap_key, wrap_key, UserKey};
+use keycache::KeyCache;
+use std::hint::black_box;
+use std::time::Duration;
+
+fn user_keys(c: &mut Criterion) {
+    let wrapping_key = key_wrapping_key(b"bench cluster secret");
+    let user_key = derive_user_key("alice", "hunter2", b"0123456789abcdef");
+    let wrapped = wrap_key(&wrapping_key, "alice", &user_key);
+    let unwrap = || {
+        vec![UserKey {
+            key: unwrap_key(&wrapping_key, "alice", &wrapped).unwrap(),
+            generation: 0,
+        }]
+    };
+
+    let mut group = c.benchmark_group("user_keys");
+    group.bench_function("derive", |b| {
+        b.iter(|| derive_user_key(black_box("alice"), black_box("hunter2"), b"0123456789abcdef"))
+    });
+    group.bench_function("unwrap", |b| b.iter(|| black_box(unwrap())));
+    let cache = KeyCache::new(1024, Duration::from_secs(300));
+    group.bench_function("cache_miss", |b| {
+        b.iter(|| {
+            cache.invalidate("alice");
+            black_box(cache.get_or_insert_with("alice", unwrap))
+        })
+    });
+    cache.get_or_insert_with("alice", unwrap);
+    group.bench_function("cache_hit", |b| b.iter(|| black_box(cache.get_or_insert_with("alice", unwrap))));
+    group.finish();
+}
+
+criterion_group!(benches, user_keys);
+criterion_main!(benches);
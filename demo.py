@@ -136,7 +136,7 @@ def run_demo_test(commands):
 
     # Run cargo with the commands
     process = subprocess.Popen(
-        ["cargo", "run", "--release", "--bin", "client", USERNAME],
+        ["cargo", "run", "--release", "--bin", "client", USERNAME, "--anonymous"],
         stdin=subprocess.PIPE,
         stdout=subprocess.PIPE,
         stderr=subprocess.STDOUT,
@@ -20,14 +20,14 @@ echo ""
 echo "To run the system:"
 echo ""
 echo "1. Open 3 terminals and run:"
-echo "   Terminal 1: cargo run --bin server 1"
-echo "   Terminal 2: cargo run --bin server 2"
-echo "   Terminal 3: cargo run --bin server 3"
+echo "   Terminal 1: cargo run --bin server 1 --insecure-dev-secret --allow-anonymous"
+echo "   Terminal 2: cargo run --bin server 2 --insecure-dev-secret --allow-anonymous"
+echo "   Terminal 3: cargo run --bin server 3 --insecure-dev-secret --allow-anonymous"
 echo ""
 echo "2. Wait 5 seconds for leader election to complete"
 echo ""
 echo "3. In a 4th terminal, run the client:"
-echo "   cargo run --bin client alice 127.0.0.1:8003"
+echo "   cargo run --bin client alice --anonymous"
 echo ""
 echo "4. Use the interactive menu to upload/download images"
 echo ""
@@ -0,0 +1,729 @@
+use crate::encryption::{from_hex, to_hex};
+use crate::protocol::{InternalMessage, SignedInternal, UserRecord};
+use argon2::Argon2;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// An image key's generation and the key, as encrypted by `set_wrapped_key`
+pub type WrappedKey = (u32, Vec<u8>);
+
+/// How long a session token stays valid
+pub const TOKEN_TTL_SECS: u64 = 12 * 60 * 60;
+/// Rounds of SHA-256 passwords went through before Argon2id
+const LEGACY_HASH_ROUNDS: u32 = 10_000;
+/// Marks an Argon2id password hash; hashes without it are iterated SHA-256
+const ARGON2_PREFIX: &str = "argon2id:";
+/// How far a signed internal message's time may be from this node's clock
+pub const PEER_MESSAGE_MAX_AGE_MS: u64 = 30_000;
+
+/// Why a session token was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum TokenError {
+    #[error("malformed or forged token")]
+    Invalid,
+    #[error("token expired")]
+    Expired,
+    #[error("token was issued to another user")]
+    WrongUser,
+}
+
+/// Signs and checks session tokens with the cluster secret
+///
+/// A token is `<username>:<expiry>:<HMAC of both>`, so any node holding the
+/// secret can check it without a shared session store.
+#[derive(Clone)]
+pub struct TokenSigner {
+    secret: Vec<u8>,
+}
+
+impl TokenSigner {
+    pub fn new(secret: &[u8]) -> Self {
+        TokenSigner {
+            secret: secret.to_vec(),
+        }
+    }
+
+    /// Issue a token for `username`, returning it with its expiry (unix seconds)
+    pub fn issue(&self, username: &str, now: u64) -> (String, u64) {
+        let expires_at = now + TOKEN_TTL_SECS;
+        let signature = self.mac(username, expires_at).finalize().into_bytes();
+        (format!("{}:{}:{}", username, expires_at, to_hex(&signature)), expires_at)
+    }
+
+    /// Check that `token` was issued by the cluster to `username` and has not expired
+    pub fn verify(&self, token: &str, username: &str, now: u64) -> Result<(), TokenError> {
+        // Usernames may contain ':', the expiry and signature cannot
+        let mut parts = token.rsplitn(3, ':');
+        let (Some(signature), Some(expires_at), Some(token_user)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(TokenError::Invalid);
+        };
+        let expires_at: u64 = expires_at.parse().map_err(|_| TokenError::Invalid)?;
+        let signature = from_hex(signature).ok_or(TokenError::Invalid)?;
+        self.mac(token_user, expires_at)
+            .verify_slice(&signature)
+            .map_err(|_| TokenError::Invalid)?;
+
+        if token_user != username {
+            return Err(TokenError::WrongUser);
+        }
+        if now >= expires_at {
+            return Err(TokenError::Expired);
+        }
+        Ok(())
+    }
+
+    fn mac(&self, username: &str, expires_at: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(username.as_bytes());
+        mac.update(b":");
+        mac.update(expires_at.to_string().as_bytes());
+        mac
+    }
+}
+
+/// Why a signed internal message was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum PeerAuthError {
+    #[error("bad signature")]
+    Invalid,
+    #[error("signed too long ago, or clocks are too far apart")]
+    Stale,
+    #[error("signed, but not an internal message")]
+    Malformed,
+}
+
+/// Signs and checks the InternalMessages nodes send each other
+///
+/// The key is derived from the cluster secret and differs from the token
+/// key, so a session token never passes for a message signature.
+#[derive(Clone)]
+pub struct PeerSigner {
+    key: [u8; 32],
+}
+
+impl PeerSigner {
+    pub fn new(secret: &[u8]) -> Self {
+        PeerSigner {
+            key: Sha256::new()
+                .chain_update(b"distinsta-internal:")
+                .chain_update(secret)
+                .finalize()
+                .into(),
+        }
+    }
+
+    /// Sign `message` as sent at `now_ms` (unix milliseconds)
+    pub fn sign(&self, message: &InternalMessage, now_ms: u64) -> SignedInternal {
+        let message = bincode::serialize(message).expect("internal messages always serialize");
+        let hmac = self.mac(now_ms, &message).finalize().into_bytes().to_vec();
+        SignedInternal {
+            sent_at_ms: now_ms,
+            message,
+            hmac,
+        }
+    }
+
+    /// The message inside `signed`, if a node of the cluster signed it
+    /// within `PEER_MESSAGE_MAX_AGE_MS` of `now_ms`
+    pub fn open(&self, signed: SignedInternal, now_ms: u64) -> Result<InternalMessage, PeerAuthError> {
+        self.mac(signed.sent_at_ms, &signed.message)
+            .verify_slice(&signed.hmac)
+            .map_err(|_| PeerAuthError::Invalid)?;
+        if now_ms.abs_diff(signed.sent_at_ms) > PEER_MESSAGE_MAX_AGE_MS {
+            return Err(PeerAuthError::Stale);
+        }
+        bincode::deserialize(&signed.message).map_err(|_| PeerAuthError::Malformed)
+    }
+
+    fn mac(&self, sent_at_ms: u64, message: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(&sent_at_ms.to_be_bytes());
+        mac.update(message);
+        mac
+    }
+}
+
+/// Outcome of checking a password against the registry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordCheck {
+    Valid,
+    UnknownUser,
+    WrongPassword,
+}
+
+/// Registered users, kept in a JSON file next to the node's images
+///
+/// Nodes push their registries to each other with UserSync, so a user
+/// registered through the leader is known to every node once it has synced.
+pub struct UserRegistry {
+    path: PathBuf,
+    users: Mutex<HashMap<String, UserRecord>>,
+}
+
+impl UserRegistry {
+    /// Load the registry from `path`, starting empty if the file does not exist
+    pub fn load(path: PathBuf) -> std::io::Result<Self> {
+        let users = match std::fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(UserRegistry {
+            path,
+            users: Mutex::new(users),
+        })
+    }
+
+    /// Add a user, returning `false` if the name is taken
+    ///
+    /// Hashes the password, so it takes as long as Argon2 does.
+    pub fn register(&self, username: &str, password: &str, now: u64) -> std::io::Result<bool> {
+        if self.contains(username) {
+            return Ok(false);
+        }
+        let salt: [u8; 16] = rand::random();
+        let key_salt: [u8; 16] = rand::random();
+        let password_hash = hash_password(&salt, password);
+        let mut users = self.users.lock().unwrap();
+        if users.contains_key(username) {
+            return Ok(false);
+        }
+        let record = UserRecord {
+            salt: to_hex(&salt),
+            password_hash,
+            created_at_unix: now,
+            key_salt: Some(to_hex(&key_salt)),
+            wrapped_key: None,
+            key_generation: 0,
+            retired_keys: HashMap::new(),
+            public_key: None,
+            public_key_at_ms: 0,
+        };
+        users.insert(username.to_string(), record);
+        if let Err(e) = self.save(&users) {
+            users.remove(username);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    pub fn contains(&self, username: &str) -> bool {
+        self.users.lock().unwrap().contains_key(username)
+    }
+
+    /// The salt `username`'s image key is derived with
+    pub fn key_salt(&self, username: &str) -> Option<String> {
+        let users = self.users.lock().unwrap();
+        let record = users.get(username)?;
+        Some(record.key_salt.clone().unwrap_or_else(|| record.salt.clone()))
+    }
+
+    /// `username`'s image key as encrypted by `set_wrapped_key`
+    pub fn wrapped_key(&self, username: &str) -> Option<Vec<u8>> {
+        from_hex(self.users.lock().unwrap().get(username)?.wrapped_key.as_deref()?)
+    }
+
+    /// Record `username`'s encrypted image key, returning `false` if they are
+    /// not registered or already have one
+    pub fn set_wrapped_key(&self, username: &str, wrapped: &[u8]) -> std::io::Result<bool> {
+        let mut users = self.users.lock().unwrap();
+        match users.get_mut(username) {
+            Some(record) if record.wrapped_key.is_none() => record.wrapped_key = Some(to_hex(wrapped)),
+            _ => return Ok(false),
+        }
+        if let Err(e) = self.save(&users) {
+            if let Some(record) = users.get_mut(username) {
+                record.wrapped_key = None;
+            }
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// The X25519 public key `username` registered for receiving shares
+    pub fn public_key(&self, username: &str) -> Option<[u8; 32]> {
+        from_hex(self.users.lock().unwrap().get(username)?.public_key.as_deref()?)?.try_into().ok()
+    }
+
+    /// Record the public key `username` registered at `now_ms`, replacing
+    /// any earlier one; returns `false` if they are not registered
+    pub fn set_public_key(&self, username: &str, public_key: &[u8; 32], now_ms: u64) -> std::io::Result<bool> {
+        let mut users = self.users.lock().unwrap();
+        let Some(record) = users.get_mut(username) else {
+            return Ok(false);
+        };
+        let previous = (record.public_key.take(), record.public_key_at_ms);
+        record.public_key = Some(to_hex(public_key));
+        record.public_key_at_ms = now_ms;
+        if let Err(e) = self.save(&users) {
+            if let Some(record) = users.get_mut(username) {
+                (record.public_key, record.public_key_at_ms) = previous;
+            }
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// `username`'s current key generation and its earlier image keys not
+    /// yet retired, as encrypted by `set_wrapped_key`
+    pub fn retired_keys(&self, username: &str) -> Option<(u32, Vec<WrappedKey>)> {
+        let users = self.users.lock().unwrap();
+        let record = users.get(username)?;
+        let retired = record
+            .retired_keys
+            .iter()
+            .filter_map(|(generation, wrapped)| Some((*generation, from_hex(wrapped)?)))
+            .collect();
+        Some((record.key_generation, retired))
+    }
+
+    /// Give `username` a new password and an image key of the next
+    /// generation, derived with `key_salt`, keeping their old key until
+    /// `finish_rotation`; returns the new generation, `None` if they are not
+    /// registered or have no image key yet
+    pub fn change_password(
+        &self,
+        username: &str,
+        password: &str,
+        key_salt: &str,
+        wrapped: &[u8],
+    ) -> std::io::Result<Option<u32>> {
+        let salt: [u8; 16] = rand::random();
+        let password_hash = hash_password(&salt, password);
+        let mut users = self.users.lock().unwrap();
+        let Some(record) = users.get_mut(username) else {
+            return Ok(None);
+        };
+        let Some(old_key) = record.wrapped_key.clone() else {
+            return Ok(None);
+        };
+        let previous = record.clone();
+        record.salt = to_hex(&salt);
+        record.password_hash = password_hash;
+        record.key_salt = Some(key_salt.to_string());
+        record.retired_keys.insert(record.key_generation, old_key);
+        record.key_generation += 1;
+        record.wrapped_key = Some(to_hex(wrapped));
+        let generation = record.key_generation;
+        if let Err(e) = self.save(&users) {
+            users.insert(username.to_string(), previous);
+            return Err(e);
+        }
+        Ok(Some(generation))
+    }
+
+    /// Forget `username`'s image keys from before `generation`, once no node
+    /// stores an image under them; returns whether any were dropped
+    pub fn finish_rotation(&self, username: &str, generation: u32) -> std::io::Result<bool> {
+        let mut users = self.users.lock().unwrap();
+        let Some(record) = users.get_mut(username) else {
+            return Ok(false);
+        };
+        let previous = record.retired_keys.clone();
+        record.retired_keys.retain(|retired, _| *retired >= generation);
+        if record.retired_keys.len() == previous.len() {
+            return Ok(false);
+        }
+        if let Err(e) = self.save(&users) {
+            if let Some(record) = users.get_mut(username) {
+                record.retired_keys = previous;
+            }
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Users whose images may still be under a retired key
+    pub fn rotations_pending(&self) -> Vec<String> {
+        let users = self.users.lock().unwrap();
+        users
+            .iter()
+            .filter(|(_, record)| !record.retired_keys.is_empty())
+            .map(|(username, _)| username.clone())
+            .collect()
+    }
+
+    /// Every registered user, to send to a peer
+    pub fn snapshot(&self) -> HashMap<String, UserRecord> {
+        self.users.lock().unwrap().clone()
+    }
+
+    /// Merge users learned from a peer, returning how many were added or replaced
+    ///
+    /// If both sides registered the same name, the earlier registration wins
+    /// (ties broken by hash), so every node keeps the same record. A copy of
+    /// the same registration that carries an image key replaces one without,
+    /// one after a later password change replaces an earlier one, and one
+    /// with fewer retired keys replaces one still holding them. Either way
+    /// the registration keeps whichever public key was registered last.
+    pub fn merge(&self, incoming: HashMap<String, UserRecord>) -> std::io::Result<usize> {
+        let mut users = self.users.lock().unwrap();
+        let mut changed = Vec::new();
+        for (username, mut record) in incoming {
+            if let Some(existing) = users.get(&username).filter(|e| e.created_at_unix == record.created_at_unix) {
+                if existing.public_key_at_ms > record.public_key_at_ms {
+                    record.public_key = existing.public_key.clone();
+                    record.public_key_at_ms = existing.public_key_at_ms;
+                }
+            }
+            let replace = match users.get(&username) {
+                None => true,
+                Some(existing)
+                    if existing.created_at_unix == record.created_at_unix
+                        && existing.key_generation != record.key_generation =>
+                {
+                    record.key_generation > existing.key_generation
+                }
+                // A login replaced one side's legacy password hash
+                Some(existing)
+                    if existing.created_at_unix == record.created_at_unix
+                        && is_legacy_hash(&existing.password_hash) != is_legacy_hash(&record.password_hash) =>
+                {
+                    is_legacy_hash(&existing.password_hash)
+                }
+                Some(existing) => {
+                    let incoming = (record.created_at_unix, &record.password_hash);
+                    let current = (existing.created_at_unix, &existing.password_hash);
+                    incoming < current
+                        || (incoming == current && existing.wrapped_key.is_none() && record.wrapped_key.is_some())
+                        || (incoming == current && record.retired_keys.len() < existing.retired_keys.len())
+                }
+            };
+            if replace {
+                changed.push((username.clone(), users.insert(username, record)));
+            } else if let Some(existing) = users
+                .get_mut(&username)
+                .filter(|e| e.created_at_unix == record.created_at_unix && e.public_key_at_ms < record.public_key_at_ms)
+            {
+                let previous = existing.clone();
+                existing.public_key = record.public_key;
+                existing.public_key_at_ms = record.public_key_at_ms;
+                changed.push((username, Some(previous)));
+            }
+        }
+        if changed.is_empty() {
+            return Ok(0);
+        }
+        if let Err(e) = self.save(&users) {
+            for (username, previous) in changed {
+                match previous {
+                    Some(record) => users.insert(username, record),
+                    None => users.remove(&username),
+                };
+            }
+            return Err(e);
+        }
+        Ok(changed.len())
+    }
+
+    /// Check `password` against the user's hash, in constant time
+    ///
+    /// Hashes the password, so it takes as long as Argon2 does.
+    pub fn check_password(&self, username: &str, password: &str) -> PasswordCheck {
+        let Some((salt, stored)) = self
+            .users
+            .lock()
+            .unwrap()
+            .get(username)
+            .map(|record| (record.salt.clone(), record.password_hash.clone()))
+        else {
+            return PasswordCheck::UnknownUser;
+        };
+        let Some(salt) = from_hex(&salt) else {
+            return PasswordCheck::WrongPassword;
+        };
+        let computed = match is_legacy_hash(&stored) {
+            true => legacy_hash_password(&salt, password),
+            false => hash_password(&salt, password),
+        };
+        match bool::from(computed.as_bytes().ct_eq(stored.as_bytes())) {
+            true => PasswordCheck::Valid,
+            false => PasswordCheck::WrongPassword,
+        }
+    }
+
+    /// Replace the user's iterated SHA-256 password hash with an Argon2id
+    /// one, once they have proven `password` right; returns whether it did
+    ///
+    /// The salt stays, so the record still merges as the same registration.
+    pub fn upgrade_password_hash(&self, username: &str, password: &str) -> std::io::Result<bool> {
+        let Some(salt) = self
+            .users
+            .lock()
+            .unwrap()
+            .get(username)
+            .filter(|record| is_legacy_hash(&record.password_hash))
+            .and_then(|record| from_hex(&record.salt))
+        else {
+            return Ok(false);
+        };
+        let password_hash = hash_password(&salt, password);
+        let mut users = self.users.lock().unwrap();
+        let Some(record) = users.get_mut(username).filter(|record| is_legacy_hash(&record.password_hash)) else {
+            return Ok(false);
+        };
+        let previous = std::mem::replace(&mut record.password_hash, password_hash);
+        if let Err(e) = self.save(&users) {
+            if let Some(record) = users.get_mut(username) {
+                record.password_hash = previous;
+            }
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Write the registry to a temporary file and move it into place, so a
+    /// crash never leaves half a file
+    fn save(&self, users: &HashMap<String, UserRecord>) -> std::io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(users)?)?;
+        std::fs::rename(&tmp, &self.path)
+    }
+}
+
+/// Password verifier: Argon2id over the password, salted with the record's
+/// own salt
+///
+/// Users' image keys are Argon2id over their password too; the verifier
+/// differs by its salt and a context prefix, so it says nothing about the key.
+fn hash_password(salt: &[u8], password: &str) -> String {
+    let mut hash = Zeroizing::new([0u8; 32]);
+    let input = Zeroizing::new(format!("distinsta-verifier:{}", password));
+    Argon2::default()
+        .hash_password_into(input.as_bytes(), salt, &mut *hash)
+        .expect("salt is long enough for Argon2");
+    format!("{}{}", ARGON2_PREFIX, to_hex(&*hash))
+}
+
+fn is_legacy_hash(password_hash: &str) -> bool {
+    !password_hash.starts_with(ARGON2_PREFIX)
+}
+
+/// Password hash of users registered before Argon2id, replaced on their next login
+fn legacy_hash_password(salt: &[u8], password: &str) -> String {
+    let mut hash: [u8; 32] = Sha256::new().chain_update(salt).chain_update(password).finalize().into();
+    for _ in 0..LEGACY_HASH_ROUNDS {
+        hash = Sha256::new().chain_update(hash).chain_update(password).finalize().into();
+    }
+    to_hex(&hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW_MS: u64 = 1_700_000_000_000;
+
+    /// A registry in a file of its own, removed when the test ends
+    struct ScratchRegistry {
+        registry: UserRegistry,
+        path: PathBuf,
+    }
+
+    impl ScratchRegistry {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("distinsta-users-{}.json", uuid::Uuid::new_v4()));
+            ScratchRegistry {
+                registry: UserRegistry::load(path.clone()).unwrap(),
+                path,
+            }
+        }
+    }
+
+    impl std::ops::Deref for ScratchRegistry {
+        type Target = UserRegistry;
+
+        fn deref(&self) -> &UserRegistry {
+            &self.registry
+        }
+    }
+
+    impl Drop for ScratchRegistry {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    fn legacy_record(password: &str, created_at_unix: u64) -> UserRecord {
+        let salt = [7u8; 16];
+        UserRecord {
+            salt: to_hex(&salt),
+            password_hash: legacy_hash_password(&salt, password),
+            created_at_unix,
+            key_salt: None,
+            wrapped_key: None,
+            key_generation: 0,
+            retired_keys: HashMap::new(),
+            public_key: None,
+            public_key_at_ms: 0,
+        }
+    }
+
+    fn password_hash(registry: &UserRegistry, username: &str) -> String {
+        registry.snapshot()[username].password_hash.clone()
+    }
+
+    #[test]
+    fn signed_message_opens() {
+        let signer = PeerSigner::new(b"cluster secret");
+        let signed = signer.sign(&InternalMessage::Ping, NOW_MS);
+        assert!(matches!(signer.open(signed, NOW_MS + 1_000), Ok(InternalMessage::Ping)));
+    }
+
+    #[test]
+    fn other_secret_is_refused() {
+        let signed = PeerSigner::new(b"cluster secret").sign(&InternalMessage::Ping, NOW_MS);
+        let other = PeerSigner::new(b"another secret");
+        assert_eq!(other.open(signed, NOW_MS).unwrap_err(), PeerAuthError::Invalid);
+    }
+
+    #[test]
+    fn token_key_does_not_sign_messages() {
+        let secret = b"cluster secret";
+        let mut signed = PeerSigner::new(secret).sign(&InternalMessage::Ping, NOW_MS);
+        let mut mac = HmacSha256::new_from_slice(secret).unwrap();
+        mac.update(&signed.sent_at_ms.to_be_bytes());
+        mac.update(&signed.message);
+        signed.hmac = mac.finalize().into_bytes().to_vec();
+        assert_eq!(PeerSigner::new(secret).open(signed, NOW_MS).unwrap_err(), PeerAuthError::Invalid);
+    }
+
+    #[test]
+    fn tampering_is_refused() {
+        let signer = PeerSigner::new(b"cluster secret");
+        let mut message = signer.sign(&InternalMessage::Ping, NOW_MS);
+        message.message.push(0);
+        assert_eq!(signer.open(message, NOW_MS).unwrap_err(), PeerAuthError::Invalid);
+
+        let mut sent_at = signer.sign(&InternalMessage::Ping, NOW_MS);
+        sent_at.sent_at_ms += 1;
+        assert_eq!(signer.open(sent_at, NOW_MS).unwrap_err(), PeerAuthError::Invalid);
+
+        let mut hmac = signer.sign(&InternalMessage::Ping, NOW_MS);
+        hmac.hmac[0] ^= 1;
+        assert_eq!(signer.open(hmac, NOW_MS).unwrap_err(), PeerAuthError::Invalid);
+
+        let mut unsigned = signer.sign(&InternalMessage::Ping, NOW_MS);
+        unsigned.hmac.clear();
+        assert_eq!(signer.open(unsigned, NOW_MS).unwrap_err(), PeerAuthError::Invalid);
+    }
+
+    #[test]
+    fn stale_message_is_refused() {
+        let signer = PeerSigner::new(b"cluster secret");
+        let sign = || signer.sign(&InternalMessage::Ping, NOW_MS);
+        assert!(signer.open(sign(), NOW_MS + PEER_MESSAGE_MAX_AGE_MS).is_ok());
+        assert_eq!(signer.open(sign(), NOW_MS + PEER_MESSAGE_MAX_AGE_MS + 1).unwrap_err(), PeerAuthError::Stale);
+        // Nor from a sender whose clock runs too far ahead
+        assert_eq!(signer.open(sign(), NOW_MS - PEER_MESSAGE_MAX_AGE_MS - 1).unwrap_err(), PeerAuthError::Stale);
+    }
+
+    #[test]
+    fn signed_garbage_is_malformed() {
+        let signer = PeerSigner::new(b"cluster secret");
+        let message = vec![0xff; 8];
+        let hmac = signer.mac(NOW_MS, &message).finalize().into_bytes().to_vec();
+        let signed = SignedInternal {
+            sent_at_ms: NOW_MS,
+            message,
+            hmac,
+        };
+        assert_eq!(signer.open(signed, NOW_MS).unwrap_err(), PeerAuthError::Malformed);
+    }
+
+    #[test]
+    fn passwords_are_hashed_with_argon2id() {
+        let users = ScratchRegistry::new();
+        assert!(users.register("alice", "hunter2", 1).unwrap());
+        let hash = password_hash(&users, "alice");
+        assert!(hash.starts_with(ARGON2_PREFIX));
+        assert!(!hash.contains("hunter2"));
+
+        assert_eq!(users.check_password("alice", "hunter2"), PasswordCheck::Valid);
+        assert_eq!(users.check_password("alice", "hunter3"), PasswordCheck::WrongPassword);
+        assert_eq!(users.check_password("bob", "hunter2"), PasswordCheck::UnknownUser);
+    }
+
+    #[test]
+    fn same_password_hashes_differently_per_user() {
+        let users = ScratchRegistry::new();
+        users.register("alice", "hunter2", 1).unwrap();
+        users.register("bob", "hunter2", 1).unwrap();
+        assert_ne!(password_hash(&users, "alice"), password_hash(&users, "bob"));
+    }
+
+    #[test]
+    fn verifier_is_not_the_key_derivation() {
+        // Same salt and password as an image key derivation, different output
+        let salt = [9u8; 16];
+        let mut key = [0u8; 32];
+        Argon2::default().hash_password_into(b"hunter2", &salt, &mut key).unwrap();
+        assert_ne!(hash_password(&salt, "hunter2"), format!("{}{}", ARGON2_PREFIX, to_hex(&key)));
+    }
+
+    #[test]
+    fn corrupt_hash_is_a_wrong_password() {
+        let users = ScratchRegistry::new();
+        let mut record = legacy_record("hunter2", 1);
+        record.salt = "not hex".to_string();
+        users.merge(HashMap::from([("alice".to_string(), record)])).unwrap();
+        assert_eq!(users.check_password("alice", "hunter2"), PasswordCheck::WrongPassword);
+    }
+
+    #[test]
+    fn legacy_hash_still_verifies_and_is_upgraded() {
+        let users = ScratchRegistry::new();
+        let legacy = legacy_record("hunter2", 1);
+        users.merge(HashMap::from([("alice".to_string(), legacy.clone())])).unwrap();
+        assert_eq!(users.check_password("alice", "hunter2"), PasswordCheck::Valid);
+        assert_eq!(users.check_password("alice", "hunter3"), PasswordCheck::WrongPassword);
+
+        assert!(users.upgrade_password_hash("alice", "hunter2").unwrap());
+        let upgraded = &users.snapshot()["alice"];
+        assert!(upgraded.password_hash.starts_with(ARGON2_PREFIX));
+        assert_eq!(upgraded.salt, legacy.salt);
+        assert_eq!(users.check_password("alice", "hunter2"), PasswordCheck::Valid);
+        assert_eq!(users.check_password("alice", "hunter3"), PasswordCheck::WrongPassword);
+
+        // Already upgraded, and it was saved
+        assert!(!users.upgrade_password_hash("alice", "hunter2").unwrap());
+        let reloaded = UserRegistry::load(users.path.clone()).unwrap();
+        assert_eq!(password_hash(&reloaded, "alice"), upgraded.password_hash);
+    }
+
+    #[test]
+    fn upgrade_leaves_unknown_users_alone() {
+        let users = ScratchRegistry::new();
+        assert!(!users.upgrade_password_hash("alice", "hunter2").unwrap());
+        assert!(!users.contains("alice"));
+    }
+
+    #[test]
+    fn merge_prefers_the_upgraded_hash() {
+        let legacy = legacy_record("hunter2", 1);
+        let users = ScratchRegistry::new();
+        users.merge(HashMap::from([("alice".to_string(), legacy.clone())])).unwrap();
+        users.upgrade_password_hash("alice", "hunter2").unwrap();
+        let upgraded = users.snapshot()["alice"].clone();
+
+        // A peer that has not seen the login keeps the legacy hash
+        assert_eq!(users.merge(HashMap::from([("alice".to_string(), legacy.clone())])).unwrap(), 0);
+        assert_eq!(password_hash(&users, "alice"), upgraded.password_hash);
+
+        // and takes the upgraded one when it hears of it
+        let peer = ScratchRegistry::new();
+        peer.merge(HashMap::from([("alice".to_string(), legacy)])).unwrap();
+        assert_eq!(peer.merge(HashMap::from([("alice".to_string(), upgraded.clone())])).unwrap(), 1);
+        assert_eq!(password_hash(&peer, "alice"), upgraded.password_hash);
+    }
+}
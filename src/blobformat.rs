@@ -0,0 +1,310 @@
+/// First bytes of every sealed image written since the format was versioned
+///
+/// Images from before start with a bare header byte, or none at all; see
+/// `encryption::open_image`.
+pub const MAGIC: [u8; 4] = *b"DSTA";
+/// The one format version this build writes and reads
+pub const FORMAT_VERSION: u8 = 1;
+/// Bytes every header has: the magic, format version, cipher id and flags
+pub const FIXED_LEN: usize = MAGIC.len() + 3;
+
+/// Set when the image is sealed with a password-derived key rather than
+/// the deprecated username key
+pub const DERIVED_KEY_FLAG: u8 = 0x80;
+/// Set with `DERIVED_KEY_FLAG` when the image is encrypted under its own
+/// random data key, which the header carries wrapped by the user's key
+pub const ENVELOPE_FLAG: u8 = 0x40;
+/// Set for CTR images that end in an HMAC-SHA256 tag
+pub const MAC_FLAG: u8 = 0x20;
+/// Set with `ENVELOPE_FLAG` when the header carries the generation of the
+/// user key that wrapped the data key; images without it used generation 0
+pub const GENERATION_FLAG: u8 = 0x10;
+/// Set for AEAD images encrypted a chunk at a time, each with its own tag
+pub const CHUNKED_FLAG: u8 = 0x08;
+/// Set when the image was compressed before it was encrypted; the header
+/// then names the codec and the image's size before compressing
+pub const COMPRESSED_FLAG: u8 = 0x04;
+const KNOWN_FLAGS: u8 = DERIVED_KEY_FLAG | ENVELOPE_FLAG | MAC_FLAG | GENERATION_FLAG | CHUNKED_FLAG | COMPRESSED_FLAG;
+
+/// Codec id of zstd, the one compression this build writes and reads
+pub const ZSTD_CODEC: u8 = 1;
+
+/// Why a sealed image's header could not be read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum BlobError {
+    /// The header runs on past the end of the data; `needed` bytes would
+    /// hold as much of it as is known so far
+    #[error("sealed image ends inside its header, which needs at least {needed} bytes")]
+    Truncated { needed: usize },
+    #[error("not a sealed image: it does not start with the DSTA magic")]
+    BadMagic,
+    #[error("sealed image format version {0} is not one this build reads")]
+    UnknownVersion(u8),
+    #[error("unknown cipher id {0}")]
+    UnknownCipher(u8),
+    #[error("unknown compression codec id {0}")]
+    UnknownCodec(u8),
+    /// Unknown flag bits, or flags that cannot go together
+    #[error("invalid header flags {0:#04x}")]
+    InvalidFlags(u8),
+}
+
+/// The header in front of a sealed image's payload:
+///
+/// ```text
+/// "DSTA" | version u8 | cipher id u8 | flags u8
+///        | generation u32 BE           (with GENERATION_FLAG)
+///        | key length u8 | wrapped key (with ENVELOPE_FLAG)
+///        | codec u8 | original length u64 BE (with COMPRESSED_FLAG)
+///        | payload
+/// ```
+///
+/// The payload is the cipher's output: IV or nonce, ciphertext, then the tag
+/// or HMAC; chunked images start with a nonce prefix instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobHeader<'a> {
+    /// Which `EncryptionScheme` sealed the payload
+    pub cipher: u8,
+    /// `DERIVED_KEY_FLAG`, `MAC_FLAG` and `CHUNKED_FLAG`; the generation,
+    /// envelope and compression flags follow from the fields below
+    pub flags: u8,
+    pub generation: Option<u32>,
+    /// The image's data key, wrapped by the user's key
+    pub wrapped_key: Option<&'a [u8]>,
+    pub compression: Option<Compression>,
+}
+
+/// How an image was compressed before it was encrypted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    pub codec: u8,
+    /// Size of the image before compressing
+    pub original_len: u64,
+}
+
+impl<'a> BlobHeader<'a> {
+    /// Split a sealed image into its header and payload
+    pub fn parse(data: &'a [u8]) -> Result<(Self, &'a [u8]), BlobError> {
+        let magic_len = MAGIC.len().min(data.len());
+        if data[..magic_len] != MAGIC[..magic_len] {
+            return Err(BlobError::BadMagic);
+        }
+        if data.len() < FIXED_LEN {
+            return Err(BlobError::Truncated { needed: FIXED_LEN });
+        }
+        let (version, cipher, flags) = (data[4], data[5], data[6]);
+        if version != FORMAT_VERSION {
+            return Err(BlobError::UnknownVersion(version));
+        }
+        let derived_key = flags & DERIVED_KEY_FLAG != 0;
+        let envelope = flags & ENVELOPE_FLAG != 0;
+        let has_generation = flags & GENERATION_FLAG != 0;
+        if flags & !KNOWN_FLAGS != 0 || (envelope && !derived_key) || (has_generation && !envelope) {
+            return Err(BlobError::InvalidFlags(flags));
+        }
+
+        let mut len = FIXED_LEN;
+        let generation = match has_generation {
+            true => {
+                let bytes = data.get(len..len + 4).ok_or(BlobError::Truncated { needed: len + 4 })?;
+                len += 4;
+                Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+            }
+            false => None,
+        };
+        let wrapped_key = match envelope {
+            true => {
+                let key_len = *data.get(len).ok_or(BlobError::Truncated { needed: len + 1 })? as usize;
+                len += 1;
+                let key = data.get(len..len + key_len).ok_or(BlobError::Truncated { needed: len + key_len })?;
+                len += key_len;
+                Some(key)
+            }
+            false => None,
+        };
+        let compression = match flags & COMPRESSED_FLAG != 0 {
+            true => {
+                let fields = data.get(len..len + 9).ok_or(BlobError::Truncated { needed: len + 9 })?;
+                len += 9;
+                if fields[0] != ZSTD_CODEC {
+                    return Err(BlobError::UnknownCodec(fields[0]));
+                }
+                Some(Compression {
+                    codec: fields[0],
+                    original_len: u64::from_be_bytes(fields[1..].try_into().unwrap()),
+                })
+            }
+            false => None,
+        };
+        let header = BlobHeader {
+            cipher,
+            flags: flags & !(ENVELOPE_FLAG | GENERATION_FLAG | COMPRESSED_FLAG),
+            generation,
+            wrapped_key,
+            compression,
+        };
+        Ok((header, &data[len..]))
+    }
+
+    /// Append this header to `out`
+    pub fn write(&self, out: &mut Vec<u8>) {
+        let generation_flag = if self.generation.is_some() { GENERATION_FLAG } else { 0 };
+        let envelope_flag = if self.wrapped_key.is_some() { ENVELOPE_FLAG } else { 0 };
+        let compressed_flag = if self.compression.is_some() { COMPRESSED_FLAG } else { 0 };
+        let flags = self.flags | generation_flag | envelope_flag | compressed_flag;
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&[FORMAT_VERSION, self.cipher, flags]);
+        if let Some(generation) = self.generation {
+            out.extend_from_slice(&generation.to_be_bytes());
+        }
+        if let Some(wrapped_key) = self.wrapped_key {
+            out.push(u8::try_from(wrapped_key.len()).expect("wrapped key is under 256 bytes"));
+            out.extend_from_slice(wrapped_key);
+        }
+        if let Some(compression) = self.compression {
+            out.push(compression.codec);
+            out.extend_from_slice(&compression.original_len.to_be_bytes());
+        }
+    }
+
+    /// Whether any of `flags` is set
+    pub fn has(&self, flags: u8) -> bool {
+        self.flags & flags != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GOLDEN_HEADERS: &str = include_str!("../testdata/blobformat/headers.txt");
+    const WRAPPED_KEY: [u8; 60] = {
+        let mut key = [0u8; 60];
+        let mut i = 0;
+        while i < key.len() {
+            key[i] = 0xa0 + i as u8;
+            i += 1;
+        }
+        key
+    };
+
+    /// Each golden line: cipher, flags byte and the bytes of header and payload
+    fn golden() -> Vec<(u8, u8, Vec<u8>)> {
+        GOLDEN_HEADERS
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let fields: Vec<&str> = line.split(' ').collect();
+                let flags = u8::from_str_radix(fields[1].trim_start_matches("0x"), 16).unwrap();
+                let bytes = (0..fields[2].len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&fields[2][i..i + 2], 16).unwrap())
+                    .collect();
+                (fields[0].parse().unwrap(), flags, bytes)
+            })
+            .collect()
+    }
+
+    /// The header a golden line's flags describe
+    fn expected(cipher: u8, flags: u8) -> BlobHeader<'static> {
+        BlobHeader {
+            cipher,
+            flags: flags & (DERIVED_KEY_FLAG | MAC_FLAG | CHUNKED_FLAG),
+            generation: (flags & GENERATION_FLAG != 0).then_some(0x01020304),
+            wrapped_key: (flags & ENVELOPE_FLAG != 0).then_some(&WRAPPED_KEY[..]),
+            compression: (flags & COMPRESSED_FLAG != 0).then_some(Compression {
+                codec: ZSTD_CODEC,
+                original_len: 1_000_000,
+            }),
+        }
+    }
+
+    #[test]
+    fn golden_vectors_cover_every_cipher_and_flag_combination() {
+        let vectors = golden();
+        let mut seen: Vec<(u8, u8)> = vectors.iter().map(|(cipher, flags, _)| (*cipher, *flags)).collect();
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), vectors.len());
+        // Four ciphers; plain, derived, enveloped or enveloped with a
+        // generation key; with or without MAC, chunks and compression
+        assert_eq!(vectors.len(), 4 * 4 * 2 * 2 * 2);
+        for (cipher, flags, _) in &vectors {
+            assert!((1..=4).contains(cipher));
+            assert_eq!(flags & !KNOWN_FLAGS, 0);
+        }
+    }
+
+    #[test]
+    fn golden_vectors_parse() {
+        for (cipher, flags, bytes) in golden() {
+            let (header, payload) = BlobHeader::parse(&bytes).unwrap_or_else(|e| panic!("{} {:#04x}: {}", cipher, flags, e));
+            assert_eq!(header, expected(cipher, flags), "{} {:#04x}", cipher, flags);
+            assert_eq!(payload, b"payload");
+        }
+    }
+
+    #[test]
+    fn golden_vectors_are_written_byte_for_byte() {
+        for (cipher, flags, bytes) in golden() {
+            let mut written = Vec::new();
+            expected(cipher, flags).write(&mut written);
+            written.extend_from_slice(b"payload");
+            assert_eq!(written, bytes, "{} {:#04x}", cipher, flags);
+        }
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        for (cipher, flags, _) in golden() {
+            let header = expected(cipher, flags);
+            let mut written = Vec::new();
+            header.write(&mut written);
+            assert_eq!(BlobHeader::parse(&written), Ok((header, &[][..])));
+        }
+    }
+
+    #[test]
+    fn every_cut_inside_a_header_is_truncated() {
+        for (cipher, flags, bytes) in golden() {
+            let header_len = bytes.len() - b"payload".len();
+            for cut in 0..header_len {
+                match BlobHeader::parse(&bytes[..cut]) {
+                    Err(BlobError::Truncated { needed }) => {
+                        assert!(needed > cut && needed <= header_len, "{} {:#04x} cut at {}", cipher, flags, cut)
+                    }
+                    other => panic!("{} {:#04x} cut at {}: {:?}", cipher, flags, cut, other),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn other_data_is_not_a_header() {
+        assert_eq!(BlobHeader::parse(b"PNG\x0d\x0a\x1a\x0a"), Err(BlobError::BadMagic));
+        assert_eq!(BlobHeader::parse(b"DSX"), Err(BlobError::BadMagic));
+        assert_eq!(BlobHeader::parse(b"dsta\x01\x02\x00"), Err(BlobError::BadMagic));
+    }
+
+    #[test]
+    fn unknown_versions_are_refused() {
+        for version in [0, 2, 0xff] {
+            assert_eq!(BlobHeader::parse(&[b'D', b'S', b'T', b'A', version, 2, 0]), Err(BlobError::UnknownVersion(version)));
+        }
+    }
+
+    #[test]
+    fn invalid_flags_are_refused() {
+        let header = |flags: u8| [b'D', b'S', b'T', b'A', FORMAT_VERSION, 2, flags];
+        for flags in [0x01, 0x02, ENVELOPE_FLAG, GENERATION_FLAG, DERIVED_KEY_FLAG | GENERATION_FLAG] {
+            assert_eq!(BlobHeader::parse(&header(flags)), Err(BlobError::InvalidFlags(flags)));
+        }
+    }
+
+    #[test]
+    fn unknown_codecs_are_refused() {
+        let mut bytes = vec![b'D', b'S', b'T', b'A', FORMAT_VERSION, 2, COMPRESSED_FLAG, 2];
+        bytes.extend_from_slice(&5u64.to_be_bytes());
+        assert_eq!(BlobHeader::parse(&bytes), Err(BlobError::UnknownCodec(2)));
+    }
+}
@@ -1,312 +1,3441 @@
+use crate::framing::{read_frame, write_frame, FrameError};
+use crate::transport::{connect_peer, MessageCodec, SendError, TcpTransport, Transport, MAX_FRAME_LEN};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::RwLock;
-use tokio::time::{sleep, timeout, Duration};
+use std::time::SystemTime;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::{watch, Mutex, Notify, RwLock};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+/// Failures talking to peers or handling bully messages
+#[derive(Debug, thiserror::Error)]
+pub enum BullyError {
+    #[error(transparent)]
+    Send(#[from] SendError),
+    #[error("timed out waiting for {0}")]
+    Timeout(String),
+    #[error("malformed bully message: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Frame(#[from] FrameError),
+    #[error("Node {0} is not a known peer")]
+    PeerUnknown(u32),
+    #[error("Node {0} is already in the peer list")]
+    DuplicatePeer(u32),
+    #[error("{0} is this node's own address")]
+    SelfAddress(String),
+    /// Cluster secret is set and the message was unsigned or carried a bad HMAC
+    #[error("bully message failed authentication")]
+    Unauthenticated,
+    #[error("unexpected response from {0}")]
+    UnexpectedResponse(String),
+    #[error("cannot derive UDP address from {0}")]
+    InvalidAddress(String),
+    #[error("this node is not the leader")]
+    NotLeader,
+}
+
+/// Why `handle_message` dropped a message without acting on it
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum Rejection {
+    /// The message claims to come from this node's own id
+    #[error("message claims to come from this node")]
+    FromSelf,
+    /// Another node at this address runs with our id
+    #[error("another node at {0} uses this node's id")]
+    DuplicateId(String),
+    /// COORDINATOR for a node that is not in the peer list
+    #[error("COORDINATOR names unknown Node {0}")]
+    UnknownLeader(u32),
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum BullyMessage {
-    Election { from_id: u32 },
-    Answer { from_id: u32 },
-    Coordinator { leader_id: u32 },
-    Heartbeat { from_id: u32 },
-    HeartbeatAck { from_id: u32 },
+    /// `from_address` is the sender's listening address, used to learn unknown
+    /// peers and correct stale entries
+    Election {
+        from_id: u32,
+        term: u64,
+        priority: u32,
+        #[serde(default)]
+        from_address: String,
+    },
+    Answer { from_id: u32, term: u64 },
+    /// `from_address` is learned as for Election, even when the claim itself
+    /// is dropped because the leader was unknown
+    Coordinator {
+        leader_id: u32,
+        term: u64,
+        priority: u32,
+        #[serde(default)]
+        from_address: String,
+    },
+    /// `load` is the sender's in-flight client request count
+    Heartbeat {
+        from_id: u32,
+        #[serde(default)]
+        address: Option<String>,
+        #[serde(default)]
+        load: u32,
+    },
+    /// Carries the responder's view of the leader so rival leaders can be spotted,
+    /// and its in-flight client request count
+    HeartbeatAck {
+        from_id: u32,
+        leader: Option<u32>,
+        #[serde(default)]
+        load: u32,
+    },
+    /// A new node announcing itself to the cluster
+    Join { id: u32, address: String, priority: u32 },
+    /// The leader is stepping down on purpose
+    Resign { from_id: u32 },
+    /// The leader asks `to_id` to take over; `term` is the leader's current term
+    TransferLeadership { to_id: u32, term: u64 },
+    /// Reply to Join with the current membership (id, address, priority) and leader
+    JoinAck {
+        peers: Vec<(u32, String, u32)>,
+        leader: Option<(u32, u64)>,
+        term: u64,
+    },
+    /// Reply to a Coordinator the receiver adopted
+    CoordinatorAck { from_id: u32 },
+    /// Reply to a message that claimed the receiver's own id; `address` is
+    /// where the receiver listens
+    DuplicateId { id: u32, address: String },
+}
+
+impl BullyMessage {
+    /// Id of the node that sent this message, if it carries one
+    pub fn sender_id(&self) -> Option<u32> {
+        match self {
+            BullyMessage::Election { from_id, .. }
+            | BullyMessage::Answer { from_id, .. }
+            | BullyMessage::Heartbeat { from_id, .. }
+            | BullyMessage::HeartbeatAck { from_id, .. }
+            | BullyMessage::CoordinatorAck { from_id }
+            | BullyMessage::Resign { from_id } => Some(*from_id),
+            BullyMessage::Coordinator { leader_id, .. } => Some(*leader_id),
+            BullyMessage::Join { id, .. } => Some(*id),
+            BullyMessage::JoinAck { .. }
+            | BullyMessage::TransferLeadership { .. }
+            | BullyMessage::DuplicateId { .. } => None,
+        }
+    }
+
+    /// Listening address the sender advertised, if the message carries one
+    pub fn sender_address(&self) -> Option<&str> {
+        match self {
+            BullyMessage::Election { from_address, .. } | BullyMessage::Coordinator { from_address, .. } => {
+                Some(from_address.as_str()).filter(|a| !a.is_empty())
+            }
+            BullyMessage::Heartbeat { address, .. } => address.as_deref(),
+            BullyMessage::Join { address, .. } => Some(address.as_str()),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct NodeInfo {
     pub id: u32,
     pub address: String,
+    /// Election priority, compared before the id
+    pub priority: u32,
+    /// Last time we received from, or successfully reached, this peer
+    pub last_seen: Option<Instant>,
+    /// Outcome of the most recent contact attempt
+    pub reachable: bool,
+}
+
+/// One peer as seen from this node, for operators
+#[derive(Debug, Clone)]
+pub struct PeerView {
+    pub id: u32,
+    pub address: String,
+    pub reachable: bool,
+    pub is_leader: bool,
+    /// Seconds since we last heard from the peer, `None` if never
+    pub last_seen_secs: Option<u64>,
+}
+
+/// Upper bound on the probe done by `cluster_view(true)`
+const VIEW_PROBE_TIMEOUT: Duration = Duration::from_millis(200);
+
+impl NodeInfo {
+    /// Election ordering: higher priority wins, ties broken by id
+    pub fn rank(&self) -> (u32, u32) {
+        (self.priority, self.id)
+    }
+}
+
+/// Who initiates heartbeats between the leader and its followers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeartbeatMode {
+    /// Followers poll the leader
+    Pull,
+    /// The leader broadcasts to followers
+    Push,
+}
+
+/// Socket type used for heartbeats; elections always use TCP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    Tcp,
+    Udp,
+}
+
+/// Where this node is in the election protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElectionState {
+    Idle,
+    /// Sending ELECTION messages to higher nodes
+    Electing { term: u64 },
+    /// A higher node answered, waiting for it to announce itself
+    AwaitingCoordinator { term: u64 },
+}
+
+/// Number of leadership transitions kept in the history
+pub const HISTORY_LEN: usize = 32;
+
+/// Why the leader this node follows changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransitionReason {
+    HeartbeatTimeout,
+    CoordinatorReceived,
+    SelfElected,
+    /// This node stepped down after losing contact with most of the cluster
+    LostQuorum,
+}
+
+impl TransitionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransitionReason::HeartbeatTimeout => "heartbeat_timeout",
+            TransitionReason::CoordinatorReceived => "coordinator_received",
+            TransitionReason::SelfElected => "self_elected",
+            TransitionReason::LostQuorum => "lost_quorum",
+        }
+    }
+}
+
+/// One entry in the leadership history
+#[derive(Debug, Clone)]
+pub struct LeaderTransition {
+    pub at: SystemTime,
+    pub old_leader: Option<u32>,
+    pub new_leader: Option<u32>,
+    pub reason: TransitionReason,
+}
+
+/// Which peers acknowledged this node's latest COORDINATOR announcement
+#[derive(Debug, Clone, Default)]
+pub struct AnnouncementStatus {
+    pub term: u64,
+    pub acked: HashSet<u32>,
+    /// Still being retried, or given up on once the retries ran out
+    pub unacked: HashSet<u32>,
+}
+
+/// Counters describing this node's election history
+#[derive(Debug, Clone, Default)]
+pub struct ElectionStats {
+    pub elections_started: u64,
+    pub elections_won: u64,
+    pub coordinators_received: u64,
+    pub heartbeat_failures: u64,
+    pub last_election_at: Option<SystemTime>,
+    pub last_election_duration: Option<Duration>,
+    /// When the current leader took over, as seen by this node
+    pub leader_since: Option<Instant>,
+    /// Smoothed heartbeat round-trip time to the leader
+    pub rtt_ewma: Option<Duration>,
+    /// Heartbeat timeout and check interval currently in effect
+    pub effective_heartbeat_timeout: Duration,
+    pub effective_heartbeat_interval: Duration,
+}
+
+impl ElectionStats {
+    pub fn leader_uptime(&self) -> Option<Duration> {
+        self.leader_since.map(|since| since.elapsed())
+    }
+}
+
+/// Smoothed round-trip time of heartbeats to the current leader
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RttEstimator {
+    leader_id: Option<u32>,
+    ewma: Option<Duration>,
+}
+
+impl RttEstimator {
+    /// Weight of each new sample, as in TCP's smoothed RTT
+    const ALPHA: f64 = 0.125;
+
+    /// Feed one successful heartbeat; samples for a new leader start over
+    pub fn record(&mut self, leader_id: u32, sample: Duration) {
+        if self.leader_id != Some(leader_id) {
+            self.leader_id = Some(leader_id);
+            self.ewma = None;
+        }
+        self.ewma = Some(match self.ewma {
+            Some(ewma) => ewma.mul_f64(1.0 - Self::ALPHA) + sample.mul_f64(Self::ALPHA),
+            None => sample,
+        });
+    }
+
+    pub fn ewma(&self) -> Option<Duration> {
+        self.ewma
+    }
+
+    /// `ewma * rtt_multiplier` within the configured bounds, or the fixed
+    /// timeout before the first sample or when adaptation is off
+    pub fn timeout(&self, config: &BullyConfig) -> Duration {
+        match self.ewma {
+            Some(ewma) if config.adaptive_heartbeat => (ewma * config.rtt_multiplier)
+                .clamp(config.min_heartbeat_timeout, config.max_heartbeat_timeout),
+            _ => config.heartbeat_timeout,
+        }
+    }
+
+    /// Check interval scaled with the timeout: short while the leader answers
+    /// quickly, backing off towards the maximum as it slows down
+    pub fn interval(&self, config: &BullyConfig) -> Duration {
+        if self.ewma.is_none() || !config.adaptive_heartbeat {
+            return config.heartbeat_interval;
+        }
+        let scale = self.timeout(config).as_secs_f64() / config.max_heartbeat_timeout.as_secs_f64();
+        config
+            .max_heartbeat_interval
+            .mul_f64(scale)
+            .max(config.min_heartbeat_interval)
+    }
+}
+
+/// Heartbeat intervals after which a peer's last reported load is no longer trusted
+pub const LOAD_STALE_INTERVALS: u32 = 3;
+
+/// Heartbeat round-trip distribution to one peer over its recent samples
+#[derive(Debug, Clone)]
+pub struct LatencyReport {
+    pub peer_id: u32,
+    pub samples: usize,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+impl LatencyReport {
+    fn from_samples(peer_id: u32, samples: &VecDeque<Duration>) -> Option<Self> {
+        let mut sorted: Vec<Duration> = samples.iter().copied().collect();
+        sorted.sort();
+        let max = *sorted.last()?;
+        // Nearest-rank percentile
+        let percentile = |p: f64| sorted[((p * sorted.len() as f64).ceil() as usize).max(1) - 1];
+        Some(LatencyReport {
+            peer_id,
+            samples: sorted.len(),
+            p50: percentile(0.50),
+            p95: percentile(0.95),
+            max,
+        })
+    }
+}
+
+/// How suspicious this node currently is of the leader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LeaderHealth {
+    pub consecutive_failures: u32,
+    pub threshold: u32,
+}
+
+impl LeaderHealth {
+    /// At least one heartbeat was missed
+    pub fn is_suspected(&self) -> bool {
+        self.consecutive_failures > 0
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.consecutive_failures >= self.threshold
+    }
+}
+
+/// Timing parameters for the election and heartbeat protocol
+#[derive(Debug, Clone)]
+pub struct BullyConfig {
+    /// How often followers check that the leader is alive
+    pub heartbeat_interval: Duration,
+    /// How long to wait for a heartbeat acknowledgment
+    pub heartbeat_timeout: Duration,
+    /// How long to wait when sending election messages to a peer
+    pub election_timeout: Duration,
+    /// Delay before starting our own election after answering a lower node
+    pub answer_wait: Duration,
+    /// How long to wait for a COORDINATOR after a higher node answered
+    pub coordinator_timeout: Duration,
+    pub heartbeat_mode: HeartbeatMode,
+    /// Consecutive missed heartbeats before the leader is declared dead
+    pub failure_threshold: u32,
+    pub heartbeat_transport: TransportKind,
+    /// UDP heartbeats use the node's TCP port plus this offset
+    pub udp_port_offset: u16,
+    /// Upper bound of the random delay before starting an election after a leader failure
+    pub election_jitter: Duration,
+    /// When set, every bully message must carry a valid HMAC under this secret
+    pub cluster_secret: Option<String>,
+    /// Initial wait before retrying when a majority of the cluster is unreachable
+    pub prevote_backoff: Duration,
+    /// This node's election priority; higher wins before node ids are compared
+    pub priority: u32,
+    /// How often the leader re-broadcasts COORDINATOR
+    pub reannounce_interval: Duration,
+    /// Extra COORDINATOR attempts for peers that did not acknowledge
+    pub coordinator_retries: u32,
+    /// Delay before the first COORDINATOR retry, doubling per attempt
+    pub coordinator_retry_backoff: Duration,
+    /// Share of the cluster, counting this node, a leader must reach to stay
+    /// leader; 0 disables self-demotion
+    pub leader_quorum_fraction: f64,
+    /// Consecutive checks below `leader_quorum_fraction` before the leader steps down
+    pub leader_quorum_checks: u32,
+    /// Extra ELECTION attempts before a higher peer is considered down
+    pub election_retries: u32,
+    /// Delay before the first ELECTION retry, growing linearly per attempt
+    pub election_retry_backoff: Duration,
+    /// Only self-promote after contacting a majority of the cluster, counting this node
+    pub quorum_mode: bool,
+    /// File the highest seen term is persisted to; `None` keeps it in memory only
+    pub state_path: Option<PathBuf>,
+    /// File the last known leader is persisted to, so a restart can rejoin it
+    /// without an election; `None` disables warm restarts
+    pub leader_state_path: Option<PathBuf>,
+    /// Derive the pull-mode heartbeat timeout and interval from the leader's round-trip time
+    pub adaptive_heartbeat: bool,
+    /// Adaptive timeout is the smoothed RTT times this factor
+    pub rtt_multiplier: u32,
+    pub min_heartbeat_timeout: Duration,
+    pub max_heartbeat_timeout: Duration,
+    pub min_heartbeat_interval: Duration,
+    pub max_heartbeat_interval: Duration,
+    /// Follow COORDINATOR announcements from nodes missing from the peer
+    /// list; otherwise such an announcement is refused, but its sender is
+    /// added as a peer
+    pub accept_unknown_leaders: bool,
+    /// Heartbeat round-trip samples kept per peer for the latency report
+    pub rtt_history_len: usize,
+}
+
+impl Default for BullyConfig {
+    fn default() -> Self {
+        BullyConfig {
+            heartbeat_interval: Duration::from_secs(5),
+            heartbeat_timeout: Duration::from_secs(2),
+            election_timeout: Duration::from_secs(2),
+            answer_wait: Duration::from_millis(100),
+            coordinator_timeout: Duration::from_secs(5),
+            heartbeat_mode: HeartbeatMode::Pull,
+            failure_threshold: 3,
+            heartbeat_transport: TransportKind::Tcp,
+            udp_port_offset: 1000,
+            election_jitter: Duration::from_millis(500),
+            cluster_secret: None,
+            prevote_backoff: Duration::from_secs(1),
+            priority: 0,
+            reannounce_interval: Duration::from_secs(15),
+            coordinator_retries: 3,
+            coordinator_retry_backoff: Duration::from_millis(200),
+            leader_quorum_fraction: 0.5,
+            leader_quorum_checks: 3,
+            election_retries: 2,
+            election_retry_backoff: Duration::from_millis(100),
+            quorum_mode: false,
+            state_path: None,
+            leader_state_path: None,
+            adaptive_heartbeat: false,
+            rtt_multiplier: 4,
+            min_heartbeat_timeout: Duration::from_millis(200),
+            max_heartbeat_timeout: Duration::from_secs(2),
+            min_heartbeat_interval: Duration::from_secs(1),
+            max_heartbeat_interval: Duration::from_secs(10),
+            accept_unknown_leaders: false,
+            rtt_history_len: 256,
+        }
+    }
+}
+
+/// Read the persisted term, starting from 0 if there is none
+fn load_term(node_id: u32, path: Option<&Path>) -> u64 {
+    let Some(path) = path else {
+        return 0;
+    };
+    match std::fs::read_to_string(path) {
+        Ok(contents) => match contents.trim().parse() {
+            Ok(term) => {
+                info!("Node {}: Resuming at term {}", node_id, term);
+                term
+            }
+            Err(_) => {
+                warn!("Node {}: Corrupt term file {}, starting at term 0", node_id, path.display());
+                0
+            }
+        },
+        Err(e) => {
+            warn!("Node {}: Cannot read term file {} ({}), starting at term 0", node_id, path.display(), e);
+            0
+        }
+    }
+}
+
+/// Last leader this node followed, as persisted across restarts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SavedLeader {
+    id: u32,
+    address: String,
+    term: u64,
+}
+
+/// Read the persisted leader, if any
+fn load_leader(node_id: u32, path: Option<&Path>) -> Option<SavedLeader> {
+    let contents = std::fs::read_to_string(path?).ok()?;
+    match serde_json::from_str(&contents) {
+        Ok(saved) => Some(saved),
+        Err(e) => {
+            warn!("Node {}: Ignoring corrupt leader file: {}", node_id, e);
+            None
+        }
+    }
+}
+
+/// Replace a state file atomically: write a sibling, fsync it, rename over
+fn write_state_file(path: &Path, contents: &str) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&tmp)?;
+    std::io::Write::write_all(&mut file, contents.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Handle to the background tasks started by `start_leader_monitoring`
+///
+/// Dropping the handle also stops monitoring.
+pub struct MonitorHandle {
+    shutdown: watch::Sender<bool>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl MonitorHandle {
+    /// Signal every monitoring task to stop and wait for them to exit
+    pub async fn shutdown(self) {
+        let _ = self.shutdown.send(true);
+        for task in self.tasks {
+            let _ = task.await;
+        }
+    }
+}
+
+/// Long-lived connection from a follower to the leader used for heartbeats
+struct HeartbeatConnection {
+    leader_id: u32,
+    stream: TcpStream,
 }
 
 pub struct BullyElection {
     pub node_id: u32,
     pub node_address: String,
+    pub config: BullyConfig,
     pub peers: Arc<RwLock<HashMap<u32, NodeInfo>>>,
     pub current_leader: Arc<RwLock<Option<u32>>>,
-    pub leader_alive: Arc<RwLock<bool>>,
+    /// Highest election term this node has seen
+    pub current_term: Arc<RwLock<u64>>,
+    /// Term in which the current leader was elected
+    pub leader_term: Arc<RwLock<u64>>,
+    /// Consecutive heartbeats the leader has missed
+    pub leader_failures: Arc<RwLock<u32>>,
+    /// When the leader last pushed a heartbeat to us (push mode) or acked ours (pull mode)
+    pub last_heartbeat_received: Arc<RwLock<Instant>>,
+    pub election_state: Arc<RwLock<ElectionState>>,
+    /// Held for a whole election run, up to and including our own COORDINATOR
+    /// broadcast, so starts that arrive meanwhile are dropped
+    election_running: Arc<Mutex<()>>,
+    pub stats: Arc<RwLock<ElectionStats>>,
+    /// Other nodes that claimed leadership while we were leader
+    pub rival_leaders: Arc<RwLock<HashSet<u32>>>,
+    /// Set once this node has resigned; it no longer takes part in elections
+    pub resigned: Arc<RwLock<bool>>,
+    /// Set once another node turned out to share our id; elections are refused from then on
+    pub degraded: Arc<RwLock<bool>>,
+    /// Set while this node is cut off from the majority after stepping down;
+    /// it holds off elections until the cluster is reachable again
+    pub partitioned: Arc<RwLock<bool>>,
+    /// Last `HISTORY_LEN` leadership transitions, oldest first
+    ///
+    /// A plain mutex: it is never held across an await.
+    history: Arc<std::sync::Mutex<VecDeque<LeaderTransition>>>,
+    /// Heartbeat round-trip estimate used by adaptive timing
+    rtt: Arc<RwLock<RttEstimator>>,
+    /// Last `rtt_history_len` heartbeat round trips per peer, oldest first
+    latency: Arc<std::sync::Mutex<HashMap<u32, VecDeque<Duration>>>>,
+    /// This node's in-flight client requests, advertised on heartbeats
+    pub load: Arc<AtomicU32>,
+    /// Last load each peer reported on a heartbeat, and when
+    peer_load: Arc<std::sync::Mutex<HashMap<u32, (u32, Instant)>>>,
+    /// Peer we are handing leadership to; its COORDINATOR is accepted even though it ranks lower
+    transfer_target: Arc<RwLock<Option<u32>>>,
+    /// Acks for the latest COORDINATOR this node sent
+    announcement: Arc<RwLock<AnnouncementStatus>>,
+    coordinator_notify: Arc<Notify>,
+    /// Woken when the current leader's heartbeat arrives, restarting the monitor's countdown
+    leader_alive: Arc<Notify>,
+    leader_tx: Arc<watch::Sender<Option<u32>>>,
+    heartbeat_conn: Arc<Mutex<Option<HeartbeatConnection>>>,
+    codec: MessageCodec,
+    /// How election messages reach peers
+    transport: Arc<dyn Transport>,
 }
 
 impl BullyElection {
-    pub fn new(node_id: u32, node_address: String) -> Self {
+    pub fn new_with_config(node_id: u32, node_address: String, config: BullyConfig) -> Self {
+        let transport = TcpTransport {
+            codec: MessageCodec {
+                node_id,
+                cluster_secret: config.cluster_secret.clone(),
+            },
+            timeout: config.election_timeout,
+        };
+        Self::new_with_transport(node_id, node_address, config, Arc::new(transport))
+    }
+
+    /// Build a node that sends its election messages through `transport`
+    ///
+    /// Heartbeats still go over TCP or UDP directly.
+    pub fn new_with_transport(
+        node_id: u32,
+        node_address: String,
+        config: BullyConfig,
+        transport: Arc<dyn Transport>,
+    ) -> Self {
+        let codec = MessageCodec {
+            node_id,
+            cluster_secret: config.cluster_secret.clone(),
+        };
+        let term = load_term(node_id, config.state_path.as_deref());
         BullyElection {
             node_id,
             node_address,
+            config,
             peers: Arc::new(RwLock::new(HashMap::new())),
             current_leader: Arc::new(RwLock::new(None)),
-            leader_alive: Arc::new(RwLock::new(true)),
+            current_term: Arc::new(RwLock::new(term)),
+            leader_term: Arc::new(RwLock::new(0)),
+            leader_failures: Arc::new(RwLock::new(0)),
+            last_heartbeat_received: Arc::new(RwLock::new(Instant::now())),
+            election_state: Arc::new(RwLock::new(ElectionState::Idle)),
+            election_running: Arc::new(Mutex::new(())),
+            stats: Arc::new(RwLock::new(ElectionStats::default())),
+            rival_leaders: Arc::new(RwLock::new(HashSet::new())),
+            resigned: Arc::new(RwLock::new(false)),
+            degraded: Arc::new(RwLock::new(false)),
+            partitioned: Arc::new(RwLock::new(false)),
+            history: Arc::new(std::sync::Mutex::new(VecDeque::with_capacity(HISTORY_LEN))),
+            transfer_target: Arc::new(RwLock::new(None)),
+            announcement: Arc::new(RwLock::new(AnnouncementStatus::default())),
+            rtt: Arc::new(RwLock::new(RttEstimator::default())),
+            latency: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            load: Arc::new(AtomicU32::new(0)),
+            peer_load: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            coordinator_notify: Arc::new(Notify::new()),
+            leader_alive: Arc::new(Notify::new()),
+            leader_tx: Arc::new(watch::channel(None).0),
+            heartbeat_conn: Arc::new(Mutex::new(None)),
+            codec,
+            transport,
         }
     }
 
-    pub async fn add_peer(&self, id: u32, address: String) {
+    /// Add a peer, refusing an id that is already taken by this node or another peer
+    pub async fn add_peer(&self, id: u32, address: String, priority: u32) -> Result<(), BullyError> {
+        if address == self.node_address {
+            return Err(BullyError::SelfAddress(address));
+        }
         let mut peers = self.peers.write().await;
-        peers.insert(id, NodeInfo { id, address });
-    }
-
-    pub async fn get_leader(&self) -> Option<u32> {
-        *self.current_leader.read().await
+        if id == self.node_id || peers.contains_key(&id) {
+            return Err(BullyError::DuplicatePeer(id));
+        }
+        peers.insert(
+            id,
+            NodeInfo {
+                id,
+                address,
+                priority,
+                last_seen: None,
+                reachable: false,
+            },
+        );
+        Ok(())
     }
 
-    pub async fn set_leader(&self, leader_id: u32) {
-        let mut leader = self.current_leader.write().await;
-        *leader = Some(leader_id);
-        let mut alive = self.leader_alive.write().await;
-        *alive = true;
-        println!("Node {}: New leader is Node {}", self.node_id, leader_id);
+    /// Record the outcome of contacting a peer
+    async fn mark_peer(&self, id: u32, reachable: bool) {
+        if let Some(peer) = self.peers.write().await.get_mut(&id) {
+            if reachable {
+                peer.last_seen = Some(Instant::now());
+            }
+            peer.reachable = reachable;
+        }
     }
 
-    pub async fn is_leader(&self) -> bool {
-        if let Some(leader_id) = self.get_leader().await {
-            leader_id == self.node_id
-        } else {
-            false
+    /// Same as `mark_peer`, for callers that only know the address
+    async fn mark_peer_address(&self, address: &str, reachable: bool) {
+        let mut peers = self.peers.write().await;
+        for peer in peers.values_mut().filter(|p| p.address == address) {
+            if reachable {
+                peer.last_seen = Some(Instant::now());
+            }
+            peer.reachable = reachable;
         }
     }
 
-    pub async fn get_all_peers(&self) -> Vec<(u32, String)> {
+    /// Seconds since each peer was last heard from, `None` if never
+    pub async fn peer_liveness(&self) -> HashMap<u32, Option<u64>> {
         let peers = self.peers.read().await;
-        peers.iter()
-            .map(|(id, info)| (*id, info.address.clone()))
+        peers
+            .values()
+            .map(|p| (p.id, p.last_seen.map(|t| t.elapsed().as_secs())))
             .collect()
     }
 
-    /// Start monitoring the leader with heartbeats
-    pub async fn start_leader_monitoring(self: Arc<Self>) {
-        tokio::spawn(async move {
-            loop {
-                sleep(Duration::from_secs(5)).await;
-
-                let leader_id = {
-                    let leader = self.current_leader.read().await;
-                    *leader
-                };
-
-                // If I'm not the leader, check if leader is alive
-                if let Some(leader_id) = leader_id {
-                    if leader_id != self.node_id {
-                        let is_alive = self.check_leader_alive(leader_id).await;
-
-                        if !is_alive {
-                            println!("Node {}: Leader {} is DOWN! Starting new election...",
-                                self.node_id, leader_id);
-                            self.start_election().await;
-                        }
-                    }
-                }
+    /// Point a known peer at a new address
+    ///
+    /// A heartbeat connection still open to the old address is dropped.
+    pub async fn update_peer(&self, id: u32, new_address: String) -> Result<(), BullyError> {
+        {
+            let mut peers = self.peers.write().await;
+            let peer = peers.get_mut(&id).ok_or(BullyError::PeerUnknown(id))?;
+            if peer.address == new_address {
+                return Ok(());
             }
-        });
-    }
+            info!(
+                "Node {}: Node {} moved from {} to {}",
+                self.node_id, id, peer.address, new_address
+            );
+            peer.address = new_address;
+        }
 
-    /// Check if the leader is alive by sending heartbeat
-    async fn check_leader_alive(&self, leader_id: u32) -> bool {
-        let peers = self.peers.read().await;
-        if let Some(leader_info) = peers.get(&leader_id) {
-            match self.send_heartbeat(&leader_info.address).await {
-                Ok(true) => {
-                    let mut alive = self.leader_alive.write().await;
-                    *alive = true;
-                    true
-                }
-                _ => {
-                    let mut alive = self.leader_alive.write().await;
-                    *alive = false;
-                    false
-                }
-            }
-        } else {
-            false
+        let mut conn = self.heartbeat_conn.lock().await;
+        if conn.as_ref().map(|c| c.leader_id) == Some(id) {
+            *conn = None;
         }
+        Ok(())
     }
 
-    /// Send heartbeat to leader
-    async fn send_heartbeat(&self, address: &str) -> Result<bool, String> {
-        let result = timeout(Duration::from_secs(2), async {
-            let mut stream = TcpStream::connect(address).await
-                .map_err(|e| e.to_string())?;
-
-            let msg = BullyMessage::Heartbeat { from_id: self.node_id };
-            let msg_json = serde_json::to_string(&msg)
-                .map_err(|e| e.to_string())?;
-            stream.write_all(msg_json.as_bytes()).await
-                .map_err(|e| e.to_string())?;
-            stream.write_all(b"\n").await
-                .map_err(|e| e.to_string())?;
+    /// Heartbeat from this node, advertising where it listens
+    fn heartbeat_message(&self) -> BullyMessage {
+        BullyMessage::Heartbeat {
+            from_id: self.node_id,
+            address: Some(self.node_address.clone()),
+            load: self.load.load(Ordering::Relaxed),
+        }
+    }
 
-            let mut buffer = vec![0u8; 1024];
-            let n = stream.read(&mut buffer).await
-                .map_err(|e| e.to_string())?;
+    /// This node's election ordering, comparable with `NodeInfo::rank`
+    pub fn rank(&self) -> (u32, u32) {
+        (self.config.priority, self.node_id)
+    }
 
-            if n > 0 {
-                if let Ok(BullyMessage::HeartbeatAck { .. }) = serde_json::from_slice(&buffer[..n]) {
-                    return Ok::<bool, String>(true);
-                }
-            }
-            Ok(false)
-        })
-        .await;
+    /// Record the priority a peer advertised in one of its messages
+    async fn learn_priority(&self, id: u32, priority: u32) {
+        if let Some(peer) = self.peers.write().await.get_mut(&id) {
+            peer.priority = priority;
+        }
+    }
 
-        match result {
-            Ok(Ok(ack)) => Ok(ack),
-            _ => Ok(false),
+    /// Add or re-address a peer from the address it advertised in a message
+    ///
+    /// Empty or unparsable addresses, and our own, are ignored.
+    async fn learn_peer(&self, id: u32, address: String, priority: u32) {
+        if address.is_empty() {
+            return;
+        }
+        if address.parse::<SocketAddr>().is_err() || address == self.node_address {
+            warn!("Node {}: Ignoring bogus address {:?} from Node {}", self.node_id, address, id);
+            return;
+        }
+        if self.get_peer(id).await.is_some() {
+            let _ = self.update_peer(id, address).await;
+        } else if self.add_peer(id, address.clone(), priority).await.is_ok() {
+            info!("Node {}: Learned Node {} at {}", self.node_id, id, address);
         }
     }
 
-    /// Start an election
-    pub async fn start_election(&self) {
-        println!("Node {}: Starting election", self.node_id);
+    /// Join an existing cluster through a seed node instead of running a blind election
+    ///
+    /// Fails if the seed could not be reached or did not answer with a JoinAck.
+    pub async fn join(&self, seed_address: &str) -> Result<(), BullyError> {
+        let msg = BullyMessage::Join {
+            id: self.node_id,
+            address: self.node_address.clone(),
+            priority: self.config.priority,
+        };
+        let response = self.send_message(seed_address, msg).await?;
 
-        let peers = self.peers.read().await.clone();
-        let higher_nodes: Vec<_> = peers
-            .iter()
-            .filter(|(id, _)| **id > self.node_id)
-            .collect();
+        let Some(BullyMessage::JoinAck { peers, leader, term }) = response else {
+            return Err(BullyError::UnexpectedResponse(seed_address.to_string()));
+        };
 
-        if higher_nodes.is_empty() {
-            // I have the highest ID, I'm the leader
-            println!("Node {}: I am the new leader!", self.node_id);
-            self.set_leader(self.node_id).await;
-            self.announce_coordinator().await;
-            return;
+        for (id, address, priority) in peers {
+            if id == self.node_id {
+                continue;
+            }
+            if self.get_peer(id).await.is_some() {
+                self.update_peer(id, address).await?;
+                self.learn_priority(id, priority).await;
+            } else {
+                self.add_peer(id, address, priority).await?;
+            }
         }
+        self.observe_term(term).await;
 
-        // Send ELECTION message to all higher nodes
-        let mut received_answer = false;
-
-        for (_peer_id, peer_info) in higher_nodes {
-            match self
-                .send_message(&peer_info.address, BullyMessage::Election { from_id: self.node_id })
-                .await
-            {
-                Ok(Some(BullyMessage::Answer { .. })) => {
-                    received_answer = true;
-                }
-                _ => {}
+        match leader {
+            Some((leader_id, leader_term)) => {
+                info!("Node {}: Joined cluster, adopting leader Node {}", self.node_id, leader_id);
+                self.set_leader(leader_id, leader_term).await;
             }
+            None => info!("Node {}: Joined cluster with no known leader", self.node_id),
         }
 
-        if !received_answer {
-            // No one responded, I'm the leader
-            println!("Node {}: No response, I am the new leader!", self.node_id);
-            self.set_leader(self.node_id).await;
-            self.announce_coordinator().await;
-        } else {
-            // Wait for coordinator announcement
-            println!(
-                "Node {}: Received answer, waiting for coordinator announcement",
-                self.node_id
-            );
+        // Outranking the leader still means taking over, but only after one
+        // heartbeat interval so the cluster has settled on our membership first
+        let outranks_leader = match leader {
+            Some((leader_id, _)) => match self.get_peer(leader_id).await {
+                Some(leader_info) => leader_info.rank() < self.rank(),
+                None => false,
+            },
+            None => true,
+        };
+        if outranks_leader {
+            tokio::spawn({
+                let bully = self.clone();
+                async move {
+                    sleep(bully.config.heartbeat_interval).await;
+                    bully.start_election().await;
+                }
+            });
         }
-    }
 
-    /// Announce that this node is the coordinator
-    async fn announce_coordinator(&self) {
-        let peers = self.peers.read().await.clone();
+        Ok(())
+    }
 
-        for (_, peer_info) in peers.iter() {
+    /// Pass a join on to every other peer so membership converges
+    async fn forward_join(&self, id: u32, address: String, priority: u32) {
+        let peers = self.get_all_peers().await;
+        for peer in peers.iter().filter(|p| p.id != id) {
             let _ = self
                 .send_message(
-                    &peer_info.address,
-                    BullyMessage::Coordinator {
-                        leader_id: self.node_id,
+                    &peer.address,
+                    BullyMessage::Join {
+                        id,
+                        address: address.clone(),
+                        priority,
                     },
                 )
                 .await;
         }
     }
 
-    /// Handle incoming Bully messages
-    pub async fn handle_message(&self, msg: BullyMessage) -> Option<BullyMessage> {
-        match msg {
-            BullyMessage::Election { from_id } => {
-                println!(
-                    "Node {}: Received ELECTION from Node {}",
-                    self.node_id, from_id
-                );
+    /// Remove a departed peer
+    ///
+    /// If the removed peer was the leader a new election is started.
+    pub async fn remove_peer(&self, id: u32) -> Result<(), BullyError> {
+        self.peers
+            .write()
+            .await
+            .remove(&id)
+            .ok_or(BullyError::PeerUnknown(id))?;
+        info!("Node {}: Removed peer Node {}", self.node_id, id);
+        self.latency.lock().unwrap().remove(&id);
+        self.peer_load.lock().unwrap().remove(&id);
 
-                if self.node_id > from_id {
-                    // Respond with ANSWER and start own election
-                    tokio::spawn({
-                        let bully = self.clone();
-                        async move {
-                            sleep(Duration::from_millis(100)).await;
-                            bully.start_election().await;
-                        }
-                    });
+        let was_leader = self.clear_leader_if(id).await;
 
-                    return Some(BullyMessage::Answer {
-                        from_id: self.node_id,
-                    });
+        if was_leader {
+            *self.heartbeat_conn.lock().await = None;
+            info!("Node {}: Removed peer was the leader, starting new election", self.node_id);
+            tokio::spawn({
+                let bully = self.clone();
+                async move {
+                    bully.start_election().await;
                 }
-                None
-            }
-            BullyMessage::Coordinator { leader_id } => {
-                println!(
-                    "Node {}: Received COORDINATOR announcement - Node {} is leader",
-                    self.node_id, leader_id
-                );
-                self.set_leader(leader_id).await;
-                None
-            }
-            BullyMessage::Heartbeat { from_id: _ } => {
-                // Respond with heartbeat acknowledgment
-                Some(BullyMessage::HeartbeatAck {
-                    from_id: self.node_id,
-                })
-            }
-            BullyMessage::HeartbeatAck { .. } => {
-                // Just note the acknowledgment
-                None
-            }
-            _ => None,
+            });
         }
+
+        Ok(())
     }
 
-    /// Send a message to a peer
-    async fn send_message(
-        &self,
-        address: &str,
-        message: BullyMessage,
-    ) -> Result<Option<BullyMessage>, Box<dyn std::error::Error>> {
-        let result = timeout(Duration::from_secs(2), async {
-            let mut stream = TcpStream::connect(address).await?;
-
-            // Send message
-            let msg_json = serde_json::to_string(&message)?;
-            stream.write_all(msg_json.as_bytes()).await?;
-            stream.write_all(b"\n").await?;
-
-            // Wait for response if needed
-            match message {
-                BullyMessage::Election { .. } => {
-                    let mut buffer = vec![0u8; 1024];
-                    let n = stream.read(&mut buffer).await?;
-                    if n > 0 {
-                        let response: BullyMessage = serde_json::from_slice(&buffer[..n])?;
-                        Ok::<Option<BullyMessage>, Box<dyn std::error::Error>>(Some(response))
-                    } else {
-                        Ok(None)
-                    }
-                }
-                _ => Ok(None),
-            }
-        })
-        .await;
+    /// Step down as leader ahead of a planned shutdown
+    ///
+    /// Peers are told immediately instead of waiting for heartbeats to fail,
+    /// and this node stays out of elections until it is restarted.
+    pub async fn resign(&self) {
+        if !self.is_leader().await {
+            return;
+        }
+        info!("Node {}: Resigning leadership", self.node_id);
 
-        match result {
-            Ok(Ok(response)) => Ok(response),
-            _ => Err("Timeout or error".into()),
+        *self.resigned.write().await = true;
+        self.clear_leader_if(self.node_id).await;
+
+        for peer in self.get_all_peers().await {
+            let _ = self
+                .send_message(&peer.address, BullyMessage::Resign { from_id: self.node_id })
+                .await;
         }
     }
 
-    pub fn clone(&self) -> Self {
-        BullyElection {
-            node_id: self.node_id,
-            node_address: self.node_address.clone(),
-            peers: Arc::clone(&self.peers),
-            current_leader: Arc::clone(&self.current_leader),
-            leader_alive: Arc::clone(&self.leader_alive),
+    /// Hand leadership to a specific peer ahead of planned maintenance
+    ///
+    /// Only the leader can transfer. If the target has not announced itself
+    /// within `coordinator_timeout`, this node re-asserts its leadership and
+    /// the transfer fails with `BullyError::Timeout`.
+    pub async fn transfer_leadership(&self, target_id: u32) -> Result<(), BullyError> {
+        if !self.is_leader().await {
+            return Err(BullyError::NotLeader);
         }
+        let target = self
+            .get_peer(target_id)
+            .await
+            .ok_or(BullyError::PeerUnknown(target_id))?;
+        info!("Node {}: Transferring leadership to Node {}", self.node_id, target_id);
+
+        let mut leader_rx = self.subscribe();
+        *self.transfer_target.write().await = Some(target_id);
+
+        let msg = BullyMessage::TransferLeadership {
+            to_id: target_id,
+            term: self.get_term().await,
+        };
+        if let Err(e) = self.send_message(&target.address, msg).await {
+            *self.transfer_target.write().await = None;
+            return Err(e.into());
+        }
+
+        let handed_over = matches!(
+            timeout(
+                self.config.coordinator_timeout,
+                leader_rx.wait_for(|leader| *leader == Some(target_id)),
+            )
+            .await,
+            Ok(Ok(_))
+        );
+        *self.transfer_target.write().await = None;
+
+        if handed_over {
+            return Ok(());
+        }
+        warn!(
+            "Node {}: Node {} did not take over, resuming leadership",
+            self.node_id, target_id
+        );
+        self.reassert_leadership().await;
+        Err(BullyError::Timeout(format!("Node {} to take over", target_id)))
+    }
+
+    /// Take over after the leader asked us to
+    ///
+    /// With a reachable majority we announce ourselves directly, otherwise
+    /// the normal election decides.
+    async fn accept_transfer(&self) {
+        if self.pre_vote().await {
+            self.reassert_leadership().await;
+        } else {
+            self.start_election().await;
+        }
+    }
+
+    pub async fn get_leader(&self) -> Option<u32> {
+        *self.current_leader.read().await
+    }
+
+    /// Get the current leader together with the term it was elected in
+    pub async fn get_leader_with_term(&self) -> Option<(u32, u64)> {
+        let leader = self.current_leader.read().await;
+        let term = *self.leader_term.read().await;
+        leader.map(|id| (id, term))
+    }
+
+    pub async fn get_term(&self) -> u64 {
+        *self.current_term.read().await
+    }
+
+    /// Raise the local term if `term` is newer
+    async fn observe_term(&self, term: u64) {
+        let mut current = self.current_term.write().await;
+        if term > *current {
+            *current = term;
+            self.persist_term(term);
+        }
+    }
+
+    /// Start a new term, persisted before anything carrying it is sent
+    async fn bump_term(&self) -> u64 {
+        let mut current = self.current_term.write().await;
+        *current += 1;
+        self.persist_term(*current);
+        *current
+    }
+
+    /// Write the term to the state file and fsync it
+    ///
+    /// Called with the term lock held so writes land in order.
+    fn persist_term(&self, term: u64) {
+        let Some(path) = &self.config.state_path else {
+            return;
+        };
+        if let Err(e) = write_state_file(path, &term.to_string()) {
+            warn!("Node {}: Failed to persist term {} to {}: {}", self.node_id, term, path.display(), e);
+        }
+    }
+
+    /// Remember the leader we follow so a restart can rejoin it quietly
+    async fn persist_leader(&self, leader_id: u32, term: u64) {
+        let Some(path) = &self.config.leader_state_path else {
+            return;
+        };
+        let Some(leader) = self.get_peer(leader_id).await else {
+            return;
+        };
+        let saved = SavedLeader {
+            id: leader_id,
+            address: leader.address,
+            term,
+        };
+        let result = serde_json::to_string(&saved)
+            .map_err(std::io::Error::from)
+            .and_then(|json| write_state_file(path, &json));
+        if let Err(e) = result {
+            warn!("Node {}: Failed to persist leader to {}: {}", self.node_id, path.display(), e);
+        }
+    }
+
+    /// Adopt the leader remembered from before a restart if it still leads
+    ///
+    /// The remembered leader is sent one heartbeat; it is adopted only if it
+    /// answers and still names itself as leader. Returns whether it was.
+    pub async fn resume_leader(&self) -> bool {
+        let Some(saved) = load_leader(self.node_id, self.config.leader_state_path.as_deref()) else {
+            return false;
+        };
+        if saved.id == self.node_id {
+            return false;
+        }
+
+        let reply = self.send_message(&saved.address, self.heartbeat_message()).await;
+        match reply {
+            Ok(Some(BullyMessage::HeartbeatAck { from_id, leader: Some(leader), .. }))
+                if from_id == saved.id && leader == saved.id =>
+            {
+                info!(
+                    "Node {}: Remembered leader Node {} is still leading, rejoining without an election",
+                    self.node_id, saved.id
+                );
+                if self.get_peer(saved.id).await.is_some() {
+                    let _ = self.update_peer(saved.id, saved.address).await;
+                }
+                self.set_leader(saved.id, saved.term).await;
+                true
+            }
+            _ => {
+                info!("Node {}: Remembered leader Node {} is gone, electing", self.node_id, saved.id);
+                false
+            }
+        }
+    }
+
+    pub async fn set_leader(&self, leader_id: u32, term: u64) {
+        self.observe_term(term).await;
+        let mut leader = self.current_leader.write().await;
+        let changed = *leader != Some(leader_id);
+        if changed {
+            let reason = if leader_id == self.node_id {
+                TransitionReason::SelfElected
+            } else {
+                TransitionReason::CoordinatorReceived
+            };
+            self.record_transition(*leader, Some(leader_id), reason);
+            let mut stats = self.stats.write().await;
+            stats.leader_since = Some(Instant::now());
+            if leader_id == self.node_id {
+                stats.elections_won += 1;
+            }
+        }
+        *leader = Some(leader_id);
+        self.notify_leader(Some(leader_id));
+        *self.leader_term.write().await = term;
+        *self.leader_failures.write().await = 0;
+        *self.last_heartbeat_received.write().await = Instant::now();
+        *self.election_state.write().await = ElectionState::Idle;
+        self.coordinator_notify.notify_waiters();
+        drop(leader);
+        info!("Node {}: New leader is Node {} (term {})", self.node_id, leader_id, term);
+
+        if changed && leader_id != self.node_id {
+            self.persist_leader(leader_id, term).await;
+        }
+    }
+
+    /// Append to the leadership history, dropping the oldest entry when full
+    fn record_transition(&self, old_leader: Option<u32>, new_leader: Option<u32>, reason: TransitionReason) {
+        let mut history = self.history.lock().unwrap();
+        if history.len() == HISTORY_LEN {
+            history.pop_front();
+        }
+        history.push_back(LeaderTransition {
+            at: SystemTime::now(),
+            old_leader,
+            new_leader,
+            reason,
+        });
+    }
+
+    /// Add one heartbeat round trip to a peer's sample window
+    fn record_latency(&self, peer_id: u32, sample: Duration) {
+        if self.config.rtt_history_len == 0 {
+            return;
+        }
+        let mut latency = self.latency.lock().unwrap();
+        let samples = latency.entry(peer_id).or_default();
+        if samples.len() >= self.config.rtt_history_len {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    /// Heartbeat round-trip percentiles per peer, ordered by peer id
+    ///
+    /// Followers only measure the leader; the leader measures every follower
+    /// in push mode over TCP.
+    pub fn latency_report(&self) -> Vec<LatencyReport> {
+        let latency = self.latency.lock().unwrap();
+        let mut report: Vec<LatencyReport> = latency
+            .iter()
+            .filter_map(|(id, samples)| LatencyReport::from_samples(*id, samples))
+            .collect();
+        report.sort_by_key(|r| r.peer_id);
+        report
+    }
+
+    /// Remember the load a peer reported on a heartbeat
+    fn record_load(&self, peer_id: u32, load: u32) {
+        self.peer_load.lock().unwrap().insert(peer_id, (load, Instant::now()));
+    }
+
+    /// Last reported load per peer, `None` if it never reported or has not
+    /// for `LOAD_STALE_INTERVALS` heartbeat intervals
+    pub fn peer_loads(&self) -> HashMap<u32, Option<u32>> {
+        // Followers may stretch their pull interval up to the maximum
+        let interval = match self.config.heartbeat_mode {
+            HeartbeatMode::Pull if self.config.adaptive_heartbeat => self.config.max_heartbeat_interval,
+            _ => self.config.heartbeat_interval,
+        };
+        let stale_after = interval * LOAD_STALE_INTERVALS;
+        let peer_load = self.peer_load.lock().unwrap();
+        peer_load
+            .iter()
+            .map(|(id, (load, at))| (*id, (at.elapsed() <= stale_after).then_some(*load)))
+            .collect()
+    }
+
+    /// Recent leadership transitions, oldest first
+    pub fn leader_history(&self) -> Vec<LeaderTransition> {
+        self.history.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Where this node currently is in the election protocol
+    pub async fn election_status(&self) -> ElectionState {
+        *self.election_state.read().await
+    }
+
+    /// Forget the leader if it is `id`, returning whether it was
+    async fn clear_leader_if(&self, id: u32) -> bool {
+        let mut leader = self.current_leader.write().await;
+        if *leader != Some(id) {
+            return false;
+        }
+        *leader = None;
+        self.notify_leader(None);
+        self.stats.write().await.leader_since = None;
+        true
+    }
+
+    /// Publish a leader change to subscribers, skipping no-op updates
+    fn notify_leader(&self, leader: Option<u32>) {
+        self.leader_tx.send_if_modified(|current| {
+            if *current == leader {
+                false
+            } else {
+                *current = leader;
+                true
+            }
+        });
+    }
+
+    /// Watch for leadership changes, including losing the leader altogether
+    pub fn subscribe(&self) -> watch::Receiver<Option<u32>> {
+        self.leader_tx.subscribe()
+    }
+
+    pub async fn is_leader(&self) -> bool {
+        if let Some(leader_id) = self.get_leader().await {
+            leader_id == self.node_id
+        } else {
+            false
+        }
+    }
+
+    /// Whether this node follows a leader that is known to be alive
+    ///
+    /// False while an election is running, no leader is known, or the leader
+    /// has not been heard from within the last heartbeat window.
+    pub async fn cluster_healthy(&self) -> bool {
+        if *self.degraded.read().await || *self.election_state.read().await != ElectionState::Idle {
+            return false;
+        }
+        let Some(leader_id) = self.get_leader().await else {
+            return false;
+        };
+        if leader_id == self.node_id {
+            return true;
+        }
+        if *self.leader_failures.read().await > 0 {
+            return false;
+        }
+        let window = self.heartbeat_check_interval().await + self.heartbeat_timeout().await;
+        self.last_heartbeat_received.read().await.elapsed() <= window
+    }
+
+    /// Every peer with its reachability and whether it is our leader
+    ///
+    /// Uses cached liveness; with `refresh` all peers are probed in parallel
+    /// first, waiting at most 200ms.
+    pub async fn cluster_view(&self, refresh: bool) -> Vec<PeerView> {
+        if refresh {
+            let peers = self.get_all_peers().await;
+            self.probe_reachable(&peers, VIEW_PROBE_TIMEOUT).await;
+        }
+
+        let leader = self.get_leader().await;
+        self.get_all_peers()
+            .await
+            .into_iter()
+            .map(|p| PeerView {
+                is_leader: leader == Some(p.id),
+                last_seen_secs: p.last_seen.map(|t| t.elapsed().as_secs()),
+                reachable: p.reachable,
+                address: p.address,
+                id: p.id,
+            })
+            .collect()
+    }
+
+    /// Get a snapshot of all known peers
+    pub async fn get_all_peers(&self) -> Vec<NodeInfo> {
+        let peers = self.peers.read().await;
+        let mut all: Vec<NodeInfo> = peers.values().cloned().collect();
+        all.sort_by_key(|p| p.id);
+        all
+    }
+
+    /// Look up a single peer by id
+    pub async fn get_peer(&self, id: u32) -> Option<NodeInfo> {
+        let peers = self.peers.read().await;
+        peers.get(&id).cloned()
+    }
+
+    /// Start monitoring the leader with heartbeats
+    ///
+    /// Monitoring runs until `MonitorHandle::shutdown` is called or the
+    /// handle is dropped.
+    pub async fn start_leader_monitoring(self: Arc<Self>) -> MonitorHandle {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut tasks = Vec::new();
+
+        if self.config.heartbeat_transport == TransportKind::Udp {
+            if let Some(task) = Arc::clone(&self).start_udp_listener(shutdown_rx.clone()).await {
+                tasks.push(task);
+            }
+        }
+
+        if self.config.heartbeat_mode == HeartbeatMode::Push {
+            let bully = Arc::clone(&self);
+            let mut shutdown = shutdown_rx.clone();
+            tasks.push(tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        _ = shutdown.changed() => break,
+                        _ = sleep(bully.config.heartbeat_interval) => {}
+                    }
+                    if bully.is_leader().await {
+                        bully.broadcast_heartbeats().await;
+                    }
+                }
+            }));
+        }
+
+        // Re-announce leadership so nodes that missed the COORDINATOR converge
+        let bully = Arc::clone(&self);
+        let mut shutdown = shutdown_rx.clone();
+        tasks.push(tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = shutdown.changed() => break,
+                    _ = sleep(bully.config.reannounce_interval) => {}
+                }
+                if bully.is_leader().await {
+                    let term = *bully.leader_term.read().await;
+                    bully.announce_coordinator(term).await;
+                }
+            }
+        }));
+
+        let mut shutdown = shutdown_rx;
+        let mut leader_rx = self.subscribe();
+        tasks.push(tokio::spawn(async move {
+            let mut leaderless_ticks = 0;
+            let mut quorum_misses = 0;
+
+            loop {
+                let interval = self.heartbeat_check_interval().await;
+                tokio::select! {
+                    _ = shutdown.changed() => break,
+                    _ = sleep(interval) => {}
+                    _ = self.leader_alive.notified() => {
+                        // The leader just proved itself alive: count the next interval from now
+                        self.record_heartbeat(true).await;
+                        continue;
+                    }
+                    Ok(()) = leader_rx.changed() => {
+                        // A new leader cancels any failure countdown against the old one
+                        *self.leader_failures.write().await = 0;
+                        leaderless_ticks = 0;
+                        continue;
+                    }
+                }
+
+                if *self.partitioned.read().await {
+                    self.check_partition_healed().await;
+                    continue;
+                }
+
+                let leader_id = {
+                    let leader = self.current_leader.read().await;
+                    *leader
+                };
+
+                // No leader for more than one interval, most likely a missed announcement
+                let Some(leader_id) = leader_id else {
+                    leaderless_ticks += 1;
+                    if leaderless_ticks > 1 {
+                        info!("Node {}: No leader known, starting election", self.node_id);
+                        leaderless_ticks = 0;
+                        self.start_election().await;
+                    }
+                    continue;
+                };
+                leaderless_ticks = 0;
+
+                if leader_id == self.node_id {
+                    self.check_rival_leaders().await;
+                    if self.reaches_quorum().await {
+                        quorum_misses = 0;
+                    } else {
+                        quorum_misses += 1;
+                        warn!(
+                            "Node {}: Leader reaches too few peers ({}/{} checks)",
+                            self.node_id, quorum_misses, self.config.leader_quorum_checks
+                        );
+                        if quorum_misses >= self.config.leader_quorum_checks {
+                            quorum_misses = 0;
+                            self.demote().await;
+                        }
+                    }
+                    continue;
+                }
+
+                // I'm not the leader, check if leader is alive
+                let health = match self.config.heartbeat_mode {
+                    HeartbeatMode::Pull => match self.check_leader_alive(leader_id).await {
+                        Ok(()) => self.record_heartbeat(true).await,
+                        // Nothing listens at the leader's address, no point waiting out the threshold
+                        Err(BullyError::Send(SendError::ConnectRefused(_))) => self.record_leader_down().await,
+                        Err(_) => self.record_heartbeat(false).await,
+                    },
+                    HeartbeatMode::Push => {
+                        let is_alive = self.check_pushed_heartbeat().await;
+                        self.record_heartbeat(is_alive).await
+                    }
+                };
+                if health.is_dead() {
+                    let jitter = self.election_jitter().await;
+                    warn!("Node {}: Leader {} is DOWN! Starting new election in {:?}...",
+                        self.node_id, leader_id, jitter);
+                    tokio::select! {
+                        _ = shutdown.changed() => break,
+                        _ = sleep(jitter) => {}
+                        _ = leader_rx.changed() => {}
+                    }
+
+                    // A COORDINATOR may have arrived while we were waiting
+                    if self.get_leader().await != Some(leader_id) {
+                        info!("Node {}: New leader announced, skipping election", self.node_id);
+                        continue;
+                    }
+                    self.start_election().await;
+                }
+            }
+            debug!("Node {}: Leader monitoring stopped", self.node_id);
+        }));
+
+        MonitorHandle {
+            shutdown: shutdown_tx,
+            tasks,
+        }
+    }
+
+    /// Whether this node plus the peers it can reach exceed `leader_quorum_fraction` of the cluster
+    async fn reaches_quorum(&self) -> bool {
+        if self.config.leader_quorum_fraction <= 0.0 {
+            return true;
+        }
+        let peers = self.get_all_peers().await;
+        let reached = 1 + self.probe_reachable(&peers, self.config.heartbeat_timeout).await;
+        reached as f64 > self.config.leader_quorum_fraction * (peers.len() + 1) as f64
+    }
+
+    /// Step down as leader after losing contact with most of the cluster
+    ///
+    /// Subscribers see the leader go to `None`; the monitor loop then waits
+    /// for the cluster to become reachable before electing again.
+    async fn demote(&self) {
+        warn!("Node {}: Lost contact with the majority, stepping down as leader", self.node_id);
+        *self.partitioned.write().await = true;
+        if self.clear_leader_if(self.node_id).await {
+            self.record_transition(Some(self.node_id), None, TransitionReason::LostQuorum);
+        }
+    }
+
+    /// While partitioned, elect again once the majority is reachable
+    async fn check_partition_healed(&self) {
+        // The other side already has a leader that reached us
+        let healed = self.get_leader().await.is_some() || self.reaches_quorum().await;
+        if !healed {
+            debug!("Node {}: Still cut off from the cluster", self.node_id);
+            return;
+        }
+        info!("Node {}: Cluster reachable again", self.node_id);
+        *self.partitioned.write().await = false;
+        if self.get_leader().await.is_none() {
+            self.start_election().await;
+        }
+    }
+
+    /// Heartbeat every node that claimed leadership alongside us
+    ///
+    /// A rival that still believes it is leader gets our COORDINATOR again;
+    /// one that has stepped down (or is gone) is forgotten.
+    async fn check_rival_leaders(&self) {
+        let rivals: Vec<u32> = self.rival_leaders.read().await.iter().copied().collect();
+        for rival_id in rivals {
+            let Some(rival) = self.get_peer(rival_id).await else {
+                self.rival_leaders.write().await.remove(&rival_id);
+                continue;
+            };
+            let response = self
+                .send_message(&rival.address, self.heartbeat_message())
+                .await
+                .ok()
+                .flatten();
+            match response {
+                Some(BullyMessage::HeartbeatAck { leader: Some(leader), .. }) if leader == rival_id => {
+                    info!("Node {}: Node {} still claims leadership, re-asserting", self.node_id, rival_id);
+                    self.reassert_leadership().await;
+                }
+                _ => {
+                    self.rival_leaders.write().await.remove(&rival_id);
+                }
+            }
+        }
+    }
+
+    /// Announce ourselves again under a fresh term that beats any rival's
+    async fn reassert_leadership(&self) {
+        let term = self.bump_term().await;
+        self.set_leader(self.node_id, term).await;
+        self.announce_coordinator(term).await;
+    }
+
+    /// Random delay before electing so followers don't all start at once
+    ///
+    /// Higher nodes, which would win anyway, draw from a shorter window.
+    async fn election_jitter(&self) -> Duration {
+        let peers = self.get_all_peers().await;
+        let higher = peers.iter().filter(|p| p.rank() > self.rank()).count() as u32;
+        let window = self.config.election_jitter * (higher + 1) / (peers.len() as u32 + 1);
+        let max_ms = window.as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(0..=max_ms))
+    }
+
+    /// Leader side of push mode: send a heartbeat to every peer
+    async fn broadcast_heartbeats(&self) {
+        for peer in self.get_all_peers().await {
+            let bully = self.clone();
+            tokio::spawn(async move {
+                let msg = bully.heartbeat_message();
+                match bully.config.heartbeat_transport {
+                    TransportKind::Tcp => {
+                        let sent_at = Instant::now();
+                        if let Ok(reply) = bully.send_message(&peer.address, msg).await {
+                            bully.record_latency(peer.id, sent_at.elapsed());
+                            if let Some(BullyMessage::HeartbeatAck { from_id, load, .. }) = reply {
+                                bully.record_load(from_id, load);
+                            }
+                        }
+                    }
+                    TransportKind::Udp => {
+                        let _ = bully.send_udp_heartbeat(&peer.address, msg, false).await;
+                    }
+                }
+            });
+        }
+    }
+
+    /// Follower side of push mode: the leader is alive if it pushed recently
+    async fn check_pushed_heartbeat(&self) -> bool {
+        let elapsed = self.last_heartbeat_received.read().await.elapsed();
+        elapsed <= self.config.heartbeat_interval + self.config.heartbeat_timeout
+    }
+
+    /// Feed one heartbeat outcome into the failure detector
+    async fn record_heartbeat(&self, success: bool) -> LeaderHealth {
+        let mut failures = self.leader_failures.write().await;
+        if success {
+            *failures = 0;
+        } else {
+            *failures += 1;
+            self.stats.write().await.heartbeat_failures += 1;
+        }
+        LeaderHealth {
+            consecutive_failures: *failures,
+            threshold: self.config.failure_threshold,
+        }
+    }
+
+    /// The leader refused our connection, declare it dead right away
+    async fn record_leader_down(&self) -> LeaderHealth {
+        let mut failures = self.leader_failures.write().await;
+        *failures = self.config.failure_threshold;
+        self.stats.write().await.heartbeat_failures += 1;
+        LeaderHealth {
+            consecutive_failures: *failures,
+            threshold: self.config.failure_threshold,
+        }
+    }
+
+    /// Snapshot of the election counters
+    pub async fn stats(&self) -> ElectionStats {
+        let mut stats = self.stats.read().await.clone();
+        let rtt = *self.rtt.read().await;
+        stats.rtt_ewma = rtt.ewma();
+        stats.effective_heartbeat_timeout = rtt.timeout(&self.config);
+        stats.effective_heartbeat_interval = rtt.interval(&self.config);
+        stats
+    }
+
+    /// Heartbeat timeout currently in effect
+    async fn heartbeat_timeout(&self) -> Duration {
+        self.rtt.read().await.timeout(&self.config)
+    }
+
+    /// How long the monitoring loop waits between leader checks
+    async fn heartbeat_check_interval(&self) -> Duration {
+        match self.config.heartbeat_mode {
+            HeartbeatMode::Pull => self.rtt.read().await.interval(&self.config),
+            HeartbeatMode::Push => self.config.heartbeat_interval,
+        }
+    }
+
+    /// Current suspicion level of the leader
+    pub async fn leader_health(&self) -> LeaderHealth {
+        LeaderHealth {
+            consecutive_failures: *self.leader_failures.read().await,
+            threshold: self.config.failure_threshold,
+        }
+    }
+
+    /// Check if the leader is alive by sending heartbeat
+    ///
+    /// A leader that accepted the heartbeat but answered too slowly gets one
+    /// more try before the miss is reported.
+    async fn check_leader_alive(&self, leader_id: u32) -> Result<(), BullyError> {
+        let leader_info = self
+            .get_peer(leader_id)
+            .await
+            .ok_or(BullyError::PeerUnknown(leader_id))?;
+
+        let mut result = self.ping_leader(leader_id, &leader_info.address).await;
+        if let Err(BullyError::Send(SendError::ReadTimeout(_))) = result {
+            debug!("Node {}: Leader {} slow to answer, retrying heartbeat", self.node_id, leader_id);
+            result = self.ping_leader(leader_id, &leader_info.address).await;
+        }
+
+        match result {
+            Ok(rtt) => {
+                self.rtt.write().await.record(leader_id, rtt);
+                self.record_latency(leader_id, rtt);
+                *self.last_heartbeat_received.write().await = Instant::now();
+                self.mark_peer(leader_id, true).await;
+                Ok(())
+            }
+            Err(e) => {
+                debug!("Node {}: Heartbeat to leader {} failed: {}", self.node_id, leader_id, e);
+                self.mark_peer(leader_id, false).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// One heartbeat round trip to the leader, returning how long it took
+    async fn ping_leader(&self, leader_id: u32, address: &str) -> Result<Duration, BullyError> {
+        let sent_at = Instant::now();
+        match self.config.heartbeat_transport {
+            TransportKind::Tcp => self.send_heartbeat(leader_id, address).await?,
+            TransportKind::Udp => {
+                let msg = self.heartbeat_message();
+                self.send_udp_heartbeat(address, msg, true).await?
+            }
+        }
+        Ok(sent_at.elapsed())
+    }
+
+    /// UDP address paired with a node's TCP address
+    fn udp_address(&self, address: &str) -> Option<SocketAddr> {
+        let mut addr: SocketAddr = address.parse().ok()?;
+        addr.set_port(addr.port().checked_add(self.config.udp_port_offset)?);
+        Some(addr)
+    }
+
+    /// Serve heartbeat datagrams from known peers until shutdown is signalled
+    async fn start_udp_listener(self: Arc<Self>, mut shutdown: watch::Receiver<bool>) -> Option<JoinHandle<()>> {
+        let Some(bind_addr) = self.udp_address(&self.node_address) else {
+            warn!("Node {}: Cannot derive UDP address from {}", self.node_id, self.node_address);
+            return None;
+        };
+        let socket = match UdpSocket::bind(bind_addr).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("Node {}: Failed to bind UDP heartbeat socket {}: {}", self.node_id, bind_addr, e);
+                return None;
+            }
+        };
+        info!("Node {}: UDP heartbeats on {}", self.node_id, bind_addr);
+
+        Some(tokio::spawn(async move {
+            let mut buffer = vec![0u8; 1024];
+            loop {
+                let received = tokio::select! {
+                    _ = shutdown.changed() => break,
+                    received = socket.recv_from(&mut buffer) => received,
+                };
+                let Ok((n, source)) = received else {
+                    continue;
+                };
+                // Anything that is not a heartbeat from a known peer is dropped
+                let Ok(msg) = self.decode(&buffer[..n]) else {
+                    continue;
+                };
+                let BullyMessage::Heartbeat { from_id, .. } = msg else {
+                    continue;
+                };
+                if self.get_peer(from_id).await.is_none() {
+                    continue;
+                }
+                if let Some(response) = self.handle_message(msg).await {
+                    if let Ok(response_json) = self.encode(&response) {
+                        let _ = socket.send_to(response_json.as_bytes(), source).await;
+                    }
+                }
+            }
+        }))
+    }
+
+    /// Send a heartbeat datagram, optionally waiting for the ack
+    async fn send_udp_heartbeat(
+        &self,
+        address: &str,
+        msg: BullyMessage,
+        wait_for_ack: bool,
+    ) -> Result<(), BullyError> {
+        let target = self
+            .udp_address(address)
+            .ok_or_else(|| BullyError::InvalidAddress(address.to_string()))?;
+
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(target).await?;
+        let msg_json = self.encode(&msg)?;
+        socket.send(msg_json.as_bytes()).await?;
+
+        if !wait_for_ack {
+            return Ok(());
+        }
+
+        let mut buffer = vec![0u8; 1024];
+        let n = match timeout(self.heartbeat_timeout().await, socket.recv(&mut buffer)).await {
+            Ok(Ok(n)) => n,
+            // ICMP port unreachable: nothing is listening on the leader's port
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                return Err(SendError::ConnectRefused(address.to_string()).into());
+            }
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => return Err(SendError::ReadTimeout(address.to_string()).into()),
+        };
+        match self.decode(&buffer[..n]) {
+            Ok(BullyMessage::HeartbeatAck { .. }) => Ok(()),
+            Ok(BullyMessage::DuplicateId { address: other, .. }) => {
+                self.mark_duplicate_id(&other).await;
+                Err(SendError::bad_response(address, "peer shares this node's id").into())
+            }
+            Ok(other) => Err(SendError::bad_response(address, format!("expected HeartbeatAck, got {:?}", other)).into()),
+            Err(e) => Err(SendError::bad_response(address, e).into()),
+        }
+    }
+
+    /// Send heartbeat to leader, reusing the open connection when possible
+    async fn send_heartbeat(&self, leader_id: u32, address: &str) -> Result<(), BullyError> {
+        let mut conn = self.heartbeat_conn.lock().await;
+
+        // Leader changed since the connection was opened
+        if conn.as_ref().map(|c| c.leader_id) != Some(leader_id) {
+            *conn = None;
+        }
+
+        let heartbeat_timeout = self.heartbeat_timeout().await;
+        let result = async {
+            if let Some(existing) = conn.as_mut() {
+                match self.exchange_heartbeat(existing, address, heartbeat_timeout).await {
+                    Ok(()) => return Ok(()),
+                    // The leader is there, just slow; a new connection would not help
+                    Err(e @ SendError::ReadTimeout(_)) => return Err(e),
+                    // Connection dropped on the leader's side, reconnect below
+                    Err(_) => *conn = None,
+                }
+            }
+
+            let stream = connect_peer(address, heartbeat_timeout).await?;
+            let fresh = conn.insert(HeartbeatConnection {
+                leader_id,
+                stream,
+            });
+            self.exchange_heartbeat(fresh, address, heartbeat_timeout).await
+        }
+        .await;
+
+        if result.is_err() {
+            *conn = None;
+        }
+        result.map_err(BullyError::from)
+    }
+
+    /// Write one Heartbeat frame and read back one HeartbeatAck frame
+    async fn exchange_heartbeat(
+        &self,
+        conn: &mut HeartbeatConnection,
+        address: &str,
+        read_timeout: Duration,
+    ) -> Result<(), SendError> {
+        let msg_json = self
+            .encode(&self.heartbeat_message())
+            .map_err(|e| SendError::bad_response(address, e))?;
+
+        let reply = timeout(read_timeout, async {
+            write_frame(&mut conn.stream, msg_json.as_bytes(), MAX_FRAME_LEN).await?;
+            let frame = read_frame(&mut conn.stream, MAX_FRAME_LEN)
+                .await?
+                .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+            self.decode(&frame)
+        })
+        .await
+        .map_err(|_| SendError::ReadTimeout(address.to_string()))?
+        .map_err(|e| SendError::bad_response(address, e))?;
+
+        match reply {
+            BullyMessage::HeartbeatAck { .. } => Ok(()),
+            BullyMessage::DuplicateId { address: other, .. } => {
+                self.mark_duplicate_id(&other).await;
+                Err(SendError::bad_response(address, "peer shares this node's id"))
+            }
+            other => Err(SendError::bad_response(
+                address,
+                format!("expected HeartbeatAck, got {:?}", other),
+            )),
+        }
+    }
+
+    /// Start an election
+    ///
+    /// If a higher node answers but never announces itself as coordinator
+    /// within `coordinator_timeout`, the election is restarted.
+    pub async fn start_election(&self) {
+        if *self.resigned.read().await {
+            return;
+        }
+        if *self.degraded.read().await {
+            warn!("Node {}: Refusing to run an election, another node shares this id", self.node_id);
+            return;
+        }
+        if *self.partitioned.read().await {
+            debug!("Node {}: Cut off from the cluster, not electing", self.node_id);
+            return;
+        }
+
+        // Only one election runs at a time on this node
+        let Ok(_running) = self.election_running.try_lock() else {
+            debug!(
+                "Node {}: Election already in progress ({:?})",
+                self.node_id,
+                *self.election_state.read().await
+            );
+            return;
+        };
+        *self.election_state.write().await = ElectionState::Electing { term: 0 };
+
+        let started = Instant::now();
+        self.stats.write().await.elections_started += 1;
+
+        // Any other leader we still follow has stopped answering heartbeats
+        let old_leader = self.get_leader().await;
+        if old_leader.is_some() && old_leader != Some(self.node_id) {
+            self.record_transition(old_leader, None, TransitionReason::HeartbeatTimeout);
+        }
+
+        self.run_election().await;
+
+        let mut stats = self.stats.write().await;
+        stats.last_election_at = Some(SystemTime::now());
+        stats.last_election_duration = Some(started.elapsed());
+    }
+
+    /// Election rounds, repeated until a leader is settled
+    async fn run_election(&self) {
+        let mut backoff = self.config.prevote_backoff;
+
+        loop {
+            // In quorum mode, never bully our way to leadership from a minority partition
+            if !self.pre_vote().await {
+                warn!(
+                    "Node {}: Cannot reach a majority of the cluster, retrying election in {:?}",
+                    self.node_id, backoff
+                );
+                if !self.back_off(&mut backoff).await {
+                    return;
+                }
+                continue;
+            }
+
+            let term = self.bump_term().await;
+            *self.election_state.write().await = ElectionState::Electing { term };
+            info!("Node {}: Starting election (term {})", self.node_id, term);
+
+            let peers = self.get_all_peers().await;
+            let higher_nodes: Vec<_> = peers
+                .iter()
+                .filter(|p| p.rank() > self.rank())
+                .collect();
+
+            if higher_nodes.is_empty() {
+                if !self.has_quorum(0).await {
+                    warn!(
+                        "Node {}: No quorum, not self-promoting; retrying election in {:?}",
+                        self.node_id, backoff
+                    );
+                    if !self.back_off(&mut backoff).await {
+                        return;
+                    }
+                    continue;
+                }
+                // I have the highest ID, I'm the leader
+                info!("Node {}: I am the new leader!", self.node_id);
+                self.set_leader(self.node_id, term).await;
+                self.announce_coordinator(term).await;
+                return;
+            }
+
+            // Send ELECTION message to all higher nodes at once
+            let mut sends = JoinSet::new();
+            for peer_info in higher_nodes {
+                let bully = self.clone();
+                let address = peer_info.address.clone();
+                sends.spawn(async move { bully.send_election(&address, term).await });
+            }
+
+            let mut received_answer = false;
+            let mut contacted = 0;
+            while let Some(result) = sends.join_next().await {
+                let Ok(Ok(response)) = result else {
+                    continue;
+                };
+                contacted += 1;
+                if let Some(BullyMessage::Answer { term: answer_term, .. }) = response {
+                    if answer_term >= term {
+                        // One higher node alive is enough, no need to wait for the rest
+                        received_answer = true;
+                        sends.abort_all();
+                        break;
+                    }
+                }
+            }
+
+            {
+                let mut state = self.election_state.write().await;
+                if *state != (ElectionState::Electing { term }) {
+                    // A coordinator was adopted while we were sending
+                    return;
+                }
+                if received_answer {
+                    *state = ElectionState::AwaitingCoordinator { term };
+                }
+            }
+
+            if !received_answer {
+                // A higher node may have replied that it shares our id
+                if self.is_degraded().await {
+                    *self.election_state.write().await = ElectionState::Idle;
+                    return;
+                }
+                if !self.has_quorum(contacted).await {
+                    warn!(
+                        "Node {}: No quorum, not self-promoting; retrying election in {:?}",
+                        self.node_id, backoff
+                    );
+                    if !self.back_off(&mut backoff).await {
+                        return;
+                    }
+                    continue;
+                }
+                // No one responded, I'm the leader
+                info!("Node {}: No response, I am the new leader!", self.node_id);
+                self.set_leader(self.node_id, term).await;
+                self.announce_coordinator(term).await;
+                return;
+            }
+
+            // Wait for coordinator announcement
+            info!(
+                "Node {}: Received answer, waiting for coordinator announcement",
+                self.node_id
+            );
+
+            if self.wait_for_coordinator().await {
+                return;
+            }
+
+            warn!(
+                "Node {}: No COORDINATOR received within {:?}, restarting election",
+                self.node_id, self.config.coordinator_timeout
+            );
+        }
+    }
+
+    /// Sleep before another election round, doubling the delay up to 16x
+    ///
+    /// Returns false if a coordinator was adopted in the meantime.
+    async fn back_off(&self, backoff: &mut Duration) -> bool {
+        sleep(*backoff).await;
+        *backoff = (*backoff * 2).min(self.config.prevote_backoff * 16);
+        *self.election_state.read().await != ElectionState::Idle
+    }
+
+    /// In quorum mode, check that this node plus the peers it reached make up
+    /// a strict majority, N/2 + 1 of the N nodes, before it crowns itself
+    ///
+    /// `contacted_higher` is how many higher nodes took our ELECTION; lower
+    /// nodes are probed here.
+    async fn has_quorum(&self, contacted_higher: usize) -> bool {
+        if !self.config.quorum_mode {
+            return true;
+        }
+        let peers = self.get_all_peers().await;
+        let lower: Vec<NodeInfo> = peers
+            .iter()
+            .filter(|p| p.rank() < self.rank())
+            .cloned()
+            .collect();
+        let reached = 1 + contacted_higher + self.probe_reachable(&lower, self.config.heartbeat_timeout).await;
+        reached * 2 > peers.len() + 1
+    }
+
+    /// Send ELECTION to one peer, retrying before concluding it is down
+    ///
+    /// A refused connection means the peer is down and is not retried; a slow
+    /// reply is always retried at least once.
+    async fn send_election(&self, address: &str, term: u64) -> Result<Option<BullyMessage>, SendError> {
+        let msg = BullyMessage::Election {
+            from_id: self.node_id,
+            term,
+            priority: self.config.priority,
+            from_address: self.node_address.clone(),
+        };
+
+        let mut attempt = 0;
+        loop {
+            match self.send_message(address, msg.clone()).await {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    debug!(
+                        "Node {}: ELECTION to {} failed (attempt {}): {}",
+                        self.node_id,
+                        address,
+                        attempt + 1,
+                        e
+                    );
+                    let retry = match e {
+                        SendError::ConnectRefused(_) => false,
+                        SendError::ReadTimeout(_) => attempt < self.config.election_retries.max(1),
+                        _ => attempt < self.config.election_retries,
+                    };
+                    if !retry {
+                        return Err(e);
+                    }
+                }
+            }
+            attempt += 1;
+            sleep(self.config.election_retry_backoff * attempt).await;
+        }
+    }
+
+    /// Probe every peer and report whether a majority of the cluster is reachable
+    ///
+    /// Only checked in quorum mode; otherwise any node may bully its way to
+    /// leadership, as a two-node cluster that lost one node must.
+    async fn pre_vote(&self) -> bool {
+        if !self.config.quorum_mode {
+            return true;
+        }
+        let peers = self.get_all_peers().await;
+        if peers.is_empty() {
+            return true;
+        }
+
+        let reachable = self.probe_reachable(&peers, self.config.heartbeat_timeout).await;
+
+        // This node counts towards the majority too
+        (reachable + 1) * 2 > peers.len() + 1
+    }
+
+    /// Try a TCP connect to each peer concurrently, returning how many accepted
+    async fn probe_reachable(&self, peers: &[NodeInfo], probe_timeout: Duration) -> usize {
+        let mut probes = JoinSet::new();
+        for peer in peers.iter().cloned() {
+            let transport = Arc::clone(&self.transport);
+            probes.spawn(async move {
+                let ok = transport.probe(&peer.address, probe_timeout).await;
+                (peer.id, ok)
+            });
+        }
+
+        let mut reachable = 0;
+        while let Some(result) = probes.join_next().await {
+            if let Ok((id, ok)) = result {
+                self.mark_peer(id, ok).await;
+                if ok {
+                    reachable += 1;
+                }
+            }
+        }
+        reachable
+    }
+
+    /// Wait until a coordinator is set, returning false on timeout
+    async fn wait_for_coordinator(&self) -> bool {
+        timeout(self.config.coordinator_timeout, async {
+            loop {
+                // Register interest before checking the state so a concurrent
+                // set_leader cannot slip in between
+                let notified = self.coordinator_notify.notified();
+                if !matches!(*self.election_state.read().await, ElectionState::AwaitingCoordinator { .. }) {
+                    return;
+                }
+                notified.await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    /// Announce that this node is the coordinator
+    ///
+    /// Peers that do not acknowledge are retried in the background and marked
+    /// unreachable if they never do.
+    async fn announce_coordinator(&self, term: u64) {
+        let peers = self.get_all_peers().await;
+        *self.announcement.write().await = AnnouncementStatus {
+            term,
+            acked: HashSet::new(),
+            unacked: peers.iter().map(|p| p.id).collect(),
+        };
+
+        let pending = self.send_coordinator(peers, term).await;
+        if pending.is_empty() {
+            return;
+        }
+        tokio::spawn({
+            let bully = self.clone();
+            async move {
+                bully.retry_coordinator(pending, term).await;
+            }
+        });
+    }
+
+    /// Send COORDINATOR to each peer concurrently, returning those that did not acknowledge
+    async fn send_coordinator(&self, peers: Vec<NodeInfo>, term: u64) -> Vec<NodeInfo> {
+        let mut sends = JoinSet::new();
+        for peer in peers {
+            let bully = self.clone();
+            sends.spawn(async move {
+                let msg = BullyMessage::Coordinator {
+                    leader_id: bully.node_id,
+                    term,
+                    priority: bully.config.priority,
+                    from_address: bully.node_address.clone(),
+                };
+                let reply = bully.send_message(&peer.address, msg).await;
+                (peer, matches!(reply, Ok(Some(BullyMessage::CoordinatorAck { .. }))))
+            });
+        }
+
+        let mut pending = Vec::new();
+        while let Some(result) = sends.join_next().await {
+            let Ok((peer, acked)) = result else {
+                continue;
+            };
+            if !acked {
+                pending.push(peer);
+                continue;
+            }
+            let mut announcement = self.announcement.write().await;
+            if announcement.term == term {
+                announcement.unacked.remove(&peer.id);
+                announcement.acked.insert(peer.id);
+            }
+        }
+        pending
+    }
+
+    /// Re-send COORDINATOR to peers that missed it, backing off between rounds
+    async fn retry_coordinator(&self, mut pending: Vec<NodeInfo>, term: u64) {
+        let mut backoff = self.config.coordinator_retry_backoff;
+        for _ in 0..self.config.coordinator_retries {
+            sleep(backoff).await;
+            backoff *= 2;
+            // A newer announcement or a lost leadership supersedes this one
+            if self.announcement.read().await.term != term || !self.is_leader().await {
+                return;
+            }
+            pending = self.send_coordinator(pending, term).await;
+            if pending.is_empty() {
+                return;
+            }
+        }
+
+        for peer in pending {
+            warn!(
+                "Node {}: Node {} never acknowledged COORDINATOR (term {})",
+                self.node_id, peer.id, term
+            );
+            self.mark_peer(peer.id, false).await;
+        }
+    }
+
+    /// Which peers acknowledged the latest COORDINATOR announcement
+    pub async fn announcement_status(&self) -> AnnouncementStatus {
+        self.announcement.read().await.clone()
+    }
+
+    /// Handle incoming Bully messages
+    pub async fn handle_message(&self, msg: BullyMessage) -> Option<BullyMessage> {
+        if let Err(reason) = self.validate(&msg).await {
+            if let Rejection::DuplicateId(address) = &reason {
+                self.mark_duplicate_id(address).await;
+                return Some(BullyMessage::DuplicateId {
+                    id: self.node_id,
+                    address: self.node_address.clone(),
+                });
+            }
+            warn!("Node {}: Dropped {:?}: {}", self.node_id, msg, reason);
+            // The claim is refused, but the sender is now a known peer and
+            // its next one is judged like any other
+            if let BullyMessage::Coordinator { leader_id, priority, from_address, .. } = msg {
+                self.learn_peer(leader_id, from_address, priority).await;
+            }
+            return None;
+        }
+        if let Some(sender_id) = msg.sender_id() {
+            self.mark_peer(sender_id, true).await;
+        }
+
+        match msg {
+            BullyMessage::Election { from_id, term, priority, from_address } => {
+                debug!(
+                    "Node {}: Received ELECTION from Node {} (term {})",
+                    self.node_id, from_id, term
+                );
+                self.learn_peer(from_id, from_address, priority).await;
+                self.observe_term(term).await;
+                self.learn_priority(from_id, priority).await;
+
+                if self.rank() > (priority, from_id) && !*self.resigned.read().await {
+                    // Respond with ANSWER and start own election
+                    tokio::spawn({
+                        let bully = self.clone();
+                        async move {
+                            sleep(bully.config.answer_wait).await;
+                            bully.start_election().await;
+                        }
+                    });
+
+                    return Some(BullyMessage::Answer {
+                        from_id: self.node_id,
+                        term: self.get_term().await,
+                    });
+                }
+                None
+            }
+            BullyMessage::Coordinator { leader_id, term, priority, from_address } => {
+                self.stats.write().await.coordinators_received += 1;
+                self.learn_peer(leader_id, from_address, priority).await;
+
+                // Split brain: another node claims leadership while we hold it
+                let transferring = *self.transfer_target.read().await == Some(leader_id);
+                if leader_id != self.node_id && self.is_leader().await && !transferring {
+                    if (priority, leader_id) < self.rank() {
+                        warn!(
+                            "Node {}: Lower Node {} also claims leadership, re-asserting",
+                            self.node_id, leader_id
+                        );
+                        self.observe_term(term).await;
+                        self.rival_leaders.write().await.insert(leader_id);
+                        tokio::spawn({
+                            let bully = self.clone();
+                            async move {
+                                bully.reassert_leadership().await;
+                            }
+                        });
+                        return None;
+                    }
+                    warn!(
+                        "Node {}: Higher Node {} claims leadership, stepping down",
+                        self.node_id, leader_id
+                    );
+                    self.rival_leaders.write().await.clear();
+                }
+
+                let current_term = self.get_term().await;
+                if term < current_term {
+                    debug!(
+                        "Node {}: Ignoring stale COORDINATOR from Node {} (term {} < {})",
+                        self.node_id, leader_id, term, current_term
+                    );
+                    return None;
+                }
+                info!(
+                    "Node {}: Received COORDINATOR announcement - Node {} is leader (term {})",
+                    self.node_id, leader_id, term
+                );
+                self.learn_priority(leader_id, priority).await;
+                self.set_leader(leader_id, term).await;
+                Some(BullyMessage::CoordinatorAck { from_id: self.node_id })
+            }
+            BullyMessage::Heartbeat { from_id, address, load } => {
+                if let Some(address) = address {
+                    let _ = self.update_peer(from_id, address).await;
+                }
+                self.record_load(from_id, load);
+                if self.get_leader().await == Some(from_id) {
+                    *self.last_heartbeat_received.write().await = Instant::now();
+                    self.leader_alive.notify_one();
+                }
+                // Respond with heartbeat acknowledgment
+                Some(BullyMessage::HeartbeatAck {
+                    from_id: self.node_id,
+                    leader: self.get_leader().await,
+                    load: self.load.load(Ordering::Relaxed),
+                })
+            }
+            BullyMessage::HeartbeatAck { .. } => {
+                // Just note the acknowledgment
+                None
+            }
+            BullyMessage::Resign { from_id } => {
+                let was_leader = self.clear_leader_if(from_id).await;
+                if !was_leader {
+                    return None;
+                }
+                info!("Node {}: Leader {} resigned", self.node_id, from_id);
+
+                // The highest remaining node takes over right away; the rest only
+                // step in if it never announces itself
+                let outranked = self
+                    .get_all_peers()
+                    .await
+                    .iter()
+                    .any(|p| p.id != from_id && p.rank() > self.rank());
+                tokio::spawn({
+                    let bully = self.clone();
+                    async move {
+                        if outranked {
+                            sleep(bully.config.coordinator_timeout).await;
+                            if bully.get_leader().await.is_some() {
+                                return;
+                            }
+                        }
+                        bully.start_election().await;
+                    }
+                });
+                None
+            }
+            BullyMessage::TransferLeadership { to_id, term } => {
+                if to_id != self.node_id || *self.resigned.read().await {
+                    return None;
+                }
+                info!("Node {}: Asked to take over leadership", self.node_id);
+                self.observe_term(term).await;
+                tokio::spawn({
+                    let bully = self.clone();
+                    async move {
+                        bully.accept_transfer().await;
+                    }
+                });
+                None
+            }
+            BullyMessage::Join { id, address, priority } => {
+                let known = self.get_peer(id).await.map(|p| p.address);
+                if known.as_deref() != Some(address.as_str()) {
+                    info!("Node {}: Node {} joined from {}", self.node_id, id, address);
+                    if known.is_some() {
+                        let _ = self.update_peer(id, address.clone()).await;
+                        self.learn_priority(id, priority).await;
+                    } else {
+                        let _ = self.add_peer(id, address.clone(), priority).await;
+                    }
+                    tokio::spawn({
+                        let bully = self.clone();
+                        async move {
+                            bully.forward_join(id, address, priority).await;
+                        }
+                    });
+                }
+
+                let mut peers: Vec<(u32, String, u32)> = self
+                    .get_all_peers()
+                    .await
+                    .into_iter()
+                    .filter(|p| p.id != id)
+                    .map(|p| (p.id, p.address, p.priority))
+                    .collect();
+                peers.push((self.node_id, self.node_address.clone(), self.config.priority));
+
+                Some(BullyMessage::JoinAck {
+                    peers,
+                    leader: self.get_leader_with_term().await,
+                    term: self.get_term().await,
+                })
+            }
+            BullyMessage::DuplicateId { id, address } => {
+                if id == self.node_id && address != self.node_address {
+                    self.mark_duplicate_id(&address).await;
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Check that a message is one this node should act on
+    pub async fn validate(&self, msg: &BullyMessage) -> Result<(), Rejection> {
+        if msg.sender_id() == Some(self.node_id) {
+            return match msg.sender_address() {
+                Some(address) if address != self.node_address => Err(Rejection::DuplicateId(address.to_string())),
+                _ => Err(Rejection::FromSelf),
+            };
+        }
+        if let BullyMessage::Coordinator { leader_id, .. } = msg {
+            if !self.config.accept_unknown_leaders && self.get_peer(*leader_id).await.is_none() {
+                return Err(Rejection::UnknownLeader(*leader_id));
+            }
+        }
+        Ok(())
+    }
+
+    /// Serialize a message for the wire, signing it if a cluster secret is set
+    pub fn encode(&self, msg: &BullyMessage) -> Result<String, BullyError> {
+        self.codec.encode(msg)
+    }
+
+    /// Parse a message from the wire, checking its signature if a cluster secret is set
+    pub fn decode(&self, data: &[u8]) -> Result<BullyMessage, BullyError> {
+        self.codec.decode(data)
+    }
+
+    /// Send a message to a peer, returning its reply if the message expects one
+    async fn send_message(
+        &self,
+        address: &str,
+        message: BullyMessage,
+    ) -> Result<Option<BullyMessage>, SendError> {
+        let result = self.transport.send(address, message).await;
+        self.mark_peer_address(address, result.is_ok()).await;
+        if let Ok(Some(BullyMessage::DuplicateId { address: other, .. })) = &result {
+            self.mark_duplicate_id(other).await;
+        }
+        result
+    }
+
+    /// Another node runs with our id: stop taking part in elections
+    ///
+    /// Bully comparisons are meaningless with two nodes under one id, so the
+    /// node stays degraded until it is restarted with a unique id.
+    async fn mark_duplicate_id(&self, other_address: &str) {
+        let mut degraded = self.degraded.write().await;
+        if !*degraded {
+            error!(
+                "Node {}: DUPLICATE NODE ID - the node at {} also uses id {}, refusing to take part in elections",
+                self.node_id, other_address, self.node_id
+            );
+            *degraded = true;
+        }
+    }
+
+    /// Whether another node was found running with this node's id
+    pub async fn is_degraded(&self) -> bool {
+        *self.degraded.read().await
+    }
+
+    pub fn clone(&self) -> Self {
+        BullyElection {
+            node_id: self.node_id,
+            node_address: self.node_address.clone(),
+            config: self.config.clone(),
+            peers: Arc::clone(&self.peers),
+            current_leader: Arc::clone(&self.current_leader),
+            current_term: Arc::clone(&self.current_term),
+            leader_term: Arc::clone(&self.leader_term),
+            leader_failures: Arc::clone(&self.leader_failures),
+            last_heartbeat_received: Arc::clone(&self.last_heartbeat_received),
+            election_state: Arc::clone(&self.election_state),
+            election_running: Arc::clone(&self.election_running),
+            stats: Arc::clone(&self.stats),
+            rival_leaders: Arc::clone(&self.rival_leaders),
+            history: Arc::clone(&self.history),
+            transfer_target: Arc::clone(&self.transfer_target),
+            announcement: Arc::clone(&self.announcement),
+            rtt: Arc::clone(&self.rtt),
+            latency: Arc::clone(&self.latency),
+            load: Arc::clone(&self.load),
+            peer_load: Arc::clone(&self.peer_load),
+            resigned: Arc::clone(&self.resigned),
+            degraded: Arc::clone(&self.degraded),
+            partitioned: Arc::clone(&self.partitioned),
+            coordinator_notify: Arc::clone(&self.coordinator_notify),
+            leader_alive: Arc::clone(&self.leader_alive),
+            leader_tx: Arc::clone(&self.leader_tx),
+            heartbeat_conn: Arc::clone(&self.heartbeat_conn),
+            codec: self.codec.clone(),
+            transport: Arc::clone(&self.transport),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::ChannelTransport;
+    use std::sync::atomic::AtomicBool;
+
+    fn adaptive() -> BullyConfig {
+        BullyConfig {
+            adaptive_heartbeat: true,
+            ..BullyConfig::default()
+        }
+    }
+
+    fn estimator(leader_id: u32, samples_ms: &[u64]) -> RttEstimator {
+        let mut rtt = RttEstimator::default();
+        for &ms in samples_ms {
+            rtt.record(leader_id, Duration::from_millis(ms));
+        }
+        rtt
+    }
+
+    #[test]
+    fn adaptive_heartbeat_is_off_by_default() {
+        let config = BullyConfig::default();
+        assert!(!config.adaptive_heartbeat);
+        assert!(!crate::config::ElectionSettings::default().adaptive_heartbeat);
+
+        let rtt = estimator(3, &[5, 5, 5]);
+        assert_eq!(rtt.timeout(&config), config.heartbeat_timeout);
+        assert_eq!(rtt.interval(&config), config.heartbeat_interval);
+    }
+
+    #[test]
+    fn fixed_timing_until_the_first_sample() {
+        let config = adaptive();
+        let rtt = RttEstimator::default();
+        assert_eq!(rtt.ewma(), None);
+        assert_eq!(rtt.timeout(&config), config.heartbeat_timeout);
+        assert_eq!(rtt.interval(&config), config.heartbeat_interval);
+    }
+
+    #[test]
+    fn ewma_smooths_samples() {
+        let rtt = estimator(3, &[100, 200]);
+        // 100 * 7/8 + 200 / 8
+        assert_eq!(rtt.ewma(), Some(Duration::from_micros(112_500)));
+    }
+
+    #[test]
+    fn new_leader_starts_over() {
+        let mut rtt = estimator(3, &[400, 400, 400]);
+        rtt.record(4, Duration::from_millis(20));
+        assert_eq!(rtt.ewma(), Some(Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn timeout_scales_with_rtt_within_bounds() {
+        let config = adaptive();
+        // 250ms x 4
+        assert_eq!(estimator(3, &[250]).timeout(&config), Duration::from_secs(1));
+        // A LAN round trip is held up at the minimum
+        assert_eq!(estimator(3, &[2]).timeout(&config), config.min_heartbeat_timeout);
+        // A very slow leader is capped at the maximum
+        assert_eq!(estimator(3, &[5_000]).timeout(&config), config.max_heartbeat_timeout);
+    }
+
+    #[test]
+    fn interval_backs_off_with_the_timeout() {
+        let config = adaptive();
+        // Timeout at the maximum: interval at the maximum
+        assert_eq!(estimator(3, &[5_000]).interval(&config), config.max_heartbeat_interval);
+        // Timeout 1s of 2s: half the maximum interval
+        assert_eq!(estimator(3, &[250]).interval(&config), Duration::from_secs(5));
+        // Fast leader: never below the minimum interval
+        assert_eq!(estimator(3, &[2]).interval(&config), config.min_heartbeat_interval);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn leader_window_follows_the_estimate() {
+        let node = BullyElection::new_with_config(1, "127.0.0.1:0".into(), adaptive());
+        *node.current_leader.write().await = Some(3);
+        *node.last_heartbeat_received.write().await = Instant::now();
+        // 250ms round trips: 1s timeout, 5s interval
+        for _ in 0..4 {
+            node.rtt.write().await.record(3, Duration::from_millis(250));
+        }
+        assert_eq!(node.heartbeat_timeout().await, Duration::from_secs(1));
+
+        tokio::time::advance(Duration::from_millis(5_900)).await;
+        assert!(node.cluster_healthy().await);
+        tokio::time::advance(Duration::from_millis(200)).await;
+        assert!(!node.cluster_healthy().await);
+    }
+
+    fn address(id: u32) -> String {
+        format!("127.0.0.1:{}", 9000 + id)
+    }
+
+    /// `n` nodes with ids 1..=n, all peers of each other, wired over `net`
+    async fn cluster(n: u32, net: &Arc<ChannelTransport>) -> Vec<BullyElection> {
+        cluster_with(n, net, BullyConfig::default()).await
+    }
+
+    async fn cluster_with(n: u32, net: &Arc<ChannelTransport>, config: BullyConfig) -> Vec<BullyElection> {
+        let config = BullyConfig {
+            election_timeout: Duration::from_millis(200),
+            coordinator_timeout: Duration::from_secs(1),
+            coordinator_retry_backoff: Duration::from_millis(50),
+            ..config
+        };
+        let mut nodes = Vec::new();
+        for id in 1..=n {
+            let transport: Arc<dyn Transport> = net.clone();
+            let node = BullyElection::new_with_transport(id, address(id), config.clone(), transport);
+            for peer in (1..=n).filter(|&peer| peer != id) {
+                node.add_peer(peer, address(peer), 0).await.unwrap();
+            }
+            net.attach(&node);
+            nodes.push(node);
+        }
+        nodes
+    }
+
+    /// Wait until every node in `nodes` follows `leader`
+    async fn settled_on(nodes: &[BullyElection], leader: u32) {
+        let agreed = async {
+            loop {
+                let mut all = true;
+                for node in nodes {
+                    all &= node.get_leader().await == Some(leader);
+                }
+                if all {
+                    return;
+                }
+                sleep(Duration::from_millis(50)).await;
+            }
+        };
+        timeout(Duration::from_secs(60), agreed)
+            .await
+            .unwrap_or_else(|_| panic!("nodes did not settle on {}", leader));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn five_nodes_elect_the_highest_id() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = cluster(5, &net).await;
+        nodes[0].start_election().await;
+        settled_on(&nodes, 5).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_elections_agree() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = cluster(5, &net).await;
+        let mut elections = JoinSet::new();
+        for node in &nodes {
+            let node = node.clone();
+            elections.spawn(async move { node.start_election().await });
+        }
+        while elections.join_next().await.is_some() {}
+        settled_on(&nodes, 5).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dropped_coordinator_is_retried() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = cluster(5, &net).await;
+        // Lose the first COORDINATOR from node 5 to every other node
+        let mut missed = HashSet::new();
+        net.drop_where(move |to, msg| {
+            matches!(msg, BullyMessage::Coordinator { leader_id: 5, .. }) && missed.insert(to.to_string())
+        });
+        nodes[0].start_election().await;
+        settled_on(&nodes, 5).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn highest_node_down_next_one_wins() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = cluster(5, &net).await;
+        net.set_down(&address(5), true);
+        nodes[0].start_election().await;
+        settled_on(&nodes[..4], 4).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delayed_and_reordered_messages() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = cluster(5, &net).await;
+        net.set_delay(Duration::from_millis(5), Duration::from_millis(50));
+        nodes[0].start_election().await;
+        settled_on(&nodes, 5).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn announcement_to_offline_peer_stays_unacked() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = cluster(3, &net).await;
+        net.set_down(&address(2), true);
+        nodes[2].start_election().await;
+        // Well past every retry
+        sleep(Duration::from_secs(5)).await;
+
+        let status = nodes[2].announcement_status().await;
+        assert!(status.term > 0);
+        assert_eq!(status.acked, HashSet::from([1]));
+        assert_eq!(status.unacked, HashSet::from([2]));
+        assert!(!nodes[2].get_peer(2).await.unwrap().reachable);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn announcement_retry_reaches_returning_peer() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = cluster(3, &net).await;
+        net.set_down(&address(2), true);
+        nodes[2].start_election().await;
+        assert_eq!(nodes[2].announcement_status().await.unacked, HashSet::from([2]));
+
+        // Back before the first retry
+        net.set_down(&address(2), false);
+        sleep(Duration::from_secs(5)).await;
+
+        let status = nodes[2].announcement_status().await;
+        assert_eq!(status.acked, HashSet::from([1, 2]));
+        assert!(status.unacked.is_empty());
+        assert_eq!(nodes[1].get_leader().await, Some(3));
+    }
+
+    fn quorum_mode() -> BullyConfig {
+        BullyConfig {
+            quorum_mode: true,
+            prevote_backoff: Duration::from_millis(100),
+            ..BullyConfig::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn majority_elects_a_leader_in_quorum_mode() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = cluster_with(5, &net, quorum_mode()).await;
+        // 3 of 5 is a strict majority
+        net.set_down(&address(4), true);
+        net.set_down(&address(5), true);
+        nodes[0].start_election().await;
+        settled_on(&nodes[..3], 3).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn half_of_an_even_cluster_is_no_quorum() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = cluster_with(4, &net, quorum_mode()).await;
+        // 2 of 4 is not a majority
+        net.set_down(&address(3), true);
+        net.set_down(&address(4), true);
+        let election = tokio::spawn({
+            let node = nodes[1].clone();
+            async move { node.start_election().await }
+        });
+        sleep(Duration::from_secs(10)).await;
+        assert_eq!(nodes[0].get_leader().await, None);
+        assert_eq!(nodes[1].get_leader().await, None);
+        election.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn minority_still_elects_without_quorum_mode() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = cluster(4, &net).await;
+        net.set_down(&address(3), true);
+        net.set_down(&address(4), true);
+        nodes[0].start_election().await;
+        settled_on(&nodes[..2], 2).await;
+    }
+
+    #[tokio::test]
+    async fn no_peers_is_an_empty_snapshot() {
+        let node = BullyElection::new_with_config(1, address(1), BullyConfig::default());
+        assert!(node.get_all_peers().await.is_empty());
+        assert!(node.get_peer(2).await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn peers_added_concurrently_are_all_seen() {
+        let node = BullyElection::new_with_config(1, address(1), BullyConfig::default());
+        let mut tasks = JoinSet::new();
+        for id in 2..=21 {
+            let node = node.clone();
+            tasks.spawn(async move {
+                node.add_peer(id, address(id), 0).await.unwrap();
+                // Readers never see a peer half added
+                for peer in node.get_all_peers().await {
+                    assert_eq!(peer.address, address(peer.id));
+                }
+            });
+        }
+        while let Some(task) = tasks.join_next().await {
+            task.unwrap();
+        }
+
+        let peers = node.get_all_peers().await;
+        let ids: Vec<u32> = peers.iter().map(|p| p.id).collect();
+        assert_eq!(ids, (2..=21).collect::<Vec<_>>());
+        let peer = node.get_peer(7).await.unwrap();
+        assert_eq!((peer.id, peer.address), (7, address(7)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn leader_dying_before_its_coordinator_is_elected_around() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = cluster(3, &net).await;
+        // Node 3 answers, then goes down instead of announcing itself
+        let crash = Arc::clone(&net);
+        net.drop_where(move |_, msg| {
+            let announcing = matches!(msg, BullyMessage::Coordinator { leader_id: 3, .. });
+            if announcing {
+                crash.set_down(&address(3), true);
+            }
+            announcing
+        });
+        let started = Instant::now();
+        nodes[0].start_election().await;
+        settled_on(&nodes[..2], 2).await;
+        // Only after waiting out the COORDINATOR node 3 never sent
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn one_missed_heartbeat_is_only_suspicion() {
+        let node = BullyElection::new_with_config(1, address(1), BullyConfig::default());
+        assert!(!node.leader_health().await.is_suspected());
+
+        let health = node.record_heartbeat(false).await;
+        assert!(health.is_suspected());
+        assert!(!health.is_dead());
+    }
+
+    #[tokio::test]
+    async fn missing_the_threshold_in_a_row_is_death() {
+        let node = BullyElection::new_with_config(1, address(1), BullyConfig::default());
+        node.record_heartbeat(false).await;
+        node.record_heartbeat(false).await;
+        assert!(!node.leader_health().await.is_dead());
+        assert!(node.record_heartbeat(false).await.is_dead());
+        assert_eq!(node.stats().await.heartbeat_failures, 3);
+    }
+
+    #[tokio::test]
+    async fn an_answered_heartbeat_clears_suspicion() {
+        let node = BullyElection::new_with_config(1, address(1), BullyConfig::default());
+        node.record_heartbeat(false).await;
+        node.record_heartbeat(false).await;
+        assert!(!node.record_heartbeat(true).await.is_suspected());
+        // The count starts over
+        node.record_heartbeat(false).await;
+        assert!(!node.record_heartbeat(false).await.is_dead());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_starts_run_one_election() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = cluster(3, &net).await;
+        let coordinators = Arc::new(AtomicU32::new(0));
+        let counted = Arc::clone(&coordinators);
+        net.drop_where(move |_, msg| {
+            if matches!(msg, BullyMessage::Coordinator { .. }) {
+                counted.fetch_add(1, Ordering::SeqCst);
+            }
+            false
+        });
+
+        let mut elections = JoinSet::new();
+        for _ in 0..10 {
+            let node = nodes[2].clone();
+            elections.spawn(async move { node.start_election().await });
+        }
+        while elections.join_next().await.is_some() {}
+        settled_on(&nodes, 3).await;
+
+        // One COORDINATOR to each of the two peers
+        assert_eq!(coordinators.load(Ordering::SeqCst), 2);
+        assert_eq!(nodes[2].stats().await.elections_started, 1);
+        assert_eq!(nodes[2].election_status().await, ElectionState::Idle);
+    }
+
+    #[tokio::test]
+    async fn higher_nodes_draw_shorter_jitter() {
+        let config = BullyConfig {
+            election_jitter: Duration::from_millis(500),
+            ..BullyConfig::default()
+        };
+        let lowest = BullyElection::new_with_config(1, address(1), config.clone());
+        let highest = BullyElection::new_with_config(5, address(5), config);
+        for id in 2..=5 {
+            lowest.add_peer(id, address(id), 0).await.unwrap();
+            highest.add_peer(id - 1, address(id - 1), 0).await.unwrap();
+        }
+        for _ in 0..100 {
+            assert!(lowest.election_jitter().await <= Duration::from_millis(500));
+            assert!(highest.election_jitter().await <= Duration::from_millis(100));
+        }
+    }
+
+    #[tokio::test]
+    async fn no_jitter_when_it_is_off() {
+        let config = BullyConfig {
+            election_jitter: Duration::ZERO,
+            ..BullyConfig::default()
+        };
+        let node = BullyElection::new_with_config(1, address(1), config);
+        node.add_peer(2, address(2), 0).await.unwrap();
+        assert_eq!(node.election_jitter().await, Duration::ZERO);
+    }
+
+    /// How many of three followers elect after their leader, node 4, dies
+    async fn followers_electing(jitter: Duration) -> usize {
+        let config = BullyConfig {
+            heartbeat_mode: HeartbeatMode::Push,
+            heartbeat_interval: Duration::from_secs(1),
+            heartbeat_timeout: Duration::from_millis(500),
+            election_jitter: jitter,
+            ..BullyConfig::default()
+        };
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = cluster_with(4, &net, config).await;
+        net.set_down(&address(4), true);
+        let mut monitors = Vec::new();
+        for node in &nodes[..3] {
+            node.set_leader(4, 1).await;
+            monitors.push(Arc::new(node.clone()).start_leader_monitoring().await);
+        }
+        settled_on(&nodes[..3], 3).await;
+        for monitor in monitors {
+            monitor.shutdown().await;
+        }
+
+        let mut electing = 0;
+        for node in &nodes[..3] {
+            if node.stats().await.elections_started > 0 {
+                electing += 1;
+            }
+        }
+        electing
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn jitter_keeps_followers_from_all_electing() {
+        const RUNS: usize = 20;
+        let mut electing = 0;
+        for _ in 0..RUNS {
+            electing += followers_electing(Duration::from_secs(2)).await;
+        }
+        // Without jitter all three would elect every time
+        assert!(electing * 2 < RUNS * 5, "{} elections in {} runs", electing, RUNS);
+    }
+
+    /// Nodes 1..=n with the given priorities; peers are told each other's
+    /// priority only if `known` is set, otherwise they learn it from messages
+    async fn prioritized(priorities: &[u32], known: bool, net: &Arc<ChannelTransport>) -> Vec<BullyElection> {
+        let n = priorities.len() as u32;
+        let mut nodes = Vec::new();
+        for id in 1..=n {
+            let config = BullyConfig {
+                election_timeout: Duration::from_millis(200),
+                priority: priorities[id as usize - 1],
+                ..BullyConfig::default()
+            };
+            let transport: Arc<dyn Transport> = net.clone();
+            let node = BullyElection::new_with_transport(id, address(id), config, transport);
+            for peer in (1..=n).filter(|&peer| peer != id) {
+                let priority = if known { priorities[peer as usize - 1] } else { 0 };
+                node.add_peer(peer, address(peer), priority).await.unwrap();
+            }
+            net.attach(&node);
+            nodes.push(node);
+        }
+        nodes
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn low_id_with_high_priority_wins() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = prioritized(&[10, 0, 0, 0], true, &net).await;
+        nodes[3].start_election().await;
+        settled_on(&nodes, 1).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn equal_priorities_fall_back_to_the_id() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = prioritized(&[5, 5, 5, 0], true, &net).await;
+        nodes[0].start_election().await;
+        settled_on(&nodes, 3).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn priority_is_learned_from_announcements() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = prioritized(&[10, 0, 0], false, &net).await;
+        // Nobody knows node 1's priority yet, so node 3 takes over
+        nodes[2].start_election().await;
+        settled_on(&nodes, 3).await;
+
+        nodes[0].start_election().await;
+        settled_on(&nodes, 1).await;
+        for node in &nodes[1..] {
+            assert_eq!(node.get_peer(1).await.unwrap().priority, 10);
+        }
+    }
+
+    /// Three nodes led by node 3, where node 1 missed every COORDINATOR
+    async fn node_1_missed_the_announcement(net: &Arc<ChannelTransport>, config: BullyConfig) -> Vec<BullyElection> {
+        let nodes = cluster_with(3, net, config).await;
+        let missing = Arc::new(AtomicBool::new(true));
+        let missed = Arc::clone(&missing);
+        net.drop_where(move |to, msg| {
+            to == address(1) && matches!(msg, BullyMessage::Coordinator { .. }) && missed.load(Ordering::SeqCst)
+        });
+        nodes[2].start_election().await;
+        settled_on(&nodes[1..], 3).await;
+        // Past every COORDINATOR retry
+        sleep(Duration::from_secs(1)).await;
+        assert_eq!(nodes[0].get_leader().await, None);
+        missing.store(false, Ordering::SeqCst);
+        nodes
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reannouncement_reaches_a_node_that_missed_the_coordinator() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let config = BullyConfig {
+            reannounce_interval: Duration::from_secs(2),
+            ..BullyConfig::default()
+        };
+        let nodes = node_1_missed_the_announcement(&net, config).await;
+        let monitor = Arc::new(nodes[2].clone()).start_leader_monitoring().await;
+        settled_on(&nodes, 3).await;
+        assert_eq!(nodes[0].stats().await.elections_started, 0);
+        monitor.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn leaderless_node_elects_on_its_own() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = node_1_missed_the_announcement(&net, BullyConfig::default()).await;
+        // Node 3 does not re-announce; node 1 notices it has no leader
+        let monitor = Arc::new(nodes[0].clone()).start_leader_monitoring().await;
+        settled_on(&nodes, 3).await;
+        assert_eq!(nodes[0].stats().await.elections_started, 1);
+        monitor.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn adding_a_taken_id_or_own_address_fails() {
+        let node = BullyElection::new_with_config(1, address(1), BullyConfig::default());
+        node.add_peer(2, address(2), 0).await.unwrap();
+        assert!(matches!(node.add_peer(2, address(3), 0).await, Err(BullyError::DuplicatePeer(2))));
+        assert!(matches!(node.add_peer(1, address(3), 0).await, Err(BullyError::DuplicatePeer(1))));
+        assert!(matches!(
+            node.add_peer(3, address(1), 0).await,
+            Err(BullyError::SelfAddress(a)) if a == address(1)
+        ));
+    }
+
+    #[tokio::test]
+    async fn unknown_peers_cannot_be_updated_or_removed() {
+        let node = BullyElection::new_with_config(1, address(1), BullyConfig::default());
+        assert!(matches!(node.update_peer(2, address(2)).await, Err(BullyError::PeerUnknown(2))));
+        assert!(matches!(node.remove_peer(2).await, Err(BullyError::PeerUnknown(2))));
+    }
+
+    #[tokio::test]
+    async fn joining_through_a_dead_seed_fails() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let transport: Arc<dyn Transport> = net.clone();
+        let node = BullyElection::new_with_transport(1, address(1), BullyConfig::default(), transport);
+        assert!(matches!(
+            node.join(&address(2)).await,
+            Err(BullyError::Send(SendError::ConnectRefused(a))) if a == address(2)
+        ));
+    }
+
+    #[tokio::test]
+    async fn only_the_leader_can_transfer() {
+        let node = BullyElection::new_with_config(1, address(1), BullyConfig::default());
+        node.add_peer(2, address(2), 0).await.unwrap();
+        assert!(matches!(node.transfer_leadership(2).await, Err(BullyError::NotLeader)));
+        node.set_leader(1, 1).await;
+        assert!(matches!(node.transfer_leadership(3).await, Err(BullyError::PeerUnknown(3))));
+    }
+
+    #[test]
+    fn garbage_on_the_wire_is_a_serde_error() {
+        let node = BullyElection::new_with_config(1, address(1), BullyConfig::default());
+        assert!(matches!(node.decode(b"{not json"), Err(BullyError::Serde(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transfer_to_a_peer_that_never_takes_over_times_out() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = cluster(2, &net).await;
+        nodes[1].start_election().await;
+        settled_on(&nodes, 2).await;
+
+        // A resigned node ignores the request
+        *nodes[0].resigned.write().await = true;
+        assert!(matches!(nodes[1].transfer_leadership(1).await, Err(BullyError::Timeout(_))));
+        assert!(nodes[1].is_leader().await);
+    }
+
+    /// Lose the first `n` ELECTION messages to `to`, counting every one sent there
+    fn lose_elections(net: &ChannelTransport, to: u32, n: u32) -> Arc<AtomicU32> {
+        let sent = Arc::new(AtomicU32::new(0));
+        let counted = Arc::clone(&sent);
+        net.drop_where(move |address_to, msg| {
+            if address_to != address(to) || !matches!(msg, BullyMessage::Election { .. }) {
+                return false;
+            }
+            counted.fetch_add(1, Ordering::SeqCst) < n
+        });
+        sent
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peer_answering_the_second_election_is_not_bullied() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = cluster(2, &net).await;
+        let sent = lose_elections(&net, 2, 1);
+        nodes[0].start_election().await;
+        settled_on(&nodes, 2).await;
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_peer_is_given_up_after_the_retries() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = cluster(2, &net).await;
+        let sent = lose_elections(&net, 2, u32::MAX);
+        nodes[0].start_election().await;
+        assert!(nodes[0].is_leader().await);
+        assert_eq!(sent.load(Ordering::SeqCst), 1 + BullyConfig::default().election_retries);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn election_retries_run_side_by_side() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = cluster(4, &net).await;
+        for id in 2..=4 {
+            lose_elections(&net, id, u32::MAX);
+        }
+        let started = Instant::now();
+        nodes[0].start_election().await;
+        assert!(nodes[0].is_leader().await);
+        // Three 200ms timeouts and 300ms of backoff, as for a single peer
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn monitoring_stops_on_shutdown() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = cluster(2, &net).await;
+        let monitor = Arc::new(nodes[0].clone()).start_leader_monitoring().await;
+        timeout(Duration::from_secs(1), monitor.shutdown())
+            .await
+            .expect("monitoring tasks did not exit");
+
+        // Leaderless for many intervals, yet nothing elects
+        sleep(Duration::from_secs(60)).await;
+        assert_eq!(nodes[0].stats().await.elections_started, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dropping_the_handle_stops_monitoring() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = cluster(2, &net).await;
+        drop(Arc::new(nodes[0].clone()).start_leader_monitoring().await);
+        sleep(Duration::from_secs(60)).await;
+        assert_eq!(nodes[0].stats().await.elections_started, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn running_monitor_elects_when_leaderless() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = cluster(2, &net).await;
+        let monitor = Arc::new(nodes[0].clone()).start_leader_monitoring().await;
+        sleep(Duration::from_secs(60)).await;
+        assert!(nodes[0].stats().await.elections_started > 0);
+        monitor.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cluster_is_healthy_only_under_a_live_leader() {
+        let node = BullyElection::new_with_config(1, address(1), BullyConfig::default());
+        node.add_peer(2, address(2), 0).await.unwrap();
+        assert!(!node.cluster_healthy().await);
+
+        node.set_leader(2, 1).await;
+        assert!(node.cluster_healthy().await);
+        node.record_heartbeat(false).await;
+        assert!(!node.cluster_healthy().await);
+        node.record_heartbeat(true).await;
+        assert!(node.cluster_healthy().await);
+
+        // Silent for longer than a heartbeat interval and timeout
+        sleep(Duration::from_secs(8)).await;
+        assert!(!node.cluster_healthy().await);
+
+        *node.election_state.write().await = ElectionState::Electing { term: 2 };
+        node.set_leader(1, 2).await;
+        assert!(node.cluster_healthy().await);
+        *node.election_state.write().await = ElectionState::Electing { term: 3 };
+        assert!(!node.cluster_healthy().await);
+    }
+
+    /// A directory for state files, removed when the test ends
+    struct StateDir(PathBuf);
+
+    impl StateDir {
+        fn new() -> Self {
+            StateDir(std::env::temp_dir().join(format!("distinsta-bully-{}", uuid::Uuid::new_v4())))
+        }
+    }
+
+    impl Drop for StateDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn with_state(dir: &StateDir) -> BullyConfig {
+        BullyConfig {
+            state_path: Some(dir.0.join("term")),
+            ..BullyConfig::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn term_survives_a_restart() {
+        let dir = StateDir::new();
+        let node = BullyElection::new_with_config(1, address(1), with_state(&dir));
+        assert_eq!(node.get_term().await, 0);
+        node.start_election().await;
+        node.start_election().await;
+        assert_eq!(node.get_term().await, 2);
+        drop(node);
+
+        let restarted = BullyElection::new_with_config(1, address(1), with_state(&dir));
+        assert_eq!(restarted.get_term().await, 2);
+        restarted.start_election().await;
+        assert_eq!(restarted.get_term().await, 3);
+        assert!(!dir.0.join("term.tmp").exists());
+    }
+
+    #[tokio::test]
+    async fn observed_terms_are_persisted_but_never_lowered() {
+        let dir = StateDir::new();
+        let node = BullyElection::new_with_config(1, address(1), with_state(&dir));
+        node.observe_term(7).await;
+        node.observe_term(4).await;
+        assert_eq!(node.get_term().await, 7);
+        assert_eq!(std::fs::read_to_string(dir.0.join("term")).unwrap(), "7");
+    }
+
+    #[test]
+    fn corrupt_or_missing_term_file_starts_at_zero() {
+        let dir = StateDir::new();
+        assert_eq!(load_term(1, Some(&dir.0.join("term"))), 0);
+        write_state_file(&dir.0.join("term"), "not a term").unwrap();
+        assert_eq!(load_term(1, Some(&dir.0.join("term"))), 0);
+        assert_eq!(load_term(1, None), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn first_answer_ends_the_election_without_waiting_for_silent_peers() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = cluster(6, &net).await;
+        let silent: HashSet<String> = (2..=5).map(address).collect();
+        net.drop_where(move |to, _| silent.contains(to));
+
+        nodes[0].start_election().await;
+        assert_eq!(nodes[0].get_leader().await, Some(6));
+        // Node 6 answered at once and announced itself after `answer_wait`,
+        // well before a single ELECTION to the silent nodes timed out
+        let took = nodes[0].stats().await.last_election_duration.unwrap();
+        assert!(took < Duration::from_millis(200), "election took {:?}", took);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_higher_peers_cost_one_timeout_not_one_each() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let nodes = cluster(6, &net).await;
+        for id in 2..=6 {
+            net.drop_where(move |to, _| to == address(id));
+        }
+
+        nodes[0].start_election().await;
+        assert!(nodes[0].is_leader().await);
+        // One peer's three ELECTION attempts with their backoff, 900ms, then
+        // one unanswered COORDINATOR round; one by one it would be 4.5s
+        let took = nodes[0].stats().await.last_election_duration.unwrap();
+        assert!(took < Duration::from_millis(1_500), "election took {:?}", took);
+    }
+
+    fn election_from(from_id: u32, from_address: String) -> BullyMessage {
+        BullyMessage::Election {
+            from_id,
+            term: 1,
+            priority: 0,
+            from_address,
+        }
+    }
+
+    fn coordinator_from(leader_id: u32) -> BullyMessage {
+        BullyMessage::Coordinator {
+            leader_id,
+            term: 1,
+            priority: 0,
+            from_address: address(leader_id),
+        }
+    }
+
+    #[tokio::test]
+    async fn own_messages_are_dropped() {
+        let node = BullyElection::new_with_config(1, address(1), BullyConfig::default());
+        let echo = election_from(1, address(1));
+        assert_eq!(node.validate(&echo).await, Err(Rejection::FromSelf));
+        assert!(node.handle_message(echo).await.is_none());
+        assert_eq!(node.get_term().await, 0);
+        assert!(!node.is_degraded().await);
+    }
+
+    #[tokio::test]
+    async fn another_node_with_our_id_is_told_and_degrades_us() {
+        let node = BullyElection::new_with_config(1, address(1), BullyConfig::default());
+        let impostor = election_from(1, address(7));
+        assert_eq!(node.validate(&impostor).await, Err(Rejection::DuplicateId(address(7))));
+        match node.handle_message(impostor).await {
+            Some(BullyMessage::DuplicateId { id: 1, address: ours }) => assert_eq!(ours, address(1)),
+            other => panic!("expected DuplicateId, got {:?}", other),
+        }
+        assert!(node.is_degraded().await);
+        node.start_election().await;
+        assert_eq!(node.stats().await.elections_started, 0);
+    }
+
+    #[tokio::test]
+    async fn coordinator_for_an_unknown_node_is_dropped() {
+        let node = BullyElection::new_with_config(1, address(1), BullyConfig::default());
+        assert_eq!(node.validate(&coordinator_from(9)).await, Err(Rejection::UnknownLeader(9)));
+        assert!(node.handle_message(coordinator_from(9)).await.is_none());
+        assert_eq!(node.get_leader().await, None);
+    }
+
+    #[tokio::test]
+    async fn refused_coordinator_still_teaches_its_address() {
+        let node = BullyElection::new_with_config(1, address(1), BullyConfig::default());
+        assert!(node.handle_message(coordinator_from(9)).await.is_none());
+        assert_eq!(node.get_leader().await, None);
+        assert_eq!(node.get_peer(9).await.unwrap().address, address(9));
+
+        // Known now, its next claim is followed
+        assert_eq!(node.validate(&coordinator_from(9)).await, Ok(()));
+        assert!(matches!(
+            node.handle_message(coordinator_from(9)).await,
+            Some(BullyMessage::CoordinatorAck { from_id: 1 })
+        ));
+        assert_eq!(node.get_leader().await, Some(9));
+    }
+
+    #[tokio::test]
+    async fn refused_coordinator_with_a_bogus_address_teaches_nothing() {
+        let node = BullyElection::new_with_config(1, address(1), BullyConfig::default());
+        for from_address in [String::new(), "not an address".to_string(), address(1)] {
+            let coordinator = BullyMessage::Coordinator { leader_id: 9, term: 1, priority: 0, from_address };
+            assert!(node.handle_message(coordinator).await.is_none());
+        }
+        assert!(node.get_peer(9).await.is_none());
+        assert_eq!(node.get_leader().await, None);
+    }
+
+    #[tokio::test]
+    async fn unknown_leaders_are_followed_when_allowed() {
+        let config = BullyConfig {
+            accept_unknown_leaders: true,
+            ..BullyConfig::default()
+        };
+        let node = BullyElection::new_with_config(1, address(1), config);
+        assert!(matches!(
+            node.handle_message(coordinator_from(9)).await,
+            Some(BullyMessage::CoordinatorAck { from_id: 1 })
+        ));
+        assert_eq!(node.get_leader().await, Some(9));
+        assert_eq!(node.get_peer(9).await.unwrap().address, address(9));
+    }
+
+    /// Three nodes led by node 3, where node 1 remembers its leader in `dir`
+    async fn remembering_cluster(net: &Arc<ChannelTransport>, dir: &StateDir) -> Vec<BullyElection> {
+        let mut nodes = cluster(3, net).await;
+        nodes[0] = restarted(net, dir).await;
+        nodes[2].start_election().await;
+        settled_on(&nodes, 3).await;
+        nodes
+    }
+
+    /// Node 1 of a three-node cluster, started afresh on the state in `dir`
+    async fn restarted(net: &Arc<ChannelTransport>, dir: &StateDir) -> BullyElection {
+        let config = BullyConfig {
+            leader_state_path: Some(dir.0.join("leader")),
+            ..with_state(dir)
+        };
+        let transport: Arc<dyn Transport> = net.clone();
+        let node = BullyElection::new_with_transport(1, address(1), config, transport);
+        for peer in 2..=3 {
+            node.add_peer(peer, address(peer), 0).await.unwrap();
+        }
+        net.attach(&node);
+        node
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restarted_follower_rejoins_its_leader_without_an_election() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let dir = StateDir::new();
+        let nodes = remembering_cluster(&net, &dir).await;
+        let elections = Arc::new(AtomicU32::new(0));
+        let counted = Arc::clone(&elections);
+        net.drop_where(move |_, msg| {
+            if matches!(msg, BullyMessage::Election { .. }) {
+                counted.fetch_add(1, Ordering::SeqCst);
+            }
+            false
+        });
+
+        let node = restarted(&net, &dir).await;
+        assert!(node.resume_leader().await);
+        assert_eq!(node.get_leader_with_term().await, nodes[2].get_leader_with_term().await);
+        assert_eq!(elections.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn remembered_leader_that_is_gone_is_not_adopted() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let dir = StateDir::new();
+        remembering_cluster(&net, &dir).await;
+        net.set_down(&address(3), true);
+
+        let node = restarted(&net, &dir).await;
+        assert!(!node.resume_leader().await);
+        assert_eq!(node.get_leader().await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn remembered_leader_that_stepped_down_is_not_adopted() {
+        let net = Arc::new(ChannelTransport::new(Duration::from_millis(200)));
+        let dir = StateDir::new();
+        let nodes = remembering_cluster(&net, &dir).await;
+        let remembered = std::fs::read_to_string(dir.0.join("leader")).unwrap();
+        nodes[2].transfer_leadership(2).await.unwrap();
+        // Node 1 went down before it heard of the handover
+        write_state_file(&dir.0.join("leader"), &remembered).unwrap();
+
+        let node = restarted(&net, &dir).await;
+        assert!(!node.resume_leader().await);
+    }
+
+    #[tokio::test]
+    async fn nothing_remembered_means_electing() {
+        let dir = StateDir::new();
+        let config = BullyConfig {
+            leader_state_path: Some(dir.0.join("leader")),
+            ..BullyConfig::default()
+        };
+        let node = BullyElection::new_with_config(1, address(1), config);
+        assert!(!node.resume_leader().await);
     }
 }
@@ -1,66 +1,329 @@
+mod blobformat;
+mod compression;
+// Only the server addresses are read here; the rest configures nodes
+#[allow(dead_code)]
 mod config;
+// The client only opens images, never seals them
+#[allow(dead_code)]
+mod encryption;
+// Telling frames from legacy JSON lines is left to servers
+#[allow(dead_code)]
+mod framing;
+// Internal and admin messages are only exchanged between nodes and admins
+#[allow(dead_code)]
 mod protocol;
+// Only `extract` is used here; servers embed
+#[allow(dead_code)]
+mod stego;
+// Only `sealed_name` is used here; servers make thumbnails
+#[allow(dead_code)]
+mod thumbnail;
+mod validation;
 
+use compression::{compress_if_smaller, decompress};
 use config::Config;
-use protocol::{ClientRequest, ServerResponse};
+use encryption::{
+    derive_user_key, generate_share_keypair, is_authenticated, key_generation, open_image, open_metadata, open_shared, unwrap_for_viewer,
+    CryptoError, SecretKey, UserKey,
+};
+use framing::{decode, encode, read_frame, write_frame, Format, FrameError};
+use protocol::{BatchOutcome, BatchResult, CatalogEntry, ClientRequest, Codec, ErrorCode, ImageMetadata, ImageSelector, ImageSort, MetadataStrip, StorageCompression, DOWNLOAD_CHUNK_SIZE, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, in_version, is_supported, RequestEnvelope, ResponseEnvelope, SealedEntry, ServerEvent, ServerResponse, TaggedRequest};
+use sha2::{Digest, Sha256};
 use std::env;
 use std::fs;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use tokio::net::TcpStream;
+use tokio::time::{sleep, Duration};
+use zeroize::Zeroizing;
+use validation::{validate_filename, validate_request, validate_username, IMAGE_EXTENSIONS};
 
+/// Broadcasts attempted while the cluster reports it is re-electing
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Images larger than this are uploaded in chunks
+const CHUNKED_UPLOAD_THRESHOLD: usize = 4 * 1024 * 1024;
+const UPLOAD_CHUNK_SIZE: usize = 1024 * 1024;
+/// Images of `upload-dir` are sent in batches of at most this many bytes
+const UPLOAD_BATCH_BYTES: usize = 16 * 1024 * 1024;
+/// Largest image accepted from a compressed response
+const MAX_DECOMPRESSED_LEN: usize = 512 * 1024 * 1024;
+/// A subscription silent for this long is dead; servers send a Keepalive
+/// every 15 seconds
+const SUBSCRIPTION_TIMEOUT: Duration = Duration::from_secs(45);
+/// Wait before subscribing again after the event stream was lost
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(2);
+/// Images shown per page by `list`
+const LIST_PAGE_SIZE: u32 = 20;
+/// Where `keygen` keeps each user's private key for shared images
+const SHARE_KEY_DIR: &str = "keys";
+
+/// What one broadcast to every server produced
+struct BroadcastOutcome {
+    /// Successful responses, with the server (1-based) that sent each
+    responses: Vec<(usize, ServerResponse)>,
+    /// Some server asked for a retry, e.g. during a re-election
+    retry_requested: bool,
+    /// Some server did not know the codec the request was compressed with
+    codec_rejected: bool,
+    /// Some server does not speak the request's protocol version, and speaks this one
+    server_version: Option<u16>,
+}
+
+/// What `upload` and `upload-dir` ask the servers to do before encrypting;
+/// `None` leaves it to their settings
+#[derive(Debug, Clone, Copy, Default)]
+struct UploadFlags {
+    strip_metadata: Option<bool>,
+    compress: Option<bool>,
+}
+
+#[derive(Clone)]
 struct Client {
     username: String,
     server_addresses: Vec<String>,
+    max_frame_len: usize,
+    /// Serialization of requests; servers answer in the same format
+    format: Format,
+    /// Session token from logging in, sent with every request for this user
+    token: String,
+    /// Key the user's images are encrypted under, derived at login
+    image_key: Option<SecretKey>,
 }
 
 impl Client {
-    fn new(username: String, server_addresses: Vec<String>) -> Self {
+    fn new(username: String, server_addresses: Vec<String>, max_frame_len: usize, format: Format) -> Self {
         Client {
             username,
             server_addresses,
+            max_frame_len,
+            format,
+            token: String::new(),
+            image_key: None,
+        }
+    }
+
+    /// Ask for the password and log in, registering the user first if the
+    /// cluster does not know them
+    ///
+    /// `DISTINSTA_PASSWORD` is used instead of asking, e.g. for scripted demos.
+    async fn log_in(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let password = match env::var("DISTINSTA_PASSWORD") {
+            Ok(password) => password,
+            Err(_) => prompt_password(&format!("Password for {} (new users are registered): ", self.username))?,
+        };
+
+        let login = ClientRequest::Login {
+            username: self.username.clone(),
+            password: password.clone(),
+        };
+        let response = match self.broadcast_request(login).await? {
+            ServerResponse::Error {
+                code: ErrorCode::UnknownUser,
+                ..
+            } => {
+                println!("{} is not registered yet, registering", self.username);
+                let register = ClientRequest::Register {
+                    username: self.username.clone(),
+                    password: password.clone(),
+                };
+                self.broadcast_request(register).await?
+            }
+            response => response,
+        };
+
+        match response {
+            ServerResponse::LoggedIn {
+                token,
+                expires_at_unix,
+                key_salt,
+            } => {
+                println!("Logged in as {} (session valid until {} unix)", self.username, expires_at_unix);
+                self.token = token;
+                // The same Argon2id derivation the servers ran, for `decrypt`
+                let username = self.username.clone();
+                let key = tokio::task::spawn_blocking(move || derive_user_key(&username, &password, key_salt.as_bytes()));
+                self.image_key = Some(key.await?);
+                Ok(())
+            }
+            ServerResponse::Error { message, .. } => Err(message.into()),
+            _ => Err("Unexpected response from server".into()),
+        }
+    }
+
+    /// Replace the user's password; the cluster moves their images to a new
+    /// key in the background, and this session keeps working
+    async fn change_password(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let old_password = prompt_password("Current password: ")?;
+        let new_password = prompt_password("New password: ")?;
+        if new_password != prompt_password("New password again: ")? {
+            return Err("The new passwords do not match".into());
+        }
+        let request = ClientRequest::ChangePassword {
+            username: self.username.clone(),
+            token: self.token.clone(),
+            old_password,
+            new_password: new_password.clone(),
+        };
+
+        match self.broadcast_request(request).await? {
+            ServerResponse::LoggedIn { token, key_salt, .. } => {
+                self.token = token;
+                let username = self.username.clone();
+                let key = tokio::task::spawn_blocking(move || derive_user_key(&username, &new_password, key_salt.as_bytes()));
+                self.image_key = Some(key.await?);
+                println!("\n✓ Password changed; images saved before may need downloading again to decrypt\n");
+                Ok(())
+            }
+            ServerResponse::Error { message, .. } => Err(message.into()),
+            _ => Err("Unexpected response from server".into()),
         }
     }
 
     /// Broadcast request to all servers and wait for first successful response
+    ///
+    /// If no server processed it and some asked us to retry (the cluster is
+    /// re-electing), the broadcast is repeated a few times before giving up.
     async fn broadcast_request(&self, request: ClientRequest) -> Result<ServerResponse, Box<dyn std::error::Error>> {
-        println!("Broadcasting request to {} servers...", self.server_addresses.len());
+        let (_, response) = self.broadcast_request_with_server(request).await?;
+        Ok(response)
+    }
+
+    /// Like `broadcast_request`, also returning which server (1-based) answered
+    async fn broadcast_request_with_server(
+        &self,
+        request: ClientRequest,
+    ) -> Result<(usize, ServerResponse), Box<dyn std::error::Error>> {
+        // One id for every server and every retry of this request
+        let request_id = uuid::Uuid::new_v4().to_string();
+        println!("Request ID: {}", request_id);
+        let mut version = PROTOCOL_VERSION;
+        let (mut payload, mut compressed) = self.encode_request(&request_id, &request, true, version)?;
+
+        for attempt in 1..=MAX_ATTEMPTS {
+            let outcome = self.broadcast_once(&request_id, &payload, version).await;
+
+            // Return the first successful response (from assigned server)
+            if let Some(response) = outcome.responses.into_iter().next() {
+                return Ok(response);
+            }
+            if let Some(server_version) = outcome.server_version {
+                version = downgrade_to(version, server_version)?;
+                (payload, compressed) = self.encode_request(&request_id, &request, compressed, version)?;
+                continue;
+            }
+            if outcome.codec_rejected && compressed {
+                println!("Servers cannot decompress the request, resending it uncompressed");
+                (payload, compressed) = self.encode_request(&request_id, &request, false, version)?;
+                continue;
+            }
+            if !outcome.retry_requested {
+                return Err("No server processed the request (all servers declined)".into());
+            }
+            if attempt < MAX_ATTEMPTS {
+                println!("Cluster is re-electing, retrying in {:?} (attempt {}/{})",
+                    RETRY_DELAY, attempt, MAX_ATTEMPTS);
+                sleep(RETRY_DELAY).await;
+            }
+        }
+
+        Err("Cluster is still re-electing, try again later".into())
+    }
+
+    /// Send a request to one server (1-based), e.g. the one handling a chunked upload
+    async fn send_request(
+        &self,
+        server_id: usize,
+        request: ClientRequest,
+    ) -> Result<ServerResponse, Box<dyn std::error::Error>> {
+        let address = self
+            .server_addresses
+            .get(server_id - 1)
+            .ok_or("Unknown server")?;
+        let request_id = uuid::Uuid::new_v4().to_string();
+        let mut version = PROTOCOL_VERSION;
+        let mut compress = true;
+        loop {
+            let (payload, compressed) = self.encode_request(&request_id, &request, compress, version)?;
+            match exchange(address, &payload, &request_id, version, self.max_frame_len).await? {
+                ServerResponse::UnsupportedVersion { server_version } if server_version != version => {
+                    version = downgrade_to(version, server_version)?;
+                }
+                ServerResponse::UnsupportedCodec { .. } if compressed => compress = false,
+                response => return Ok(response),
+            }
+        }
+    }
+
+    /// Serialize a request, refusing it before sending if servers would reject
+    /// its frame or its names
+    ///
+    /// With `compress`, image bytes are zstd-compressed when that saves more
+    /// than 10%. Also returns whether they were.
+    fn encode_request(
+        &self,
+        request_id: &str,
+        request: &ClientRequest,
+        compress: bool,
+        protocol_version: u16,
+    ) -> Result<(Vec<u8>, bool), Box<dyn std::error::Error>> {
+        validate_request(request, MAX_DECOMPRESSED_LEN)?;
+        let mut body = request.clone();
+        let mut compression = None;
+        if compress {
+            if let Some(data) = body.image_data_mut() {
+                if let Some(compressed) = compress_if_smaller(data) {
+                    println!("Compressed image data: {} -> {} bytes", data.len(), compressed.len());
+                    *data = compressed;
+                    compression = Some(Codec::Zstd);
+                }
+            }
+        }
+        let compressed = compression.is_some();
+
+        let payload = in_version(protocol_version, || {
+            encode(
+                self.format,
+                &TaggedRequest::Client(RequestEnvelope {
+                    protocol_version,
+                    request_id: request_id.to_string(),
+                    body,
+                    compression,
+                }),
+            )
+        })?;
+        if payload.len() > self.max_frame_len {
+            return Err(FrameError::FrameTooLarge {
+                len: payload.len(),
+                max: self.max_frame_len,
+            }
+            .into());
+        }
+        Ok((payload, compressed))
+    }
 
-        let request_json = serde_json::to_string(&request)?;
+    /// Send one request to every server and sort out their responses
+    ///
+    /// Responses tagged with a different request id are dropped.
+    async fn broadcast_once(&self, request_id: &str, payload: &[u8], version: u16) -> BroadcastOutcome {
+        println!("Broadcasting request to {} servers...", self.server_addresses.len());
 
         // Send to all servers concurrently
         let mut tasks = vec![];
 
         for (idx, address) in self.server_addresses.iter().enumerate() {
             let addr = address.clone();
-            let req = request_json.clone();
+            let req = payload.to_vec();
+            let expected_id = request_id.to_string();
+            let max_frame_len = self.max_frame_len;
 
             let task = tokio::spawn(async move {
                 println!("  Sending to server {} at {}", idx + 1, addr);
 
-                match TcpStream::connect(&addr).await {
-                    Ok(mut stream) => {
-                        if stream.write_all(req.as_bytes()).await.is_err() {
-                            return Err("Write failed".to_string());
-                        }
-                        if stream.write_all(b"\n").await.is_err() {
-                            return Err("Write newline failed".to_string());
-                        }
-
-                        let mut reader = BufReader::new(&mut stream);
-                        let mut response_line = String::new();
-
-                        match reader.read_line(&mut response_line).await {
-                            Ok(_) => {
-                                match serde_json::from_str::<ServerResponse>(&response_line) {
-                                    Ok(response) => Ok((idx + 1, response)),
-                                    Err(e) => Err(format!("Parse error: {}", e)),
-                                }
-                            }
-                            Err(e) => Err(format!("Read error: {}", e)),
-                        }
-                    }
-                    Err(e) => Err(format!("Connection failed: {}", e)),
-                }
+                exchange(&addr, &req, &expected_id, version, max_frame_len)
+                    .await
+                    .map(|response| (idx + 1, response))
             });
 
             tasks.push(task);
@@ -68,39 +331,100 @@ impl Client {
 
         // Wait for all tasks and collect results
         let mut successful_responses = vec![];
+        let mut retry_requested = false;
+        let mut codec_rejected = false;
+        let mut server_version = None;
         for task in tasks {
-            if let Ok(result) = task.await {
-                if let Ok((server_id, response)) = result {
-                    // Only accept non-error responses (from assigned server)
-                    match &response {
-                        ServerResponse::EncryptedImageData { .. } => {
-                            println!("  ✓ Server {} processed request", server_id);
-                            successful_responses.push(response);
-                        }
-                        ServerResponse::Error { message } => {
-                            println!("  - Server {} declined: {}", server_id, message);
-                        }
+            if let Ok(Ok((server_id, response))) = task.await {
+                // Only accept non-error responses (from assigned server)
+                match &response {
+                    ServerResponse::EncryptedImageData { .. }
+                    | ServerResponse::ImageData { .. }
+                    | ServerResponse::ImageList { .. } => {
+                        println!("  ✓ Server {} processed request", server_id);
+                        successful_responses.push((server_id, response));
+                    }
+                    ServerResponse::Deleted { .. }
+                    | ServerResponse::UploadStarted { .. }
+                    | ServerResponse::ChunkReceived { .. }
+                    | ServerResponse::MissingChunks { .. }
+                    | ServerResponse::Shared { .. }
+                    | ServerResponse::AccessUpdated { .. }
+                    | ServerResponse::BatchUploaded { .. }
+                    | ServerResponse::LoggedIn { .. }
+                    | ServerResponse::ImageUpdated { .. }
+                    | ServerResponse::Notifications { .. }
+                    | ServerResponse::ClusterStatus { .. }
+                    | ServerResponse::Quota { .. }
+                    | ServerResponse::Thumbnail { .. }
+                    | ServerResponse::PublicKeyRegistered { .. }
+                    | ServerResponse::SharedImageData { .. }
+                    | ServerResponse::SealedSharedImage { .. } => {
+                        println!("  ✓ Server {} processed request", server_id);
+                        successful_responses.push((server_id, response));
+                    }
+                    // The responsible server looked and the image does not exist
+                    ServerResponse::NotFound { .. } => {
+                        println!("  ✓ Server {} answered: not found", server_id);
+                        successful_responses.push((server_id, response));
+                    }
+                    ServerResponse::UnsupportedVersion { server_version: version } => {
+                        println!("  ~ Server {} speaks protocol version {}", server_id, version);
+                        server_version = Some(*version);
+                    }
+                    ServerResponse::UnsupportedCodec { codec } => {
+                        println!("  ~ Server {} does not support {} compression", server_id, codec);
+                        codec_rejected = true;
+                    }
+                    ServerResponse::Forbidden { .. }
+                    | ServerResponse::QuotaExhausted { .. }
+                    | ServerResponse::DecompressFailed { .. }
+                    | ServerResponse::BatchTooLarge { .. }
+                    | ServerResponse::ChecksumMismatch { .. } => {
+                        println!("  ✗ Server {} refused the request", server_id);
+                        successful_responses.push((server_id, response));
+                    }
+                    // Stream frames never answer a broadcast, and `exchange`
+                    // reads past progress reports
+                    ServerResponse::DownloadBegin { .. }
+                    | ServerResponse::DownloadChunk { .. }
+                    | ServerResponse::DownloadEnd { .. }
+                    | ServerResponse::Progress { .. }
+                    | ServerResponse::Event { .. }
+                    | ServerResponse::Keepalive => {
+                        println!("  ✗ Server {} sent an unexpected response", server_id);
+                    }
+                    ServerResponse::Error { code: ErrorCode::NotAssigned, .. } => {}
+                    ServerResponse::Error { code: ErrorCode::Retriable, message } => {
+                        println!("  ~ Server {} asked to retry: {}", server_id, message);
+                        retry_requested = true;
+                    }
+                    // The responsible server failed; its answer is final
+                    ServerResponse::Error { code, message } => {
+                        println!("  ✗ Server {} failed ({:?}): {}", server_id, code, message);
+                        successful_responses.push((server_id, response));
                     }
                 }
             }
         }
 
-        if successful_responses.is_empty() {
-            return Err("No server processed the request (all servers declined)".into());
+        BroadcastOutcome {
+            responses: successful_responses,
+            retry_requested,
+            codec_rejected,
+            server_version,
         }
-
-        // Return the first successful response (from assigned server)
-        Ok(successful_responses.into_iter().next().unwrap())
     }
 
-    async fn upload_image(&self, filepath: &str) -> Result<(), Box<dyn std::error::Error>> {
+    /// Upload one image, stripped and compressed as `flags` ask
+    async fn upload_image(&self, filepath: &str, flags: UploadFlags) -> Result<(), Box<dyn std::error::Error>> {
         println!("\n=== Uploading Image ===");
         println!("File: {}", filepath);
         println!("User: {}", self.username);
 
         // Read image file
         let image_data = fs::read(filepath)?;
-        let filename = std::path::Path::new(filepath)
+        let filename = Path::new(filepath)
             .file_name()
             .unwrap()
             .to_str()
@@ -109,14 +433,32 @@ impl Client {
 
         println!("Image size: {} bytes", image_data.len());
 
-        let request = ClientRequest::UploadImage {
-            username: self.username.clone(),
-            image_data,
-            filename: filename.clone(),
+        let response = if image_data.len() > CHUNKED_UPLOAD_THRESHOLD {
+            self.upload_chunked(&filename, &image_data, flags).await?
+        } else {
+            let request = ClientRequest::UploadImage {
+                username: self.username.clone(),
+                token: self.token.clone(),
+                sha256: Some(Sha256::digest(&image_data).into()),
+                image_data,
+                filename: filename.clone(),
+                strip_metadata: flags.strip_metadata,
+                compress: flags.compress,
+            };
+            self.broadcast_request(request).await?
         };
 
-        match self.broadcast_request(request).await? {
-            ServerResponse::EncryptedImageData { data } => {
+        match response {
+            ServerResponse::EncryptedImageData { metadata, data } => {
+                // The server reports what it encrypted; anything else was
+                // corrupted on the way back
+                if let Some(metadata) = &metadata {
+                    if !matches_metadata(&data, metadata) {
+                        eprintln!("\n✗ Error: The encrypted image was corrupted in transit, upload it again");
+                        return Ok(());
+                    }
+                }
+
                 // Save encrypted image to images directory with timestamp
                 fs::create_dir_all("images")?;
 
@@ -126,12 +468,12 @@ impl Client {
                     .unwrap()
                     .as_secs();
 
-                let file_stem = std::path::Path::new(&filename)
+                let file_stem = Path::new(&filename)
                     .file_stem()
                     .unwrap()
                     .to_str()
                     .unwrap();
-                let extension = std::path::Path::new(&filename)
+                let extension = Path::new(&filename)
                     .extension()
                     .and_then(|e| e.to_str())
                     .unwrap_or("png");
@@ -142,8 +484,14 @@ impl Client {
                 fs::write(&encrypted_path, data)?;
                 println!("\n✓ Success!");
                 println!("Encrypted image saved to: {}", encrypted_path);
+                if let Some(metadata) = metadata {
+                    save_metadata(Path::new(&encrypted_path), &metadata)?;
+                }
             }
-            ServerResponse::Error { message } => {
+            ServerResponse::ChecksumMismatch { filename } => {
+                eprintln!("\n✗ Error: {} was corrupted on the way to the server, upload it again", filename);
+            }
+            ServerResponse::DecompressFailed { message } | ServerResponse::Error { message, .. } => {
                 eprintln!("\n✗ Error: {}", message);
             }
             _ => {
@@ -154,89 +502,1529 @@ impl Client {
         Ok(())
     }
 
-    async fn run_repl(&self) {
-        println!("\n=== Distributed Image Storage Client (REPL) ===");
+    /// Upload every image in a directory in as few batches as fit
+    /// `UPLOAD_BATCH_BYTES`, then print how each one fared
+    async fn upload_dir(&self, dir: &str, flags: UploadFlags) -> Result<(), Box<dyn std::error::Error>> {
+        println!("\n=== Uploading Directory ===");
+        println!("Directory: {}", dir);
         println!("User: {}", self.username);
-        println!("Multicast mode: Broadcasting to all servers");
-        println!("Type 'help' for commands, 'quit' to exit");
-        println!("================================================\n");
 
-        loop {
-            print!("{}> ", self.username);
-            std::io::Write::flush(&mut std::io::stdout()).unwrap();
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file() && is_image(path))
+            .collect();
+        paths.sort();
+        if paths.is_empty() {
+            println!("\nNo images found in {}\n", dir);
+            return Ok(());
+        }
 
-            let mut input = String::new();
-            match std::io::stdin().read_line(&mut input) {
+        let mut results = Vec::new();
+        let mut batch = Vec::new();
+        let mut batch_bytes = 0;
+        for path in paths {
+            let filename = path.file_name().unwrap().to_string_lossy().into_owned();
+            // One bad name would get the whole batch refused
+            if let Err(e) = validate_filename("filename", &filename) {
+                results.push(failed(&filename, e.to_string()));
+                continue;
+            }
+            let data = match fs::read(&path) {
+                Ok(data) if data.is_empty() => {
+                    results.push(failed(&filename, "empty file".to_string()));
+                    continue;
+                }
+                Ok(data) if data.len() <= UPLOAD_BATCH_BYTES => data,
                 Ok(_) => {
-                    let input = input.trim();
+                    results.push(failed(&filename, "too large for a batch, use upload".to_string()));
+                    continue;
+                }
+                Err(e) => {
+                    results.push(failed(&filename, e.to_string()));
+                    continue;
+                }
+            };
+            if batch_bytes + data.len() > UPLOAD_BATCH_BYTES {
+                results.extend(self.upload_batch(std::mem::take(&mut batch), flags).await);
+                batch_bytes = 0;
+            }
+            batch_bytes += data.len();
+            batch.push((filename, data));
+        }
+        if !batch.is_empty() {
+            results.extend(self.upload_batch(batch, flags).await);
+        }
 
-                    if input.is_empty() {
-                        continue;
+        println!();
+        let mut uploaded = 0;
+        for result in &results {
+            match &result.outcome {
+                BatchOutcome::Uploaded { metadata } => {
+                    uploaded += 1;
+                    match metadata {
+                        Some(metadata) => println!("  ✓ {:<32} {:>12} bytes  Node {}",
+                            result.filename, metadata.encrypted_size, metadata.processed_by_node),
+                        None => println!("  ✓ {}", result.filename),
                     }
+                }
+                BatchOutcome::Failed { message } => println!("  ✗ {:<32} {}", result.filename, message),
+            }
+        }
+        println!("{} of {} image(s) uploaded\n", uploaded, results.len());
+        Ok(())
+    }
 
-                    match input {
-                        "quit" | "exit" | "q" => {
-                            println!("Goodbye!");
-                            break;
-                        }
-                        "help" | "h" => {
-                            println!("\nAvailable commands:");
-                            println!("  upload <image_path>  - Upload and encrypt an image");
-                            println!("  help                 - Show this help message");
-                            println!("  quit                 - Exit the client\n");
-                        }
-                        _ if input.starts_with("upload ") => {
-                            let parts: Vec<&str> = input.splitn(2, ' ').collect();
-                            if parts.len() == 2 {
-                                let image_path = parts[1].trim();
-                                if let Err(e) = self.upload_image(image_path).await {
-                                    eprintln!("Upload failed: {}\n", e);
-                                }
-                            } else {
-                                eprintln!("Usage: upload <image_path>\n");
-                            }
+    /// Send one UploadBatch, turning a failure of the whole batch into a
+    /// failure of each of its images
+    async fn upload_batch(&self, images: Vec<(String, Vec<u8>)>, flags: UploadFlags) -> Vec<BatchResult> {
+        let filenames: Vec<String> = images.iter().map(|(filename, _)| filename.clone()).collect();
+        println!("Uploading a batch of {} image(s)", images.len());
+        let request = ClientRequest::UploadBatch {
+            username: self.username.clone(),
+            token: self.token.clone(),
+            images,
+            strip_metadata: flags.strip_metadata,
+            compress: flags.compress,
+        };
+        let message = match self.broadcast_request(request).await {
+            Ok(ServerResponse::BatchUploaded { results }) => return results,
+            Ok(ServerResponse::BatchTooLarge { total_bytes, max_bytes }) => {
+                format!("batch of {} bytes is over the servers' {} byte limit", total_bytes, max_bytes)
+            }
+            Ok(ServerResponse::Error { message, .. }) => message,
+            Ok(_) => "Unexpected response from server".to_string(),
+            Err(e) => e.to_string(),
+        };
+        filenames.iter().map(|filename| failed(filename, message.clone())).collect()
+    }
+
+    /// Upload a large image chunk by chunk to the server that accepts it,
+    /// resending whatever chunks it reports missing
+    async fn upload_chunked(
+        &self,
+        filename: &str,
+        image_data: &[u8],
+        flags: UploadFlags,
+    ) -> Result<ServerResponse, Box<dyn std::error::Error>> {
+        let begin = ClientRequest::BeginUpload {
+            username: self.username.clone(),
+            token: self.token.clone(),
+            filename: filename.to_string(),
+            total_size: image_data.len() as u64,
+            chunk_size: UPLOAD_CHUNK_SIZE as u64,
+            strip_metadata: flags.strip_metadata,
+            compress: flags.compress,
+        };
+        let (server_id, upload_id) = match self.broadcast_request_with_server(begin).await? {
+            (server_id, ServerResponse::UploadStarted { upload_id }) => (server_id, upload_id),
+            (_, response) => return Ok(response),
+        };
+
+        let chunks: Vec<&[u8]> = image_data.chunks(UPLOAD_CHUNK_SIZE).collect();
+        let sha256 = sha256_hex(image_data);
+        println!("Uploading {} chunks to server {} (upload {})", chunks.len(), server_id, upload_id);
+
+        let mut pending: Vec<u64> = (0..chunks.len() as u64).collect();
+        for _ in 0..MAX_ATTEMPTS {
+            for seq in pending {
+                let request = ClientRequest::UploadChunk {
+                    upload_id: upload_id.clone(),
+                    seq,
+                    data: chunks[seq as usize].to_vec(),
+                };
+                // A lost chunk is reported as missing when finishing, and resent
+                match self.send_request(server_id, request).await {
+                    Ok(ServerResponse::ChunkReceived { .. }) => {}
+                    Ok(ServerResponse::Error { message, .. }) => return Err(message.into()),
+                    Ok(_) => return Err("Unexpected response from server".into()),
+                    Err(e) => eprintln!("  Chunk {} failed: {}", seq, e),
+                }
+            }
+
+            let finish = ClientRequest::FinishUpload {
+                upload_id: upload_id.clone(),
+                sha256: sha256.clone(),
+            };
+            match self.send_request(server_id, finish).await? {
+                ServerResponse::MissingChunks { missing, .. } => {
+                    println!("Server is missing chunks {:?}, resending", missing);
+                    pending = missing.into_iter().filter(|&seq| (seq as usize) < chunks.len()).collect();
+                }
+                response => return Ok(response),
+            }
+        }
+
+        Err(format!("Upload {} is still missing chunks, giving up", upload_id).into())
+    }
+
+    async fn download_image(&self, image: ImageSelector) -> Result<(), Box<dyn std::error::Error>> {
+        println!("\n=== Downloading Image ===");
+        println!("File: {}", image);
+        println!("User: {}", self.username);
+
+        fs::create_dir_all("downloads")?;
+        let request = ClientRequest::DownloadImage {
+            username: self.username.clone(),
+            token: self.token.clone(),
+            image: image.clone(),
+        };
+        let response = match &image {
+            // Streams are only asked for by name; an image named by its
+            // content id comes in one piece
+            ImageSelector::Name(filename) => {
+                let name = Path::new(filename).file_name().ok_or("Invalid filename")?;
+                match self.stream_download(filename, &Path::new("downloads").join(name)).await? {
+                    None => return Ok(()),
+                    // Servers from before streaming send the image in one piece
+                    Some(ServerResponse::UnsupportedVersion { .. }) => self.broadcast_request(request).await?,
+                    Some(response) => response,
+                }
+            }
+            ImageSelector::ContentId(_) => self.broadcast_request(request).await?,
+        };
+
+        match response {
+            ServerResponse::ImageData { metadata, data, filename } => {
+                let path = Path::new("downloads").join(Path::new(&filename).file_name().ok_or("Invalid filename")?);
+                if let Some(metadata) = &metadata {
+                    if !matches_metadata(&data, metadata) {
+                        eprintln!("\n✗ Error: {} was corrupted in transit, download it again", filename);
+                        return Ok(());
+                    }
+                }
+                fs::write(&path, &data)?;
+                println!("\n✓ Success!");
+                println!("Encrypted image ({} bytes) saved to: {}", data.len(), path.display());
+                if let Some(metadata) = metadata {
+                    save_metadata(&path, &metadata)?;
+                }
+            }
+            ServerResponse::NotFound { filename } => {
+                eprintln!("\n✗ No image named {} was uploaded by {}", filename, self.username);
+            }
+            ServerResponse::Error { message, .. } => {
+                eprintln!("\n✗ Error: {}", message);
+            }
+            _ => {
+                eprintln!("\n✗ Unexpected response from server");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Download an image chunk by chunk into `path`, `None` once it is saved
+    ///
+    /// Chunks go to `<path>.partial`, which is kept if the connection drops so
+    /// the next download resumes after its last whole chunk. Servers are asked
+    /// one at a time until the assigned one answers; any answer but a stream,
+    /// e.g. NotFound or ImageData, is returned as is.
+    async fn stream_download(&self, filename: &str, path: &Path) -> Result<Option<ServerResponse>, Box<dyn std::error::Error>> {
+        let partial = partial_path(path);
+        let from_seq = match fs::metadata(&partial) {
+            Ok(existing) => existing.len() / DOWNLOAD_CHUNK_SIZE as u64,
+            Err(_) => 0,
+        };
+        if from_seq > 0 {
+            println!("Resuming {} after {} chunks", partial.display(), from_seq);
+        }
+
+        let request_id = uuid::Uuid::new_v4().to_string();
+        println!("Request ID: {}", request_id);
+        let request = ClientRequest::StreamDownload {
+            username: self.username.clone(),
+            token: self.token.clone(),
+            filename: filename.to_string(),
+            from_seq,
+        };
+        let (payload, _) = self.encode_request(&request_id, &request, false, PROTOCOL_VERSION)?;
+
+        for attempt in 1..=MAX_ATTEMPTS {
+            let mut retry_requested = false;
+            for (idx, address) in self.server_addresses.iter().enumerate() {
+                let (mut stream, response) = match open_stream(address, &payload, &request_id, self.max_frame_len).await {
+                    Ok(opened) => opened,
+                    Err(e) => {
+                        println!("  - Server {} unavailable: {}", idx + 1, e);
+                        continue;
+                    }
+                };
+                match response {
+                    ServerResponse::DownloadBegin { metadata, total_chunks } => {
+                        println!("  ✓ Server {} is sending {} chunks", idx + 1, total_chunks);
+                        let sha256 = self
+                            .receive_chunks(&mut stream, &request_id, &partial, from_seq, total_chunks)
+                            .await?;
+                        if sha256_file(&partial)? != sha256 {
+                            fs::remove_file(&partial)?;
+                            return Err(format!("{} was corrupted in transit, download it again", filename).into());
                         }
-                        _ => {
-                            eprintln!("Unknown command: '{}'. Type 'help' for available commands.\n", input);
+                        fs::rename(&partial, path)?;
+                        println!("\n✓ Success!");
+                        println!("Encrypted image ({} bytes) saved to: {}", fs::metadata(path)?.len(), path.display());
+                        if let Some(metadata) = metadata {
+                            save_metadata(path, &metadata)?;
                         }
+                        return Ok(None);
                     }
+                    ServerResponse::Error { code: ErrorCode::NotAssigned, .. } => {}
+                    ServerResponse::Error { code: ErrorCode::Retriable, message } => {
+                        println!("  ~ Server {} asked to retry: {}", idx + 1, message);
+                        retry_requested = true;
+                    }
+                    response => return Ok(Some(response)),
                 }
+            }
+            if !retry_requested {
+                return Err("No server processed the request (all servers declined)".into());
+            }
+            if attempt < MAX_ATTEMPTS {
+                println!("Cluster is re-electing, retrying in {:?} (attempt {}/{})",
+                    RETRY_DELAY, attempt, MAX_ATTEMPTS);
+                sleep(RETRY_DELAY).await;
+            }
+        }
+
+        Err("Cluster is still re-electing, try again later".into())
+    }
+
+    /// Append chunks `from_seq` to `total_chunks` of a stream to `partial`,
+    /// returning the hash the server sent at the end
+    async fn receive_chunks(
+        &self,
+        stream: &mut TcpStream,
+        request_id: &str,
+        partial: &Path,
+        from_seq: u64,
+        total_chunks: u64,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        if from_seq > total_chunks {
+            fs::remove_file(partial)?;
+            return Err("The partial download is from a different image, download it again".into());
+        }
+        let file = fs::OpenOptions::new().create(true).append(true).open(partial)?;
+        // Drop the tail of a chunk that was cut off last time
+        file.set_len(from_seq * DOWNLOAD_CHUNK_SIZE as u64)?;
+        let mut file = std::io::BufWriter::new(file);
+
+        let mut next_seq = from_seq;
+        loop {
+            let response = match read_response(stream, request_id, PROTOCOL_VERSION, self.max_frame_len).await {
+                Ok(response) => response,
                 Err(e) => {
-                    eprintln!("Error reading input: {}", e);
-                    break;
+                    file.flush()?;
+                    return Err(format!(
+                        "Download interrupted after {} of {} chunks ({}); run it again to resume from {}",
+                        next_seq, total_chunks, e, partial.display()
+                    ).into());
+                }
+            };
+            match response {
+                ServerResponse::DownloadChunk { seq, data } if seq == next_seq => {
+                    file.write_all(&data)?;
+                    next_seq += 1;
+                }
+                ServerResponse::DownloadEnd { sha256 } if next_seq == total_chunks => {
+                    file.flush()?;
+                    return Ok(sha256);
+                }
+                _ => {
+                    file.flush()?;
+                    return Err(format!("Unexpected response after chunk {} of {}", next_seq, total_chunks).into());
                 }
             }
         }
     }
-}
 
-#[tokio::main]
-async fn main() {
-    let args: Vec<String> = env::args().collect();
+    /// Show page `page` (from 1) of the user's images, with `sealed` having
+    /// the servers leave their names sealed for this client to open
+    async fn list_images(
+        &self,
+        page: u64,
+        sort_by: ImageSort,
+        descending: bool,
+        sealed: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let request = ClientRequest::ListImages {
+            username: self.username.clone(),
+            token: self.token.clone(),
+            offset: (page - 1) * LIST_PAGE_SIZE as u64,
+            limit: LIST_PAGE_SIZE,
+            sort_by,
+            descending,
+            sealed_names: sealed,
+        };
 
-    if args.len() < 2 {
-        eprintln!("Usage: {} <username>", args[0]);
-        eprintln!("Example: {} alice", args[0]);
-        eprintln!("\nNote: Client broadcasts to all servers (8001, 8002, 8003)");
-        std::process::exit(1);
+        match self.broadcast_request(request).await? {
+            ServerResponse::ImageList { total_count: 0, .. } => {
+                println!("\nNo images uploaded by {}\n", self.username);
+            }
+            ServerResponse::ImageList { images, total_count, .. } if images.is_empty() => {
+                println!("\nNo page {}; {} has {} image(s)\n", page, self.username, total_count);
+            }
+            ServerResponse::ImageList { mut images, total_count, has_more } => {
+                for image in &mut images {
+                    let Some(sealed) = image.sealed_entry.take() else {
+                        continue;
+                    };
+                    match self.open_entry(&sealed) {
+                        Some(entry) => {
+                            image.filename = entry.filename;
+                            if let Some(metadata) = entry.metadata {
+                                image.tags = metadata.tags;
+                                image.caption = metadata.caption;
+                            }
+                        }
+                        None => image.filename = format!("(sealed) {}", &image.filename[..16.min(image.filename.len())]),
+                    }
+                }
+                println!("\n{:<32} {:>12} {:>12} {:>6}", "FILENAME", "SIZE", "UPLOADED", "NODE");
+                for image in &images {
+                    let thumbnail = if image.has_thumbnail { "  [thumbnail]" } else { "" };
+                    println!("{:<32} {:>12} {:>12} {:>6}{}",
+                        image.filename, image.size_bytes, image.uploaded_at_unix, image.node_id, thumbnail);
+                    if !image.tags.is_empty() {
+                        println!("    tags: {}", image.tags.join(", "));
+                    }
+                    if let Some(caption) = &image.caption {
+                        println!("    caption: {}", caption);
+                    }
+                }
+                let pages = total_count.div_ceil(LIST_PAGE_SIZE as u64);
+                println!("Page {} of {} ({} image(s) in total)", page, pages, total_count);
+                if has_more {
+                    println!("Next: list --page {}", page + 1);
+                }
+                println!();
+            }
+            ServerResponse::Error { message, .. } => {
+                eprintln!("\n✗ Error: {}", message);
+            }
+            _ => {
+                eprintln!("\n✗ Unexpected response from server");
+            }
+        }
+
+        Ok(())
     }
 
-    let username = args[1].clone();
+    async fn delete_image(&self, image: ImageSelector) -> Result<(), Box<dyn std::error::Error>> {
+        print!("Delete {} from the cluster? This cannot be undone [y/N] ", image);
+        std::io::Write::flush(&mut std::io::stdout())?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            println!("Not deleted\n");
+            return Ok(());
+        }
 
-    // Load configuration from config.toml
-    let config = Config::load("config.toml").expect("Failed to load config.toml");
-    let server_addresses = config.get_all_server_addresses();
+        let request = ClientRequest::DeleteImage {
+            username: self.username.clone(),
+            token: self.token.clone(),
+            image,
+        };
 
-    if server_addresses.is_empty() {
-        eprintln!("Error: No servers found in config.toml");
-        std::process::exit(1);
-    }
+        match self.broadcast_request(request).await? {
+            ServerResponse::Deleted { filename } => {
+                println!("\n✓ Deleted {}\n", filename);
+            }
+            ServerResponse::NotFound { filename } => {
+                eprintln!("\n✗ No image named {} was uploaded by {}", filename, self.username);
+            }
+            ServerResponse::Forbidden { message } | ServerResponse::Error { message, .. } => {
+                eprintln!("\n✗ Error: {}", message);
+            }
+            _ => {
+                eprintln!("\n✗ Unexpected response from server");
+            }
+        }
 
-    println!("Client will broadcast to servers:");
-    for addr in &server_addresses {
-        println!("  - {}", addr);
+        Ok(())
     }
 
-    let client = Client::new(username, server_addresses);
-    client.run_repl().await;
+    /// Rename an image or replace its tags or caption, without re-uploading it
+    async fn update_image_meta(
+        &self,
+        filename: &str,
+        new_filename: Option<String>,
+        tags: Option<Vec<String>>,
+        caption: Option<String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let request = ClientRequest::UpdateImageMeta {
+            username: self.username.clone(),
+            token: self.token.clone(),
+            filename: filename.to_string(),
+            new_filename,
+            tags,
+            caption,
+        };
+
+        match self.broadcast_request(request).await? {
+            ServerResponse::ImageUpdated { filename, metadata } => {
+                println!("\n✓ Updated {}", filename);
+                if let Some(metadata) = metadata {
+                    if !metadata.tags.is_empty() {
+                        println!("  Tags: {}", metadata.tags.join(", "));
+                    }
+                    if let Some(caption) = metadata.caption {
+                        println!("  Caption: {}", caption);
+                    }
+                }
+                println!();
+            }
+            ServerResponse::NotFound { filename } => {
+                eprintln!("\n✗ No image named {} was uploaded by {}", filename, self.username);
+            }
+            ServerResponse::Forbidden { message } | ServerResponse::Error { message, .. } => {
+                eprintln!("\n✗ Error: {}", message);
+            }
+            _ => {
+                eprintln!("\n✗ Unexpected response from server");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Print the user's events in the background as they happen
+    ///
+    /// When the stream is lost, the next server is subscribed to, resuming
+    /// after the last view seen so none are missed.
+    fn watch_events(&self) {
+        let client = self.clone();
+        tokio::spawn(async move {
+            let mut after_seq = 0;
+            for address in client.server_addresses.iter().cycle() {
+                match client.follow_events(address, &mut after_seq).await {
+                    Ok(()) => return,
+                    Err(_) => sleep(RESUBSCRIBE_DELAY).await,
+                }
+            }
+        });
+    }
+
+    /// Subscribe through one server and print its events until the stream is
+    /// lost; `Ok` means the server refused the subscription and retrying is
+    /// pointless
+    async fn follow_events(&self, address: &str, after_seq: &mut u64) -> Result<(), String> {
+        let request_id = uuid::Uuid::new_v4().to_string();
+        let request = ClientRequest::Subscribe {
+            username: self.username.clone(),
+            token: self.token.clone(),
+            after_seq: *after_seq,
+        };
+        let (payload, _) = self
+            .encode_request(&request_id, &request, false, PROTOCOL_VERSION)
+            .map_err(|e| e.to_string())?;
+        let (mut stream, first) = open_stream(address, &payload, &request_id, self.max_frame_len).await?;
+        match first {
+            ServerResponse::Keepalive => {}
+            ServerResponse::Error { message, .. } => {
+                eprintln!("Not following events: {}", message);
+                return Ok(());
+            }
+            _ => return Err("Unexpected response to Subscribe".to_string()),
+        }
+
+        loop {
+            let response = tokio::time::timeout(SUBSCRIPTION_TIMEOUT, read_response(&mut stream, &request_id, PROTOCOL_VERSION, self.max_frame_len))
+                .await
+                .map_err(|_| "Server stopped sending keepalives".to_string())??;
+            match response {
+                ServerResponse::Keepalive => {}
+                ServerResponse::Event { seq, event } => {
+                    // Only views are replayed, so only they move the resume point
+                    if matches!(event, ServerEvent::Viewed(_)) {
+                        *after_seq = (*after_seq).max(seq);
+                    }
+                    self.print_event(&event);
+                }
+                _ => return Err("Unexpected response on the event stream".to_string()),
+            }
+        }
+    }
+
+    /// Print an event above the prompt and redraw the prompt after it
+    fn print_event(&self, event: &ServerEvent) {
+        let line = match event {
+            ServerEvent::Viewed(notice) => format!("{} viewed {} ({} views left)",
+                notice.viewer, notice.filename, notice.remaining_views),
+            ServerEvent::QuotaChanged { owner, filename, allowed_views: 0, .. } => {
+                format!("{} revoked your access to {}", owner, filename)
+            }
+            ServerEvent::QuotaChanged { owner, filename, allowed_views, used_views } => {
+                format!("{} gave you {} views of {} ({} used)", owner, allowed_views, filename, used_views)
+            }
+            ServerEvent::LeaderChanged { leader: Some(leader) } => format!("Node {} is now the leader", leader),
+            ServerEvent::LeaderChanged { leader: None } => "The cluster has no leader".to_string(),
+        };
+        print!("\r\x1b[2K* {}\n{}> ", line, self.username);
+        let _ = std::io::stdout().flush();
+    }
+
+    /// Print the leader and every node's reachability and load
+    async fn cluster_status(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let (server_idx, response) = self.broadcast_request_with_server(ClientRequest::ClusterStatus).await?;
+        match response {
+            ServerResponse::ClusterStatus { leader_id, nodes } => {
+                println!("\nAs seen by server {}:", server_idx);
+                println!("{:<6} {:<22} {:<10} {:>6}", "NODE", "ADDRESS", "STATE", "LOAD");
+                for node in &nodes {
+                    let mut id = node.id.to_string();
+                    if leader_id == Some(node.id) {
+                        id.push('*');
+                    }
+                    let state = if node.is_self {
+                        "self"
+                    } else if node.reachable {
+                        "up"
+                    } else {
+                        "down"
+                    };
+                    let load = node.current_load.map(|load| load.to_string()).unwrap_or_else(|| "?".to_string());
+                    println!("{:<6} {:<22} {:<10} {:>6}", id, node.address, state, load);
+                }
+                match leader_id {
+                    Some(_) => println!("* leader\n"),
+                    None => println!("No leader known\n"),
+                }
+            }
+            ServerResponse::Error { message, .. } => {
+                eprintln!("\n✗ Error: {}", message);
+            }
+            _ => {
+                eprintln!("\n✗ Unexpected response from server");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Show who viewed the user's shared images since the last call
+    async fn fetch_notifications(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let request = ClientRequest::FetchNotifications {
+            username: self.username.clone(),
+            token: self.token.clone(),
+        };
+
+        match self.broadcast_request(request).await? {
+            ServerResponse::Notifications { notifications } if notifications.is_empty() => {
+                println!("\nNo new views of your shared images\n");
+            }
+            ServerResponse::Notifications { notifications } => {
+                println!("\n{:<12} {:<16} {:<32} {:>10}", "TIME", "VIEWER", "FILENAME", "VIEWS LEFT");
+                for notice in &notifications {
+                    println!("{:<12} {:<16} {:<32} {:>10}",
+                        notice.timestamp, notice.viewer, notice.filename, notice.remaining_views);
+                }
+                println!("{} view(s)\n", notifications.len());
+            }
+            ServerResponse::Error { message, .. } => {
+                eprintln!("\n✗ Error: {}", message);
+            }
+            _ => {
+                eprintln!("\n✗ Unexpected response from server");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Show how much the user stores across the cluster and may store
+    async fn show_quota(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let request = ClientRequest::GetQuota {
+            username: self.username.clone(),
+            token: self.token.clone(),
+        };
+
+        match self.broadcast_request(request).await? {
+            ServerResponse::Quota { used_bytes, limit_bytes: Some(limit) } => {
+                let percent = used_bytes as f64 * 100.0 / limit as f64;
+                println!("\nUsed {} of {} bytes ({:.1}%)\n", used_bytes, limit, percent);
+            }
+            ServerResponse::Quota { used_bytes, limit_bytes: None } => {
+                println!("\nUsed {} bytes, no quota\n", used_bytes);
+            }
+            ServerResponse::Error { message, .. } => {
+                eprintln!("\n✗ Error: {}", message);
+            }
+            _ => {
+                eprintln!("\n✗ Unexpected response from server");
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn share_image(
+        &self,
+        image: ImageSelector,
+        with_user: &str,
+        allowed_views: u32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let request = ClientRequest::ShareImage {
+            owner: self.username.clone(),
+            token: self.token.clone(),
+            image,
+            with_user: with_user.to_string(),
+            allowed_views,
+        };
+
+        match self.broadcast_request(request).await? {
+            ServerResponse::Shared { filename, with_user, allowed_views } => {
+                println!("\n✓ Shared {} with {} ({} views)\n", filename, with_user, allowed_views);
+            }
+            ServerResponse::NotFound { filename } => {
+                eprintln!("\n✗ No image named {} was uploaded by {}", filename, self.username);
+            }
+            ServerResponse::Forbidden { message } | ServerResponse::Error { message, .. } => {
+                eprintln!("\n✗ Error: {}", message);
+            }
+            _ => {
+                eprintln!("\n✗ Unexpected response from server");
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn update_access(
+        &self,
+        filename: &str,
+        with_user: &str,
+        new_allowed_views: u32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let request = ClientRequest::UpdateAccess {
+            owner: self.username.clone(),
+            token: self.token.clone(),
+            filename: filename.to_string(),
+            with_user: with_user.to_string(),
+            new_allowed_views,
+        };
+
+        match self.broadcast_request(request).await? {
+            ServerResponse::AccessUpdated { filename, with_user, allowed_views: 0, used_views } => {
+                println!("\n✓ Revoked {}'s access to {} ({} views used)\n", with_user, filename, used_views);
+            }
+            ServerResponse::AccessUpdated { filename, with_user, allowed_views, used_views } => {
+                println!("\n✓ {} may now view {} {} times ({} used)\n",
+                    with_user, filename, allowed_views, used_views);
+            }
+            ServerResponse::NotFound { filename } => {
+                eprintln!("\n✗ No image named {} was uploaded by {}", filename, self.username);
+            }
+            ServerResponse::Forbidden { message } | ServerResponse::Error { message, .. } => {
+                eprintln!("\n✗ Error: {}", message);
+            }
+            _ => {
+                eprintln!("\n✗ Unexpected response from server");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decrypt an image saved by `upload`, `download` or `view` into
+    /// `decrypted/`, taking it out of its cover first with `stego`
+    ///
+    /// The image was encrypted for the name it is stored under, which is read
+    /// from its `.meta.json` unless given.
+    fn decrypt_image(&self, path: &str, stored_name: Option<&str>, stego: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let path = Path::new(path);
+        let mut data = fs::read(path)?;
+        if stego {
+            data = stego::extract(&data)?;
+        }
+        let filename = match stored_name {
+            Some(name) => name.to_string(),
+            None => {
+                let mut sidecar = path.as_os_str().to_owned();
+                sidecar.push(".meta.json");
+                match fs::read(&sidecar) {
+                    Ok(json) => serde_json::from_slice::<ImageMetadata>(&json)?.filename,
+                    Err(_) => path.file_name().ok_or("Invalid path")?.to_string_lossy().into_owned(),
+                }
+            }
+        };
+
+        let image = self
+            .open(&data, &filename)
+            .map_err(|e| format!("Cannot decrypt {} as {}: {}", path.display(), filename, e))?;
+        fs::create_dir_all("decrypted")?;
+        let name = Path::new(&filename).file_name().ok_or("Invalid filename")?;
+        let out = Path::new("decrypted").join(name);
+        fs::write(&out, image.as_slice())?;
+        if !is_authenticated(&data) {
+            println!("\n⚠ {} predates integrity checks: with the wrong username or filename it decrypts to noise instead of failing", filename);
+        }
+        println!("\n✓ Decrypted image ({} bytes) saved to: {}\n", image.len(), out.display());
+        Ok(())
+    }
+
+    /// Decrypt one of the user's images, sealed for `filename`
+    ///
+    /// Only the current key is known here, tried whatever generation the
+    /// image names; older images fail to open until downloaded again.
+    fn open(&self, data: &[u8], filename: &str) -> Result<Zeroizing<Vec<u8>>, CryptoError> {
+        let keys: Vec<UserKey> = self
+            .image_key
+            .clone()
+            .map(|key| UserKey {
+                key,
+                generation: key_generation(data).unwrap_or(0),
+            })
+            .into_iter()
+            .collect();
+        open_image(data, &keys, &self.username, filename).map(Zeroizing::new)
+    }
+
+    /// Open an image's catalog entry that the servers left sealed, `None` if
+    /// the current key does not
+    fn open_entry(&self, sealed: &SealedEntry) -> Option<CatalogEntry> {
+        let key = UserKey {
+            key: self.image_key.clone()?,
+            generation: sealed.generation,
+        };
+        let json = Zeroizing::new(open_metadata(&sealed.sealed, &[key], &self.username, sealed.generation).ok()?);
+        serde_json::from_slice(&json).ok()
+    }
+
+    /// Fetch an image's thumbnail and decrypt it into `thumbnails/`
+    async fn fetch_thumbnail(&self, image: ImageSelector) -> Result<(), Box<dyn std::error::Error>> {
+        let request = ClientRequest::GetThumbnail {
+            username: self.username.clone(),
+            token: self.token.clone(),
+            image,
+        };
+
+        match self.broadcast_request(request).await? {
+            ServerResponse::Thumbnail { filename, data } => {
+                let thumbnail = self
+                    .open(&data, &thumbnail::sealed_name(&filename))
+                    .map_err(|e| format!("Cannot decrypt the thumbnail of {}: {}", filename, e))?;
+                fs::create_dir_all("thumbnails")?;
+                let name = Path::new(&filename).file_name().ok_or("Server returned an invalid filename")?;
+                let path = Path::new("thumbnails").join(name).with_extension("jpg");
+                fs::write(&path, thumbnail.as_slice())?;
+                println!("\n✓ Thumbnail of {} ({} bytes) saved to: {}\n", filename, thumbnail.len(), path.display());
+            }
+            ServerResponse::NotFound { filename } => {
+                eprintln!("\n✗ No image named {} was uploaded by {}", filename, self.username);
+            }
+            ServerResponse::Error { message, .. } => {
+                eprintln!("\n✗ Error: {}", message);
+            }
+            _ => {
+                eprintln!("\n✗ Unexpected response from server");
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn view_shared_image(&self, owner: &str, filename: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let request = ClientRequest::ViewSharedImage {
+            viewer: self.username.clone(),
+            token: self.token.clone(),
+            owner: owner.to_string(),
+            filename: filename.to_string(),
+        };
+
+        match self.broadcast_request(request).await? {
+            ServerResponse::SharedImageData { owner, filename, data, remaining_views } => {
+                fs::create_dir_all("shared")?;
+                let name = Path::new(&filename)
+                    .file_name()
+                    .ok_or("Server returned an invalid filename")?
+                    .to_string_lossy();
+                let path = Path::new("shared").join(format!("{}_{}", owner, name));
+
+                fs::write(&path, &data)?;
+                println!("\n✓ Success!");
+                println!("Image shared by {} ({} bytes) saved to: {}", owner, data.len(), path.display());
+                println!("Views left: {}\n", remaining_views);
+            }
+            ServerResponse::SealedSharedImage {
+                owner,
+                filename,
+                data,
+                wrapped_key,
+                remaining_views,
+            } => {
+                // Nobody but this client can open it, so it is opened here
+                let private_key = self.share_private_key()?;
+                let image = unwrap_for_viewer(&wrapped_key, &private_key, &owner, &filename, &self.username)
+                    .and_then(|data_key| open_shared(&data, &data_key, &owner, &filename))
+                    .map_err(|e| format!("Cannot decrypt {}'s {}: {}", owner, filename, e))?;
+                fs::create_dir_all("shared")?;
+                let name = Path::new(&filename)
+                    .file_name()
+                    .ok_or("Server returned an invalid filename")?
+                    .to_string_lossy();
+                let path = Path::new("shared").join(format!("{}_{}", owner, name));
+
+                fs::write(&path, &image)?;
+                println!("\n✓ Success!");
+                println!("Image shared by {} decrypted ({} bytes) and saved to: {}", owner, image.len(), path.display());
+                println!("Views left: {}\n", remaining_views);
+            }
+            ServerResponse::QuotaExhausted { filename } => {
+                eprintln!("\n✗ You have no views of {} left", filename);
+            }
+            ServerResponse::NotFound { filename } => {
+                eprintln!("\n✗ {} has no image named {}", owner, filename);
+            }
+            ServerResponse::Forbidden { message } | ServerResponse::Error { message, .. } => {
+                eprintln!("\n✗ Error: {}", message);
+            }
+            _ => {
+                eprintln!("\n✗ Unexpected response from server");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Make a keypair for receiving shared images, keep the private key in
+    /// `keys/` and register the public key, so images shared from now on
+    /// reach this user still encrypted by their owner
+    async fn generate_share_key(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let (private_key, public_key) = generate_share_keypair();
+        let path = self.share_key_path();
+        let previous = fs::read(&path).ok();
+        fs::create_dir_all(SHARE_KEY_DIR)?;
+        write_private(&path, private_key.expose())?;
+
+        let request = ClientRequest::RegisterPublicKey {
+            username: self.username.clone(),
+            token: self.token.clone(),
+            public_key,
+        };
+        let registered = match self.broadcast_request(request).await {
+            Ok(ServerResponse::PublicKeyRegistered { .. }) => Ok(()),
+            Ok(ServerResponse::Error { message, .. }) => Err(message.into()),
+            Ok(_) => Err("Unexpected response from server".into()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = registered {
+            // Keep the key the cluster still wraps to
+            match previous {
+                Some(previous) => write_private(&path, &previous)?,
+                None => fs::remove_file(&path)?,
+            }
+            return Err(e);
+        }
+        println!("\n✓ Public key registered; private key saved to: {}", path.display());
+        println!("Keep it: images shared with you can only be opened with it\n");
+        Ok(())
+    }
+
+    /// Where `keygen` saved this user's private key
+    fn share_key_path(&self) -> PathBuf {
+        Path::new(SHARE_KEY_DIR).join(format!("{}.x25519", self.username))
+    }
+
+    /// The private key `keygen` saved for this user
+    fn share_private_key(&self) -> Result<SecretKey, Box<dyn std::error::Error>> {
+        let path = self.share_key_path();
+        let bytes = Zeroizing::new(fs::read(&path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?);
+        let bytes: [u8; 32] = bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| format!("{} is not a private key", path.display()))?;
+        Ok(SecretKey::new(bytes))
+    }
+
+    async fn run_repl(&mut self) {
+        println!("\n=== Distributed Image Storage Client (REPL) ===");
+        println!("User: {}", self.username);
+        println!("Multicast mode: Broadcasting to all servers");
+        println!("Type 'help' for commands, 'quit' to exit");
+        println!("================================================\n");
+
+        loop {
+            print!("{}> ", self.username);
+            std::io::Write::flush(&mut std::io::stdout()).unwrap();
+
+            let mut input = String::new();
+            match std::io::stdin().read_line(&mut input) {
+                Ok(_) => {
+                    let input = input.trim();
+
+                    if input.is_empty() {
+                        continue;
+                    }
+
+                    match input {
+                        "quit" | "exit" | "q" => {
+                            println!("Goodbye!");
+                            break;
+                        }
+                        "help" | "h" => {
+                            println!("\nAvailable commands:");
+                            println!("  upload <image_path>  - Upload and encrypt an image");
+                            println!("  upload-dir <dir>     - Upload every image in a directory");
+                            println!("                         (upload and upload-dir take --strip-metadata to remove EXIF");
+                            println!("                         and GPS data first, or --keep-metadata to keep it, and");
+                            println!("                         --compress or --no-compress to have it stored compressed or not)");
+                            println!("  download <filename>  - Fetch a stored (encrypted) image");
+                            println!("  thumbnail <filename> - Fetch and decrypt an image's preview into thumbnails/");
+                            println!("                         (download, thumbnail, delete and share also take id:<content id>)");
+                            println!("  list [--page <n>] [--sort name|date|size[-desc]] - List your uploaded images");
+                            println!("                         (--sealed has this client decrypt the names, not the servers)");
+                            println!("  delete <filename>    - Delete one of your images");
+                            println!("  share <filename> <user> <views> - Let another user view an image");
+                            println!("  view <owner> <filename>         - View an image shared with you");
+                            println!("  decrypt [--stego] <path> [<name>] - Decrypt a saved image into decrypted/");
+                            println!("  access <filename> <user> <views> - Change a viewer's total views (0 revokes)");
+                            println!("  rename <old> <new>   - Rename one of your images");
+                            println!("  tag <filename> <tags...>        - Replace an image's tags (none clears them)");
+                            println!("  caption <filename> <text...>    - Replace an image's caption (none clears it)");
+                            println!("  notifications        - Show who viewed your shared images");
+                            println!("  status               - Show the leader and every node's state and load");
+                            println!("  quota                - Show how much you store and may store");
+                            println!("  passwd               - Change your password");
+                            println!("  keygen               - Make a keypair so images shared with you stay encrypted");
+                            println!("                         until this client opens them");
+                            println!("  help                 - Show this help message");
+                            println!("  quit                 - Exit the client\n");
+                        }
+                        "status" => {
+                            if let Err(e) = self.cluster_status().await {
+                                eprintln!("Status failed: {}\n", e);
+                            }
+                        }
+                        "quota" => {
+                            if let Err(e) = self.show_quota().await {
+                                eprintln!("Quota failed: {}\n", e);
+                            }
+                        }
+                        "passwd" => {
+                            if let Err(e) = self.change_password().await {
+                                eprintln!("Changing the password failed: {}\n", e);
+                            }
+                        }
+                        "keygen" => {
+                            if let Err(e) = self.generate_share_key().await {
+                                eprintln!("Keygen failed: {}\n", e);
+                            }
+                        }
+                        "notifications" => {
+                            if let Err(e) = self.fetch_notifications().await {
+                                eprintln!("Fetching notifications failed: {}\n", e);
+                            }
+                        }
+                        _ if input == "list" || input == "ls" || input.starts_with("list ") || input.starts_with("ls ") => {
+                            match parse_list_args(input.split_whitespace().skip(1)) {
+                                Some((page, sort_by, descending, sealed)) => {
+                                    if let Err(e) = self.list_images(page, sort_by, descending, sealed).await {
+                                        eprintln!("List failed: {}\n", e);
+                                    }
+                                }
+                                None => eprintln!("Usage: list [--page <n>] [--sort name|date|size[-desc]] [--sealed]\n"),
+                            }
+                        }
+                        _ if input.starts_with("thumbnail ") => {
+                            let filename = input["thumbnail ".len()..].trim();
+                            if filename.is_empty() {
+                                eprintln!("Usage: thumbnail <filename>\n");
+                            } else if let Err(e) = self.fetch_thumbnail(parse_selector(filename)).await {
+                                eprintln!("Thumbnail failed: {}\n", e);
+                            }
+                        }
+                        _ if input.starts_with("delete ") => {
+                            let filename = input["delete ".len()..].trim();
+                            if filename.is_empty() {
+                                eprintln!("Usage: delete <filename>\n");
+                            } else if let Err(e) = self.delete_image(parse_selector(filename)).await {
+                                eprintln!("Delete failed: {}\n", e);
+                            }
+                        }
+                        _ if input.starts_with("share ") => {
+                            let parts: Vec<&str> = input.split_whitespace().collect();
+                            match (parts.get(1), parts.get(2), parts.get(3).and_then(|v| v.parse().ok())) {
+                                (Some(filename), Some(with_user), Some(views)) if parts.len() == 4 => {
+                                    if let Err(e) = self.share_image(parse_selector(filename), with_user, views).await {
+                                        eprintln!("Share failed: {}\n", e);
+                                    }
+                                }
+                                _ => eprintln!("Usage: share <filename> <user> <views>\n"),
+                            }
+                        }
+                        _ if input.starts_with("access ") => {
+                            let parts: Vec<&str> = input.split_whitespace().collect();
+                            match (parts.get(1), parts.get(2), parts.get(3).and_then(|v| v.parse().ok())) {
+                                (Some(filename), Some(with_user), Some(views)) if parts.len() == 4 => {
+                                    if let Err(e) = self.update_access(filename, with_user, views).await {
+                                        eprintln!("Access update failed: {}\n", e);
+                                    }
+                                }
+                                _ => eprintln!("Usage: access <filename> <user> <views>\n"),
+                            }
+                        }
+                        _ if input.starts_with("rename ") => {
+                            let parts: Vec<&str> = input.split_whitespace().collect();
+                            if parts.len() == 3 {
+                                let new_filename = Some(parts[2].to_string());
+                                if let Err(e) = self.update_image_meta(parts[1], new_filename, None, None).await {
+                                    eprintln!("Rename failed: {}\n", e);
+                                }
+                            } else {
+                                eprintln!("Usage: rename <old> <new>\n");
+                            }
+                        }
+                        _ if input.starts_with("tag ") => {
+                            let mut parts = input.split_whitespace().skip(1);
+                            match parts.next() {
+                                Some(filename) => {
+                                    let tags = parts.map(str::to_string).collect();
+                                    if let Err(e) = self.update_image_meta(filename, None, Some(tags), None).await {
+                                        eprintln!("Tagging failed: {}\n", e);
+                                    }
+                                }
+                                None => eprintln!("Usage: tag <filename> <tags...>\n"),
+                            }
+                        }
+                        _ if input.starts_with("caption ") => {
+                            let mut parts = input["caption ".len()..].trim().splitn(2, char::is_whitespace);
+                            match parts.next().filter(|filename| !filename.is_empty()) {
+                                Some(filename) => {
+                                    let caption = parts.next().unwrap_or("").trim().to_string();
+                                    if let Err(e) = self.update_image_meta(filename, None, None, Some(caption)).await {
+                                        eprintln!("Caption failed: {}\n", e);
+                                    }
+                                }
+                                None => eprintln!("Usage: caption <filename> <text...>\n"),
+                            }
+                        }
+                        _ if input.starts_with("decrypt ") => {
+                            let mut parts: Vec<&str> = input.split_whitespace().skip(1).collect();
+                            let stego = parts.first() == Some(&"--stego");
+                            if stego {
+                                parts.remove(0);
+                            }
+                            match parts.as_slice() {
+                                [path] | [path, _] => {
+                                    if let Err(e) = self.decrypt_image(path, parts.get(1).copied(), stego) {
+                                        eprintln!("Decrypt failed: {}\n", e);
+                                    }
+                                }
+                                _ => eprintln!("Usage: decrypt [--stego] <path> [<name>]\n"),
+                            }
+                        }
+                        _ if input.starts_with("view ") => {
+                            let parts: Vec<&str> = input.split_whitespace().collect();
+                            if parts.len() == 3 {
+                                if let Err(e) = self.view_shared_image(parts[1], parts[2]).await {
+                                    eprintln!("View failed: {}\n", e);
+                                }
+                            } else {
+                                eprintln!("Usage: view <owner> <filename>\n");
+                            }
+                        }
+                        _ if input.starts_with("upload-dir ") => {
+                            let (flags, dir) = parse_upload_flags(&input["upload-dir ".len()..]);
+                            if dir.is_empty() {
+                                eprintln!("Usage: upload-dir [--strip-metadata|--keep-metadata] [--compress|--no-compress] <dir>\n");
+                            } else if let Err(e) = self.upload_dir(dir, flags).await {
+                                eprintln!("Upload failed: {}\n", e);
+                            }
+                        }
+                        _ if input.starts_with("upload ") => {
+                            let (flags, image_path) = parse_upload_flags(&input["upload ".len()..]);
+                            if image_path.is_empty() {
+                                eprintln!("Usage: upload [--strip-metadata|--keep-metadata] [--compress|--no-compress] <image_path>\n");
+                            } else if let Err(e) = self.upload_image(image_path, flags).await {
+                                eprintln!("Upload failed: {}\n", e);
+                            }
+                        }
+                        _ if input.starts_with("download ") => {
+                            let filename = input["download ".len()..].trim();
+                            if filename.is_empty() {
+                                eprintln!("Usage: download <filename>\n");
+                            } else if let Err(e) = self.download_image(parse_selector(filename)).await {
+                                eprintln!("Download failed: {}\n", e);
+                            }
+                        }
+                        _ => {
+                            eprintln!("Unknown command: '{}'. Type 'help' for available commands.\n", input);
+                        }
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Error reading input: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Whether a received image is the one the metadata describes, as is or
+/// hidden in a cover by a server with `stego_output`
+fn matches_metadata(data: &[u8], metadata: &ImageMetadata) -> bool {
+    sha256_hex(data) == metadata.content_sha256
+        || stego::extract(data).is_ok_and(|payload| sha256_hex(&payload) == metadata.content_sha256)
+}
+
+/// The protocol version to retry with after a server answered UnsupportedVersion
+///
+/// Fails with an explanation if this client cannot speak the server's version.
+fn downgrade_to(current: u16, server_version: u16) -> Result<u16, String> {
+    if server_version != current && is_supported(server_version) {
+        println!("Servers speak protocol version {}, switching from {}", server_version, current);
+        return Ok(server_version);
+    }
+    Err(format!(
+        "Servers speak protocol version {}, this client speaks {} to {}; upgrade the {}",
+        server_version,
+        MIN_PROTOCOL_VERSION,
+        PROTOCOL_VERSION,
+        if server_version > PROTOCOL_VERSION { "client" } else { "servers" }
+    ))
+}
+
+/// Send one tagged request to a server and read its response
+///
+/// A response tagged with a different request id is dropped. The response is
+/// read in the layout of `version`, the one the request was written in.
+async fn exchange(
+    address: &str,
+    payload: &[u8],
+    expected_id: &str,
+    version: u16,
+    max_frame_len: usize,
+) -> Result<ServerResponse, String> {
+    let mut stream = TcpStream::connect(address)
+        .await
+        .map_err(|e| format!("Connection failed: {}", e))?;
+    write_frame(&mut stream, payload, max_frame_len)
+        .await
+        .map_err(|e| format!("Write failed: {}", e))?;
+
+    // Long uploads report progress before the actual response
+    let mut reported = false;
+    loop {
+        match read_response(&mut stream, expected_id, version, max_frame_len).await {
+            Ok(ServerResponse::Progress { bytes_received, stage, .. }) => {
+                print!("\r  {:?}: {:.1} MiB        ", stage, bytes_received as f64 / (1024.0 * 1024.0));
+                let _ = std::io::stdout().flush();
+                reported = true;
+            }
+            response => {
+                if reported {
+                    println!();
+                }
+                return response;
+            }
+        }
+    }
+}
+
+/// Send one tagged request whose response may be a stream, returning the
+/// connection to read the rest of it from
+async fn open_stream(
+    address: &str,
+    payload: &[u8],
+    expected_id: &str,
+    max_frame_len: usize,
+) -> Result<(TcpStream, ServerResponse), String> {
+    let mut stream = TcpStream::connect(address)
+        .await
+        .map_err(|e| format!("Connection failed: {}", e))?;
+    write_frame(&mut stream, payload, max_frame_len)
+        .await
+        .map_err(|e| format!("Write failed: {}", e))?;
+    let response = read_response(&mut stream, expected_id, PROTOCOL_VERSION, max_frame_len).await?;
+    Ok((stream, response))
+}
+
+/// Read the next response frame in the layout of protocol `version`, undoing
+/// compression of its image bytes
+async fn read_response(
+    stream: &mut TcpStream,
+    expected_id: &str,
+    version: u16,
+    max_frame_len: usize,
+) -> Result<ServerResponse, String> {
+    let frame = read_frame(stream, max_frame_len)
+        .await
+        .map_err(|e| format!("Read error: {}", e))?
+        .ok_or("Server closed the connection")?;
+
+    match in_version(version, || decode::<ResponseEnvelope>(&frame)).map(|(_, envelope)| envelope) {
+        Ok(mut envelope) if envelope.request_id == expected_id => {
+            if let (Some(codec), Some(data)) = (&envelope.compression, envelope.body.image_data_mut()) {
+                *data = decompress(codec, data, MAX_DECOMPRESSED_LEN)
+                    .map_err(|e| format!("Bad compressed response: {}", e))?;
+            }
+            Ok(envelope.body)
+        }
+        Ok(envelope) => {
+            println!("  Dropping response for request {}", envelope.request_id);
+            Err("Mismatched request id".to_string())
+        }
+        Err(e) => Err(format!("Parse error: {}", e)),
+    }
+}
+
+/// Ask for a password on stdin
+fn prompt_password(prompt: &str) -> std::io::Result<String> {
+    print!("{}", prompt);
+    std::io::stdout().flush()?;
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Page, order, direction and whether names stay sealed from `list`'s
+/// options; page 1 by name, ascending, opened by the servers when not given,
+/// and `None` for anything unrecognised
+fn parse_list_args<'a>(mut args: impl Iterator<Item = &'a str>) -> Option<(u64, ImageSort, bool, bool)> {
+    let mut page = 1;
+    let mut sort_by = ImageSort::Name;
+    let mut descending = false;
+    let mut sealed = false;
+    while let Some(arg) = args.next() {
+        match arg {
+            "--page" => page = args.next()?.parse().ok().filter(|&p| p > 0)?,
+            "--sort" => {
+                let value = args.next()?;
+                let (key, desc) = match value.strip_suffix("-desc") {
+                    Some(key) => (key, true),
+                    None => (value, false),
+                };
+                sort_by = match key {
+                    "name" => ImageSort::Name,
+                    "date" => ImageSort::UploadedAt,
+                    "size" => ImageSort::Size,
+                    _ => return None,
+                };
+                descending = desc;
+            }
+            "--sealed" => sealed = true,
+            _ => return None,
+        }
+    }
+    Some((page, sort_by, descending, sealed))
+}
+
+/// Whether a file has one of `IMAGE_EXTENSIONS`, in any case
+fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| IMAGE_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+/// A batch result for an image that was not uploaded
+fn failed(filename: &str, message: String) -> BatchResult {
+    BatchResult {
+        filename: filename.to_string(),
+        outcome: BatchOutcome::Failed { message },
+    }
+}
+
+/// Where a download is kept until it is complete and verified
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    PathBuf::from(partial)
+}
+
+/// Hex SHA-256 of a file, read without loading it whole
+fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// A command's image argument: `id:<content id>` names an image by its
+/// content id, anything else by its filename
+fn parse_selector(arg: &str) -> ImageSelector {
+    match arg.strip_prefix("id:") {
+        Some(content_id) => ImageSelector::ContentId(content_id.to_ascii_lowercase()),
+        None => ImageSelector::Name(arg.to_string()),
+    }
+}
+
+/// Split leading `--strip-metadata`, `--keep-metadata`, `--compress` and
+/// `--no-compress` off the arguments of `upload` or `upload-dir`; what they
+/// leave out the server decides
+fn parse_upload_flags(args: &str) -> (UploadFlags, &str) {
+    let mut flags = UploadFlags::default();
+    let mut args = args.trim();
+    loop {
+        let Some((flag, rest)) = args.split_once(' ') else {
+            return (flags, args);
+        };
+        match flag {
+            "--strip-metadata" => flags.strip_metadata = Some(true),
+            "--keep-metadata" => flags.strip_metadata = Some(false),
+            "--compress" => flags.compress = Some(true),
+            "--no-compress" => flags.compress = Some(false),
+            _ => return (flags, args),
+        }
+        args = rest.trim();
+    }
+}
+
+/// Write a private key readable only by its owner where the platform allows
+fn write_private(path: &Path, key: &[u8]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(key)
+}
+
+/// Print an image's metadata and keep it next to the saved file as `<file>.meta.json`
+fn save_metadata(path: &Path, metadata: &ImageMetadata) -> std::io::Result<()> {
+    println!("  Original size:  {} bytes", metadata.original_size);
+    println!("  Encrypted size: {} bytes", metadata.encrypted_size);
+    println!("  Processed by:   Node {}", metadata.processed_by_node);
+    println!("  Uploaded at:    {} (unix)", metadata.uploaded_at_unix);
+    println!("  SHA-256:        {}", metadata.content_sha256);
+    if let Some(content_id) = &metadata.content_id {
+        println!("  Content id:     {}", content_id);
+    }
+    if let Some(reason) = &metadata.thumbnail_error {
+        println!("  No thumbnail:   {}", reason);
+    }
+    match &metadata.metadata_strip {
+        Some(MetadataStrip::Stripped { bytes_removed }) => println!("  Metadata:       {} bytes stripped", bytes_removed),
+        Some(MetadataStrip::NotStripped { reason }) => println!("  ⚠ Metadata kept: {}", reason),
+        None => {}
+    }
+    match &metadata.storage_compression {
+        Some(StorageCompression::Zstd { compressed_size }) => println!("  Stored:         compressed to {} bytes", compressed_size),
+        Some(StorageCompression::Skipped { reason }) => println!("  Stored:         uncompressed, {}", reason),
+        None => {}
+    }
+
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".meta.json");
+    fs::write(&sidecar, serde_json::to_vec_pretty(metadata)?)?;
+    println!("Metadata saved to: {}", Path::new(&sidecar).display());
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() < 2 {
+        eprintln!("Usage: {} <username> [--format bincode|json] [--anonymous]", args[0]);
+        eprintln!("Example: {} alice", args[0]);
+        eprintln!("\nNote: Client broadcasts to all servers (8001, 8002, 8003)");
+        std::process::exit(1);
+    }
+
+    let username = args[1].clone();
+    if let Err(e) = validate_username("username", &username) {
+        eprintln!("Invalid username {:?}: {}", username, e);
+        std::process::exit(1);
+    }
+
+    // Binary by default; JSON is easier to read when debugging
+    let mut format = Format::Bincode;
+    // Skip logging in; only servers started with --allow-anonymous serve this
+    let mut anonymous = false;
+    let mut rest = args[2..].iter();
+    while let Some(flag) = rest.next() {
+        match flag.as_str() {
+            "--format" => match rest.next().and_then(|name| Format::parse(name)) {
+                Some(f) => format = f,
+                None => {
+                    eprintln!("--format must be bincode or json");
+                    std::process::exit(1);
+                }
+            },
+            "--anonymous" => anonymous = true,
+            other => {
+                eprintln!("Unknown argument: {}", other);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Load configuration from config.toml
+    let config = Config::load("config.toml").expect("Failed to load config.toml");
+    let server_addresses = config.get_all_server_addresses();
+
+    if server_addresses.is_empty() {
+        eprintln!("Error: No servers found in config.toml");
+        std::process::exit(1);
+    }
+
+    println!("Client will broadcast to servers:");
+    for addr in &server_addresses {
+        println!("  - {}", addr);
+    }
+
+    let mut client = Client::new(username, server_addresses, config.max_frame_bytes, format);
+    if !anonymous {
+        if let Err(e) = client.log_in().await {
+            eprintln!("Login failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+    client.watch_events();
+    client.run_repl().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_version_is_not_a_downgrade() {
+        assert!(downgrade_to(PROTOCOL_VERSION, PROTOCOL_VERSION).is_err());
+    }
+
+    #[test]
+    fn older_server_is_downgraded_to() {
+        assert_eq!(downgrade_to(PROTOCOL_VERSION, MIN_PROTOCOL_VERSION), Ok(MIN_PROTOCOL_VERSION));
+    }
+
+    #[test]
+    fn newer_server_needs_a_newer_client() {
+        let error = downgrade_to(PROTOCOL_VERSION, PROTOCOL_VERSION + 1).unwrap_err();
+        assert!(error.ends_with("upgrade the client"), "{}", error);
+    }
+
+    #[test]
+    fn ancient_server_needs_upgrading() {
+        let error = downgrade_to(PROTOCOL_VERSION, MIN_PROTOCOL_VERSION - 1).unwrap_err();
+        assert!(error.ends_with("upgrade the servers"), "{}", error);
+    }
+
+    fn metadata_of(data: &[u8]) -> ImageMetadata {
+        ImageMetadata {
+            filename: "cat.png".to_string(),
+            original_size: 3,
+            encrypted_size: data.len() as u64,
+            processed_by_node: 1,
+            uploaded_at_unix: 0,
+            content_sha256: sha256_hex(data),
+            tags: Vec::new(),
+            caption: None,
+            content_id: None,
+            thumbnail_error: None,
+            metadata_strip: None,
+            storage_compression: None,
+        }
+    }
+
+    #[test]
+    fn answer_changed_on_the_way_back_is_caught() {
+        let mut data = b"encrypted cat".to_vec();
+        let metadata = metadata_of(&data);
+        assert!(matches_metadata(&data, &metadata));
+        data[4] ^= 1;
+        assert!(!matches_metadata(&data, &metadata));
+    }
 }
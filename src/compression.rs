@@ -0,0 +1,108 @@
+use crate::protocol::Codec;
+use std::io::Read;
+
+/// Compressed data is only sent if it is at most this share of the original
+const MAX_COMPRESSED_RATIO: f64 = 0.9;
+/// Images are only stored compressed if that takes them to at most this
+/// share of their size
+const MAX_STORED_RATIO: f64 = 0.95;
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CompressionError {
+    #[error("codec {0} is not supported")]
+    Unsupported(String),
+    #[error("decompressed data exceeds {0} bytes")]
+    TooLarge(usize),
+    #[error("corrupt compressed data: {0}")]
+    Corrupt(#[from] std::io::Error),
+}
+
+/// Compress `data` with zstd, `None` if that saves less than 10%, as for
+/// already-compressed PNGs or encrypted data
+pub fn compress_if_smaller(data: &[u8]) -> Option<Vec<u8>> {
+    if data.is_empty() {
+        return None;
+    }
+    let compressed = zstd::bulk::compress(data, ZSTD_LEVEL).ok()?;
+    if compressed.len() as f64 > data.len() as f64 * MAX_COMPRESSED_RATIO {
+        return None;
+    }
+    Some(compressed)
+}
+
+/// Compress an image with zstd to store it, or give the share of its size
+/// that would have been left if that is over 95%, as for JPEGs and PNGs
+pub fn compress_for_storage(data: &[u8]) -> Result<Vec<u8>, f64> {
+    let compressed = zstd::bulk::compress(data, ZSTD_LEVEL).map_err(|_| 1.0)?;
+    let ratio = compressed.len() as f64 / data.len().max(1) as f64;
+    if ratio > MAX_STORED_RATIO {
+        return Err(ratio);
+    }
+    Ok(compressed)
+}
+
+/// Undo `compress_for_storage`, `None` unless it gives exactly
+/// `original_len` bytes
+pub fn decompress_stored(data: &[u8], original_len: u64) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(original_len as usize);
+    zstd::stream::read::Decoder::new(data)
+        .ok()?
+        .take(original_len + 1)
+        .read_to_end(&mut out)
+        .ok()?;
+    (out.len() as u64 == original_len).then_some(out)
+}
+
+/// Undo `compress_if_smaller`, refusing output over `max_len` bytes so a
+/// small malicious payload cannot expand without bound
+pub fn decompress(codec: &Codec, data: &[u8], max_len: usize) -> Result<Vec<u8>, CompressionError> {
+    match codec {
+        Codec::Zstd => {
+            let mut out = Vec::new();
+            zstd::stream::read::Decoder::new(data)?
+                .take(max_len as u64 + 1)
+                .read_to_end(&mut out)?;
+            if out.len() > max_len {
+                return Err(CompressionError::TooLarge(max_len));
+            }
+            Ok(out)
+        }
+        Codec::Unknown(name) => Err(CompressionError::Unsupported(name.clone())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_images_round_trip_when_they_compress() {
+        let image = vec![0x7Fu8; 100_000];
+        let compressed = compress_for_storage(&image).unwrap();
+        assert!(compressed.len() < image.len() / 100);
+        assert_eq!(decompress_stored(&compressed, image.len() as u64).unwrap(), image);
+    }
+
+    #[test]
+    fn images_that_barely_compress_are_skipped_with_their_ratio() {
+        let mut noise = vec![0u8; 4096];
+        rand::Rng::fill(&mut rand::thread_rng(), noise.as_mut_slice());
+        let ratio = compress_for_storage(&noise).unwrap_err();
+        assert!(ratio > MAX_STORED_RATIO, "{}", ratio);
+        // A fiftieth repeated is not enough either; half is plenty
+        noise[..82].fill(0);
+        assert!(compress_for_storage(&noise).is_err());
+        noise[..2048].fill(0);
+        assert!(compress_for_storage(&noise).is_ok());
+    }
+
+    #[test]
+    fn stored_images_of_another_size_are_refused() {
+        let image = vec![0x7Fu8; 10_000];
+        let compressed = compress_for_storage(&image).unwrap();
+        assert!(decompress_stored(&compressed, image.len() as u64 - 1).is_none());
+        assert!(decompress_stored(&compressed, image.len() as u64 + 1).is_none());
+        assert!(decompress_stored(&compressed[..compressed.len() / 2], image.len() as u64).is_none());
+    }
+}
@@ -5,6 +5,201 @@ use std::fs;
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub servers: HashMap<String, String>,
+    /// Optional election priority per node, e.g. `node1 = 10`
+    #[serde(default)]
+    pub priorities: HashMap<String, u32>,
+    #[serde(default)]
+    pub election: ElectionSettings,
+    /// Where each node keeps the encrypted images it processed, under `node<id>/`
+    #[serde(default = "default_storage_dir")]
+    pub storage_dir: String,
+    /// Largest client, admin or internal message in bytes; must stay below
+    /// 544 MiB so frames cannot be mistaken for legacy JSON lines
+    #[serde(default = "default_max_frame_bytes")]
+    pub max_frame_bytes: usize,
+    /// Bytes each user may store across the cluster unless given their own
+    /// quota; 0 means no limit
+    #[serde(default = "default_quota_bytes")]
+    pub quota_bytes: u64,
+    /// Cipher for newly stored images: "aes-128-ctr", "aes-256-ctr",
+    /// "aes-256-gcm" or "chacha20-poly1305"
+    #[serde(default = "default_encryption_scheme")]
+    pub encryption_scheme: String,
+    /// Keep serving images under the deprecated username keys, and sealing
+    /// new ones that way for users who have not logged in since
+    /// password-derived keys were introduced
+    #[serde(default = "default_legacy_username_keys")]
+    pub legacy_username_keys: bool,
+    /// Remove EXIF and other metadata from uploads that do not say whether
+    /// to
+    #[serde(default)]
+    pub strip_metadata: bool,
+    /// Compress uploads that do not say whether to with zstd before
+    /// encrypting them
+    #[serde(default)]
+    pub compress_storage: bool,
+    /// Users whose unwrapped image keys each node keeps in memory; 0 unwraps
+    /// them from the registry on every request
+    #[serde(default = "default_key_cache_capacity")]
+    pub key_cache_capacity: usize,
+    /// Seconds before a cached user's keys are unwrapped again
+    #[serde(default = "default_key_cache_ttl_secs")]
+    pub key_cache_ttl_secs: u64,
+    /// Hand clients their encrypted images hidden in a PNG instead of as is
+    #[serde(default)]
+    pub stego_output: bool,
+    /// PNG to hide encrypted images in; without one a plain cover just large
+    /// enough is made for each image
+    #[serde(default)]
+    pub stego_cover: Option<String>,
+    /// "hash" (every node takes the uploads a hash assigns it) or "leader"
+    /// (the leader hands uploads to the nodes its load balancer picks)
+    #[serde(default = "default_routing")]
+    pub routing: String,
+    /// How the leader picks a node for an upload: "round-robin" or
+    /// "least-loaded", by the in-flight requests nodes report
+    #[serde(default = "default_balancing")]
+    pub balancing: String,
+    /// Operators must send this with every admin request; without one, admin
+    /// requests are refused
+    #[serde(default)]
+    pub admin_token: Option<String>,
+}
+
+fn default_storage_dir() -> String {
+    "storage".to_string()
+}
+
+fn default_max_frame_bytes() -> usize {
+    crate::framing::DEFAULT_MAX_FRAME_LEN
+}
+
+fn default_quota_bytes() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_encryption_scheme() -> String {
+    "aes-256-gcm".to_string()
+}
+
+fn default_legacy_username_keys() -> bool {
+    true
+}
+
+fn default_routing() -> String {
+    "hash".to_string()
+}
+
+fn default_balancing() -> String {
+    "round-robin".to_string()
+}
+
+fn default_key_cache_capacity() -> usize {
+    1024
+}
+
+fn default_key_cache_ttl_secs() -> u64 {
+    300
+}
+
+/// Election and heartbeat timings, all in milliseconds
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ElectionSettings {
+    pub heartbeat_interval_ms: u64,
+    pub heartbeat_timeout_ms: u64,
+    pub election_timeout_ms: u64,
+    pub answer_wait_ms: u64,
+    pub coordinator_timeout_ms: u64,
+    /// "pull" (followers poll the leader) or "push" (leader broadcasts)
+    pub heartbeat_mode: String,
+    /// Consecutive missed heartbeats before the leader is declared dead
+    pub failure_threshold: u32,
+    /// "tcp" or "udp"; elections always use TCP
+    pub heartbeat_transport: String,
+    /// UDP heartbeats use the node's port plus this offset
+    pub udp_port_offset: u16,
+    /// Upper bound of the random delay before a follower starts an election
+    pub election_jitter_ms: u64,
+    /// Shared secret used to sign election messages; unsigned messages are
+    /// rejected when set
+    pub cluster_secret: Option<String>,
+    /// Initial retry delay when a majority of the cluster is unreachable, in
+    /// quorum mode
+    pub prevote_backoff_ms: u64,
+    /// How often the leader re-broadcasts its COORDINATOR announcement
+    pub reannounce_interval_ms: u64,
+    /// Extra COORDINATOR attempts for peers that did not acknowledge
+    pub coordinator_retries: u32,
+    /// Delay before the first COORDINATOR retry, doubling per attempt
+    pub coordinator_retry_backoff_ms: u64,
+    /// Share of the cluster, counting this node, a leader must reach to stay
+    /// leader; 0 disables self-demotion
+    pub leader_quorum_fraction: f64,
+    /// Consecutive checks below `leader_quorum_fraction` before the leader steps down
+    pub leader_quorum_checks: u32,
+    /// Extra ELECTION attempts before a higher peer is considered down
+    pub election_retries: u32,
+    /// Delay before the first ELECTION retry
+    pub election_retry_backoff_ms: u64,
+    /// Only self-promote after reaching a majority of the cluster
+    pub quorum_mode: bool,
+    /// Directory holding each node's persisted election term
+    pub state_dir: Option<String>,
+    /// Adapt the pull-mode heartbeat timeout and interval to the leader's RTT
+    pub adaptive_heartbeat: bool,
+    /// Adaptive timeout is the smoothed RTT times this factor
+    pub rtt_multiplier: u32,
+    pub min_heartbeat_timeout_ms: u64,
+    pub max_heartbeat_timeout_ms: u64,
+    pub min_heartbeat_interval_ms: u64,
+    pub max_heartbeat_interval_ms: u64,
+    /// Heartbeat round-trip samples kept per peer for the latency report
+    pub rtt_history_len: usize,
+    /// Follow COORDINATOR announcements from nodes missing from the peer list
+    pub accept_unknown_leaders: bool,
+    /// Delay after binding before the initial election
+    pub startup_delay_ms: u64,
+    /// Delay after the initial election before monitoring starts
+    pub election_settle_ms: u64,
+}
+
+impl Default for ElectionSettings {
+    fn default() -> Self {
+        ElectionSettings {
+            heartbeat_interval_ms: 5000,
+            heartbeat_timeout_ms: 2000,
+            election_timeout_ms: 2000,
+            answer_wait_ms: 100,
+            coordinator_timeout_ms: 5000,
+            heartbeat_mode: "pull".to_string(),
+            failure_threshold: 3,
+            heartbeat_transport: "tcp".to_string(),
+            udp_port_offset: 1000,
+            election_jitter_ms: 500,
+            cluster_secret: None,
+            prevote_backoff_ms: 1000,
+            reannounce_interval_ms: 15000,
+            coordinator_retries: 3,
+            coordinator_retry_backoff_ms: 200,
+            leader_quorum_fraction: 0.5,
+            leader_quorum_checks: 3,
+            election_retries: 2,
+            election_retry_backoff_ms: 100,
+            quorum_mode: false,
+            state_dir: Some("state".to_string()),
+            adaptive_heartbeat: false,
+            rtt_multiplier: 4,
+            min_heartbeat_timeout_ms: 200,
+            max_heartbeat_timeout_ms: 2000,
+            min_heartbeat_interval_ms: 1000,
+            max_heartbeat_interval_ms: 10000,
+            rtt_history_len: 256,
+            accept_unknown_leaders: false,
+            startup_delay_ms: 2000,
+            election_settle_ms: 3000,
+        }
+    }
 }
 
 impl Config {
@@ -19,6 +214,12 @@ impl Config {
         self.servers.get(&key).cloned()
     }
 
+    /// Election priority for a node, 0 when not configured
+    pub fn get_priority(&self, node_id: u32) -> u32 {
+        let key = format!("node{}", node_id);
+        self.priorities.get(&key).copied().unwrap_or(0)
+    }
+
     pub fn get_all_server_addresses(&self) -> Vec<String> {
         let mut addresses = vec![];
         for i in 1..=3 {
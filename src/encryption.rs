@@ -1,34 +1,2175 @@
-use aes::Aes128;
-use ctr::cipher::{KeyIvInit, StreamCipher};
+use crate::blobformat::{
+    BlobError, BlobHeader, Compression, CHUNKED_FLAG, DERIVED_KEY_FLAG, ENVELOPE_FLAG, GENERATION_FLAG, MAC_FLAG, MAGIC,
+    ZSTD_CODEC,
+};
+use crate::compression::{compress_for_storage, decompress_stored};
+use aes::{Aes128, Aes256};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, Nonce, Payload};
+use aes_gcm::Aes256Gcm;
+use argon2::Argon2;
+use chacha20poly1305::ChaCha20Poly1305;
+use ctr::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
 use ctr::Ctr128BE;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rayon::prelude::*;
 use sha2::{Digest, Sha256};
+use std::fmt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
 type Aes128Ctr = Ctr128BE<Aes128>;
+type Aes256Ctr = Ctr128BE<Aes256>;
+type HmacSha256 = Hmac<Sha256>;
 
-/// Simple AES encryption for image data
-pub fn encrypt_data(data: &[u8], key: &[u8; 16]) -> Vec<u8> {
-    let mut encrypted = data.to_vec();
-    let iv = [0u8; 16]; // Simple IV for demo purposes
-    let mut cipher = Aes128Ctr::new(key.into(), &iv.into());
-    cipher.apply_keystream(&mut encrypted);
+const IV_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const MAC_LEN: usize = 32;
+/// Flags in the header byte of images from before `blobformat`, which it
+/// shares with the cipher id; the wrapped key and generation follow it
+const HEADER_FLAGS: u8 = DERIVED_KEY_FLAG | ENVELOPE_FLAG | MAC_FLAG | GENERATION_FLAG | CHUNKED_FLAG;
+/// Plaintext bytes in every chunk of a chunked image but the last
+const STREAM_CHUNK_LEN: usize = 64 * 1024;
+/// Random bytes a chunked image starts with; each chunk's nonce is these,
+/// the chunk's index as a big-endian u32 and 1 for the last chunk, else 0
+const STREAM_NONCE_PREFIX_LEN: usize = NONCE_LEN - 5;
+/// First byte of a stored image that starts with an access header: this
+/// byte, the header's length as a big-endian u32, the header, its HMAC, then
+/// the sealed image
+const ACCESS_HEADER: u8 = 0x0f;
+/// Bytes before an access header's contents
+const ACCESS_PREFIX_LEN: usize = 1 + 4;
+/// CTR payloads at least this long are split into segments encrypted in
+/// parallel, each from its own position in the keystream
+const PARALLEL_CTR_MIN_LEN: usize = 8 * 1024 * 1024;
+/// Bytes in each parallel CTR segment, a whole number of AES blocks
+const PARALLEL_CTR_SEGMENT_LEN: usize = 1024 * 1024;
+/// Bytes of an X25519 public key, which users register to receive shares
+pub const PUBLIC_KEY_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum CryptoError {
+    #[error("encrypted data is too short to hold its wrapped key, IV, nonce, tag or MAC")]
+    Truncated,
+    /// Wrong key, wrong associated data, or the data was changed
+    #[error("encrypted data failed its integrity check: wrong key, owner or filename, or the data was changed")]
+    Tampered,
+    #[error("encrypted with a password-derived key that is not available")]
+    NoKey,
+    #[error("decrypted image does not decompress to the size its header records")]
+    Decompress,
+    #[error("image has no data key of its own to share by public key")]
+    NoDataKey,
+    #[error("not an X25519 public key a data key can be wrapped to")]
+    InvalidPublicKey,
+    #[error(transparent)]
+    Format(#[from] BlobError),
+}
+
+/// Key bytes, wiped when dropped and never printed
+#[derive(Clone)]
+pub struct SecretKey<const N: usize = 32>([u8; N]);
+
+impl<const N: usize> SecretKey<N> {
+    pub fn new(bytes: [u8; N]) -> Self {
+        SecretKey(bytes)
+    }
+
+    pub fn expose(&self) -> &[u8; N] {
+        &self.0
+    }
+}
+
+impl<const N: usize> fmt::Debug for SecretKey<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretKey([REDACTED; {}])", N)
+    }
+}
+
+impl<const N: usize> Zeroize for SecretKey<N> {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<const N: usize> Drop for SecretKey<N> {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl<const N: usize> ZeroizeOnDrop for SecretKey<N> {}
+
+/// Why `encrypt_stream` or `decrypt_stream` failed
+#[derive(Debug, thiserror::Error)]
+pub enum StreamError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Crypto(#[from] CryptoError),
+}
+
+/// How an image is encrypted; the first byte of every sealed image names it
+///
+/// The CTR schemes do not detect tampering. ChaCha20-Poly1305 is the faster
+/// choice on machines without AES instructions, e.g. some ARM boards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionScheme {
+    Aes128Ctr,
+    Aes256Ctr,
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl EncryptionScheme {
+    /// The scheme as named in config.toml
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "aes-128-ctr" => Some(EncryptionScheme::Aes128Ctr),
+            "aes-256-ctr" => Some(EncryptionScheme::Aes256Ctr),
+            "aes-256-gcm" => Some(EncryptionScheme::Aes256Gcm),
+            "chacha20-poly1305" => Some(EncryptionScheme::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EncryptionScheme::Aes128Ctr => "aes-128-ctr",
+            EncryptionScheme::Aes256Ctr => "aes-256-ctr",
+            EncryptionScheme::Aes256Gcm => "aes-256-gcm",
+            EncryptionScheme::ChaCha20Poly1305 => "chacha20-poly1305",
+        }
+    }
+
+    /// First byte of images sealed with this scheme; 1 and 2 match images
+    /// stored before schemes were selectable
+    fn header(self) -> u8 {
+        match self {
+            EncryptionScheme::Aes128Ctr => 1,
+            EncryptionScheme::Aes256Gcm => 2,
+            EncryptionScheme::Aes256Ctr => 3,
+            EncryptionScheme::ChaCha20Poly1305 => 4,
+        }
+    }
+
+    fn from_header(header: u8) -> Option<Self> {
+        match header {
+            1 => Some(EncryptionScheme::Aes128Ctr),
+            2 => Some(EncryptionScheme::Aes256Gcm),
+            3 => Some(EncryptionScheme::Aes256Ctr),
+            4 => Some(EncryptionScheme::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    /// Whether the scheme needs an HMAC to detect tampering
+    fn is_ctr(self) -> bool {
+        matches!(self, EncryptionScheme::Aes128Ctr | EncryptionScheme::Aes256Ctr)
+    }
+
+    /// Bytes in this scheme's key
+    fn key_len(self) -> usize {
+        match self {
+            EncryptionScheme::Aes128Ctr => 16,
+            EncryptionScheme::Aes256Ctr | EncryptionScheme::Aes256Gcm | EncryptionScheme::ChaCha20Poly1305 => 32,
+        }
+    }
+
+    /// This scheme keyed with `user_key` from `derive_user_key`, or with the
+    /// deprecated username key if `None`; see `keyed` for `mac`
+    pub fn cipher(self, user_key: Option<&SecretKey>, username: &str, mac: bool) -> Box<dyn Cipher> {
+        match (self, user_key) {
+            (_, Some(user_key)) => self.keyed(&scheme_key(self, user_key.expose()).expose()[..self.key_len()], mac),
+            (_, None) => self.keyed(&self.username_key(username), mac),
+        }
+    }
+
+    /// The deprecated username key for this scheme
+    fn username_key(self, username: &str) -> Zeroizing<Vec<u8>> {
+        Zeroizing::new(match self {
+            EncryptionScheme::Aes128Ctr => generate_key_from_username(username).expose().to_vec(),
+            _ => generate_key256_from_username(self, username).expose().to_vec(),
+        })
+    }
+
+    /// This scheme keyed with `key`, which must be `key_len` bytes
+    ///
+    /// With `mac`, CTR schemes split `key` into an encryption and an HMAC key
+    /// and tag their output; AEAD schemes ignore it.
+    fn keyed(self, key: &[u8], mac: bool) -> Box<dyn Cipher> {
+        match self {
+            EncryptionScheme::Aes128Ctr => Box::new(Aes128CtrCipher(CtrKeys::new(key, mac))),
+            EncryptionScheme::Aes256Ctr => Box::new(Aes256CtrCipher(CtrKeys::new(key, mac))),
+            EncryptionScheme::Aes256Gcm => Box::new(Aes256GcmCipher(SecretKey::new(key.try_into().expect("32-byte key")))),
+            EncryptionScheme::ChaCha20Poly1305 => {
+                Box::new(ChaCha20Poly1305Cipher(SecretKey::new(key.try_into().expect("32-byte key"))))
+            }
+        }
+    }
+}
+
+/// A user's image key and its generation, which goes up by one each time
+/// the key is replaced
+#[derive(Debug, Clone)]
+pub struct UserKey {
+    pub key: SecretKey,
+    pub generation: u32,
+}
+
+/// An `EncryptionScheme` with its key
+pub trait Cipher {
+    /// Encrypt under a fresh random IV or nonce, which leads the output;
+    /// `aad` is authenticated by the AEAD schemes and by CTR with an HMAC
+    fn encrypt(&self, data: &[u8], aad: &[u8]) -> Vec<u8>;
+    /// Decrypt what `encrypt` gave, failing if an AEAD scheme finds it changed
+    fn decrypt(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError>;
+}
+
+struct Aes128CtrCipher(CtrKeys);
+struct Aes256CtrCipher(CtrKeys);
+struct Aes256GcmCipher(SecretKey);
+struct ChaCha20Poly1305Cipher(SecretKey);
+
+/// A CTR key and, unless the image is from before CTR images were tagged,
+/// its HMAC key
+struct CtrKeys {
+    encrypt: Zeroizing<Vec<u8>>,
+    mac: Option<SecretKey>,
+}
+
+impl CtrKeys {
+    /// Use `key` as is, or with `mac` split it with HKDF into separate keys
+    /// for encrypting and tagging
+    fn new(key: &[u8], mac: bool) -> Self {
+        if !mac {
+            return CtrKeys {
+                encrypt: Zeroizing::new(key.to_vec()),
+                mac: None,
+            };
+        }
+        let hkdf = Hkdf::<Sha256>::new(None, key);
+        let mut encrypt = Zeroizing::new(vec![0u8; key.len()]);
+        let mut mac = SecretKey::new([0u8; 32]);
+        hkdf.expand(b"distinsta-ctr-enc", &mut encrypt).expect("HKDF output fits");
+        hkdf.expand(b"distinsta-ctr-mac", &mut mac.0).expect("HKDF output fits");
+        CtrKeys {
+            encrypt,
+            mac: Some(mac),
+        }
+    }
+
+    /// Encrypt, then append an HMAC over `aad` and the IV and ciphertext
+    fn seal<C: KeyIvInit + StreamCipher + StreamCipherSeek>(&self, data: &[u8], aad: &[u8]) -> Vec<u8> {
+        let mut sealed = ctr_encrypt::<C>(&self.encrypt, data);
+        if let Some(mac_key) = &self.mac {
+            let tag = ctr_mac(mac_key.expose(), aad, &sealed).finalize().into_bytes();
+            sealed.extend_from_slice(&tag);
+        }
+        sealed
+    }
+
+    /// Check the HMAC in constant time, if there is one, then decrypt
+    fn open<C: KeyIvInit + StreamCipher + StreamCipherSeek>(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let Some(mac_key) = &self.mac else {
+            return ctr_decrypt::<C>(&self.encrypt, data);
+        };
+        if data.len() < IV_LEN + MAC_LEN {
+            return Err(CryptoError::Truncated);
+        }
+        let (sealed, tag) = data.split_at(data.len() - MAC_LEN);
+        ctr_mac(mac_key.expose(), aad, sealed)
+            .verify_slice(tag)
+            .map_err(|_| CryptoError::Tampered)?;
+        ctr_decrypt::<C>(&self.encrypt, sealed)
+    }
+}
+
+impl Cipher for Aes128CtrCipher {
+    fn encrypt(&self, data: &[u8], aad: &[u8]) -> Vec<u8> {
+        self.0.seal::<Aes128Ctr>(data, aad)
+    }
+
+    fn decrypt(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.0.open::<Aes128Ctr>(data, aad)
+    }
+}
+
+impl Cipher for Aes256CtrCipher {
+    fn encrypt(&self, data: &[u8], aad: &[u8]) -> Vec<u8> {
+        self.0.seal::<Aes256Ctr>(data, aad)
+    }
+
+    fn decrypt(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.0.open::<Aes256Ctr>(data, aad)
+    }
+}
+
+impl Cipher for Aes256GcmCipher {
+    fn encrypt(&self, data: &[u8], aad: &[u8]) -> Vec<u8> {
+        encrypt_data_aead(data, self.0.expose(), aad)
+    }
+
+    fn decrypt(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        decrypt_data_aead(data, self.0.expose(), aad)
+    }
+}
+
+impl Cipher for ChaCha20Poly1305Cipher {
+    fn encrypt(&self, data: &[u8], aad: &[u8]) -> Vec<u8> {
+        aead_encrypt::<ChaCha20Poly1305>(self.0.expose(), data, aad)
+    }
+
+    fn decrypt(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        aead_decrypt::<ChaCha20Poly1305>(self.0.expose(), data, aad)
+    }
+}
+
+/// Encrypt an image for `username` to store as `filename`, under a fresh
+/// random data key wrapped by their password-derived key or, if `None`,
+/// directly under the deprecated username key
+///
+/// The output is a `BlobHeader` naming the scheme and kind of key, with the
+/// wrapped data key if there is one, then the cipher's output. Changing the user's key
+/// only means wrapping each data key again. AEAD schemes bind the image, and
+/// the wrapped key always, to the user and filename, so it cannot be passed
+/// off as another image; CTR schemes do the same with an HMAC-SHA256 over the
+/// user and filename, header, IV and ciphertext, appended to the output.
+pub fn seal_image(
+    scheme: EncryptionScheme,
+    user_key: Option<&UserKey>,
+    data: &[u8],
+    username: &str,
+    filename: &str,
+) -> Vec<u8> {
+    seal_payload(scheme, user_key, data, None, username, filename)
+}
+
+/// What `seal_compressed` did with an image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompressionOutcome {
+    /// Sealed compressed, `compressed_len` bytes before encrypting
+    Compressed { compressed_len: usize },
+    /// Sealed as it is, since compressing only took it to `ratio` of its size
+    Skipped { ratio: f64 },
+}
+
+/// `seal_image` for an image compressed with zstd first, unless that saves
+/// less than 5%
+///
+/// The header names the codec and the image's size, and the AEAD tag or
+/// HMAC covers both, so `open_image` decompresses it again whoever opens it.
+pub fn seal_compressed(
+    scheme: EncryptionScheme,
+    user_key: Option<&UserKey>,
+    data: &[u8],
+    username: &str,
+    filename: &str,
+) -> (Vec<u8>, CompressionOutcome) {
+    match compress_for_storage(data) {
+        Ok(compressed) => {
+            let compressed = Zeroizing::new(compressed);
+            let compression = Compression {
+                codec: ZSTD_CODEC,
+                original_len: data.len() as u64,
+            };
+            let sealed = seal_payload(scheme, user_key, &compressed, Some(compression), username, filename);
+            (sealed, CompressionOutcome::Compressed { compressed_len: compressed.len() })
+        }
+        Err(ratio) => (seal_image(scheme, user_key, data, username, filename), CompressionOutcome::Skipped { ratio }),
+    }
+}
+
+/// Whether an image was compressed before it was sealed
+pub fn is_compressed(data: &[u8]) -> bool {
+    matches!(strip_header(data).map(parse_header), Ok(Ok(Some(Header { compression: Some(_), .. }))))
+}
+
+/// `seal_image` for `data` as it is to be encrypted, compressed as
+/// `compression` says
+fn seal_payload(
+    scheme: EncryptionScheme,
+    user_key: Option<&UserKey>,
+    data: &[u8],
+    compression: Option<Compression>,
+    username: &str,
+    filename: &str,
+) -> Vec<u8> {
+    let aad = image_aad(username, filename);
+    let mac_flag = if scheme.is_ctr() { MAC_FLAG } else { 0 };
+    let (wrapped_key, cipher) = match user_key {
+        Some(user_key) => {
+            let mut data_key = Zeroizing::new(vec![0u8; scheme.key_len()]);
+            OsRng.fill_bytes(&mut data_key);
+            let wrapped_key = encrypt_data_aead(&data_key, user_key.key.expose(), &aad);
+            (Some((user_key.generation, wrapped_key)), scheme.keyed(&data_key, true))
+        }
+        None => (None, scheme.cipher(None, username, true)),
+    };
+    let mut sealed = Vec::new();
+    blob_header(scheme, mac_flag, wrapped_key.as_ref(), compression).write(&mut sealed);
+    let aad = payload_aad(aad, compression);
+    let aad = if scheme.is_ctr() { [sealed.as_slice(), &aad].concat() } else { aad };
+    sealed.extend(cipher.encrypt(data, &aad));
+    sealed
+}
+
+/// Decrypt an image stored for `username` as `filename`, in whichever scheme
+/// its header names
+///
+/// Any access header in front is skipped unchecked. Images sealed with a
+/// user key need the one of the generation they name among `user_keys`. CTR
+/// images from before they carried an HMAC are decrypted without a check.
+/// Images from before IVs were random have no header and were encrypted with
+/// AES-128-CTR under an all-zero IV; anything without a known header is
+/// decrypted that way. Such an image whose first encrypted byte happens to be
+/// a known header, or whose first four are the `blobformat` magic, is
+/// misread, so re-upload old images where it matters. Compressed images are
+/// decompressed.
+pub fn open_image(data: &[u8], user_keys: &[UserKey], username: &str, filename: &str) -> Result<Vec<u8>, CryptoError> {
+    let data = strip_header(data)?;
+    let Some(header) = parse_header(data)? else {
+        let mut decrypted = data.to_vec();
+        apply_ctr_keystream::<Aes128Ctr>(generate_key_from_username(username).expose(), &[0u8; IV_LEN], &mut decrypted);
+        return Ok(decrypted);
+    };
+    let aad = image_aad(username, filename);
+    let key = header.key(user_keys, username, &aad)?;
+    open_payload(&header, &key, aad)
+}
+
+/// `open_image` for an image shared with the viewer, under the data key
+/// they unwrapped with `unwrap_for_viewer`
+// Only clients open shared images; nodes hand them out as stored
+#[allow(dead_code)]
+pub fn open_shared(data: &[u8], data_key: &[u8], owner: &str, filename: &str) -> Result<Vec<u8>, CryptoError> {
+    let data = strip_header(data)?;
+    let header = parse_header(data)?.ok_or(CryptoError::NoDataKey)?;
+    if header.wrapped_key.is_none() {
+        return Err(CryptoError::NoDataKey);
+    }
+    // Anyone can wrap a key to a public key, so its length is not a given
+    if data_key.len() != header.scheme.key_len() {
+        return Err(CryptoError::Tampered);
+    }
+    open_payload(&header, data_key, image_aad(owner, filename))
+}
+
+/// Decrypt the cipher's output of an image under `key`, `aad` binding it
+/// to its owner and name, and decompress it if it was compressed
+fn open_payload(header: &Header<'_>, key: &[u8], aad: Vec<u8>) -> Result<Vec<u8>, CryptoError> {
+    let aad = payload_aad(aad, header.compression);
+    let opened = if header.chunked {
+        open_chunks(header.scheme, key, header.sealed, &aad)?
+    } else {
+        let aad = if header.mac { [header.prefix, &aad].concat() } else { aad };
+        header.scheme.keyed(key, header.mac).decrypt(header.sealed, &aad)?
+    };
+    match header.compression {
+        Some(compression) => {
+            let compressed = Zeroizing::new(opened);
+            decompress_stored(&compressed, compression.original_len).ok_or(CryptoError::Decompress)
+        }
+        None => Ok(opened),
+    }
+}
+
+/// Encrypt an image read from `reader` into `writer` like `seal_image`, a
+/// chunk at a time, returning the bytes written
+///
+/// Only one chunk of the image is in memory at once. CTR images come out as
+/// `seal_image` makes them. AEAD images are split into chunks of 64 KiB, each
+/// with its own tag under a nonce naming its position and whether it is the
+/// last, so chunks cannot be reordered, dropped or cut off unnoticed.
+pub async fn encrypt_stream<R, W>(
+    scheme: EncryptionScheme,
+    user_key: Option<&UserKey>,
+    username: &str,
+    filename: &str,
+    mut reader: R,
+    mut writer: W,
+) -> Result<u64, StreamError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let aad = image_aad(username, filename);
+    let kind_flag = if scheme.is_ctr() { MAC_FLAG } else { CHUNKED_FLAG };
+    let (wrapped_key, key) = match user_key {
+        Some(user_key) => {
+            let mut data_key = Zeroizing::new(vec![0u8; scheme.key_len()]);
+            OsRng.fill_bytes(&mut data_key);
+            let wrapped_key = encrypt_data_aead(&data_key, user_key.key.expose(), &aad);
+            (Some((user_key.generation, wrapped_key)), data_key)
+        }
+        None => (None, scheme.username_key(username)),
+    };
+    let mut prefix = Vec::new();
+    blob_header(scheme, kind_flag, wrapped_key.as_ref(), None).write(&mut prefix);
+    writer.write_all(&prefix).await?;
+
+    let written = match scheme {
+        EncryptionScheme::Aes128Ctr => {
+            let aad = [prefix.as_slice(), &aad].concat();
+            ctr_encrypt_stream::<Aes128Ctr, _, _>(&CtrKeys::new(&key, true), &aad, &mut reader, &mut writer).await?
+        }
+        EncryptionScheme::Aes256Ctr => {
+            let aad = [prefix.as_slice(), &aad].concat();
+            ctr_encrypt_stream::<Aes256Ctr, _, _>(&CtrKeys::new(&key, true), &aad, &mut reader, &mut writer).await?
+        }
+        EncryptionScheme::Aes256Gcm => aead_encrypt_stream::<Aes256Gcm, _, _>(&key, &aad, &mut reader, &mut writer).await?,
+        EncryptionScheme::ChaCha20Poly1305 => {
+            aead_encrypt_stream::<ChaCha20Poly1305, _, _>(&key, &aad, &mut reader, &mut writer).await?
+        }
+    };
+    writer.flush().await?;
+    Ok(prefix.len() as u64 + written)
+}
+
+/// Decrypt a stored image read from `reader` into `writer` like
+/// `open_image`, returning the bytes written
+///
+/// Chunked and CTR images are decrypted a chunk at a time; others, sealed in
+/// one piece, and compressed images are read whole. Whatever reached `writer` must be thrown away
+/// if this fails: a CTR image is checked against its HMAC only at the end.
+pub async fn decrypt_stream<R, W>(
+    user_keys: &[UserKey],
+    username: &str,
+    filename: &str,
+    mut reader: R,
+    mut writer: W,
+) -> Result<u64, StreamError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let truncated = |e: std::io::Error| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => StreamError::Crypto(CryptoError::Truncated),
+        _ => StreamError::Io(e),
+    };
+    let mut prefix = vec![reader.read_u8().await.map_err(truncated)?];
+    if prefix[0] == ACCESS_HEADER {
+        let len = reader.read_u32().await.map_err(truncated)? as u64 + MAC_LEN as u64;
+        let skipped = tokio::io::copy(&mut (&mut reader).take(len), &mut tokio::io::sink()).await?;
+        if skipped < len {
+            return Err(CryptoError::Truncated.into());
+        }
+        prefix[0] = reader.read_u8().await.map_err(truncated)?;
+    }
+    if prefix[0] == MAGIC[0] {
+        prefix.resize(MAGIC.len(), 0);
+        let n = read_full(&mut reader, &mut prefix[1..]).await?;
+        prefix.truncate(1 + n);
+        if prefix != MAGIC {
+            return open_whole(prefix, user_keys, username, filename, reader, writer).await;
+        }
+        // Read as much more as the header says it needs until it is whole
+        while let Err(BlobError::Truncated { needed }) = BlobHeader::parse(&prefix) {
+            let read = prefix.len();
+            prefix.resize(needed, 0);
+            reader.read_exact(&mut prefix[read..]).await.map_err(truncated)?;
+        }
+    } else {
+        let Some((_, prefix_len)) = header_layout(prefix[0]) else {
+            return open_whole(prefix, user_keys, username, filename, reader, writer).await;
+        };
+        prefix.resize(prefix_len, 0);
+        reader.read_exact(&mut prefix[1..]).await.map_err(truncated)?;
+    }
+    let header = parse_header(&prefix)?.expect("the whole header was read");
+    let scheme = header.scheme;
+    if header.compression.is_some() || (!header.chunked && !scheme.is_ctr()) {
+        return open_whole(prefix, user_keys, username, filename, reader, writer).await;
+    }
+
+    let aad = image_aad(username, filename);
+    let key = header.key(user_keys, username, &aad)?;
+    let written = match scheme {
+        EncryptionScheme::Aes128Ctr | EncryptionScheme::Aes256Ctr => {
+            let keys = CtrKeys::new(&key, header.mac);
+            let aad = [header.prefix, &aad].concat();
+            match scheme {
+                EncryptionScheme::Aes128Ctr => ctr_decrypt_stream::<Aes128Ctr, _, _>(&keys, &aad, &mut reader, &mut writer).await?,
+                _ => ctr_decrypt_stream::<Aes256Ctr, _, _>(&keys, &aad, &mut reader, &mut writer).await?,
+            }
+        }
+        EncryptionScheme::Aes256Gcm => aead_decrypt_stream::<Aes256Gcm, _, _>(&key, &aad, &mut reader, &mut writer).await?,
+        EncryptionScheme::ChaCha20Poly1305 => {
+            aead_decrypt_stream::<ChaCha20Poly1305, _, _>(&key, &aad, &mut reader, &mut writer).await?
+        }
+    };
+    writer.flush().await?;
+    Ok(written)
+}
+
+/// Decrypt an image stored for `owner` and encrypt it for `recipient` like
+/// `encrypt_stream`, returning the new sealed image
+///
+/// The decrypted image passes from one to the other through a pipe of one
+/// chunk, so no more of it than that is in the clear at once, and never on
+/// disk; images that `decrypt_stream` reads whole are the exception. Fails
+/// like `decrypt_stream` if `owner_keys` cannot open the image.
+pub async fn reencrypt_for(
+    scheme: EncryptionScheme,
+    data: &[u8],
+    owner_keys: &[UserKey],
+    owner: &str,
+    recipient_key: Option<&UserKey>,
+    recipient: &str,
+    filename: &str,
+) -> Result<Vec<u8>, StreamError> {
+    let (plain_in, plain_out) = tokio::io::duplex(STREAM_CHUNK_LEN);
+    let mut reencrypted = Vec::with_capacity(data.len());
+    tokio::try_join!(
+        decrypt_stream(owner_keys, owner, filename, data, plain_in),
+        encrypt_stream(scheme, recipient_key, recipient, filename, plain_out, &mut reencrypted),
+    )?;
+    Ok(reencrypted)
+}
+
+/// `decrypt_stream` for images that cannot be decrypted in pieces: read the
+/// rest after `start` and open it in memory
+async fn open_whole<R, W>(
+    mut start: Vec<u8>,
+    user_keys: &[UserKey],
+    username: &str,
+    filename: &str,
+    mut reader: R,
+    mut writer: W,
+) -> Result<u64, StreamError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    reader.read_to_end(&mut start).await?;
+    let (user_keys, username, filename) = (user_keys.to_vec(), username.to_string(), filename.to_string());
+    let image = tokio::task::spawn_blocking(move || open_image(&start, &user_keys, &username, &filename))
+        .await
+        .map_err(std::io::Error::other)?
+        .map(Zeroizing::new)?;
+    writer.write_all(&image).await?;
+    writer.flush().await?;
+    Ok(image.len() as u64)
+}
+
+/// Whether opening an image checks it, so that a wrong key or a changed
+/// image fails instead of decrypting to noise; false only for CTR images
+/// from before they were tagged and images from before headers
+pub fn is_authenticated(data: &[u8]) -> bool {
+    match strip_header(data).map(parse_header) {
+        Ok(Ok(Some(header))) => header.mac || !header.scheme.is_ctr(),
+        _ => false,
+    }
+}
+
+/// Whether an image was stored under the deprecated username key
+pub fn uses_username_key(data: &[u8]) -> bool {
+    key_generation(data).is_none()
+}
+
+/// Generation of the user key an image was sealed with, `None` for images
+/// under the deprecated username key
+pub fn key_generation(data: &[u8]) -> Option<u32> {
+    match parse_header(strip_header(data).ok()?) {
+        Ok(Some(Header {
+            derived_key: true,
+            generation,
+            ..
+        })) => Some(generation),
+        _ => None,
+    }
+}
+
+/// Move an image from `old_key` (or the username key) to `new_key`
+///
+/// Images with their own data key under an AEAD scheme only get the data key
+/// wrapped again, leaving the encrypted image as it is. Others are decrypted
+/// and sealed again: CTR images, whose HMAC covers the wrapped key, and
+/// images from before data keys. Any access header is dropped, and images
+/// from before `blobformat` come out in it.
+pub fn rotate_user_key(
+    data: &[u8],
+    old_key: &UserKey,
+    new_key: &UserKey,
+    username: &str,
+    filename: &str,
+) -> Result<Vec<u8>, CryptoError> {
+    let data = strip_header(data)?;
+    let aad = image_aad(username, filename);
+    if let Some(Header {
+        scheme,
+        chunked,
+        derived_key: true,
+        wrapped_key: Some(wrapped_key),
+        generation,
+        compression,
+        sealed,
+        ..
+    }) = parse_header(data)?
+    {
+        if !scheme.is_ctr() {
+            if generation != old_key.generation {
+                return Err(CryptoError::NoKey);
+            }
+            let data_key = Zeroizing::new(decrypt_data_aead(wrapped_key, old_key.key.expose(), &aad)?);
+            let chunked_flag = if chunked { CHUNKED_FLAG } else { 0 };
+            let wrapped_key = (new_key.generation, encrypt_data_aead(&data_key, new_key.key.expose(), &aad));
+            let mut rotated = Vec::with_capacity(data.len());
+            blob_header(scheme, chunked_flag, Some(&wrapped_key), compression).write(&mut rotated);
+            rotated.extend_from_slice(sealed);
+            return Ok(rotated);
+        }
+    }
+    let scheme = parse_header(data)?.map_or(EncryptionScheme::Aes256Gcm, |header| header.scheme);
+    let plain = Zeroizing::new(open_image(data, std::slice::from_ref(old_key), username, filename)?);
+    match is_compressed(data) {
+        true => Ok(seal_compressed(scheme, Some(new_key), &plain, username, filename).0),
+        false => Ok(seal_image(scheme, Some(new_key), &plain, username, filename)),
+    }
+}
+
+/// A fresh X25519 keypair for receiving shared images: the private key the
+/// client keeps, and the public key it registers
+// Clients make their own keypairs
+#[allow(dead_code)]
+pub fn generate_share_keypair() -> (SecretKey, [u8; PUBLIC_KEY_LEN]) {
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public_key = PublicKey::from(&secret).to_bytes();
+    (SecretKey::new(secret.to_bytes()), public_key)
+}
+
+/// Whether a data key can be wrapped to `public_key`; a low-order point
+/// would give every sender the same, guessable shared secret
+pub fn is_valid_public_key(public_key: &[u8; PUBLIC_KEY_LEN]) -> bool {
+    EphemeralSecret::random_from_rng(OsRng)
+        .diffie_hellman(&PublicKey::from(*public_key))
+        .was_contributory()
+}
+
+/// The data key of one of `owner`'s images, to wrap for a viewer with
+/// `wrap_for_viewer`; images from before data keys have none
+pub fn image_data_key(
+    data: &[u8],
+    user_keys: &[UserKey],
+    owner: &str,
+    filename: &str,
+) -> Result<Zeroizing<Vec<u8>>, CryptoError> {
+    match parse_header(strip_header(data)?)? {
+        Some(header) if header.wrapped_key.is_some() => header.key(user_keys, owner, &image_aad(owner, filename)),
+        _ => Err(CryptoError::NoDataKey),
+    }
+}
+
+/// Hex SHA-256 of the wrapped data key in an image's header, `None` if it
+/// has none
+///
+/// Changes whenever the image is sealed again, so a data key wrapped for a
+/// viewer can be told to be stale. Rewrapping under a rotated user key
+/// changes it too, though the data key stays the same.
+pub fn data_key_id(data: &[u8]) -> Option<String> {
+    let header = parse_header(strip_header(data).ok()?).ok()??;
+    header.wrapped_key.map(sha256_hex)
+}
+
+/// Wrap an image's data key for the viewer holding the private half of
+/// `public_key`
+///
+/// X25519 with a fresh ephemeral key gives a secret only the viewer can
+/// also compute; HKDF-SHA256 turns it into an AES-256-GCM key, and the data
+/// key is encrypted under it bound to the owner, filename and viewer. The
+/// output is the ephemeral public key, then `encrypt_data_aead`'s. Nodes can
+/// then hand out the image as stored and never decrypt it for the viewer.
+pub fn wrap_for_viewer(
+    data_key: &[u8],
+    public_key: &[u8; PUBLIC_KEY_LEN],
+    owner: &str,
+    filename: &str,
+    viewer: &str,
+) -> Result<Vec<u8>, CryptoError> {
+    let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral).to_bytes();
+    let shared = ephemeral.diffie_hellman(&PublicKey::from(*public_key));
+    if !shared.was_contributory() {
+        return Err(CryptoError::InvalidPublicKey);
+    }
+    let key = share_key(shared.as_bytes(), &ephemeral_public, public_key);
+    let mut wrapped = ephemeral_public.to_vec();
+    wrapped.extend(encrypt_data_aead(data_key, key.expose(), &share_aad(owner, filename, viewer)));
+    Ok(wrapped)
+}
+
+/// Unwrap a data key from `wrap_for_viewer` with the viewer's private key
+// Only the viewer's client holds the private key
+#[allow(dead_code)]
+pub fn unwrap_for_viewer(
+    wrapped: &[u8],
+    private_key: &SecretKey,
+    owner: &str,
+    filename: &str,
+    viewer: &str,
+) -> Result<Zeroizing<Vec<u8>>, CryptoError> {
+    if wrapped.len() < PUBLIC_KEY_LEN {
+        return Err(CryptoError::Truncated);
+    }
+    let (ephemeral_public, sealed) = wrapped.split_at(PUBLIC_KEY_LEN);
+    let ephemeral_public: [u8; PUBLIC_KEY_LEN] = ephemeral_public.try_into().unwrap();
+    let secret = StaticSecret::from(*private_key.expose());
+    let public_key = PublicKey::from(&secret).to_bytes();
+    let shared = secret.diffie_hellman(&PublicKey::from(ephemeral_public));
+    if !shared.was_contributory() {
+        return Err(CryptoError::Tampered);
+    }
+    let key = share_key(shared.as_bytes(), &ephemeral_public, &public_key);
+    decrypt_data_aead(sealed, key.expose(), &share_aad(owner, filename, viewer)).map(Zeroizing::new)
+}
+
+/// AES-256-GCM key for one wrapped data key, from the X25519 shared secret
+/// salted with both public keys
+fn share_key(shared: &[u8; 32], ephemeral_public: &[u8; PUBLIC_KEY_LEN], public_key: &[u8; PUBLIC_KEY_LEN]) -> SecretKey {
+    let salt = [ephemeral_public.as_slice(), public_key].concat();
+    let mut key = SecretKey::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(b"distinsta-share-key", &mut key.0)
+        .expect("HKDF output fits");
+    key
+}
+
+/// Associated data binding a wrapped data key to the image and its viewer
+fn share_aad(owner: &str, filename: &str, viewer: &str) -> Vec<u8> {
+    let mut aad = b"share:".to_vec();
+    aad.extend(image_aad(owner, filename));
+    aad.push(b'/');
+    aad.extend_from_slice(viewer.as_bytes());
+    aad
+}
+
+/// A sealed image split at its header
+struct Header<'a> {
+    scheme: EncryptionScheme,
+    /// An AEAD image encrypted in chunks by `encrypt_stream`
+    chunked: bool,
+    /// Sealed with a password-derived key rather than the username key
+    derived_key: bool,
+    /// Generation of that key
+    generation: u32,
+    /// A CTR image that ends in an HMAC
+    mac: bool,
+    /// The image's data key, wrapped by the user's key, if it has its own
+    wrapped_key: Option<&'a [u8]>,
+    /// How the image was compressed before it was encrypted, if it was
+    compression: Option<Compression>,
+    /// Everything before the cipher's output, which the HMAC covers
+    prefix: &'a [u8],
+    /// The cipher's output
+    sealed: &'a [u8],
+}
+
+impl Header<'_> {
+    /// The key the cipher's output is under, unwrapped or derived with the
+    /// user key of the image's generation from `user_keys`
+    fn key(&self, user_keys: &[UserKey], username: &str, aad: &[u8]) -> Result<Zeroizing<Vec<u8>>, CryptoError> {
+        if !self.derived_key {
+            return Ok(self.scheme.username_key(username));
+        }
+        let user_key = user_keys
+            .iter()
+            .find(|key| key.generation == self.generation)
+            .ok_or(CryptoError::NoKey)?;
+        match self.wrapped_key {
+            Some(wrapped_key) => decrypt_data_aead(wrapped_key, user_key.key.expose(), aad).map(Zeroizing::new),
+            None => Ok(Zeroizing::new(
+                scheme_key(self.scheme, user_key.key.expose()).expose()[..self.scheme.key_len()].to_vec(),
+            )),
+        }
+    }
+}
+
+/// Header of an image sealed under `scheme` with `flags`, and with the
+/// generation of the user key and the data key it wrapped if it has its own
+fn blob_header(
+    scheme: EncryptionScheme,
+    flags: u8,
+    wrapped_key: Option<&(u32, Vec<u8>)>,
+    compression: Option<Compression>,
+) -> BlobHeader<'_> {
+    let derived_key_flag = if wrapped_key.is_some() { DERIVED_KEY_FLAG } else { 0 };
+    BlobHeader {
+        cipher: scheme.header(),
+        flags: flags | derived_key_flag,
+        generation: wrapped_key.map(|(generation, _)| *generation),
+        wrapped_key: wrapped_key.map(|(_, key)| key.as_slice()),
+        compression,
+    }
+}
+
+/// Associated data of an image's payload: its owner and name, and for a
+/// compressed image the codec and size from its header, so that they cannot
+/// be changed unnoticed under AEAD schemes either
+fn payload_aad(mut aad: Vec<u8>, compression: Option<Compression>) -> Vec<u8> {
+    if let Some(compression) = compression {
+        aad.push(compression.codec);
+        aad.extend_from_slice(&compression.original_len.to_be_bytes());
+    }
+    aad
+}
+
+/// The scheme a header byte from before `blobformat` names and the length of
+/// the header it starts, `None` if it is not one `seal_image` or
+/// `encrypt_stream` wrote
+fn header_layout(header: u8) -> Option<(EncryptionScheme, usize)> {
+    let scheme = EncryptionScheme::from_header(header & !HEADER_FLAGS)?;
+    let derived_key = header & DERIVED_KEY_FLAG != 0;
+    let envelope = header & ENVELOPE_FLAG != 0;
+    let has_generation = header & GENERATION_FLAG != 0;
+    let chunked = header & CHUNKED_FLAG != 0;
+    if (envelope && !derived_key) || (has_generation && !envelope) || (chunked && scheme.is_ctr()) {
+        return None;
+    }
+    let generation_len = if has_generation { 4 } else { 0 };
+    let wrapped_len = if envelope { NONCE_LEN + scheme.key_len() + TAG_LEN } else { 0 };
+    Some((scheme, 1 + generation_len + wrapped_len))
+}
+
+/// Split a sealed image at its header, in `blobformat` or the header byte
+/// from before it; `None` for images from before headers
+fn parse_header(data: &[u8]) -> Result<Option<Header<'_>>, CryptoError> {
+    if data.starts_with(&MAGIC) {
+        let (blob, sealed) = BlobHeader::parse(data)?;
+        let scheme = EncryptionScheme::from_header(blob.cipher).ok_or(BlobError::UnknownCipher(blob.cipher))?;
+        if blob.has(CHUNKED_FLAG) && scheme.is_ctr() {
+            return Err(BlobError::InvalidFlags(blob.flags).into());
+        }
+        return Ok(Some(Header {
+            scheme,
+            chunked: blob.has(CHUNKED_FLAG),
+            derived_key: blob.has(DERIVED_KEY_FLAG),
+            generation: blob.generation.unwrap_or(0),
+            mac: blob.has(MAC_FLAG),
+            wrapped_key: blob.wrapped_key,
+            compression: blob.compression,
+            prefix: &data[..data.len() - sealed.len()],
+            sealed,
+        }));
+    }
+    let Some(&header) = data.first() else {
+        return Ok(None);
+    };
+    let Some((scheme, prefix_len)) = header_layout(header) else {
+        return Ok(None);
+    };
+    let envelope = header & ENVELOPE_FLAG != 0;
+    let has_generation = header & GENERATION_FLAG != 0;
+    let generation_len = if has_generation { 4 } else { 0 };
+    if data.len() < prefix_len {
+        return Err(CryptoError::Truncated);
+    }
+    let (prefix, sealed) = data.split_at(prefix_len);
+    let generation = match has_generation {
+        true => u32::from_be_bytes(prefix[1..5].try_into().unwrap()),
+        false => 0,
+    };
+    Ok(Some(Header {
+        scheme,
+        chunked: header & CHUNKED_FLAG != 0,
+        derived_key: header & DERIVED_KEY_FLAG != 0,
+        generation,
+        mac: header & MAC_FLAG != 0,
+        wrapped_key: envelope.then(|| &prefix[1 + generation_len..]),
+        compression: None,
+        prefix,
+        sealed,
+    }))
+}
+
+/// A user's image key: Argon2id over their username and password, salted
+/// with the random salt from their registration
+pub fn derive_user_key(username: &str, password: &str, salt: &[u8]) -> SecretKey {
+    let mut key = SecretKey::new([0u8; 32]);
+    let input = Zeroizing::new(format!("{}:{}", username, password));
+    Argon2::default()
+        .hash_password_into(input.as_bytes(), salt, &mut key.0)
+        .expect("salt is long enough for Argon2");
+    key
+}
+
+/// Key under which nodes store users' image keys, from the cluster secret
+pub fn key_wrapping_key(secret: &[u8]) -> SecretKey {
+    let mut hasher = Sha256::new();
+    hasher.update(b"distinsta-key-wrap:");
+    hasher.update(secret);
+    SecretKey::new(hasher.finalize().into())
+}
+
+/// Key under which nodes authenticate the access headers of stored images,
+/// from the cluster secret
+pub fn access_header_key(secret: &[u8]) -> SecretKey {
+    let mut hasher = Sha256::new();
+    hasher.update(b"distinsta-access-header:");
+    hasher.update(secret);
+    SecretKey::new(hasher.finalize().into())
+}
+
+/// Key under which nodes derive the tokens stored images and their catalog
+/// entries are named by, from the cluster secret
+///
+/// Not the owner's key, so tokens survive password changes and any node
+/// finds an image by name without unwrapping it.
+pub fn catalog_key(secret: &[u8]) -> SecretKey {
+    let mut hasher = Sha256::new();
+    hasher.update(b"distinsta-catalog:");
+    hasher.update(secret);
+    SecretKey::new(hasher.finalize().into())
+}
+
+/// Hex HMAC-SHA256 naming the catalog bucket of one of `username`'s images
+///
+/// The lowercase filename is used, so names differing only in case share a
+/// bucket; `file_token` tells them apart within it.
+pub fn lookup_token(catalog_key: &SecretKey, username: &str, filename: &str) -> String {
+    name_token(catalog_key, b"lookup", username, &filename.to_lowercase())
+}
+
+/// Hex HMAC-SHA256 the image named exactly `filename` and its sidecars are
+/// stored under
+pub fn file_token(catalog_key: &SecretKey, username: &str, filename: &str) -> String {
+    name_token(catalog_key, b"file", username, filename)
+}
+
+fn name_token(catalog_key: &SecretKey, label: &[u8], username: &str, name: &str) -> String {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(catalog_key.expose()).expect("HMAC accepts keys of any length");
+    mac.update(label);
+    mac.update(&(username.len() as u64).to_be_bytes());
+    mac.update(username.as_bytes());
+    mac.update(name.as_bytes());
+    to_hex(&mac.finalize().into_bytes())
+}
+
+/// Key a user's catalog entries are sealed under, from their image key
+fn metadata_key(user_key: &SecretKey) -> SecretKey {
+    let mut key = SecretKey::new([0u8; 32]);
+    Hkdf::<Sha256>::new(None, user_key.expose())
+        .expand(b"distinsta-metadata-key", &mut key.0)
+        .expect("HKDF output fits");
+    key
+}
+
+/// Encrypt one of `username`'s catalog entries, which hold an image's name,
+/// tags and caption, under the metadata key of `user_key`
+pub fn seal_metadata(user_key: &UserKey, username: &str, entry: &[u8]) -> Vec<u8> {
+    let key = metadata_key(&user_key.key);
+    encrypt_data_aead(entry, key.expose(), &metadata_aad(username, user_key.generation))
+}
+
+/// Decrypt a catalog entry from `seal_metadata` with whichever of
+/// `user_keys` is of `generation`
+pub fn open_metadata(sealed: &[u8], user_keys: &[UserKey], username: &str, generation: u32) -> Result<Vec<u8>, CryptoError> {
+    let user_key = user_keys
+        .iter()
+        .find(|key| key.generation == generation)
+        .ok_or(CryptoError::NoKey)?;
+    let key = metadata_key(&user_key.key);
+    decrypt_data_aead(sealed, key.expose(), &metadata_aad(username, generation))
+}
+
+fn metadata_aad(username: &str, generation: u32) -> Vec<u8> {
+    let mut aad = b"meta:".to_vec();
+    aad.extend_from_slice(&generation.to_be_bytes());
+    aad.extend_from_slice(username.as_bytes());
+    aad
+}
+
+/// Bytes an access header takes at the start of a stored image, 0 if it has
+/// none; `start` must hold at least the first five bytes of one that does
+pub fn access_header_len(start: &[u8]) -> Result<usize, CryptoError> {
+    if start.first() != Some(&ACCESS_HEADER) {
+        return Ok(0);
+    }
+    let len_bytes = start.get(1..ACCESS_PREFIX_LEN).ok_or(CryptoError::Truncated)?;
+    let len = u32::from_be_bytes(len_bytes.try_into().unwrap()) as usize;
+    Ok(ACCESS_PREFIX_LEN + len + MAC_LEN)
+}
+
+/// The sealed image of a stored image, without any access header
+pub fn strip_header(data: &[u8]) -> Result<&[u8], CryptoError> {
+    let header_len = access_header_len(data)?;
+    data.get(header_len..).ok_or(CryptoError::Truncated)
+}
+
+/// The contents of a stored image's access header, `None` if it has none,
+/// failing if its HMAC does not match `owner` and the sealed image after it
+pub fn read_header<'a>(data: &'a [u8], mac_key: &SecretKey, owner: &str) -> Result<Option<&'a [u8]>, CryptoError> {
+    let header_len = access_header_len(data)?;
+    if header_len == 0 {
+        return Ok(None);
+    }
+    if data.len() < header_len {
+        return Err(CryptoError::Truncated);
+    }
+    let (header, sealed) = data.split_at(header_len);
+    let (contents, tag) = header[ACCESS_PREFIX_LEN..].split_at(header_len - ACCESS_PREFIX_LEN - MAC_LEN);
+    header_mac(mac_key, owner, contents, sealed)
+        .verify_slice(tag)
+        .map_err(|_| CryptoError::Tampered)?;
+    Ok(Some(contents))
+}
+
+/// Put a new access header in front of a stored image in place of any it
+/// had, leaving the sealed image as it is
+///
+/// The header's HMAC covers `owner` and a digest of the sealed image, so the
+/// header cannot be moved to another image or changed without the key.
+pub fn update_header(data: &[u8], contents: &[u8], mac_key: &SecretKey, owner: &str) -> Result<Vec<u8>, CryptoError> {
+    let sealed = strip_header(data)?;
+    let len = u32::try_from(contents.len()).expect("access header is under 4 GiB");
+    let mut updated = Vec::with_capacity(ACCESS_PREFIX_LEN + contents.len() + MAC_LEN + sealed.len());
+    updated.push(ACCESS_HEADER);
+    updated.extend_from_slice(&len.to_be_bytes());
+    updated.extend_from_slice(contents);
+    updated.extend_from_slice(&header_mac(mac_key, owner, contents, sealed).finalize().into_bytes());
+    updated.extend_from_slice(sealed);
+    Ok(updated)
+}
+
+fn header_mac(mac_key: &SecretKey, owner: &str, contents: &[u8], sealed: &[u8]) -> HmacSha256 {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(mac_key.expose()).expect("HMAC accepts keys of any length");
+    mac.update(&(owner.len() as u64).to_be_bytes());
+    mac.update(owner.as_bytes());
+    mac.update(&(contents.len() as u64).to_be_bytes());
+    mac.update(contents);
+    mac.update(&sha256(sealed));
+    mac
+}
+
+/// Encrypt a user's image key for storage in their user record
+pub fn wrap_key(wrapping_key: &SecretKey, username: &str, user_key: &SecretKey) -> Vec<u8> {
+    encrypt_data_aead(user_key.expose(), wrapping_key.expose(), username.as_bytes())
+}
+
+pub fn unwrap_key(wrapping_key: &SecretKey, username: &str, wrapped: &[u8]) -> Result<SecretKey, CryptoError> {
+    let key = Zeroizing::new(decrypt_data_aead(wrapped, wrapping_key.expose(), username.as_bytes())?);
+    let key: [u8; 32] = key.as_slice().try_into().map_err(|_| CryptoError::Truncated)?;
+    Ok(SecretKey::new(key))
+}
+
+/// AES-256-GCM encrypt under a fresh random nonce, giving nonce ‖ ciphertext ‖ tag
+pub fn encrypt_data_aead(data: &[u8], key: &[u8; 32], aad: &[u8]) -> Vec<u8> {
+    aead_encrypt::<Aes256Gcm>(key, data, aad)
+}
+
+/// Decrypt data from `encrypt_data_aead`, failing unless `key` and `aad` match
+/// and the data is unchanged
+pub fn decrypt_data_aead(data: &[u8], key: &[u8; 32], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    aead_decrypt::<Aes256Gcm>(key, data, aad)
+}
+
+fn ctr_encrypt<C: KeyIvInit + StreamCipher + StreamCipherSeek>(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut iv = [0u8; IV_LEN];
+    OsRng.fill_bytes(&mut iv);
+    let mut encrypted = Vec::with_capacity(IV_LEN + data.len());
+    encrypted.extend_from_slice(&iv);
+    encrypted.extend_from_slice(data);
+    apply_ctr_keystream::<C>(key, &iv, &mut encrypted[IV_LEN..]);
+    encrypted
+}
+
+fn ctr_decrypt<C: KeyIvInit + StreamCipher + StreamCipherSeek>(key: &[u8], data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    if data.len() < IV_LEN {
+        return Err(CryptoError::Truncated);
+    }
+    let (iv, ciphertext) = data.split_at(IV_LEN);
+    let mut decrypted = ciphertext.to_vec();
+    apply_ctr_keystream::<C>(key, iv, &mut decrypted);
+    Ok(decrypted)
+}
+
+/// XOR `data` with the CTR keystream for `key` and `iv`, splitting large
+/// payloads across threads; every segment seeks to its own offset, so the
+/// result is the same as applying the keystream in one pass
+fn apply_ctr_keystream<C: KeyIvInit + StreamCipher + StreamCipherSeek>(key: &[u8], iv: &[u8], data: &mut [u8]) {
+    let cipher = || C::new_from_slices(key, iv).expect("key and IV sizes match the cipher");
+    if data.len() < PARALLEL_CTR_MIN_LEN {
+        cipher().apply_keystream(data);
+        return;
+    }
+    data.par_chunks_mut(PARALLEL_CTR_SEGMENT_LEN).enumerate().for_each(|(i, segment)| {
+        let mut segment_cipher = cipher();
+        segment_cipher.seek((i * PARALLEL_CTR_SEGMENT_LEN) as u64);
+        segment_cipher.apply_keystream(segment);
+    });
+}
+
+/// HMAC of a CTR image: `aad`, length-prefixed so it cannot run into the
+/// IV, then the IV and ciphertext
+fn ctr_mac(mac_key: &[u8; 32], aad: &[u8], sealed: &[u8]) -> HmacSha256 {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(mac_key).expect("HMAC accepts keys of any length");
+    mac.update(&(aad.len() as u64).to_be_bytes());
+    mac.update(aad);
+    mac.update(sealed);
+    mac
+}
+
+/// `CtrKeys::seal` from `reader` to `writer`, returning the bytes written
+async fn ctr_encrypt_stream<C, R, W>(keys: &CtrKeys, aad: &[u8], reader: &mut R, writer: &mut W) -> Result<u64, StreamError>
+where
+    C: KeyIvInit + StreamCipher,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut iv = [0u8; IV_LEN];
+    OsRng.fill_bytes(&mut iv);
+    let mut cipher = C::new_from_slices(&keys.encrypt, &iv).expect("key and IV sizes match the cipher");
+    let mut mac = keys.mac.as_ref().map(|mac_key| ctr_mac(mac_key.expose(), aad, &iv));
+    writer.write_all(&iv).await?;
+    let mut written = IV_LEN as u64;
+
+    let mut chunk = Zeroizing::new(vec![0u8; STREAM_CHUNK_LEN]);
+    loop {
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        cipher.apply_keystream(&mut chunk[..n]);
+        if let Some(mac) = &mut mac {
+            mac.update(&chunk[..n]);
+        }
+        writer.write_all(&chunk[..n]).await?;
+        written += n as u64;
+    }
+    if let Some(mac) = mac {
+        writer.write_all(&mac.finalize().into_bytes()).await?;
+        written += MAC_LEN as u64;
+    }
+    Ok(written)
+}
+
+/// `CtrKeys::open` from `reader` to `writer`, holding back what may be the
+/// HMAC until the end; returns the bytes written
+async fn ctr_decrypt_stream<C, R, W>(keys: &CtrKeys, aad: &[u8], reader: &mut R, writer: &mut W) -> Result<u64, StreamError>
+where
+    C: KeyIvInit + StreamCipher,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut iv = [0u8; IV_LEN];
+    if read_full(reader, &mut iv).await? < IV_LEN {
+        return Err(CryptoError::Truncated.into());
+    }
+    let mut cipher = C::new_from_slices(&keys.encrypt, &iv).expect("key and IV sizes match the cipher");
+    let mut mac = keys.mac.as_ref().map(|mac_key| ctr_mac(mac_key.expose(), aad, &iv));
+    let held_back = if mac.is_some() { MAC_LEN } else { 0 };
+
+    let mut pending = Vec::with_capacity(STREAM_CHUNK_LEN + MAC_LEN);
+    let mut chunk = vec![0u8; STREAM_CHUNK_LEN];
+    let mut written = 0;
+    loop {
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        pending.extend_from_slice(&chunk[..n]);
+        if pending.len() <= held_back {
+            continue;
+        }
+        let mut ready: Zeroizing<Vec<u8>> = Zeroizing::new(pending.drain(..pending.len() - held_back).collect());
+        if let Some(mac) = &mut mac {
+            mac.update(&ready);
+        }
+        cipher.apply_keystream(&mut ready);
+        writer.write_all(&ready).await?;
+        written += ready.len() as u64;
+    }
+    if let Some(mac) = mac {
+        if pending.len() < MAC_LEN {
+            return Err(CryptoError::Truncated.into());
+        }
+        mac.verify_slice(&pending).map_err(|_| CryptoError::Tampered)?;
+    }
+    Ok(written)
+}
+
+/// Encrypt `reader` into chunks of `STREAM_CHUNK_LEN` bytes, each with its
+/// own tag, after a random nonce prefix; returns the bytes written
+///
+/// The chunk after the current one is read before it is sealed, to know
+/// whether it is the last.
+async fn aead_encrypt_stream<C, R, W>(key: &[u8], aad: &[u8], reader: &mut R, writer: &mut W) -> Result<u64, StreamError>
+where
+    C: Aead + KeyInit,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let cipher = C::new_from_slice(key).expect("key size matches the cipher");
+    let mut nonce_prefix = [0u8; STREAM_NONCE_PREFIX_LEN];
+    OsRng.fill_bytes(&mut nonce_prefix);
+    writer.write_all(&nonce_prefix).await?;
+    let mut written = STREAM_NONCE_PREFIX_LEN as u64;
+
+    let mut chunk = Zeroizing::new(vec![0u8; STREAM_CHUNK_LEN]);
+    let mut next = Zeroizing::new(vec![0u8; STREAM_CHUNK_LEN]);
+    let mut len = read_full(reader, &mut chunk).await?;
+    let mut index = 0;
+    loop {
+        let next_len = if len == STREAM_CHUNK_LEN { read_full(reader, &mut next).await? } else { 0 };
+        let last = next_len == 0;
+        let nonce = chunk_nonce(&nonce_prefix, index, last);
+        let sealed = cipher
+            .encrypt(&nonce_for::<C>(&nonce), Payload { msg: &chunk[..len], aad })
+            .expect("chunk fits the cipher");
+        writer.write_all(&sealed).await?;
+        written += sealed.len() as u64;
+        if last {
+            return Ok(written);
+        }
+        std::mem::swap(&mut chunk, &mut next);
+        len = next_len;
+        index += 1;
+    }
+}
+
+/// Decrypt what `aead_encrypt_stream` wrote, checking each chunk before it
+/// reaches `writer`; returns the bytes written
+async fn aead_decrypt_stream<C, R, W>(key: &[u8], aad: &[u8], reader: &mut R, writer: &mut W) -> Result<u64, StreamError>
+where
+    C: Aead + KeyInit,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let cipher = C::new_from_slice(key).expect("key size matches the cipher");
+    let mut nonce_prefix = [0u8; STREAM_NONCE_PREFIX_LEN];
+    if read_full(reader, &mut nonce_prefix).await? < STREAM_NONCE_PREFIX_LEN {
+        return Err(CryptoError::Truncated.into());
+    }
+
+    let sealed_len = STREAM_CHUNK_LEN + TAG_LEN;
+    let mut chunk = vec![0u8; sealed_len];
+    let mut next = vec![0u8; sealed_len];
+    let mut len = read_full(reader, &mut chunk).await?;
+    let mut index = 0;
+    let mut written = 0;
+    loop {
+        let next_len = if len == sealed_len { read_full(reader, &mut next).await? } else { 0 };
+        let last = next_len == 0;
+        let plain = Zeroizing::new(open_chunk(&cipher, &nonce_prefix, index, last, &chunk[..len], aad)?);
+        writer.write_all(&plain).await?;
+        written += plain.len() as u64;
+        if last {
+            return Ok(written);
+        }
+        std::mem::swap(&mut chunk, &mut next);
+        len = next_len;
+        index += 1;
+    }
+}
+
+/// Decrypt a whole chunked image in memory
+fn open_chunks(scheme: EncryptionScheme, key: &[u8], sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    match scheme {
+        EncryptionScheme::ChaCha20Poly1305 => {
+            open_chunks_with(&ChaCha20Poly1305::new_from_slice(key).expect("32-byte key"), sealed, aad)
+        }
+        _ => open_chunks_with(&Aes256Gcm::new_from_slice(key).expect("32-byte key"), sealed, aad),
+    }
+}
+
+fn open_chunks_with<C: Aead>(cipher: &C, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    if sealed.len() < STREAM_NONCE_PREFIX_LEN + TAG_LEN {
+        return Err(CryptoError::Truncated);
+    }
+    let (nonce_prefix, chunks) = sealed.split_at(STREAM_NONCE_PREFIX_LEN);
+    let nonce_prefix: &[u8; STREAM_NONCE_PREFIX_LEN] = nonce_prefix.try_into().unwrap();
+    let count = chunks.len().div_ceil(STREAM_CHUNK_LEN + TAG_LEN);
+    let mut image = Vec::with_capacity(chunks.len());
+    for (index, chunk) in chunks.chunks(STREAM_CHUNK_LEN + TAG_LEN).enumerate() {
+        let plain = Zeroizing::new(open_chunk(cipher, nonce_prefix, index as u32, index + 1 == count, chunk, aad)?);
+        image.extend_from_slice(&plain);
+    }
+    Ok(image)
+}
+
+fn open_chunk<C: Aead>(
+    cipher: &C,
+    nonce_prefix: &[u8; STREAM_NONCE_PREFIX_LEN],
+    index: u32,
+    last: bool,
+    chunk: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    if chunk.len() < TAG_LEN {
+        return Err(CryptoError::Truncated);
+    }
+    let nonce = chunk_nonce(nonce_prefix, index, last);
+    cipher
+        .decrypt(&nonce_for::<C>(&nonce), Payload { msg: chunk, aad })
+        .map_err(|_| CryptoError::Tampered)
+}
+
+/// Nonce of one chunk of a chunked image
+fn chunk_nonce(prefix: &[u8; STREAM_NONCE_PREFIX_LEN], index: u32, last: bool) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..STREAM_NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[STREAM_NONCE_PREFIX_LEN..NONCE_LEN - 1].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+    nonce
+}
+
+/// Read until `buf` is full or the reader ends, returning the bytes read
+async fn read_full<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = reader.read(&mut buf[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+/// Encrypt under a fresh random 96-bit nonce, giving nonce ‖ ciphertext ‖ tag
+fn aead_encrypt<C: Aead + KeyInit>(key: &[u8], data: &[u8], aad: &[u8]) -> Vec<u8> {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = C::new_from_slice(key)
+        .expect("key size matches the cipher")
+        .encrypt(&nonce_for::<C>(&nonce), Payload { msg: data, aad })
+        .expect("image too large for the cipher");
+    let mut encrypted = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+    encrypted.extend_from_slice(&nonce);
+    encrypted.extend(ciphertext);
     encrypted
 }
 
-/// Simple AES decryption for image data
-pub fn decrypt_data(data: &[u8], key: &[u8; 16]) -> Vec<u8> {
-    let mut decrypted = data.to_vec();
-    let iv = [0u8; 16];
-    let mut cipher = Aes128Ctr::new(key.into(), &iv.into());
-    cipher.apply_keystream(&mut decrypted);
-    decrypted
+/// `nonce` as cipher `C` takes it; `nonce` must be `C`'s nonce size
+fn nonce_for<C: AeadCore>(nonce: &[u8]) -> Nonce<C> {
+    let mut typed = Nonce::<C>::default();
+    typed.copy_from_slice(nonce);
+    typed
+}
+
+fn aead_decrypt<C: Aead + KeyInit>(key: &[u8], data: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    if data.len() < NONCE_LEN + TAG_LEN {
+        return Err(CryptoError::Truncated);
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    C::new_from_slice(key)
+        .expect("key size matches the cipher")
+        .decrypt(&nonce_for::<C>(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| CryptoError::Tampered)
+}
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Hex SHA-256 digest, used to let clients check what they received
+pub fn sha256_hex(data: &[u8]) -> String {
+    to_hex(&sha256(data))
+}
+
+/// Lowercase hex of a digest, e.g. one computed over a stream
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Bytes from `to_hex`, `None` if `hex` is not hex
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Associated data binding a sealed image to its owner and name; usernames
+/// cannot contain '/', so no two pairs give the same bytes
+fn image_aad(username: &str, filename: &str) -> Vec<u8> {
+    format!("{}/{}", username, filename).into_bytes()
+}
+
+/// A scheme's own key from `secret`, so no two schemes share a key
+fn scheme_key(scheme: EncryptionScheme, secret: &[u8]) -> SecretKey {
+    let mut hasher = Sha256::new();
+    hasher.update(format!("distinsta-{}:", scheme.name()).as_bytes());
+    hasher.update(secret);
+    SecretKey::new(hasher.finalize().into())
+}
+
+/// Deprecated 256-bit username key for one of the 256-bit schemes; anyone
+/// who knows the username can compute it
+pub fn generate_key256_from_username(scheme: EncryptionScheme, username: &str) -> SecretKey {
+    scheme_key(scheme, username.as_bytes())
 }
 
-/// Generate a simple key from username
-pub fn generate_key_from_username(username: &str) -> [u8; 16] {
+/// Deprecated: a key anyone who knows the username can compute, kept for
+/// images stored before password-derived keys and for users without one
+pub fn generate_key_from_username(username: &str) -> SecretKey<16> {
     let mut hasher = Sha256::new();
     hasher.update(username.as_bytes());
-    let result = hasher.finalize();
-    let mut key = [0u8; 16];
-    key.copy_from_slice(&result[0..16]);
+    let mut result = hasher.finalize();
+    let mut key = SecretKey::new([0u8; 16]);
+    key.0.copy_from_slice(&result[0..16]);
+    result[..].zeroize();
     key
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GOLDEN_IMAGES: &str = include_str!("../testdata/blobformat/images.txt");
+
+    fn golden_plaintext() -> Vec<u8> {
+        "distinsta golden vector\n".repeat(40).into_bytes()
+    }
+
+    fn golden_keys() -> Vec<UserKey> {
+        [0, 3]
+            .into_iter()
+            .map(|generation| UserKey {
+                key: SecretKey::new([0x42; 32]),
+                generation,
+            })
+            .collect()
+    }
+
+    /// Each golden image: its scheme, how it was sealed and its bytes
+    fn golden_images() -> Vec<(EncryptionScheme, &'static str, Vec<u8>)> {
+        GOLDEN_IMAGES
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let fields: Vec<&str> = line.split(' ').collect();
+                (EncryptionScheme::from_name(fields[0]).unwrap(), fields[1], from_hex(fields[2]).unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn golden_images_open() {
+        let images = golden_images();
+        assert_eq!(images.len(), 4 * 5);
+        for (scheme, kind, sealed) in images {
+            let opened = open_image(&sealed, &golden_keys(), "alice", "golden.png");
+            assert_eq!(opened.as_deref().ok(), Some(&golden_plaintext()[..]), "{} {}", scheme.name(), kind);
+        }
+    }
+
+    #[test]
+    fn golden_images_have_the_headers_they_were_sealed_with() {
+        for (scheme, kind, sealed) in golden_images() {
+            let (header, _) = BlobHeader::parse(&sealed).unwrap();
+            assert_eq!(header.cipher, scheme.header(), "{} {}", scheme.name(), kind);
+            assert_eq!(header.has(MAC_FLAG), scheme.is_ctr(), "{} {}", scheme.name(), kind);
+            assert_eq!(header.has(CHUNKED_FLAG), kind.ends_with("streamed") && !scheme.is_ctr(), "{} {}", scheme.name(), kind);
+            assert_eq!(header.has(DERIVED_KEY_FLAG), kind.starts_with("generation"), "{} {}", scheme.name(), kind);
+            let generation = kind.strip_prefix("generation-").map(|rest| rest[..1].parse().unwrap());
+            assert_eq!(header.generation, generation, "{} {}", scheme.name(), kind);
+            assert_eq!(header.compression.is_some(), kind.ends_with("compressed"), "{} {}", scheme.name(), kind);
+        }
+    }
+
+    #[test]
+    fn golden_images_are_bound_to_their_owner_and_name() {
+        for (scheme, kind, sealed) in golden_images() {
+            for (username, filename) in [("bob", "golden.png"), ("alice", "other.png")] {
+                let opened = open_image(&sealed, &golden_keys(), username, filename);
+                assert!(opened.is_err(), "{} {} opened as {}/{}", scheme.name(), kind, username, filename);
+            }
+        }
+    }
+
+    #[test]
+    fn changed_golden_images_are_refused() {
+        for (scheme, kind, mut sealed) in golden_images() {
+            let last = sealed.len() - 1;
+            sealed[last] ^= 1;
+            assert!(open_image(&sealed, &golden_keys(), "alice", "golden.png").is_err(), "{} {}", scheme.name(), kind);
+        }
+    }
+
+    #[test]
+    fn fresh_images_round_trip_in_every_scheme() {
+        let plain = golden_plaintext();
+        let keys = golden_keys();
+        for scheme in [
+            EncryptionScheme::Aes128Ctr,
+            EncryptionScheme::Aes256Ctr,
+            EncryptionScheme::Aes256Gcm,
+            EncryptionScheme::ChaCha20Poly1305,
+        ] {
+            for user_key in [None, Some(&keys[1])] {
+                let sealed = seal_image(scheme, user_key, &plain, "alice", "fresh.png");
+                assert_eq!(open_image(&sealed, &keys, "alice", "fresh.png").unwrap(), plain);
+                let (sealed, _) = seal_compressed(scheme, user_key, &plain, "alice", "fresh.png");
+                assert!(is_compressed(&sealed));
+                assert_eq!(open_image(&sealed, &keys, "alice", "fresh.png").unwrap(), plain);
+            }
+        }
+    }
+
+    #[test]
+    fn same_image_sealed_twice_differs() {
+        let plain = golden_plaintext();
+        let first = seal_image(EncryptionScheme::Aes128Ctr, None, &plain, "alice", "twice.png");
+        let second = seal_image(EncryptionScheme::Aes128Ctr, None, &plain, "alice", "twice.png");
+        assert_ne!(first, second);
+
+        let cipher = EncryptionScheme::Aes128Ctr.cipher(None, "alice", false);
+        let (first, second) = (cipher.encrypt(&plain, b""), cipher.encrypt(&plain, b""));
+        assert_ne!(first[..IV_LEN], second[..IV_LEN]);
+        assert_eq!(first.len(), IV_LEN + plain.len());
+        assert_eq!(cipher.decrypt(&first, b"").unwrap(), plain);
+        assert_eq!(cipher.decrypt(&second, b"").unwrap(), plain);
+    }
+
+    #[test]
+    fn images_under_one_key_do_not_share_a_keystream() {
+        let cipher = EncryptionScheme::Aes128Ctr.cipher(None, "alice", false);
+        let (a, b) = (vec![0x00; 64], vec![0xff; 64]);
+        let (sealed_a, sealed_b) = (cipher.encrypt(&a, b""), cipher.encrypt(&b, b""));
+        let xored: Vec<u8> = sealed_a[IV_LEN..].iter().zip(&sealed_b[IV_LEN..]).map(|(x, y)| x ^ y).collect();
+        assert_ne!(xored, vec![0xff; 64]);
+    }
+
+    #[test]
+    fn zero_iv_images_from_before_headers_still_open() {
+        let zero_iv = |plain: &[u8]| {
+            let mut legacy = plain.to_vec();
+            apply_ctr_keystream::<Aes128Ctr>(generate_key_from_username("alice").expose(), &[0u8; IV_LEN], &mut legacy);
+            legacy
+        };
+        // Old images whose first bytes encrypt to a known header are misread
+        let plain = (0..=u8::MAX)
+            .map(|first| [&[first][..], b"an image stored before IVs were random"].concat())
+            .find(|plain| matches!(strip_header(&zero_iv(plain)).map(parse_header), Ok(Ok(None))))
+            .unwrap();
+        let legacy = zero_iv(&plain);
+
+        assert_eq!(open_image(&legacy, &[], "alice", "old.png").unwrap(), plain);
+        assert_ne!(open_image(&legacy, &[], "bob", "old.png").unwrap(), plain);
+    }
+
+    #[test]
+    fn truncated_ctr_output_is_refused() {
+        let cipher = EncryptionScheme::Aes128Ctr.cipher(None, "alice", false);
+        assert!(matches!(cipher.decrypt(&[0; IV_LEN - 1], b""), Err(CryptoError::Truncated)));
+        assert_eq!(cipher.decrypt(&cipher.encrypt(b"", b""), b"").unwrap(), b"");
+    }
+
+    const SCHEMES: [EncryptionScheme; 4] = [
+        EncryptionScheme::Aes128Ctr,
+        EncryptionScheme::Aes256Ctr,
+        EncryptionScheme::Aes256Gcm,
+        EncryptionScheme::ChaCha20Poly1305,
+    ];
+
+    #[test]
+    fn scheme_names_and_headers_round_trip() {
+        for scheme in SCHEMES {
+            assert_eq!(EncryptionScheme::from_name(scheme.name()), Some(scheme));
+            assert_eq!(EncryptionScheme::from_name(&scheme.name().to_uppercase()), None);
+            assert_eq!(EncryptionScheme::from_header(scheme.header()), Some(scheme));
+        }
+        assert_eq!(EncryptionScheme::from_name("rot13"), None);
+        assert_eq!(EncryptionScheme::from_header(0), None);
+    }
+
+    #[test]
+    fn every_cipher_round_trips() {
+        let plain = golden_plaintext();
+        for scheme in SCHEMES {
+            for mac in [false, true] {
+                let cipher = scheme.cipher(None, "alice", mac);
+                let sealed = cipher.encrypt(&plain, b"aad");
+                assert_ne!(&sealed[sealed.len() - plain.len()..], &plain[..], "{}", scheme.name());
+                assert_eq!(cipher.decrypt(&sealed, b"aad").unwrap(), plain, "{}", scheme.name());
+            }
+        }
+    }
+
+    #[test]
+    fn ciphers_refuse_each_others_output() {
+        let plain = golden_plaintext();
+        for sealing in SCHEMES {
+            let sealed = sealing.cipher(None, "alice", true).encrypt(&plain, b"aad");
+            for opening in SCHEMES.into_iter().filter(|&scheme| scheme != sealing) {
+                let opened = opening.cipher(None, "alice", true).decrypt(&sealed, b"aad");
+                assert!(opened.is_err(), "{} opened {} output", opening.name(), sealing.name());
+            }
+        }
+    }
+
+    #[test]
+    fn images_relabelled_with_another_scheme_are_refused() {
+        let plain = golden_plaintext();
+        for sealing in SCHEMES {
+            let sealed = seal_image(sealing, None, &plain, "alice", "cat.png");
+            let (header, _) = BlobHeader::parse(&sealed).unwrap();
+            assert_eq!(header.cipher, sealing.header());
+            for opening in SCHEMES.into_iter().filter(|&scheme| scheme != sealing) {
+                // The cipher byte follows the magic and the format version
+                let mut relabelled = sealed.clone();
+                relabelled[MAGIC.len() + 1] = opening.header();
+                // An AEAD image has no MAC flag, so as CTR it reads as one
+                // from before CTR images were tagged and opens unchecked
+                let opened = open_image(&relabelled, &[], "alice", "cat.png");
+                if sealing.is_ctr() || !opening.is_ctr() {
+                    assert!(opened.is_err(), "{} image opened as {}", sealing.name(), opening.name());
+                }
+                assert_ne!(opened.ok(), Some(plain.clone()), "{} image opened as {}", sealing.name(), opening.name());
+            }
+        }
+    }
+
+    #[test]
+    fn username_keys_fit_their_scheme() {
+        assert_eq!(EncryptionScheme::Aes128Ctr.username_key("alice").len(), 16);
+        let wide: Vec<_> = SCHEMES[1..].iter().map(|scheme| scheme.username_key("alice")).collect();
+        for key in &wide {
+            assert_eq!(key.len(), 32);
+        }
+        assert_ne!(wide[0], wide[1]);
+        assert_ne!(wide[1], wide[2]);
+        assert_ne!(
+            generate_key256_from_username(EncryptionScheme::Aes256Gcm, "alice").expose(),
+            generate_key256_from_username(EncryptionScheme::Aes256Gcm, "bob").expose()
+        );
+    }
+
+    fn user_key(byte: u8, generation: u32) -> UserKey {
+        UserKey {
+            key: SecretKey::new([byte; 32]),
+            generation,
+        }
+    }
+
+    #[test]
+    fn each_image_gets_its_own_data_key() {
+        let plain = golden_plaintext();
+        let key = user_key(0x42, 0);
+        for scheme in SCHEMES {
+            let first = seal_image(scheme, Some(&key), &plain, "alice", "cat.png");
+            let second = seal_image(scheme, Some(&key), &plain, "alice", "cat.png");
+            let (first_header, _) = BlobHeader::parse(&first).unwrap();
+            let (second_header, _) = BlobHeader::parse(&second).unwrap();
+            assert!(first_header.has(DERIVED_KEY_FLAG), "{}", scheme.name());
+            let wrapped = first_header.wrapped_key.unwrap();
+            assert_eq!(wrapped.len(), NONCE_LEN + scheme.key_len() + TAG_LEN, "{}", scheme.name());
+            assert_ne!(Some(wrapped), second_header.wrapped_key, "{}", scheme.name());
+        }
+    }
+
+    #[test]
+    fn wrong_user_key_cannot_unwrap_the_data_key() {
+        let plain = golden_plaintext();
+        for scheme in SCHEMES {
+            let sealed = seal_image(scheme, Some(&user_key(0x42, 0)), &plain, "alice", "cat.png");
+            assert!(
+                matches!(open_image(&sealed, &[user_key(0x43, 0)], "alice", "cat.png"), Err(CryptoError::Tampered)),
+                "{}",
+                scheme.name()
+            );
+            assert!(
+                matches!(open_image(&sealed, &[user_key(0x42, 1)], "alice", "cat.png"), Err(CryptoError::NoKey)),
+                "{}",
+                scheme.name()
+            );
+            assert!(matches!(open_image(&sealed, &[], "alice", "cat.png"), Err(CryptoError::NoKey)), "{}", scheme.name());
+            assert_eq!(open_image(&sealed, &[user_key(0x42, 0)], "alice", "cat.png").unwrap(), plain);
+        }
+    }
+
+    #[test]
+    fn wrapped_data_keys_are_bound_to_their_image() {
+        let data_key = [7u8; 32];
+        let wrapping_key = [0x42u8; 32];
+        let wrapped = encrypt_data_aead(&data_key, &wrapping_key, b"alice/cat.png");
+        assert_eq!(decrypt_data_aead(&wrapped, &wrapping_key, b"alice/cat.png").unwrap(), data_key);
+        assert_eq!(decrypt_data_aead(&wrapped, &[0x43; 32], b"alice/cat.png"), Err(CryptoError::Tampered));
+        assert_eq!(decrypt_data_aead(&wrapped, &wrapping_key, b"alice/dog.png"), Err(CryptoError::Tampered));
+        assert_eq!(decrypt_data_aead(&wrapped[..NONCE_LEN - 1], &wrapping_key, b"alice/cat.png"), Err(CryptoError::Truncated));
+    }
+
+    #[test]
+    fn truncated_envelope_headers_are_refused() {
+        // Header bytes from before `blobformat`: the scheme with its flags,
+        // then the generation and the wrapped key
+        for scheme in SCHEMES {
+            let header = scheme.header() | DERIVED_KEY_FLAG | ENVELOPE_FLAG | GENERATION_FLAG;
+            let (_, prefix_len) = header_layout(header).unwrap();
+            assert_eq!(prefix_len, 1 + 4 + NONCE_LEN + scheme.key_len() + TAG_LEN);
+            let mut data = vec![header];
+            data.resize(prefix_len, 0);
+            for cut in 1..prefix_len {
+                assert!(matches!(parse_header(&data[..cut]), Err(CryptoError::Truncated)), "{} cut at {}", scheme.name(), cut);
+            }
+            assert!(parse_header(&data).unwrap().unwrap().sealed.is_empty());
+        }
+
+        let sealed = seal_image(EncryptionScheme::Aes256Gcm, Some(&user_key(0x42, 0)), &golden_plaintext(), "alice", "cat.png");
+        let (_, payload) = BlobHeader::parse(&sealed).unwrap();
+        for cut in MAGIC.len()..sealed.len() - payload.len() {
+            let opened = open_image(&sealed[..cut], &[user_key(0x42, 0)], "alice", "cat.png");
+            assert!(matches!(opened, Err(CryptoError::Format(BlobError::Truncated { .. }))), "cut at {}: {:?}", cut, opened);
+        }
+    }
+
+    #[test]
+    fn ctr_images_changed_anywhere_after_the_magic_are_refused() {
+        let plain = golden_plaintext();
+        for scheme in [EncryptionScheme::Aes128Ctr, EncryptionScheme::Aes256Ctr] {
+            for key in [None, Some(user_key(0x42, 0))] {
+                let sealed = seal_image(scheme, key.as_ref(), &plain, "alice", "cat.png");
+                assert!(is_authenticated(&sealed));
+                let keys = [user_key(0x42, 0)];
+                for offset in MAGIC.len()..sealed.len() {
+                    let mut changed = sealed.clone();
+                    changed[offset] ^= 1;
+                    assert!(
+                        open_image(&changed, &keys, "alice", "cat.png").is_err(),
+                        "{} change at {} of {} went unnoticed",
+                        scheme.name(),
+                        offset,
+                        sealed.len()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ctr_mac_and_cipher_keys_differ() {
+        let keys = CtrKeys::new(&[0x42; 32], true);
+        let mac_key = keys.mac.as_ref().unwrap();
+        assert_ne!(&mac_key.expose()[..], &keys.encrypt[..]);
+        assert_ne!(&keys.encrypt[..], &[0x42; 32]);
+        let unsplit = CtrKeys::new(&[0x42; 32], false);
+        assert!(unsplit.mac.is_none());
+        assert_eq!(&unsplit.encrypt[..], &[0x42; 32]);
+    }
+
+    #[test]
+    fn access_headers_are_rewritten_without_touching_the_image() {
+        let mac_key = SecretKey::new([9; 32]);
+        let sealed = seal_image(EncryptionScheme::Aes256Gcm, None, &golden_plaintext(), "alice", "cat.png");
+        assert_eq!(read_header(&sealed, &mac_key, "alice").unwrap(), None);
+
+        let first = update_header(&sealed, b"3 views left", &mac_key, "alice").unwrap();
+        assert_eq!(read_header(&first, &mac_key, "alice").unwrap(), Some(&b"3 views left"[..]));
+        assert_eq!(strip_header(&first).unwrap(), &sealed[..]);
+        let second = update_header(&first, b"2 views left", &mac_key, "alice").unwrap();
+        assert_eq!(read_header(&second, &mac_key, "alice").unwrap(), Some(&b"2 views left"[..]));
+        assert_eq!(strip_header(&second).unwrap(), &sealed[..]);
+        assert_eq!(open_image(&second, &[], "alice", "cat.png").unwrap(), golden_plaintext());
+    }
+
+    #[test]
+    fn changed_access_headers_are_refused() {
+        let mac_key = SecretKey::new([9; 32]);
+        let sealed = seal_image(EncryptionScheme::Aes256Gcm, None, &golden_plaintext(), "alice", "cat.png");
+        let stored = update_header(&sealed, b"3 views left", &mac_key, "alice").unwrap();
+        let header_len = access_header_len(&stored).unwrap();
+
+        // The contents and the HMAC after them; the length before them is
+        // covered by the HMAC through the contents it delimits
+        for offset in ACCESS_PREFIX_LEN..header_len {
+            let mut changed = stored.clone();
+            changed[offset] ^= 1;
+            assert_eq!(read_header(&changed, &mac_key, "alice"), Err(CryptoError::Tampered), "change at {}", offset);
+        }
+        assert_eq!(read_header(&stored, &mac_key, "bob"), Err(CryptoError::Tampered));
+        assert_eq!(read_header(&stored, &SecretKey::new([8; 32]), "alice"), Err(CryptoError::Tampered));
+
+        let mut image_changed = stored.clone();
+        let last = image_changed.len() - 1;
+        image_changed[last] ^= 1;
+        assert_eq!(read_header(&image_changed, &mac_key, "alice"), Err(CryptoError::Tampered));
+
+        let other = seal_image(EncryptionScheme::Aes256Gcm, None, &golden_plaintext(), "alice", "dog.png");
+        let moved = [&stored[..header_len], &other[..]].concat();
+        assert_eq!(read_header(&moved, &mac_key, "alice"), Err(CryptoError::Tampered));
+        assert_eq!(read_header(&stored[..header_len - 1], &mac_key, "alice"), Err(CryptoError::Truncated));
+    }
+
+    /// `len` bytes of a repeating pattern, made as they are read, recording
+    /// the most asked for in one read
+    struct Generated {
+        left: usize,
+        largest_read: usize,
+    }
+
+    impl AsyncRead for Generated {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            self.largest_read = self.largest_read.max(buf.remaining());
+            let n = buf.remaining().min(self.left);
+            let start = self.left;
+            buf.put_slice(&(0..n).map(|i| ((start - i) % 251) as u8).collect::<Vec<_>>());
+            self.left -= n;
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Hashes what is written to it instead of keeping it, recording the
+    /// most written at once
+    #[derive(Default)]
+    struct Hashed {
+        hasher: Sha256,
+        len: u64,
+        largest_write: usize,
+    }
+
+    impl AsyncWrite for Hashed {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.hasher.update(buf);
+            self.len += buf.len() as u64;
+            self.largest_write = self.largest_write.max(buf.len());
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn images_far_larger_than_a_chunk_stream_through_one_chunk_at_a_time() {
+        const IMAGE_LEN: usize = 1024 * 1024 + 123;
+        // What a chunk may take, with its tag; the image is 16 times that
+        const BUDGET: usize = STREAM_CHUNK_LEN + TAG_LEN;
+        let expected = {
+            let mut plain = Hashed::default();
+            tokio::io::copy(&mut Generated { left: IMAGE_LEN, largest_read: 0 }, &mut plain).await.unwrap();
+            plain.hasher.finalize()
+        };
+        let dir = std::env::temp_dir().join(format!("distinsta-stream-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let keys = [user_key(0x42, 0)];
+
+        for scheme in SCHEMES {
+            let path = dir.join(scheme.name());
+            let mut image = Generated {
+                left: IMAGE_LEN,
+                largest_read: 0,
+            };
+            let file = tokio::fs::File::create(&path).await.unwrap();
+            let written = encrypt_stream(scheme, Some(&keys[0]), "alice", "big.png", &mut image, file).await.unwrap();
+            assert!(image.largest_read <= BUDGET, "{} read {} bytes at once", scheme.name(), image.largest_read);
+            assert_eq!(written, std::fs::metadata(&path).unwrap().len());
+
+            let file = tokio::io::BufReader::new(tokio::fs::File::open(&path).await.unwrap());
+            let mut plain = Hashed::default();
+            let opened = decrypt_stream(&keys, "alice", "big.png", file, &mut plain).await.unwrap();
+            assert_eq!(opened, IMAGE_LEN as u64, "{}", scheme.name());
+            assert!(plain.largest_write <= BUDGET, "{} wrote {} bytes at once", scheme.name(), plain.largest_write);
+            assert_eq!(plain.hasher.finalize(), expected, "{}", scheme.name());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn streamed_images_open_like_sealed_ones() {
+        let plain = "streamed ".repeat(20_000).into_bytes();
+        for scheme in SCHEMES {
+            let mut sealed = Vec::new();
+            encrypt_stream(scheme, None, "alice", "cat.png", &plain[..], &mut sealed).await.unwrap();
+            assert_eq!(open_image(&sealed, &[], "alice", "cat.png").unwrap(), plain, "{}", scheme.name());
+
+            let whole = seal_image(scheme, None, &plain, "alice", "cat.png");
+            let mut opened = Vec::new();
+            decrypt_stream(&[], "alice", "cat.png", &whole[..], &mut opened).await.unwrap();
+            assert_eq!(opened, plain, "{}", scheme.name());
+        }
+    }
+
+    #[tokio::test]
+    async fn streamed_chunks_cannot_be_dropped_or_reordered() {
+        let plain = vec![7u8; STREAM_CHUNK_LEN * 3];
+        let mut sealed = Vec::new();
+        encrypt_stream(EncryptionScheme::Aes256Gcm, None, "alice", "cat.png", &plain[..], &mut sealed).await.unwrap();
+        let (_, payload) = BlobHeader::parse(&sealed).unwrap();
+        let header = &sealed[..sealed.len() - payload.len()];
+        let (prefix, chunks) = payload.split_at(STREAM_NONCE_PREFIX_LEN);
+        let chunks: Vec<&[u8]> = chunks.chunks(STREAM_CHUNK_LEN + TAG_LEN).collect();
+        assert_eq!(chunks.len(), 3);
+
+        for changed in [
+            vec![chunks[0], chunks[1]],
+            vec![chunks[1], chunks[0], chunks[2]],
+            vec![chunks[0], chunks[2]],
+        ] {
+            let changed = [header, prefix, &changed.concat()].concat();
+            let mut opened = Vec::new();
+            let result = decrypt_stream(&[], "alice", "cat.png", &changed[..], &mut opened).await;
+            assert!(matches!(result, Err(StreamError::Crypto(CryptoError::Tampered))), "{:?}", result);
+        }
+    }
+
+    #[test]
+    fn parallel_ctr_matches_one_pass() {
+        // Past the parallel threshold, ending part way into a segment
+        let len = PARALLEL_CTR_MIN_LEN + PARALLEL_CTR_SEGMENT_LEN / 2 + 5;
+        let plain: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let (key, iv) = ([0x42; 16], [7; IV_LEN]);
+        let mut one_pass = plain.clone();
+        Aes128Ctr::new_from_slices(&key, &iv).unwrap().apply_keystream(&mut one_pass);
+        let mut parallel = plain.clone();
+        apply_ctr_keystream::<Aes128Ctr>(&key, &iv, &mut parallel);
+        assert!(one_pass == parallel, "segments do not continue each other's keystream");
+
+        let cipher = EncryptionScheme::Aes128Ctr.cipher(None, "alice", true);
+        assert!(cipher.decrypt(&cipher.encrypt(&plain, b"aad"), b"aad").unwrap() == plain);
+    }
+
+    #[test]
+    fn keys_are_redacted_in_debug_output() {
+        let key = SecretKey::new([0xab; 32]);
+        assert_eq!(format!("{:?}", key), "SecretKey([REDACTED; 32])");
+        assert_eq!(format!("{:#?}", generate_key_from_username("alice")), "SecretKey([REDACTED; 16])");
+
+        let user_key = format!("{:?}", UserKey { key, generation: 2 });
+        assert!(user_key.contains("REDACTED") && user_key.contains("generation: 2"), "{}", user_key);
+        assert!(!user_key.contains("171"), "{}", user_key);
+    }
+
+    #[test]
+    fn zeroized_keys_hold_only_zeros() {
+        let mut key = generate_key_from_username("alice");
+        assert_ne!(key.expose(), &[0; 16]);
+        key.zeroize();
+        assert_eq!(key.expose(), &[0; 16]);
+    }
+
+    #[tokio::test]
+    async fn reencrypted_images_open_only_for_the_recipient() {
+        let plain = "shared ".repeat(30_000).into_bytes();
+        let (alice, bob) = ([user_key(0x42, 0)], [user_key(0x43, 0)]);
+        for scheme in SCHEMES {
+            for sealed in [
+                seal_image(scheme, Some(&alice[0]), &plain, "alice", "cat.png"),
+                seal_compressed(scheme, Some(&alice[0]), &plain, "alice", "cat.png").0,
+            ] {
+                let shared = reencrypt_for(scheme, &sealed, &alice, "alice", Some(&bob[0]), "bob", "cat.png").await.unwrap();
+                assert_eq!(open_image(&shared, &bob, "bob", "cat.png").unwrap(), plain, "{}", scheme.name());
+                assert!(open_image(&shared, &alice, "alice", "cat.png").is_err(), "{}", scheme.name());
+                assert!(open_image(&shared, &alice, "bob", "cat.png").is_err(), "{}", scheme.name());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reencrypting_with_the_wrong_owner_key_fails() {
+        let sealed = seal_image(EncryptionScheme::Aes256Gcm, Some(&user_key(0x42, 0)), b"cat", "alice", "cat.png");
+        let result = reencrypt_for(
+            EncryptionScheme::Aes256Gcm,
+            &sealed,
+            &[user_key(0x44, 0)],
+            "alice",
+            Some(&user_key(0x43, 0)),
+            "bob",
+            "cat.png",
+        )
+        .await;
+        assert!(matches!(result, Err(StreamError::Crypto(CryptoError::Tampered))), "{:?}", result);
+    }
+
+    /// Bytes zstd can do nothing with
+    fn incompressible(len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        rand::thread_rng().fill_bytes(&mut data);
+        data
+    }
+
+    #[test]
+    fn compressible_images_are_stored_compressed_and_open_in_every_scheme() {
+        let plain = vec![0x7Fu8; 200_000];
+        let keys = [user_key(0x42, 0)];
+        for scheme in SCHEMES {
+            let (sealed, outcome) = seal_compressed(scheme, Some(&keys[0]), &plain, "alice", "raw.bmp");
+            let CompressionOutcome::Compressed { compressed_len } = outcome else {
+                panic!("{} skipped compressing", scheme.name());
+            };
+            assert!(compressed_len < plain.len() / 100, "{}", scheme.name());
+            assert!(is_compressed(&sealed) && sealed.len() < plain.len() / 100, "{}", scheme.name());
+            let header = parse_header(strip_header(&sealed).unwrap()).unwrap().unwrap();
+            assert_eq!(header.compression.map(|c| c.original_len), Some(plain.len() as u64));
+            assert_eq!(open_image(&sealed, &keys, "alice", "raw.bmp").unwrap(), plain, "{}", scheme.name());
+        }
+    }
+
+    #[test]
+    fn incompressible_images_are_stored_as_they_are() {
+        let plain = incompressible(50_000);
+        let keys = [user_key(0x42, 0)];
+        for scheme in SCHEMES {
+            let (sealed, outcome) = seal_compressed(scheme, Some(&keys[0]), &plain, "alice", "photo.jpg");
+            assert!(matches!(outcome, CompressionOutcome::Skipped { ratio } if ratio > 0.95), "{:?}", outcome);
+            assert!(!is_compressed(&sealed), "{}", scheme.name());
+            assert_eq!(open_image(&sealed, &keys, "alice", "photo.jpg").unwrap(), plain, "{}", scheme.name());
+        }
+    }
+
+    #[test]
+    fn changed_original_sizes_are_refused() {
+        let plain = vec![0x7Fu8; 10_000];
+        let keys = [user_key(0x42, 0)];
+        let recorded = [&[ZSTD_CODEC][..], &(plain.len() as u64).to_be_bytes()].concat();
+        for scheme in SCHEMES {
+            let (mut sealed, _) = seal_compressed(scheme, Some(&keys[0]), &plain, "alice", "raw.bmp");
+            let at = sealed.windows(recorded.len()).position(|window| window == recorded).unwrap();
+            sealed[at + recorded.len() - 1] ^= 1;
+            assert!(open_image(&sealed, &keys, "alice", "raw.bmp").is_err(), "{}", scheme.name());
+        }
+    }
+
+    #[test]
+    fn lookup_tokens_ignore_case_and_file_tokens_do_not() {
+        let key = catalog_key(b"cluster secret");
+        assert_eq!(lookup_token(&key, "alice", "Cat.PNG"), lookup_token(&key, "alice", "cat.png"));
+        assert_ne!(file_token(&key, "alice", "Cat.PNG"), file_token(&key, "alice", "cat.png"));
+        assert_ne!(lookup_token(&key, "alice", "cat.png"), file_token(&key, "alice", "cat.png"));
+        assert_eq!(lookup_token(&key, "alice", "cat.png").len(), 64);
+    }
+
+    #[test]
+    fn name_tokens_differ_per_user_and_cluster() {
+        let key = catalog_key(b"cluster secret");
+        let token = lookup_token(&key, "alice", "cat.png");
+        assert_ne!(token, lookup_token(&key, "bob", "cat.png"));
+        assert_ne!(token, lookup_token(&catalog_key(b"other secret"), "alice", "cat.png"));
+        // The username's length is hashed in, so the boundary cannot move
+        assert_ne!(file_token(&key, "ab", "c.png"), file_token(&key, "a", "bc.png"));
+    }
+
+    #[test]
+    fn sealed_metadata_opens_only_for_its_owner_and_generation() {
+        let entry = br#"{"filename":"passport_scan.png"}"#;
+        let alice = [user_key(0x42, 2)];
+        let sealed = seal_metadata(&alice[0], "alice", entry);
+        assert!(!sealed.windows(8).any(|window| window == b"passport"));
+        assert_eq!(open_metadata(&sealed, &[user_key(0x41, 1), user_key(0x42, 2)], "alice", 2).unwrap(), entry);
+
+        assert!(matches!(open_metadata(&sealed, &alice, "alice", 1), Err(CryptoError::NoKey)));
+        assert!(matches!(open_metadata(&sealed, &[user_key(0x43, 2)], "alice", 2), Err(CryptoError::Tampered)));
+        assert!(matches!(open_metadata(&sealed, &alice, "bob", 2), Err(CryptoError::Tampered)));
+        // Nor does the image key itself open it
+        assert!(decrypt_data_aead(&sealed, alice[0].key.expose(), &metadata_aad("alice", 2)).is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Default frame limit for client, admin and node-to-node requests
+pub const DEFAULT_MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+/// How reading or writing a frame failed
+#[derive(Debug, thiserror::Error)]
+pub enum FrameError {
+    /// The frame is over the limit; nothing of it was read or written
+    #[error("frame of {len} bytes exceeds the {max} byte limit")]
+    FrameTooLarge { len: usize, max: usize },
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("unknown message format tag {0}")]
+    UnknownFormat(u8),
+    #[error("cannot encode or decode message: {0}")]
+    Codec(String),
+}
+
+/// How a tagged payload is serialized, given by its first byte
+///
+/// Election frames and frames from before tagging carry bare JSON, which
+/// starts with `{` and so never with a tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    /// Compact binary; image bytes are sent as-is instead of as a JSON array
+    Bincode,
+}
+
+impl Format {
+    pub fn tag(self) -> u8 {
+        match self {
+            Format::Json => 0,
+            Format::Bincode => 1,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(Format::Json),
+            1 => Some(Format::Bincode),
+            _ => None,
+        }
+    }
+
+    /// Parse a `--format` value
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Format::Json),
+            "bincode" => Some(Format::Bincode),
+            _ => None,
+        }
+    }
+}
+
+/// Serialize `value` as a tagged payload
+pub fn encode<T: Serialize>(format: Format, value: &T) -> Result<Vec<u8>, FrameError> {
+    let mut payload = vec![format.tag()];
+    match format {
+        Format::Json => serde_json::to_writer(&mut payload, value).map_err(|e| FrameError::Codec(e.to_string()))?,
+        Format::Bincode => bincode::serialize_into(&mut payload, value).map_err(|e| FrameError::Codec(e.to_string()))?,
+    }
+    Ok(payload)
+}
+
+/// Whether a payload starts with a format tag
+pub fn is_tagged(payload: &[u8]) -> bool {
+    payload.first().is_some_and(|&tag| Format::from_tag(tag).is_some())
+}
+
+/// Parse a payload written by `encode`, returning the format it used
+pub fn decode<T: DeserializeOwned>(payload: &[u8]) -> Result<(Format, T), FrameError> {
+    let (&tag, body) = payload
+        .split_first()
+        .ok_or_else(|| FrameError::Codec("empty payload".to_string()))?;
+    let format = Format::from_tag(tag).ok_or(FrameError::UnknownFormat(tag))?;
+    let value = match format {
+        Format::Json => serde_json::from_slice(body).map_err(|e| FrameError::Codec(e.to_string()))?,
+        Format::Bincode => bincode::deserialize(body).map_err(|e| FrameError::Codec(e.to_string()))?,
+    };
+    Ok((format, value))
+}
+
+/// Whether a connection starts with a JSON line from a peer that predates framing
+///
+/// A length prefix only starts with `{` or `"` for frames of 544 MiB or more,
+/// so the two cannot be confused as long as the frame limit stays below that.
+pub fn is_line_start(first_byte: u8) -> bool {
+    first_byte == b'{' || first_byte == b'"'
+}
+
+/// Write one frame: a 4-byte big-endian length followed by the payload
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    payload: &[u8],
+    max_len: usize,
+) -> Result<(), FrameError> {
+    if payload.len() > max_len {
+        return Err(FrameError::FrameTooLarge {
+            len: payload.len(),
+            max: max_len,
+        });
+    }
+    writer.write_all(&(payload.len() as u32).to_be_bytes()).await?;
+    writer.write_all(payload).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read one frame, returning `None` if the peer closed the connection between frames
+///
+/// The length is checked before anything is allocated, so an oversized or
+/// garbage prefix cannot make the reader buffer unbounded data.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, max_len: usize) -> Result<Option<Vec<u8>>, FrameError> {
+    let mut len_bytes = [0u8; 4];
+    // Closing before the first byte is a clean end; closing inside the length is not
+    if reader.read(&mut len_bytes[..1]).await? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut len_bytes[1..]).await?;
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    if len > max_len {
+        return Err(FrameError::FrameTooLarge { len, max: max_len });
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn large_frame_survives_partial_reads() {
+        // A 64-byte pipe hands the reader the frame a piece at a time
+        let (mut writer, mut reader) = duplex(64);
+        let payload: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        let sent = payload.clone();
+        let writing = tokio::spawn(async move { write_frame(&mut writer, &sent, 1 << 20).await });
+        let frame = read_frame(&mut reader, 1 << 20).await.unwrap();
+        writing.await.unwrap().unwrap();
+        assert_eq!(frame, Some(payload));
+    }
+
+    #[tokio::test]
+    async fn frames_are_read_one_at_a_time() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, b"first", 1024).await.unwrap();
+        write_frame(&mut stream, b"", 1024).await.unwrap();
+        write_frame(&mut stream, b"third", 1024).await.unwrap();
+
+        let mut reader = stream.as_slice();
+        assert_eq!(read_frame(&mut reader, 1024).await.unwrap(), Some(b"first".to_vec()));
+        assert_eq!(read_frame(&mut reader, 1024).await.unwrap(), Some(Vec::new()));
+        assert_eq!(read_frame(&mut reader, 1024).await.unwrap(), Some(b"third".to_vec()));
+        assert_eq!(read_frame(&mut reader, 1024).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn close_inside_a_frame_is_an_error() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, b"cut short", 1024).await.unwrap();
+        for cut in [2, 6] {
+            let mut reader = &stream[..cut];
+            match read_frame(&mut reader, 1024).await {
+                Err(FrameError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof),
+                other => panic!("expected UnexpectedEof at {}, got {:?}", cut, other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_refused_both_ways() {
+        let mut stream = Vec::new();
+        assert!(matches!(
+            write_frame(&mut stream, &[0; 17], 16).await,
+            Err(FrameError::FrameTooLarge { len: 17, max: 16 })
+        ));
+        assert!(stream.is_empty());
+
+        write_frame(&mut stream, &[0; 17], 1024).await.unwrap();
+        assert!(matches!(
+            read_frame(&mut stream.as_slice(), 16).await,
+            Err(FrameError::FrameTooLarge { len: 17, max: 16 })
+        ));
+    }
+}
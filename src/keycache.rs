@@ -0,0 +1,199 @@
+use crate::encryption::UserKey;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Users' unwrapped image keys, so that every request does not decrypt
+/// them out of the user registry again
+///
+/// Holds at most `capacity` users, dropping the least recently used, and
+/// unwraps a user's keys again once their entry is older than `ttl`. Keys
+/// are wiped when their entry goes. Two requests that miss for the same
+/// user at once unwrap only once: the second waits for the first.
+pub struct KeyCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Slot>>,
+}
+
+struct Slot {
+    keys: Arc<OnceLock<Vec<UserKey>>>,
+    created: Instant,
+    used: Instant,
+}
+
+impl KeyCache {
+    /// A cache of `capacity` users; 0 turns caching off
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        KeyCache {
+            capacity,
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `username`'s cached keys, or the ones `unwrap` returns, which are
+    /// then cached
+    pub fn get_or_insert_with(&self, username: &str, unwrap: impl FnOnce() -> Vec<UserKey>) -> Vec<UserKey> {
+        if self.capacity == 0 {
+            return unwrap();
+        }
+        let keys = {
+            let mut entries = self.entries.lock().unwrap();
+            let now = Instant::now();
+            match entries.get_mut(username) {
+                Some(slot) if now.duration_since(slot.created) < self.ttl => {
+                    slot.used = now;
+                    Arc::clone(&slot.keys)
+                }
+                _ => {
+                    entries.remove(username);
+                    if entries.len() >= self.capacity {
+                        let oldest = entries.iter().min_by_key(|(_, slot)| slot.used).map(|(name, _)| name.clone());
+                        if let Some(oldest) = oldest {
+                            entries.remove(&oldest);
+                        }
+                    }
+                    let keys = Arc::new(OnceLock::new());
+                    let slot = Slot {
+                        keys: Arc::clone(&keys),
+                        created: now,
+                        used: now,
+                    };
+                    entries.insert(username.to_string(), slot);
+                    keys
+                }
+            }
+        };
+        // Unwrapped outside the map's lock so other users are not held up
+        keys.get_or_init(unwrap).clone()
+    }
+
+    /// Forget `username`'s keys, after they changed in the registry
+    pub fn invalidate(&self, username: &str) {
+        self.entries.lock().unwrap().remove(username);
+    }
+
+    /// Forget every user's keys
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::SecretKey;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+
+    fn key(byte: u8) -> Vec<UserKey> {
+        vec![UserKey {
+            key: SecretKey::new([byte; 32]),
+            generation: 0,
+        }]
+    }
+
+    /// Looks `username` up in `cache`, counting the unwraps in `unwraps`
+    fn lookup(cache: &KeyCache, username: &str, unwraps: &AtomicUsize) -> Vec<UserKey> {
+        cache.get_or_insert_with(username, || {
+            unwraps.fetch_add(1, Ordering::SeqCst);
+            key(username.len() as u8)
+        })
+    }
+
+    #[test]
+    fn concurrent_misses_unwrap_once() {
+        let cache = KeyCache::new(16, Duration::from_secs(60));
+        let unwraps = AtomicUsize::new(0);
+        let start = Barrier::new(8);
+        std::thread::scope(|scope| {
+            let lookups: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        start.wait();
+                        cache.get_or_insert_with("alice", || {
+                            unwraps.fetch_add(1, Ordering::SeqCst);
+                            // Slow enough for the others to find the entry missing too
+                            std::thread::sleep(Duration::from_millis(50));
+                            key(7)
+                        })
+                    })
+                })
+                .collect();
+            for lookup in lookups {
+                assert_eq!(lookup.join().unwrap()[0].key.expose(), &[7; 32]);
+            }
+        });
+        assert_eq!(unwraps.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn hits_do_not_unwrap_again() {
+        let cache = KeyCache::new(16, Duration::from_secs(60));
+        let unwraps = AtomicUsize::new(0);
+        for _ in 0..3 {
+            lookup(&cache, "alice", &unwraps);
+        }
+        lookup(&cache, "bob", &unwraps);
+        assert_eq!(unwraps.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn invalidated_and_cleared_users_unwrap_again() {
+        let cache = KeyCache::new(16, Duration::from_secs(60));
+        let unwraps = AtomicUsize::new(0);
+        lookup(&cache, "alice", &unwraps);
+        lookup(&cache, "bob", &unwraps);
+        cache.invalidate("alice");
+        lookup(&cache, "alice", &unwraps);
+        lookup(&cache, "bob", &unwraps);
+        assert_eq!(unwraps.load(Ordering::SeqCst), 3);
+
+        cache.clear();
+        lookup(&cache, "alice", &unwraps);
+        lookup(&cache, "bob", &unwraps);
+        assert_eq!(unwraps.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn least_recently_used_user_is_dropped() {
+        let cache = KeyCache::new(2, Duration::from_secs(60));
+        let unwraps = AtomicUsize::new(0);
+        lookup(&cache, "alice", &unwraps);
+        std::thread::sleep(Duration::from_millis(2));
+        lookup(&cache, "bob", &unwraps);
+        std::thread::sleep(Duration::from_millis(2));
+        lookup(&cache, "alice", &unwraps);
+        std::thread::sleep(Duration::from_millis(2));
+        lookup(&cache, "carol", &unwraps);
+        assert_eq!(unwraps.load(Ordering::SeqCst), 3);
+
+        // bob went to make room; alice, used more recently, stayed
+        lookup(&cache, "alice", &unwraps);
+        assert_eq!(unwraps.load(Ordering::SeqCst), 3);
+        lookup(&cache, "bob", &unwraps);
+        assert_eq!(unwraps.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn entries_expire_after_the_ttl() {
+        let cache = KeyCache::new(16, Duration::from_millis(20));
+        let unwraps = AtomicUsize::new(0);
+        lookup(&cache, "alice", &unwraps);
+        lookup(&cache, "alice", &unwraps);
+        assert_eq!(unwraps.load(Ordering::SeqCst), 1);
+        std::thread::sleep(Duration::from_millis(30));
+        lookup(&cache, "alice", &unwraps);
+        assert_eq!(unwraps.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn zero_capacity_turns_caching_off() {
+        let cache = KeyCache::new(0, Duration::from_secs(60));
+        let unwraps = AtomicUsize::new(0);
+        lookup(&cache, "alice", &unwraps);
+        lookup(&cache, "alice", &unwraps);
+        assert_eq!(unwraps.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,6 +1,19 @@
-use std::collections::HashMap;
+use crate::auth::PeerSigner;
+use crate::framing::{decode, encode, read_frame, write_frame, Format};
+use crate::protocol::{InternalMessage, TaggedRequest};
+use crate::transport::MAX_FRAME_LEN;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+
+/// Consecutive unanswered pings before a server is marked unavailable
+pub const FAILURES_TO_EJECT: u32 = 3;
+/// Consecutive answered pings before an unavailable server is marked
+/// available again
+pub const SUCCESSES_TO_READMIT: u32 = 2;
 
 #[derive(Debug, Clone)]
 pub struct ServerLoad {
@@ -10,6 +23,44 @@ pub struct ServerLoad {
     pub available: bool,
 }
 
+/// A change in a server's availability found by the health checks
+#[derive(Debug, Clone)]
+pub enum HealthEvent {
+    Ejected { server_id: u32, address: String },
+    Readmitted { server_id: u32, address: String },
+}
+
+/// Stops the health checks when shut down or dropped
+pub struct HealthChecks {
+    shutdown: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+impl HealthChecks {
+    /// Stop the health checks and wait for a round in progress to end
+    pub async fn shutdown(mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        let _ = (&mut self.task).await;
+    }
+}
+
+impl Drop for HealthChecks {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+/// Ping and pong streaks of a server, for the health checks
+#[derive(Default)]
+struct Streak {
+    failures: u32,
+    successes: u32,
+}
+
 pub struct LoadBalancer {
     pub servers: Arc<RwLock<HashMap<u32, ServerLoad>>>,
     pub next_index: Arc<RwLock<usize>>,
@@ -69,17 +120,26 @@ impl LoadBalancer {
         Some((server.server_id, server.address.clone()))
     }
 
-    /// Get server with least load
-    pub async fn get_least_loaded_server(&self) -> Option<(u32, String)> {
+    /// Get the available server with least load, other than those in
+    /// `skip`; ties go to the lowest server id
+    pub async fn get_least_loaded_server(&self, skip: &HashSet<u32>) -> Option<(u32, String)> {
         let servers = self.servers.read().await;
 
         servers
             .values()
-            .filter(|s| s.available)
-            .min_by_key(|s| s.current_load)
+            .filter(|s| s.available && !skip.contains(&s.server_id))
+            .min_by_key(|s| (s.current_load, s.server_id))
             .map(|s| (s.server_id, s.address.clone()))
     }
 
+    /// Update the address of a registered server
+    pub async fn update_server_address(&self, server_id: u32, address: String) {
+        let mut servers = self.servers.write().await;
+        if let Some(server) = servers.get_mut(&server_id) {
+            server.address = address;
+        }
+    }
+
     /// Update server load
     pub async fn update_server_load(&self, server_id: u32, load: usize) {
         let mut servers = self.servers.write().await;
@@ -88,6 +148,22 @@ impl LoadBalancer {
         }
     }
 
+    /// Count a request handed to a server, until its next load report
+    pub async fn record_dispatch(&self, server_id: u32) {
+        let mut servers = self.servers.write().await;
+        if let Some(server) = servers.get_mut(&server_id) {
+            server.current_load = server.current_load.saturating_add(1);
+        }
+    }
+
+    /// Count a request a server finished, until its next load report
+    pub async fn record_completion(&self, server_id: u32) {
+        let mut servers = self.servers.write().await;
+        if let Some(server) = servers.get_mut(&server_id) {
+            server.current_load = server.current_load.saturating_sub(1);
+        }
+    }
+
     /// Mark server as unavailable
     pub async fn mark_server_unavailable(&self, server_id: u32) {
         let mut servers = self.servers.write().await;
@@ -106,19 +182,249 @@ impl LoadBalancer {
         }
     }
 
-    /// Get all available servers
-    pub async fn get_available_servers(&self) -> Vec<(u32, String)> {
-        let servers = self.servers.read().await;
-        servers
-            .values()
-            .filter(|s| s.available)
-            .map(|s| (s.server_id, s.address.clone()))
-            .collect()
+    /// Ping every registered server each `interval`, marking one that fails
+    /// `FAILURES_TO_EJECT` pings in a row unavailable and one that then
+    /// answers `SUCCESSES_TO_READMIT` in a row available again
+    ///
+    /// Pings are signed with `signer`. A ping not answered with Pong within
+    /// `timeout` fails. The servers are
+    /// only locked to copy their addresses and to flip a server, never while
+    /// pinging. Every flip is sent as a `HealthEvent`.
+    pub fn start_health_checks(
+        &self,
+        interval: Duration,
+        timeout: Duration,
+        signer: PeerSigner,
+    ) -> (HealthChecks, mpsc::UnboundedReceiver<HealthEvent>) {
+        let (events, events_rx) = mpsc::unbounded_channel();
+        let (shutdown, mut shutdown_rx) = oneshot::channel();
+        let lb = self.clone();
+        let task = tokio::spawn(async move {
+            // Keyed by address too, so a server registered again elsewhere
+            // starts over
+            let mut streaks: HashMap<(u32, String), Streak> = HashMap::new();
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                tokio::select! {
+                    _ = &mut shutdown_rx => break,
+                    _ = ticker.tick() => {}
+                }
+                let targets: Vec<(u32, String)> = lb
+                    .servers
+                    .read()
+                    .await
+                    .values()
+                    .map(|s| (s.server_id, s.address.clone()))
+                    .collect();
+                streaks.retain(|target, _| targets.contains(target));
+
+                let mut pings = tokio::task::JoinSet::new();
+                for (server_id, address) in targets {
+                    let signer = signer.clone();
+                    pings.spawn(async move {
+                        let answered = ping(&address, timeout, &signer).await;
+                        (server_id, address, answered)
+                    });
+                }
+                while let Some(result) = pings.join_next().await {
+                    let Ok((server_id, address, answered)) = result else {
+                        continue;
+                    };
+                    let streak = streaks.entry((server_id, address.clone())).or_default();
+                    if answered {
+                        streak.failures = 0;
+                        streak.successes += 1;
+                    } else {
+                        streak.successes = 0;
+                        streak.failures += 1;
+                    }
+                    let available = if streak.failures >= FAILURES_TO_EJECT {
+                        false
+                    } else if streak.successes >= SUCCESSES_TO_READMIT {
+                        true
+                    } else {
+                        continue;
+                    };
+
+                    let was_available = lb.servers.read().await.get(&server_id).map(|s| s.available);
+                    if was_available.is_none_or(|was| was == available) {
+                        continue;
+                    }
+                    let event = if available {
+                        lb.mark_server_available(server_id).await;
+                        HealthEvent::Readmitted { server_id, address }
+                    } else {
+                        lb.mark_server_unavailable(server_id).await;
+                        HealthEvent::Ejected { server_id, address }
+                    };
+                    let _ = events.send(event);
+                }
+            }
+        });
+        (
+            HealthChecks {
+                shutdown: Some(shutdown),
+                task,
+            },
+            events_rx,
+        )
     }
+}
 
-    /// Get server count
-    pub async fn get_server_count(&self) -> usize {
-        let servers = self.servers.read().await;
-        servers.values().filter(|s| s.available).count()
+/// Whether the node at `address` answers a Ping with Pong within `timeout`
+async fn ping(address: &str, timeout: Duration, signer: &PeerSigner) -> bool {
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let signed = signer.sign(&InternalMessage::Ping, now_ms);
+    let Ok(request) = encode(Format::Bincode, &TaggedRequest::Internal(signed)) else {
+        return false;
+    };
+    let exchange = async {
+        let mut stream = TcpStream::connect(address).await?;
+        write_frame(&mut stream, &request, MAX_FRAME_LEN).await?;
+        read_frame(&mut stream, MAX_FRAME_LEN).await
+    };
+    match tokio::time::timeout(timeout, exchange).await {
+        Ok(Ok(Some(frame))) => matches!(decode(&frame), Ok((_, InternalMessage::Pong))),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio::net::TcpListener;
+
+    const INTERVAL: Duration = Duration::from_millis(40);
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    /// A server that answers pings with Pong while `up`, and otherwise
+    /// holds the connection without a word
+    async fn fake_server(up: Arc<AtomicBool>) -> (String, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let task = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let up = Arc::clone(&up);
+                tokio::spawn(async move {
+                    if read_frame(&mut stream, MAX_FRAME_LEN).await.ok().flatten().is_none() {
+                        return;
+                    }
+                    if up.load(Ordering::Relaxed) {
+                        let pong = encode(Format::Bincode, &InternalMessage::Pong).unwrap();
+                        let _ = write_frame(&mut stream, &pong, MAX_FRAME_LEN).await;
+                    } else {
+                        tokio::time::sleep(Duration::from_secs(10)).await;
+                    }
+                });
+            }
+        });
+        (address, task)
+    }
+
+    fn signer() -> PeerSigner {
+        PeerSigner::new(b"test cluster secret")
+    }
+
+    async fn available(lb: &LoadBalancer, server_id: u32) -> bool {
+        lb.servers.read().await[&server_id].available
+    }
+
+    /// The next health event, failing the test if none comes in time
+    async fn next_event(events: &mut mpsc::UnboundedReceiver<HealthEvent>) -> HealthEvent {
+        tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn servers_that_stop_answering_are_ejected_and_readmitted_once_back() {
+        let (up, also_up) = (Arc::new(AtomicBool::new(true)), Arc::new(AtomicBool::new(true)));
+        let (flaky, flaky_task) = fake_server(Arc::clone(&up)).await;
+        let (steady, steady_task) = fake_server(also_up).await;
+        let lb = LoadBalancer::new();
+        lb.register_server(1, flaky.clone()).await;
+        lb.register_server(2, steady).await;
+        let (health_checks, mut events) = lb.start_health_checks(INTERVAL, TIMEOUT, signer());
+
+        tokio::time::sleep(INTERVAL * 3).await;
+        assert!(available(&lb, 1).await && available(&lb, 2).await);
+
+        up.store(false, Ordering::Relaxed);
+        let stopped = tokio::time::Instant::now();
+        let HealthEvent::Ejected { server_id: 1, address } = next_event(&mut events).await else {
+            panic!("server 1 was not ejected first");
+        };
+        assert_eq!(address, flaky);
+        // It takes FAILURES_TO_EJECT pings in a row, not one
+        assert!(stopped.elapsed() >= INTERVAL * (FAILURES_TO_EJECT - 1));
+        assert!(!available(&lb, 1).await && available(&lb, 2).await);
+        for _ in 0..4 {
+            assert_eq!(lb.get_next_server().await.map(|(id, _)| id), Some(2));
+        }
+
+        up.store(true, Ordering::Relaxed);
+        let HealthEvent::Readmitted { server_id: 1, .. } = next_event(&mut events).await else {
+            panic!("server 1 was not readmitted");
+        };
+        assert!(available(&lb, 1).await);
+        let picked: HashSet<u32> = [lb.get_next_server().await, lb.get_next_server().await]
+            .into_iter()
+            .flatten()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(picked, HashSet::from([1, 2]));
+
+        health_checks.shutdown().await;
+        assert!(events.recv().await.is_none());
+        flaky_task.abort();
+        steady_task.abort();
+    }
+
+    #[tokio::test]
+    async fn servers_are_not_locked_while_pinged() {
+        let (silent, silent_task) = fake_server(Arc::new(AtomicBool::new(false))).await;
+        let lb = LoadBalancer::new();
+        lb.register_server(1, silent).await;
+        let (health_checks, _events) = lb.start_health_checks(INTERVAL, Duration::from_secs(2), signer());
+
+        // Let the first ping start and hang
+        tokio::time::sleep(INTERVAL).await;
+        let locked = tokio::time::timeout(Duration::from_millis(200), lb.servers.write()).await;
+        assert!(locked.is_ok(), "servers stayed locked during a ping");
+        drop(locked);
+        lb.register_server(2, "127.0.0.1:1".to_string()).await;
+
+        health_checks.shutdown().await;
+        silent_task.abort();
+    }
+
+    #[tokio::test]
+    async fn servers_registered_again_elsewhere_start_over() {
+        let (address, task) = fake_server(Arc::new(AtomicBool::new(false))).await;
+        let lb = LoadBalancer::new();
+        lb.register_server(1, address).await;
+        let (health_checks, mut events) = lb.start_health_checks(INTERVAL, Duration::from_millis(20), signer());
+        assert!(matches!(next_event(&mut events).await, HealthEvent::Ejected { server_id: 1, .. }));
+
+        lb.unregister_server(1).await;
+        lb.register_server(1, "127.0.0.1:1".to_string()).await;
+        // A failed ping at the new address is its first, not its fourth
+        tokio::time::sleep(INTERVAL * (FAILURES_TO_EJECT - 1) / 2).await;
+        assert!(available(&lb, 1).await);
+
+        health_checks.shutdown().await;
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn dropping_the_handle_stops_the_checks() {
+        let lb = LoadBalancer::new();
+        lb.register_server(1, "127.0.0.1:1".to_string()).await;
+        let (health_checks, mut events) = lb.start_health_checks(INTERVAL, TIMEOUT, signer());
+        drop(health_checks);
+        let ended = tokio::time::timeout(Duration::from_secs(2), events.recv()).await;
+        assert!(matches!(ended, Ok(None)), "{:?}", ended);
     }
 }
@@ -0,0 +1,265 @@
+const PNG_SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+/// PNG chunks that hold metadata rather than pixels or colour information
+const PNG_METADATA_CHUNKS: [&[u8; 4]; 5] = [b"eXIf", b"tEXt", b"zTXt", b"iTXt", b"tIME"];
+/// Bits of the VP8X flags byte saying a WebP carries EXIF or XMP chunks
+const WEBP_EXIF_FLAG: u8 = 0x08;
+const WEBP_XMP_FLAG: u8 = 0x04;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StripError {
+    #[error("metadata is only stripped from JPEG, PNG and WebP images")]
+    Unsupported,
+    #[error("{0} image is malformed")]
+    Malformed(&'static str),
+}
+
+/// The image without its EXIF, XMP, IPTC, comments and text chunks, which
+/// carry e.g. GPS coordinates and camera serial numbers
+///
+/// Segments are cut out of the file as they are, so the pixels are never
+/// decoded and JPEGs are not compressed again. Colour profiles are kept.
+pub fn strip(data: &[u8]) -> Result<Vec<u8>, StripError> {
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        strip_jpeg(data)
+    } else if data.starts_with(PNG_SIGNATURE) {
+        strip_png(data)
+    } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        strip_webp(data)
+    } else {
+        Err(StripError::Unsupported)
+    }
+}
+
+/// Drop APP1 (EXIF, XMP), APP3 to APP13 (IPTC and vendor data), APP15 and
+/// comment segments, keeping JFIF (APP0), ICC profiles (APP2) and Adobe
+/// colour information (APP14)
+fn strip_jpeg(data: &[u8]) -> Result<Vec<u8>, StripError> {
+    let malformed = || StripError::Malformed("JPEG");
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&data[..2]);
+    let mut pos = 2;
+    loop {
+        if data.get(pos) != Some(&0xFF) {
+            return Err(malformed());
+        }
+        // Any number of 0xFF fill bytes may come before a marker
+        while data.get(pos + 1) == Some(&0xFF) {
+            pos += 1;
+        }
+        let marker = *data.get(pos + 1).ok_or_else(malformed)?;
+        match marker {
+            // Markers without a length
+            0x01 | 0xD0..=0xD7 => {
+                out.extend_from_slice(&[0xFF, marker]);
+                pos += 2;
+                continue;
+            }
+            // End of image, or start of scan: what follows is image data
+            0xD9 | 0xDA => {
+                out.extend_from_slice(&data[pos..]);
+                return Ok(out);
+            }
+            _ => {}
+        }
+        let len = data.get(pos + 2..pos + 4).ok_or_else(malformed)?;
+        let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+        if len < 2 {
+            return Err(malformed());
+        }
+        let segment = data.get(pos..pos + 2 + len).ok_or_else(malformed)?;
+        if !matches!(marker, 0xE1 | 0xE3..=0xED | 0xEF | 0xFE) {
+            out.extend_from_slice(segment);
+        }
+        pos += segment.len();
+    }
+}
+
+/// Drop the metadata chunks, and anything after IEND
+fn strip_png(data: &[u8]) -> Result<Vec<u8>, StripError> {
+    let malformed = || StripError::Malformed("PNG");
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(PNG_SIGNATURE);
+    let mut pos = PNG_SIGNATURE.len();
+    loop {
+        let len = data.get(pos..pos + 4).ok_or_else(malformed)?;
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+        // Length, type, data and CRC
+        let chunk = data.get(pos..pos + 12 + len).ok_or_else(malformed)?;
+        let kind = &chunk[4..8];
+        if !PNG_METADATA_CHUNKS.iter().any(|metadata| kind == metadata.as_slice()) {
+            out.extend_from_slice(chunk);
+        }
+        if kind == b"IEND" {
+            return Ok(out);
+        }
+        pos += chunk.len();
+    }
+}
+
+/// Drop the EXIF and XMP chunks of an extended WebP and clear the flags
+/// that announce them
+fn strip_webp(data: &[u8]) -> Result<Vec<u8>, StripError> {
+    let malformed = || StripError::Malformed("WebP");
+    let riff_len = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+    let end = 8 + riff_len;
+    if end > data.len() {
+        return Err(malformed());
+    }
+    let mut out = Vec::with_capacity(end);
+    out.extend_from_slice(&data[..12]);
+    let mut pos = 12;
+    while pos < end {
+        let header = data.get(pos..pos + 8).ok_or_else(malformed)?;
+        let len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+        // Chunks are padded to an even length
+        let chunk = data.get(pos..pos + 8 + len + (len & 1)).ok_or_else(malformed)?;
+        match &header[..4] {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" if len > 0 => {
+                let flags = out.len() + 8;
+                out.extend_from_slice(chunk);
+                out[flags] &= !(WEBP_EXIF_FLAG | WEBP_XMP_FLAG);
+            }
+            _ => out.extend_from_slice(chunk),
+        }
+        pos += chunk.len();
+    }
+    let riff_len = u32::try_from(out.len() - 8).map_err(|_| malformed())?;
+    out[4..8].copy_from_slice(&riff_len.to_le_bytes());
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An 8x8 JPEG whose EXIF, the first 90 bytes after SOI, gives a GPS
+    /// latitude of N 52° 31' 12.34"
+    const GPS_JPEG: &[u8] = include_bytes!("../testdata/metastrip/gps.jpg");
+    const GPS_EXIF_LEN: usize = 90;
+    /// The latitude's seconds as a TIFF rational, 1234/100
+    const GPS_SECONDS: [u8; 8] = [0, 0, 0x04, 0xD2, 0, 0, 0, 0x64];
+
+    fn contains(data: &[u8], needle: &[u8]) -> bool {
+        data.windows(needle.len()).any(|window| window == needle)
+    }
+
+    fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(kind);
+        chunk.extend_from_slice(data);
+        // Nothing here checks the CRC
+        chunk.extend_from_slice(&[0; 4]);
+        chunk
+    }
+
+    fn webp_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = kind.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn webp(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body = chunks.concat();
+        let mut webp = b"RIFF".to_vec();
+        webp.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+        webp.extend_from_slice(b"WEBP");
+        webp.extend_from_slice(&body);
+        webp
+    }
+
+    #[test]
+    fn gps_exif_is_cut_from_jpegs_without_recompressing() {
+        assert!(contains(GPS_JPEG, b"Exif\0\0") && contains(GPS_JPEG, &GPS_SECONDS));
+        let stripped = strip(GPS_JPEG).unwrap();
+        assert!(!contains(&stripped, b"Exif"));
+        assert!(!contains(&stripped, &GPS_SECONDS));
+        assert_eq!(stripped.len(), GPS_JPEG.len() - GPS_EXIF_LEN);
+        assert_eq!(&stripped[2..], &GPS_JPEG[2 + GPS_EXIF_LEN..]);
+        let original = image::load_from_memory(GPS_JPEG).unwrap();
+        assert_eq!(image::load_from_memory(&stripped).unwrap(), original);
+    }
+
+    #[test]
+    fn jpeg_comments_and_iptc_go_but_colour_profiles_stay() {
+        let segment = |marker: u8, payload: &[u8]| {
+            let mut segment = vec![0xFF, marker];
+            segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+            segment.extend_from_slice(payload);
+            segment
+        };
+        let icc = segment(0xE2, b"ICC_PROFILE\0profile");
+        let mut jpeg = GPS_JPEG[..2].to_vec();
+        jpeg.extend(segment(0xFE, b"taken by alice"));
+        jpeg.extend(segment(0xED, b"Photoshop 3.0\0iptc"));
+        jpeg.extend(&icc);
+        jpeg.extend_from_slice(&GPS_JPEG[2 + GPS_EXIF_LEN..]);
+
+        let stripped = strip(&jpeg).unwrap();
+        assert!(!contains(&stripped, b"taken by alice") && !contains(&stripped, b"iptc"));
+        assert!(contains(&stripped, &icc));
+        assert!(image::load_from_memory(&stripped).is_ok());
+    }
+
+    #[test]
+    fn png_text_and_exif_chunks_are_dropped() {
+        let mut png = std::io::Cursor::new(Vec::new());
+        image::RgbImage::from_pixel(4, 4, image::Rgb([200, 30, 30]))
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let png = png.into_inner();
+        // Metadata goes between IHDR, 8 + 25 bytes in, and IDAT
+        let ihdr_end = PNG_SIGNATURE.len() + 25;
+        let metadata = [
+            png_chunk(b"tEXt", b"Author\0alice"),
+            png_chunk(b"eXIf", &GPS_JPEG[10..GPS_EXIF_LEN + 2]),
+            png_chunk(b"tIME", &[7, 234, 10, 15, 12, 0, 0]),
+        ]
+        .concat();
+        let tagged = [&png[..ihdr_end], &metadata, &png[ihdr_end..]].concat();
+
+        assert_eq!(strip(&tagged).unwrap(), png);
+        // Whatever trails IEND goes too
+        let trailed = [png.as_slice(), b"hidden"].concat();
+        assert_eq!(strip(&trailed).unwrap(), png);
+    }
+
+    #[test]
+    fn webp_exif_and_xmp_chunks_and_their_flags_are_dropped() {
+        let vp8x = |flags: u8| webp_chunk(b"VP8X", &[flags, 0, 0, 0, 3, 0, 0, 3, 0, 0]);
+        let pixels = webp_chunk(b"VP8L", b"pixels");
+        let icc = webp_chunk(b"ICCP", b"profile");
+        let tagged = webp(&[
+            vp8x(0x20 | WEBP_EXIF_FLAG | WEBP_XMP_FLAG),
+            icc.clone(),
+            pixels.clone(),
+            webp_chunk(b"EXIF", &GPS_JPEG[10..GPS_EXIF_LEN + 2]),
+            webp_chunk(b"XMP ", b"<x:xmpmeta/>"),
+        ]);
+        assert_eq!(strip(&tagged).unwrap(), webp(&[vp8x(0x20), icc, pixels]));
+    }
+
+    #[test]
+    fn other_formats_are_unsupported() {
+        let mut bmp = std::io::Cursor::new(Vec::new());
+        image::RgbImage::from_pixel(4, 4, image::Rgb([200, 30, 30]))
+            .write_to(&mut bmp, image::ImageOutputFormat::Bmp)
+            .unwrap();
+        assert!(matches!(strip(&bmp.into_inner()), Err(StripError::Unsupported)));
+        assert!(matches!(strip(b""), Err(StripError::Unsupported)));
+    }
+
+    #[test]
+    fn cut_off_images_are_malformed() {
+        let cut = &GPS_JPEG[..40];
+        assert!(matches!(strip(cut), Err(StripError::Malformed("JPEG"))));
+        assert!(matches!(strip(&PNG_SIGNATURE[..]), Err(StripError::Malformed("PNG"))));
+        let mut webp = webp(&[webp_chunk(b"VP8L", b"pixels")]);
+        webp.truncate(webp.len() - 1);
+        assert!(matches!(strip(&webp), Err(StripError::Malformed("WebP"))));
+    }
+}
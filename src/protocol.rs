@@ -1,38 +1,1392 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::cell::Cell;
+use std::collections::HashMap;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ClientRequest {
+    /// Create an account; answered with LoggedIn
+    Register { username: String, password: String },
+    /// Get a session token for the `token` field of other requests
+    Login { username: String, password: String },
     /// Upload an image - returns encrypted image data
     UploadImage {
         username: String,
+        /// Session token from Login, also on every request below that acts
+        /// for a user
+        token: String,
         image_data: Vec<u8>,
         filename: String,
+        /// SHA-256 of `image_data` as the client read it; checked before
+        /// processing when present
+        #[serde(default)]
+        sha256: Option<[u8; 32]>,
+        /// Remove EXIF and other metadata before encrypting; `None` leaves it
+        /// to the server's `strip_metadata` setting
+        #[serde(default)]
+        strip_metadata: Option<bool>,
+        /// Compress the image with zstd before encrypting it for storage;
+        /// `None` leaves it to the server's `compress_storage` setting
+        #[serde(default)]
+        compress: Option<bool>,
     },
+    /// Fetch a previously uploaded image, as stored (encrypted)
+    DownloadImage {
+        username: String,
+        token: String,
+        image: ImageSelector,
+    },
+    /// One page of the images a user uploaded, across the cluster, sorted
+    /// before slicing; a `limit` of 0 or over `MAX_LIST_LIMIT` asks for
+    /// `MAX_LIST_LIMIT` images
+    ///
+    /// With `sealed_names`, or without a token on servers that allow that,
+    /// the names, tags and captions are left sealed for the client to open,
+    /// and images sort by name in the order of their tokens.
+    ListImages {
+        username: String,
+        token: String,
+        offset: u64,
+        limit: u32,
+        sort_by: ImageSort,
+        descending: bool,
+        #[serde(default, skip_serializing_if = "before_v22", deserialize_with = "since_v22")]
+        sealed_names: bool,
+    },
+    /// Remove one of the user's images from every node that stores it
+    DeleteImage {
+        username: String,
+        token: String,
+        image: ImageSelector,
+    },
+    /// Let `with_user` view one of the owner's images `allowed_views` times
+    ShareImage {
+        owner: String,
+        token: String,
+        image: ImageSelector,
+        with_user: String,
+        allowed_views: u32,
+    },
+    /// View an image another user shared, spending one view; answered with
+    /// SealedSharedImage if the viewer registered a public key, else
+    /// SharedImageData
+    ///
+    /// The node storing the image checks the grant and spends the view under
+    /// the same lock that reads the image, so two views racing for the last
+    /// credit cannot both succeed. For a viewer with a public key the image
+    /// is sent as stored, with its data key wrapped to that key, so no node
+    /// decrypts it. Otherwise it is decrypted with the owner's key and sent
+    /// encrypted with the viewer's, so viewers never hold the owner's
+    /// ciphertext.
+    ///
+    /// Also read under the name ViewImage, which takes the same fields.
+    #[serde(alias = "ViewImage")]
+    ViewSharedImage {
+        viewer: String,
+        token: String,
+        owner: String,
+        filename: String,
+    },
+    /// Change how many views `with_user` has in total; 0 revokes access
+    UpdateAccess {
+        owner: String,
+        token: String,
+        filename: String,
+        with_user: String,
+        new_allowed_views: u32,
+    },
+    /// Start a chunked upload; answered with UploadStarted by the assigned node
+    BeginUpload {
+        username: String,
+        token: String,
+        filename: String,
+        total_size: u64,
+        chunk_size: u64,
+        /// As for UploadImage; chunked uploads are always stored as they
+        /// are, which their metadata then says
+        #[serde(default)]
+        strip_metadata: Option<bool>,
+        /// As for UploadImage; chunked uploads are always stored
+        /// uncompressed, which their metadata then says
+        #[serde(default)]
+        compress: Option<bool>,
+    },
+    /// One chunk of a chunked upload, sent to the node that started it;
+    /// every chunk but the last is exactly `chunk_size` bytes
+    UploadChunk {
+        upload_id: String,
+        seq: u64,
+        data: Vec<u8>,
+    },
+    /// Assemble the chunks, check them against `sha256` (hex) and process the
+    /// image like UploadImage
+    FinishUpload { upload_id: String, sha256: String },
+    /// Download an image as a stream of DownloadChunk responses on one
+    /// connection, skipping the chunks before `from_seq`
+    ///
+    /// The assigned node answers with ImageData instead if it does not store
+    /// the image itself.
+    StreamDownload {
+        username: String,
+        token: String,
+        filename: String,
+        from_seq: u64,
+    },
+    /// Upload several `(filename, data)` images at once; answered with
+    /// BatchUploaded, one file failing does not fail the others
+    UploadBatch {
+        username: String,
+        token: String,
+        images: Vec<(String, Vec<u8>)>,
+        /// As for UploadImage, for every image of the batch
+        #[serde(default)]
+        strip_metadata: Option<bool>,
+        /// As for UploadImage, for every image of the batch
+        #[serde(default)]
+        compress: Option<bool>,
+    },
+    /// Rename an image and/or replace its tags or caption on every node that
+    /// stores it; fields left `None` stay as they are
+    UpdateImageMeta {
+        username: String,
+        token: String,
+        filename: String,
+        new_filename: Option<String>,
+        tags: Option<Vec<String>>,
+        caption: Option<String>,
+    },
+    /// Views of the user's shared images since the last fetch, from every
+    /// node; answered with Notifications and the queues are cleared
+    FetchNotifications { username: String, token: String },
+    /// Keep the connection open and push Event responses for the user as
+    /// they happen, with a Keepalive every so often
+    ///
+    /// Views with a `seq` after `after_seq` are replayed first, so a client
+    /// that reconnects misses none; 0 replays nothing.
+    Subscribe {
+        username: String,
+        token: String,
+        after_seq: u64,
+    },
+    /// The leader and every node as the asked node sees them; needs no login
+    ClusterStatus,
+    /// How much the user stores across the cluster and may store; answered
+    /// by the leader with Quota
+    GetQuota { username: String, token: String },
+    /// Replace the user's password, answered by the leader with LoggedIn
+    ///
+    /// The user's image key is replaced too, and their images are moved to
+    /// the new one in the background; sessions from before stay valid.
+    ChangePassword {
+        username: String,
+        token: String,
+        old_password: String,
+        new_password: String,
+    },
+    /// A small JPEG preview of one of the user's images, encrypted like the
+    /// image; answered with Thumbnail, or a NotFound error code if none was
+    /// made of it
+    GetThumbnail {
+        username: String,
+        token: String,
+        image: ImageSelector,
+    },
+    /// Register the X25519 public key images shared with the user are to be
+    /// wrapped to from now on, replacing any earlier one; answered by the
+    /// leader with PublicKeyRegistered
+    RegisterPublicKey {
+        username: String,
+        token: String,
+        public_key: [u8; 32],
+    },
+}
+
+/// Size of every DownloadChunk but the last
+pub const DOWNLOAD_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Version of the request envelope and the messages inside it
+pub const PROTOCOL_VERSION: u16 = 22;
+/// Clients from this version on are sent Progress responses
+pub const PROGRESS_VERSION: u16 = 6;
+/// Oldest version this build still speaks; envelopes from before versioning
+/// count as version 1
+///
+/// Version 3 replaced the `retriable` flag of errors with an `ErrorCode`, which
+/// older builds cannot parse. Version 4 added StreamDownload, version 5
+/// UploadBatch and version 6 Progress responses. Version 7 added Register and
+/// Login and requires their token on requests, which older clients cannot send.
+/// Version 8 added UpdateImageMeta and tags and captions to image metadata and
+/// listings, which changes their binary layout. Version 9 added
+/// FetchNotifications, version 10 Subscribe and version 11 ClusterStatus.
+/// Version 12 added paging and sorting to ListImages and its answer, which
+/// changes their binary layout. Version 13 added GetQuota and QuotaExceeded.
+/// Version 14 added the key salt to LoggedIn, which changes its binary layout.
+/// Version 15 added the Corrupted error code and version 16 ChangePassword.
+/// Version 17 added content ids, which DownloadImage, DeleteImage and
+/// ShareImage may name an image by, changing their binary layout. Version 18
+/// added GetThumbnail and whether a listed image has a thumbnail, which
+/// changes the binary layout of ImageList. Version 19 added `strip_metadata`
+/// to uploads and what it did to image metadata, changing their layout.
+/// Version 20 added `compress` likewise. Version 21 added RegisterPublicKey
+/// and SealedSharedImage. Version 22 added `sealed_names` to ListImages and
+/// sealed entries to ImageList; both are left out for version 21 peers, see
+/// `in_version`.
+pub const MIN_PROTOCOL_VERSION: u16 = 21;
+
+/// Whether this build can talk to a peer speaking `version`
+pub fn is_supported(version: u16) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+}
+
+thread_local! {
+    static WIRE_VERSION: Cell<u16> = const { Cell::new(PROTOCOL_VERSION) };
+}
+
+/// Run `f` with messages (de)serialized in the layout of protocol `version`
+///
+/// Binary layouts have no room for missing fields, so the fields added since
+/// `MIN_PROTOCOL_VERSION` check the version in effect: they are not written
+/// for an older peer and read as their default from one. Wrap the `encode`
+/// and `decode` of anything exchanged with a client in this.
+pub fn in_version<T>(version: u16, f: impl FnOnce() -> T) -> T {
+    let previous = WIRE_VERSION.with(|v| v.replace(version));
+    let result = f();
+    WIRE_VERSION.with(|v| v.set(previous));
+    result
+}
+
+fn before_v22<T>(_: &T) -> bool {
+    WIRE_VERSION.with(|v| v.get() < 22)
+}
+
+fn since_v22<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    if WIRE_VERSION.with(|v| v.get() < 22) {
+        return Ok(T::default());
+    }
+    T::deserialize(deserializer)
+}
+
+/// Most images one ListImages page holds
+pub const MAX_LIST_LIMIT: u32 = 500;
+
+fn unversioned() -> u16 {
+    1
+}
+
+/// A client request tagged with an id that the response echoes
+///
+/// The client sends the same id to every server it broadcasts to, so server
+/// logs for one request can be matched up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestEnvelope {
+    /// Must stay the first field, followed by `request_id`, so that any
+    /// version can read them through `EnvelopeProbe`
+    #[serde(default = "unversioned")]
+    pub protocol_version: u16,
+    /// UUID v4 chosen by the client
+    pub request_id: String,
+    pub body: ClientRequest,
+    /// How the image bytes in `body` are compressed, if at all
+    #[serde(default)]
+    pub compression: Option<Codec>,
+}
+
+/// Compression applied to the image bytes of a request or response
+///
+/// Sent as its name so that a node can report a codec it does not know
+/// instead of failing to parse the whole message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum Codec {
+    Zstd,
+    /// A codec this build does not implement
+    Unknown(String),
+}
+
+impl From<String> for Codec {
+    fn from(name: String) -> Self {
+        match name.as_str() {
+            "zstd" => Codec::Zstd,
+            _ => Codec::Unknown(name),
+        }
+    }
+}
+
+impl From<Codec> for String {
+    fn from(codec: Codec) -> Self {
+        match codec {
+            Codec::Zstd => "zstd".to_string(),
+            Codec::Unknown(name) => name,
+        }
+    }
+}
+
+impl ClientRequest {
+    /// The image bytes carried by the request, the part that gets compressed
+    pub fn image_data_mut(&mut self) -> Option<&mut Vec<u8>> {
+        match self {
+            ClientRequest::UploadImage { image_data, .. } => Some(image_data),
+            ClientRequest::UploadChunk { data, .. } => Some(data),
+            _ => None,
+        }
+    }
+
+    /// The user a request acts for and the token proving it, `None` for
+    /// requests that need no token
+    ///
+    /// Chunks and FinishUpload belong to an upload started with a token.
+    pub fn credentials(&self) -> Option<(&str, &str)> {
+        match self {
+            ClientRequest::UploadImage { username, token, .. }
+            | ClientRequest::DownloadImage { username, token, .. }
+            | ClientRequest::ListImages { username, token, .. }
+            | ClientRequest::DeleteImage { username, token, .. }
+            | ClientRequest::BeginUpload { username, token, .. }
+            | ClientRequest::StreamDownload { username, token, .. }
+            | ClientRequest::UploadBatch { username, token, .. }
+            | ClientRequest::UpdateImageMeta { username, token, .. }
+            | ClientRequest::FetchNotifications { username, token }
+            | ClientRequest::Subscribe { username, token, .. }
+            | ClientRequest::GetQuota { username, token }
+            | ClientRequest::ChangePassword { username, token, .. }
+            | ClientRequest::GetThumbnail { username, token, .. }
+            | ClientRequest::RegisterPublicKey { username, token, .. }
+            | ClientRequest::ShareImage { owner: username, token, .. }
+            | ClientRequest::UpdateAccess { owner: username, token, .. }
+            | ClientRequest::ViewSharedImage { viewer: username, token, .. } => Some((username, token)),
+            ClientRequest::Register { .. }
+            | ClientRequest::Login { .. }
+            | ClientRequest::UploadChunk { .. }
+            | ClientRequest::FinishUpload { .. }
+            | ClientRequest::ClusterStatus => None,
+        }
+    }
+}
+
+impl ServerResponse {
+    /// The image bytes carried by the response, the part that gets compressed
+    pub fn image_data_mut(&mut self) -> Option<&mut Vec<u8>> {
+        match self {
+            ServerResponse::EncryptedImageData { data, .. }
+            | ServerResponse::ImageData { data, .. }
+            | ServerResponse::SharedImageData { data, .. }
+            | ServerResponse::SealedSharedImage { data, .. } => Some(data),
+            _ => None,
+        }
+    }
+}
+
+/// A request in a tagged frame
+///
+/// Binary payloads carry no field names, so unlike JSON they cannot be told
+/// apart by trying each request type in turn; this says which one it is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TaggedRequest {
+    Client(RequestEnvelope),
+    Internal(SignedInternal),
+}
+
+/// An InternalMessage signed by the node that sent it
+///
+/// Nodes only act on internal messages carrying an HMAC under the cluster
+/// secret, since clients reach the same port.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedInternal {
+    /// Unix milliseconds the message was signed at; old messages are refused
+    pub sent_at_ms: u64,
+    /// The InternalMessage, bincode-encoded
+    pub message: Vec<u8>,
+    /// HMAC-SHA256 of `sent_at_ms` and `message`
+    pub hmac: Vec<u8>,
+}
+
+/// Just enough of a tagged request to answer one from a version this node cannot parse
+#[derive(Debug, Deserialize)]
+pub enum TaggedProbe {
+    Client(EnvelopeProbe),
+}
+
+/// The leading fields every version of `RequestEnvelope` starts with
+#[derive(Debug, Deserialize)]
+pub struct EnvelopeProbe {
+    pub protocol_version: u16,
+    pub request_id: String,
+}
+
+/// A server response carrying the id of the request it answers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponseEnvelope {
+    pub request_id: String,
+    pub body: ServerResponse,
+    /// Only set when the request was compressed, so older clients never see it
+    #[serde(default)]
+    pub compression: Option<Codec>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerResponse {
     /// Returns the encrypted image data
-    EncryptedImageData { data: Vec<u8> },
+    EncryptedImageData {
+        /// Missing from servers that predate metadata
+        #[serde(default)]
+        metadata: Option<Box<ImageMetadata>>,
+        data: Vec<u8>,
+    },
+    /// A stored image returned by DownloadImage
+    ImageData {
+        /// Missing for images stored before metadata was recorded
+        #[serde(default)]
+        metadata: Option<Box<ImageMetadata>>,
+        data: Vec<u8>,
+        filename: String,
+    },
+    /// No node in the cluster stores the requested image
+    NotFound { filename: String },
+    /// Answer to ListImages: the requested page, the number of images the
+    /// user has in total and whether any follow the page
+    ImageList {
+        images: Vec<ImageEntry>,
+        total_count: u64,
+        has_more: bool,
+    },
+    /// The image was removed from the cluster
+    Deleted { filename: String },
+    /// The request touches something the user does not own
+    Forbidden { message: String },
+    /// The image was shared
+    Shared {
+        filename: String,
+        with_user: String,
+        allowed_views: u32,
+    },
+    /// A shared image, encrypted with the viewer's key
+    SharedImageData {
+        owner: String,
+        filename: String,
+        data: Vec<u8>,
+        remaining_views: u32,
+    },
+    /// The viewer used up every view the owner granted
+    QuotaExhausted { filename: String },
+    /// The request's image bytes use a codec this node does not know; send
+    /// them uncompressed
+    UnsupportedCodec { codec: String },
+    /// The request's image bytes could not be decompressed
+    DecompressFailed { message: String },
+    /// The request's protocol version is one this node does not speak
+    UnsupportedVersion { server_version: u16 },
+    /// The uploaded bytes do not match the checksum the client sent; they
+    /// were corrupted on the way
+    ChecksumMismatch { filename: String },
+    /// A chunked upload was accepted; send its chunks to the same node
+    UploadStarted { upload_id: String },
+    ChunkReceived { upload_id: String, seq: u64 },
+    /// FinishUpload found gaps; resend these chunks and finish again
+    MissingChunks { upload_id: String, missing: Vec<u64> },
+    /// A viewer's quota after UpdateAccess; `allowed_views` 0 means revoked
+    AccessUpdated {
+        filename: String,
+        with_user: String,
+        allowed_views: u32,
+        used_views: u32,
+    },
+    /// A request that failed; `code` says how the client should react
+    Error { code: ErrorCode, message: String },
+    /// First response to StreamDownload, followed by the chunks from the
+    /// requested one on
+    DownloadBegin {
+        metadata: Option<Box<ImageMetadata>>,
+        total_chunks: u64,
+    },
+    DownloadChunk { seq: u64, data: Vec<u8> },
+    /// Last response to StreamDownload; `sha256` (hex) covers the whole image,
+    /// including chunks that were skipped
+    DownloadEnd { sha256: String },
+    /// Results of an UploadBatch, in the order of its images
+    BatchUploaded { results: Vec<BatchResult> },
+    /// An UploadBatch over the server's limit; nothing was processed
+    BatchTooLarge { total_bytes: u64, max_bytes: u64 },
+    /// Sent on the same connection while a long upload is handled; the
+    /// actual response follows
+    Progress {
+        request_id: String,
+        bytes_received: u64,
+        stage: UploadStage,
+    },
+    /// Answer to Register, Login and ChangePassword; `key_salt` is the salt
+    /// the user's image key is derived with, so a client can derive the same
+    /// key
+    LoggedIn {
+        token: String,
+        expires_at_unix: u64,
+        key_salt: String,
+    },
+    /// Answer to UpdateImageMeta; `metadata` is missing for images stored
+    /// before metadata was recorded
+    ImageUpdated {
+        filename: String,
+        metadata: Option<Box<ImageMetadata>>,
+    },
+    /// Answer to FetchNotifications, oldest first
+    Notifications { notifications: Vec<ViewNotice> },
+    /// Something happened for a subscribed user; `seq` is the time it
+    /// happened in unix milliseconds, to resume after on reconnect
+    Event { seq: u64, event: ServerEvent },
+    /// Sent on a subscription with nothing to report, first right after
+    /// subscribing, so either side notices a dead connection
+    Keepalive,
+    /// Answer to ClusterStatus, nodes sorted by id
+    ClusterStatus {
+        leader_id: Option<u32>,
+        nodes: Vec<ClusterNodeStatus>,
+    },
+    /// Answer to GetQuota; `limit_bytes` is `None` if the user may store
+    /// any amount
+    Quota { used_bytes: u64, limit_bytes: Option<u64> },
+    /// Answer to GetThumbnail: the JPEG sealed for `thumbnail::sealed_name`
+    /// of the image's filename
+    Thumbnail { filename: String, data: Vec<u8> },
+    PublicKeyRegistered { username: String },
+    /// A shared image as the owner stored it, with its data key wrapped to
+    /// the viewer's public key by `encryption::wrap_for_viewer`
+    SealedSharedImage {
+        owner: String,
+        filename: String,
+        data: Vec<u8>,
+        wrapped_key: Vec<u8>,
+        remaining_views: u32,
+    },
+}
+
+/// One node as seen by the node answering ClusterStatus
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterNodeStatus {
+    pub id: u32,
+    pub address: String,
+    pub reachable: bool,
+    /// Client requests in flight, as last reported on a heartbeat; `None`
+    /// if the node has not reported recently
+    pub current_load: Option<u32>,
+    /// The node that answered
+    pub is_self: bool,
+}
+
+/// What a subscription reports
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ServerEvent {
+    /// Someone viewed one of the user's shared images
+    Viewed(ViewNotice),
+    /// The user's views of another user's image changed; `allowed_views` 0
+    /// means access was revoked
+    QuotaChanged {
+        owner: String,
+        filename: String,
+        allowed_views: u32,
+        used_views: u32,
+    },
+    /// The cluster has a new leader, or none
+    LeaderChanged { leader: Option<u32> },
+}
+
+/// Someone viewed one of the owner's shared images
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ViewNotice {
+    pub viewer: String,
+    pub filename: String,
+    pub timestamp: u64,
+    /// The viewer's views left after this one
+    pub remaining_views: u32,
+    /// Unix milliseconds of the view, the `seq` of its Event; 0 for views
+    /// queued before subscriptions existed
+    #[serde(default)]
+    pub seq: u64,
+}
+
+/// What a node is doing with an upload, reported in Progress responses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UploadStage {
+    /// Reading the received chunks back for processing
+    Receiving,
+    Encrypting,
+    Storing,
+    /// Handing the image to the node that will store it
+    Replicating,
+}
+
+/// How one image of an UploadBatch fared
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchResult {
+    pub filename: String,
+    pub outcome: BatchOutcome,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum BatchOutcome {
+    Uploaded { metadata: Option<ImageMetadata> },
+    Failed { message: String },
+}
+
+/// Why a request failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// Another node is assigned to the request; expected during a broadcast
+    NotAssigned,
+    /// No such upload or image
+    NotFound,
+    /// The request is malformed or outside the server's limits
+    BadRequest,
+    /// The server failed, e.g. on a full disk or an unreachable peer
+    Internal,
+    /// Transient, e.g. during a leader re-election; send the request again
+    Retriable,
+    /// Register with a username that is already taken
+    UserExists,
+    /// Login with a username that was never registered
+    UnknownUser,
+    WrongPassword,
+    /// The token is missing, malformed or not signed by the cluster
+    InvalidToken,
+    /// The token is past its expiry; log in again
+    TokenExpired,
+    /// The token was issued to another user than the request names
+    TokenMismatch,
+    /// A rename target is already one of the user's images
+    NameTaken,
+    /// The upload would take the user's stored bytes past their quota
+    QuotaExceeded { used: u64, limit: u64 },
+    /// The stored image failed its integrity check, so the disk or someone
+    /// with access to it changed it; it is not served
+    Corrupted,
+}
+
+/// What the cluster knows about a processed image
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageMetadata {
+    pub filename: String,
+    /// Size of the image as uploaded
+    pub original_size: u64,
+    /// Size of the encrypted image as stored and returned
+    pub encrypted_size: u64,
+    pub processed_by_node: u32,
+    pub uploaded_at_unix: u64,
+    /// Hex SHA-256 of the encrypted data
+    pub content_sha256: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub caption: Option<String>,
+    /// Hex SHA-256 of the image as uploaded, which stays the same when it
+    /// is renamed; `None` for images uploaded before content ids
+    #[serde(default)]
+    pub content_id: Option<String>,
+    /// Why no thumbnail was made of the image, e.g. it could not be decoded
+    #[serde(default)]
+    pub thumbnail_error: Option<String>,
+    /// What became of the image's EXIF and other metadata, if the upload
+    /// asked for it to be stripped
+    #[serde(default)]
+    pub metadata_strip: Option<MetadataStrip>,
+    /// Whether the image was compressed before it was encrypted, if the
+    /// upload asked for it to be
+    #[serde(default)]
+    pub storage_compression: Option<StorageCompression>,
+}
+
+/// What `strip_metadata` did to an upload
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MetadataStrip {
+    /// The metadata was cut out before encrypting
+    Stripped { bytes_removed: u64 },
+    /// The image was stored as uploaded, metadata and all
+    NotStripped { reason: String },
+}
+
+/// What compressing an upload for storage did
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StorageCompression {
+    /// Compressed with zstd to `compressed_size` bytes, then encrypted
+    Zstd { compressed_size: u64 },
+    /// Encrypted as uploaded, e.g. because it was already compressed
+    Skipped { reason: String },
+}
+
+/// Which of a user's images a request means
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ImageSelector {
+    Name(String),
+    /// The `content_id` from the image's metadata; of several images with
+    /// the same content, the first uploaded
+    ContentId(String),
+}
+
+impl std::fmt::Display for ImageSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageSelector::Name(filename) => write!(f, "{}", filename),
+            ImageSelector::ContentId(content_id) => write!(f, "id:{}", content_id),
+        }
+    }
+}
+
+/// Order of the images in a ListImages answer; ties are broken by filename,
+/// then by node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ImageSort {
+    Name,
+    UploadedAt,
+    Size,
+}
+
+/// One stored image as reported by ListImages
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageEntry {
+    pub filename: String,
+    /// Size of the stored (encrypted) file
+    pub size_bytes: u64,
+    pub uploaded_at_unix: u64,
+    /// Node that stores the image
+    pub node_id: u32,
+    /// From the image's metadata; empty for images stored without any
+    pub tags: Vec<String>,
+    pub caption: Option<String>,
+    #[serde(default)]
+    pub content_id: Option<String>,
+    /// Whether GetThumbnail has a preview of the image
+    #[serde(default)]
+    pub has_thumbnail: bool,
+    /// The image's catalog entry, if it was left sealed; `filename` is then
+    /// its token, and tags, caption and content id are empty
+    #[serde(default, skip_serializing_if = "before_v22", deserialize_with = "since_v22")]
+    pub sealed_entry: Option<SealedEntry>,
+}
+
+/// What a node's catalog keeps of one image, sealed under its owner's key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    pub filename: String,
+    /// `None` for images stored before metadata was recorded
+    pub metadata: Option<ImageMetadata>,
+}
+
+/// A `CatalogEntry` as JSON sealed by `encryption::seal_metadata`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedEntry {
+    /// Generation of the owner's key it is sealed under
+    pub generation: u32,
+    pub sealed: Vec<u8>,
+}
+
+/// An admin request with the operator's credential
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminEnvelope {
+    /// Must match the node's configured `admin_token`
+    pub admin_token: String,
+    pub request: AdminRequest,
+}
+
+/// Operator commands sent directly to a single node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AdminRequest {
+    /// Permanently remove a decommissioned node from this node's peer list
+    RemovePeer { node_id: u32 },
+    /// Point a known peer at a new address after it moved
+    UpdatePeer { node_id: u32, address: String },
+    /// Report this node's role and election statistics
+    Status,
+    /// Hand leadership from this node (the leader) to another node
+    TransferLeadership { node_id: u32 },
+    /// Leader and reachability of every peer; `refresh` probes them first
+    ClusterView {
+        #[serde(default)]
+        refresh: bool,
+    },
+    /// Give a user their own storage quota on every reachable node; `None`
+    /// returns them to the configured default, 0 lifts the limit
+    SetQuota { username: String, quota_bytes: Option<u64> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AdminResponse {
+    Ok { message: String },
+    Status(Box<NodeStatus>),
+    ClusterView {
+        node_id: u32,
+        leader: Option<u32>,
+        peers: Vec<PeerStatus>,
+    },
     Error { message: String },
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeStatus {
+    pub node_id: u32,
+    pub leader: Option<u32>,
+    pub term: u64,
+    pub elections_started: u64,
+    pub elections_won: u64,
+    pub coordinators_received: u64,
+    pub heartbeat_failures: u64,
+    /// Unix time in milliseconds
+    pub last_election_at_ms: Option<u64>,
+    pub last_election_duration_ms: Option<u64>,
+    pub leader_uptime_secs: Option<u64>,
+    /// Smoothed heartbeat round-trip time to the leader
+    pub rtt_ewma_ms: Option<u64>,
+    /// Heartbeat timeout and check interval in effect right now
+    pub heartbeat_timeout_ms: u64,
+    pub heartbeat_interval_ms: u64,
+    /// Seconds since each peer was last heard from, `None` if never
+    pub peer_liveness: HashMap<u32, Option<u64>>,
+    /// Election phase, e.g. "Idle" or "Electing { term: 4 }"
+    pub election_status: String,
+    /// Recent leadership transitions, oldest first
+    pub leader_history: Vec<LeaderTransitionInfo>,
+    /// Heartbeat round-trip percentiles per measured peer
+    #[serde(default)]
+    pub peer_latency: Vec<PeerLatency>,
+    /// Another node was found running with this node's id
+    #[serde(default)]
+    pub degraded: bool,
+    /// Image key rotations this node has worked on since it started
+    #[serde(default)]
+    pub key_rotations: Vec<KeyRotationStatus>,
+    /// Peers that acknowledged this node's latest COORDINATOR; `None` if it never announced
+    #[serde(default)]
+    pub announcement: Option<AnnouncementInfo>,
+}
+
+/// Delivery of a node's latest COORDINATOR announcement
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnnouncementInfo {
+    pub term: u64,
+    pub acked: Vec<u32>,
+    /// Still being retried, or given up on once the retries ran out
+    pub unacked: Vec<u32>,
+}
+
+/// How far this node got moving a user's images to their current key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyRotationStatus {
+    pub username: String,
+    /// Key generation the images are being moved to
+    pub generation: u32,
+    /// Images moved in the last pass
+    pub rotated: u32,
+    /// Images still under an earlier key after the last pass
+    pub remaining: u32,
+    /// Images that could not be moved in the last pass; they are retried
+    pub failed: u32,
+    pub running: bool,
+}
+
+/// One peer as seen from the answering node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerStatus {
+    pub id: u32,
+    pub address: String,
+    pub reachable: bool,
+    pub is_leader: bool,
+    pub last_seen_secs: Option<u64>,
+}
+
+/// Heartbeat round trips to one peer over the recent sample window
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerLatency {
+    pub peer_id: u32,
+    pub samples: usize,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub max_ms: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeaderTransitionInfo {
+    /// Unix time in milliseconds
+    pub at_ms: u64,
+    pub old_leader: Option<u32>,
+    pub new_leader: Option<u32>,
+    /// heartbeat_timeout, coordinator_received or self_elected
+    pub reason: String,
+}
+
+/// A registered user's salted password hash, both hex
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserRecord {
+    pub salt: String,
+    pub password_hash: String,
+    /// Unix seconds; 0 for users registered before this was recorded
+    #[serde(default)]
+    pub created_at_unix: u64,
+    /// Hex salt for deriving the user's image key; users registered before
+    /// image keys use `salt`
+    #[serde(default)]
+    pub key_salt: Option<String>,
+    /// The user's image key, hex, encrypted under the cluster secret; set
+    /// the first time they log in or register with image keys
+    #[serde(default)]
+    pub wrapped_key: Option<String>,
+    /// Goes up by one each time the user changes their password and their
+    /// image key is replaced; images record the generation that sealed them
+    #[serde(default)]
+    pub key_generation: u32,
+    /// Earlier image keys by generation, wrapped like `wrapped_key`, kept
+    /// until every node has moved the user's images to the current one
+    #[serde(default)]
+    pub retired_keys: HashMap<u32, String>,
+    /// Hex X25519 public key images shared with the user are wrapped to
+    #[serde(default)]
+    pub public_key: Option<String>,
+    /// Unix milliseconds `public_key` was registered, so the latest wins
+    /// when registries are merged
+    #[serde(default)]
+    pub public_key_at_ms: u64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum InternalMessage {
-    /// Request from leader to worker to process image
+    /// Request from leader to worker to process image; answered with
+    /// ImageData holding the encrypted image, or an unsuccessful
+    /// ProcessingComplete
     ProcessImage {
         username: String,
         image_data: Vec<u8>,
         filename: String,
+        /// Remove the image's metadata before encrypting it
+        #[serde(default)]
+        strip_metadata: bool,
+        /// Compress the image before encrypting it
+        #[serde(default)]
+        compress: bool,
     },
     /// Response from worker to leader
     ProcessingComplete { success: bool, message: String },
     /// Retrieve image from worker
     RetrieveImage { username: String, filename: String },
     /// Image retrieval response
-    ImageData { data: Vec<u8> },
+    ImageData {
+        #[serde(default)]
+        metadata: Option<ImageMetadata>,
+        data: Vec<u8>,
+    },
+    /// The worker does not store the requested image
+    ImageNotFound,
+    /// List the images a node stores for a user, leaving their catalog
+    /// entries sealed if `sealed`
+    ListImages {
+        username: String,
+        #[serde(default)]
+        sealed: bool,
+    },
+    /// Image listing response
+    ImageList { images: Vec<ImageEntry> },
+    /// Remove a copy of an image; answered with ProcessingComplete, `success`
+    /// telling whether a copy existed
+    DeleteReplica { username: String, filename: String },
+    /// Record a share on the node storing the image; answered with
+    /// ProcessingComplete, `success` telling whether the image is stored there
+    GrantAccess {
+        owner: String,
+        filename: String,
+        with_user: String,
+        allowed_views: u32,
+    },
+    /// Spend one view of a shared image on the node storing it
+    ConsumeView {
+        viewer: String,
+        owner: String,
+        filename: String,
+    },
+    /// ConsumeView succeeded; `data` is the image as stored, and
+    /// `wrapped_key` its data key wrapped to the viewer's public key if they
+    /// registered one and the image has a data key of its own
+    ViewGranted {
+        data: Vec<u8>,
+        remaining_views: u32,
+        #[serde(default)]
+        wrapped_key: Option<Vec<u8>>,
+    },
+    /// The owner never shared the image with the viewer
+    NotShared,
+    /// The viewer has no views left
+    QuotaExhausted,
+    /// Change a viewer's quota on the node storing the image; answered with
+    /// AccessUpdated, or ImageNotFound if the image is not stored there
+    UpdateAccess {
+        owner: String,
+        filename: String,
+        with_user: String,
+        allowed_views: u32,
+    },
+    /// A viewer's quota after UpdateAccess
+    AccessUpdated { allowed_views: u32, used_views: u32 },
     /// Health check
     Ping,
     /// Health check response
     Pong,
+    /// Registered users, by name; the receiver merges them into its registry
+    /// and answers with a successful ProcessingComplete, never its own users
+    UserSync { users: HashMap<String, UserRecord> },
+    /// Apply an UpdateImageMeta to the copy of an image stored here; answered
+    /// with MetaUpdated, ImageNotFound if there is none, NameTaken, or an
+    /// unsuccessful ProcessingComplete
+    UpdateImageMeta {
+        username: String,
+        filename: String,
+        new_filename: Option<String>,
+        tags: Option<Vec<String>>,
+        caption: Option<String>,
+    },
+    /// The copy was updated; `metadata` as it now stands
+    MetaUpdated { metadata: Option<ImageMetadata> },
+    /// The rename target already exists on this node
+    NameTaken,
+    /// Queue a view for the owner on the node storing the image; answered
+    /// with ProcessingComplete
+    ViewNotification {
+        owner: String,
+        viewer: String,
+        filename: String,
+        timestamp: u64,
+        remaining_views: u32,
+        seq: u64,
+    },
+    /// Take and clear the views queued on this node for `username`
+    FetchNotifications { username: String },
+    /// The views queued on this node for `username` after `after_seq`,
+    /// leaving the queue as it is; answered with Notifications
+    PeekNotifications { username: String, after_seq: u64 },
+    /// `node_id` is taking the upload request with id `key`; answered with
+    /// ClaimedBy naming the node that gets it, the lower id if two claim it
+    Processed { key: String, node_id: u32 },
+    ClaimedBy { node_id: u32 },
+    /// Hand an event to this node's subscribers for `username`; answered
+    /// with ProcessingComplete
+    PushEvent {
+        username: String,
+        seq: u64,
+        event: ServerEvent,
+    },
+    /// Answer to FetchNotifications
+    Notifications { notifications: Vec<ViewNotice> },
+    /// Bytes this node stores for `username`; answered with StoredBytes
+    StorageUsage { username: String },
+    StoredBytes { bytes: u64 },
+    /// Ask the leader for a user's stored bytes across the cluster; answered
+    /// with StoredBytes
+    QuotaCheck { username: String },
+    /// The user's stored bytes changed on the sending node, e.g. after a
+    /// delete; the leader counts them again. Answered with ProcessingComplete
+    UsageChanged { username: String },
+    /// Set or clear a user's own quota; answered with ProcessingComplete
+    SetQuota { username: String, quota_bytes: Option<u64> },
+    /// Re-encrypt the user's images stored under their username key with
+    /// their image key; answered with KeysUpgraded
+    UpgradeKeys { username: String },
+    KeysUpgraded { images: u32 },
+    /// Move the user's images stored here from their retired keys to their
+    /// current one; answered with RotationProgress once the pass is done
+    RotateKeys { username: String },
+    RotationProgress { generation: u32, remaining: u32 },
+    /// Fetch an image's sealed thumbnail; answered with ThumbnailData,
+    /// NoThumbnail if the image is stored here without one, or ImageNotFound
+    RetrieveThumbnail { username: String, filename: String },
+    ThumbnailData { data: Vec<u8> },
+    /// `reason` is why none was made, if the image's metadata says
+    NoThumbnail { reason: Option<String> },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::{decode, encode, is_line_start, is_tagged, Format, FrameError};
+
+    /// ClientRequest as a version 21 client writes it, up to ListImages
+    #[derive(Serialize)]
+    #[allow(dead_code)]
+    enum ClientRequestV21 {
+        Register,
+        Login,
+        UploadImage,
+        DownloadImage,
+        ListImages {
+            username: String,
+            token: String,
+            offset: u64,
+            limit: u32,
+            sort_by: ImageSort,
+            descending: bool,
+        },
+    }
+
+    #[derive(Serialize)]
+    enum TaggedRequestV21 {
+        Client(RequestEnvelopeV21),
+    }
+
+    #[derive(Serialize)]
+    struct RequestEnvelopeV21 {
+        protocol_version: u16,
+        request_id: String,
+        body: ClientRequestV21,
+        compression: Option<Codec>,
+    }
+
+    /// ImageEntry as a version 21 client reads it
+    #[derive(Debug, Deserialize)]
+    struct ImageEntryV21 {
+        filename: String,
+        size_bytes: u64,
+        uploaded_at_unix: u64,
+        node_id: u32,
+        tags: Vec<String>,
+        caption: Option<String>,
+        content_id: Option<String>,
+        has_thumbnail: bool,
+    }
+
+    fn list_request(version: u16) -> TaggedRequest {
+        TaggedRequest::Client(RequestEnvelope {
+            protocol_version: version,
+            request_id: "r1".to_string(),
+            body: ClientRequest::ListImages {
+                username: "alice".to_string(),
+                token: "t".to_string(),
+                offset: 50,
+                limit: 25,
+                sort_by: ImageSort::Size,
+                descending: true,
+                sealed_names: true,
+            },
+            compression: None,
+        })
+    }
+
+    fn entry() -> ImageEntry {
+        ImageEntry {
+            filename: "cat.png".to_string(),
+            size_bytes: 1234,
+            uploaded_at_unix: 1_700_000_000,
+            node_id: 2,
+            tags: vec!["pets".to_string()],
+            caption: Some("a cat".to_string()),
+            content_id: Some("abc".to_string()),
+            has_thumbnail: true,
+            sealed_entry: Some(SealedEntry {
+                generation: 3,
+                sealed: vec![1, 2, 3],
+            }),
+        }
+    }
+
+    /// The fields of a ListImages request, in order
+    fn list_fields(request: TaggedRequest) -> (u16, u64, u32, ImageSort, bool, bool) {
+        let TaggedRequest::Client(RequestEnvelope {
+            protocol_version,
+            body: ClientRequest::ListImages { offset, limit, sort_by, descending, sealed_names, .. },
+            ..
+        }) = request
+        else {
+            panic!("not a ListImages request: {:?}", request);
+        };
+        (protocol_version, offset, limit, sort_by, descending, sealed_names)
+    }
+
+    // The downgrade path only exists while there is more than one version
+    const _: () = assert!(MIN_PROTOCOL_VERSION < PROTOCOL_VERSION);
+
+    #[test]
+    fn supported_range() {
+        assert!(is_supported(PROTOCOL_VERSION));
+        assert!(is_supported(MIN_PROTOCOL_VERSION));
+        assert!(!is_supported(MIN_PROTOCOL_VERSION - 1));
+        assert!(!is_supported(PROTOCOL_VERSION + 1));
+    }
+
+    #[test]
+    fn same_version_round_trip() {
+        for format in [Format::Bincode, Format::Json] {
+            let payload = encode(format, &list_request(PROTOCOL_VERSION)).unwrap();
+            let (_, request) = in_version(PROTOCOL_VERSION, || decode::<TaggedRequest>(&payload)).unwrap();
+            assert_eq!(list_fields(request), (PROTOCOL_VERSION, 50, 25, ImageSort::Size, true, true));
+
+            let payload = encode(format, &entry()).unwrap();
+            let (_, decoded) = decode::<ImageEntry>(&payload).unwrap();
+            assert_eq!(decoded.sealed_entry.map(|e| e.generation), Some(3));
+        }
+    }
+
+    #[test]
+    fn older_client_round_trip() {
+        let v21 = TaggedRequestV21::Client(RequestEnvelopeV21 {
+            protocol_version: 21,
+            request_id: "r1".to_string(),
+            body: ClientRequestV21::ListImages {
+                username: "alice".to_string(),
+                token: "t".to_string(),
+                offset: 50,
+                limit: 25,
+                sort_by: ImageSort::Size,
+                descending: true,
+            },
+            compression: Some(Codec::Zstd),
+        });
+        for format in [Format::Bincode, Format::Json] {
+            let payload = encode(format, &v21).unwrap();
+            let (_, probe) = decode::<TaggedProbe>(&payload).unwrap();
+            let TaggedProbe::Client(probe) = probe;
+            assert_eq!(probe.protocol_version, 21);
+
+            let (_, request) = in_version(probe.protocol_version, || decode::<TaggedRequest>(&payload)).unwrap();
+            let TaggedRequest::Client(envelope) = &request else {
+                panic!("not a client request");
+            };
+            assert_eq!(envelope.compression, Some(Codec::Zstd));
+            assert_eq!(list_fields(request), (21, 50, 25, ImageSort::Size, true, false));
+
+            // The answer leaves out the sealed entry the old client cannot read
+            let payload = in_version(21, || encode(format, &entry())).unwrap();
+            let (_, old) = decode::<ImageEntryV21>(&payload).unwrap();
+            assert_eq!(old.filename, "cat.png");
+            assert_eq!((old.size_bytes, old.uploaded_at_unix, old.node_id), (1234, 1_700_000_000, 2));
+            assert_eq!(old.tags, ["pets"]);
+            assert_eq!(old.caption.as_deref(), Some("a cat"));
+            assert_eq!(old.content_id.as_deref(), Some("abc"));
+            assert!(old.has_thumbnail);
+        }
+
+        // Written by this build for an older server, read back as that server would
+        let payload = in_version(21, || encode(Format::Bincode, &list_request(21))).unwrap();
+        let (_, request) = in_version(21, || decode::<TaggedRequest>(&payload)).unwrap();
+        assert_eq!(list_fields(request), (21, 50, 25, ImageSort::Size, true, false));
+    }
+
+    #[test]
+    fn older_layout_is_not_read_as_current() {
+        let payload = in_version(21, || encode(Format::Bincode, &vec![entry(), entry()])).unwrap();
+        assert!(decode::<Vec<ImageEntry>>(&payload).is_err());
+        let (_, entries) = in_version(21, || decode::<Vec<ImageEntry>>(&payload)).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|e| e.sealed_entry.is_none()));
+    }
+
+    #[test]
+    fn newer_client_is_probed() {
+        /// A request from a future version with a body this build does not know
+        #[derive(Serialize)]
+        enum FutureTagged {
+            Client { protocol_version: u16, request_id: String, body: (u32, String) },
+        }
+        let future = FutureTagged::Client {
+            protocol_version: PROTOCOL_VERSION + 1,
+            request_id: "r2".to_string(),
+            body: (999, "teleport".to_string()),
+        };
+        for format in [Format::Bincode, Format::Json] {
+            let payload = encode(format, &future).unwrap();
+            assert!(decode::<TaggedRequest>(&payload).is_err());
+            let (_, TaggedProbe::Client(probe)) = decode::<TaggedProbe>(&payload).unwrap();
+            assert_eq!(probe.request_id, "r2");
+            assert!(!is_supported(probe.protocol_version));
+        }
+    }
+
+    #[test]
+    fn in_version_restores_the_previous_version() {
+        in_version(21, || {
+            assert!(before_v22(&()));
+            in_version(PROTOCOL_VERSION, || assert!(!before_v22(&())));
+            assert!(before_v22(&()));
+        });
+        assert!(!before_v22(&()));
+    }
+
+    #[test]
+    fn view_image_is_read_as_view_shared_image() {
+        let json = r#"{"ViewImage":{"viewer":"bob","owner":"alice","filename":"cat.png","token":"t"}}"#;
+        let request: ClientRequest = serde_json::from_str(json).unwrap();
+        let ClientRequest::ViewSharedImage { viewer, owner, filename, token } = &request else {
+            panic!("read as {:?}", request);
+        };
+        assert_eq!((viewer.as_str(), owner.as_str(), filename.as_str(), token.as_str()), ("bob", "alice", "cat.png", "t"));
+        assert_eq!(request.credentials(), Some(("bob", "t")));
+    }
+
+    /// An upload of `len` bytes that do not repeat in any short pattern
+    fn upload(len: usize) -> TaggedRequest {
+        let image_data = (0..len).map(|i| (i * 7919 % 251) as u8).collect();
+        TaggedRequest::Client(RequestEnvelope {
+            protocol_version: PROTOCOL_VERSION,
+            request_id: "r1".to_string(),
+            body: ClientRequest::UploadImage {
+                username: "alice".to_string(),
+                token: "t".to_string(),
+                image_data,
+                filename: "cat.png".to_string(),
+                sha256: None,
+                strip_metadata: None,
+                compress: None,
+            },
+            compression: None,
+        })
+    }
+
+    #[test]
+    fn bincode_sends_image_bytes_as_they_are() {
+        let request = upload(4 << 20);
+        let json = encode(Format::Json, &request).unwrap();
+        let bincode = encode(Format::Bincode, &request).unwrap();
+        // Bincode adds a little framing; JSON writes each byte as a number and a comma
+        assert!(bincode.len() < (4 << 20) + 256, "bincode took {} bytes", bincode.len());
+        assert!(json.len() > 3 * bincode.len(), "json took {} bytes", json.len());
+    }
+
+    #[test]
+    fn both_formats_round_trip_every_message_kind() {
+        let response = ResponseEnvelope {
+            request_id: "r1".to_string(),
+            body: ServerResponse::Error {
+                message: "no".to_string(),
+                code: ErrorCode::QuotaExceeded { used: 5, limit: 4 },
+            },
+            compression: None,
+        };
+        let internal = InternalMessage::ImageData {
+            metadata: None,
+            data: vec![0, 1, 255],
+        };
+        for format in [Format::Json, Format::Bincode] {
+            let payload = encode(format, &upload(1024)).unwrap();
+            assert!(is_tagged(&payload));
+            let (read_as, request) = decode::<TaggedRequest>(&payload).unwrap();
+            assert_eq!(read_as, format);
+            assert_eq!(format!("{:?}", request), format!("{:?}", upload(1024)));
+
+            let (_, read) = decode::<ResponseEnvelope>(&encode(format, &response).unwrap()).unwrap();
+            assert_eq!(format!("{:?}", read), format!("{:?}", response));
+            let (_, read) = decode::<InternalMessage>(&encode(format, &internal).unwrap()).unwrap();
+            assert_eq!(format!("{:?}", read), format!("{:?}", internal));
+        }
+    }
+
+    #[test]
+    fn untagged_and_unknown_payloads_are_told_apart() {
+        let bare = serde_json::to_vec(&upload(16)).unwrap();
+        assert!(!is_tagged(&bare));
+        assert!(is_line_start(bare[0]));
+
+        let mut unknown = encode(Format::Bincode, &upload(16)).unwrap();
+        unknown[0] = 9;
+        assert!(matches!(decode::<TaggedRequest>(&unknown), Err(FrameError::UnknownFormat(9))));
+        assert!(matches!(decode::<TaggedRequest>(&[]), Err(FrameError::Codec(_))));
+    }
+
+    #[test]
+    fn format_names() {
+        assert_eq!(Format::parse("json"), Some(Format::Json));
+        assert_eq!(Format::parse("bincode"), Some(Format::Bincode));
+        assert_eq!(Format::parse("msgpack"), None);
+    }
 }
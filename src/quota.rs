@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How much each user may store, and what they store as far as this node
+/// last counted
+///
+/// Limits are the configured default unless a user was given their own with
+/// the SetQuota admin request; those are kept in a JSON file next to the
+/// node's images. A limit of 0 means no limit.
+pub struct Quotas {
+    path: PathBuf,
+    default_bytes: u64,
+    overrides: Mutex<HashMap<String, u64>>,
+    /// Stored bytes per user and when they were counted
+    usage: Mutex<HashMap<String, (u64, Instant)>>,
+}
+
+impl Quotas {
+    /// Load per-user limits from `path`, starting with none if the file does
+    /// not exist
+    pub fn load(path: PathBuf, default_bytes: u64) -> std::io::Result<Self> {
+        let overrides = match std::fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Quotas {
+            path,
+            default_bytes,
+            overrides: Mutex::new(overrides),
+            usage: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The user's limit in bytes, `None` if unlimited
+    pub fn limit(&self, username: &str) -> Option<u64> {
+        let limit = self
+            .overrides
+            .lock()
+            .unwrap()
+            .get(username)
+            .copied()
+            .unwrap_or(self.default_bytes);
+        (limit > 0).then_some(limit)
+    }
+
+    /// Give the user their own limit, or return them to the default with `None`
+    pub fn set_limit(&self, username: &str, quota_bytes: Option<u64>) -> std::io::Result<()> {
+        let mut overrides = self.overrides.lock().unwrap();
+        let previous = match quota_bytes {
+            Some(bytes) => overrides.insert(username.to_string(), bytes),
+            None => overrides.remove(username),
+        };
+        if let Err(e) = self.save(&overrides) {
+            match previous {
+                Some(bytes) => overrides.insert(username.to_string(), bytes),
+                None => overrides.remove(username),
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// The user's stored bytes, if counted within `max_age`
+    pub fn usage(&self, username: &str, max_age: Duration) -> Option<u64> {
+        match self.usage.lock().unwrap().get(username) {
+            Some((bytes, at)) if at.elapsed() < max_age => Some(*bytes),
+            _ => None,
+        }
+    }
+
+    pub fn record_usage(&self, username: &str, bytes: u64) {
+        self.usage.lock().unwrap().insert(username.to_string(), (bytes, Instant::now()));
+    }
+
+    /// Add a new upload to the user's counted bytes, if they were counted
+    pub fn charge(&self, username: &str, bytes: u64) {
+        if let Some((used, _)) = self.usage.lock().unwrap().get_mut(username) {
+            *used += bytes;
+        }
+    }
+
+    /// Drop the user's counted bytes so they are counted again
+    pub fn forget_usage(&self, username: &str) {
+        self.usage.lock().unwrap().remove(username);
+    }
+
+    fn save(&self, overrides: &HashMap<String, u64>) -> std::io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(overrides)?)?;
+        std::fs::rename(&tmp, &self.path)
+    }
+}
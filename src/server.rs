@@ -1,86 +1,621 @@
+mod auth;
+mod blobformat;
 mod bully;
+mod compression;
+// The list of every server address is for clients
+#[allow(dead_code)]
 mod config;
 mod encryption;
+// `--format` is a client flag
+#[allow(dead_code)]
+mod framing;
+mod keycache;
 mod loadbalancer;
+mod metastrip;
 mod protocol;
+mod quota;
+// Only `embed` is used here; the client extracts
+#[allow(dead_code)]
+mod stego;
+mod storage;
+mod thumbnail;
+mod transport;
+mod validation;
 
-use bully::{BullyElection, BullyMessage};
-use config::Config;
-use encryption::{encrypt_data, generate_key_from_username};
-use loadbalancer::LoadBalancer;
-use protocol::{ClientRequest, ServerResponse};
+use auth::{PasswordCheck, PeerSigner, TokenError, TokenSigner, UserRegistry};
+use bully::{BullyConfig, BullyElection, HeartbeatMode, TransportKind};
+use compression::{compress_if_smaller, decompress};
+use config::{Config, ElectionSettings};
+use encryption::{
+    access_header_key, catalog_key, data_key_id, decrypt_stream, derive_user_key, encrypt_stream, image_data_key, is_authenticated,
+    is_valid_public_key, key_generation, key_wrapping_key, open_image, wrap_for_viewer,
+    reencrypt_for, rotate_user_key, CryptoError, CompressionOutcome, is_compressed, seal_compressed, seal_image, sha256, sha256_hex, to_hex, unwrap_key, uses_username_key, wrap_key, EncryptionScheme, SecretKey,
+    StreamError, UserKey,
+};
+use keycache::KeyCache;
+use loadbalancer::{HealthChecks, HealthEvent, LoadBalancer, FAILURES_TO_EJECT};
+use quota::Quotas;
+use protocol::{
+    AdminEnvelope, AdminRequest, AnnouncementInfo, AdminResponse, BatchOutcome, BatchResult, ClientRequest, ClusterNodeStatus, Codec, ErrorCode, DOWNLOAD_CHUNK_SIZE, TaggedProbe, PROTOCOL_VERSION, in_version, is_supported, ImageEntry, ImageSelector, ImageSort, MAX_LIST_LIMIT, ImageMetadata, InternalMessage, MetadataStrip, KeyRotationStatus, LeaderTransitionInfo, NodeStatus,
+    PeerLatency, PeerStatus, RequestEnvelope, ResponseEnvelope, ServerResponse, TaggedRequest, UploadStage,
+    PROGRESS_VERSION, ServerEvent, StorageCompression, UserRecord, ViewNotice,
+};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::time::{sleep, Duration};
+use tokio::sync::{mpsc, RwLock};
+use tokio::time::{sleep, Duration, Instant};
+use zeroize::Zeroizing;
+use storage::{ImageStore, MetaUpdate, ShareKey, ViewCheck};
+use thumbnail::ThumbnailError;
+use framing::{decode, encode, is_line_start, is_tagged, read_frame, write_frame, Format, FrameError};
+use transport::MAX_FRAME_LEN;
+use validation::{validate_filename, validate_request, validate_username, ValidationError};
+
+/// Stands in for cluster_secret with --insecure-dev-secret; fine for demos only
+const DEV_TOKEN_SECRET: &[u8] = b"distinsta-insecure-dev-secret";
+
+/// How long to wait on one peer for an answer to an InternalMessage
+const PEER_REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+/// A peer that does not answer a Ping within this long counts as down
+const PING_TIMEOUT: Duration = Duration::from_millis(300);
+/// How long a worker may take to encrypt and store a forwarded upload
+const PROCESS_IMAGE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long an upload's request id stays claimed by the node that took it
+const CLAIM_TTL: Duration = Duration::from_secs(300);
+/// Most claimed request ids remembered; the oldest are forgotten first
+const MAX_CLAIMS: usize = 10_000;
+/// How often an idle subscription is sent a Keepalive
+const SUBSCRIPTION_KEEPALIVE: Duration = Duration::from_secs(15);
+/// How often nodes push their user registries, to catch up peers that were
+/// down when a user registered
+const USER_SYNC_INTERVAL: Duration = Duration::from_secs(30);
+/// How long the leader trusts its count of a user's stored bytes before
+/// asking every node again
+const QUOTA_RECOUNT_INTERVAL: Duration = Duration::from_secs(60);
+/// How long a follower trusts the leader's figure for a user's stored bytes
+const QUOTA_CACHE_TTL: Duration = Duration::from_secs(10);
+/// How far past their quota a follower may let a user go on a cached figure
+const QUOTA_OVERSHOOT: u64 = 16 * 1024 * 1024;
+
+/// Largest image accepted through a chunked upload
+const MAX_UPLOAD_SIZE: u64 = 512 * 1024 * 1024;
+const MAX_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+/// Largest total size of the images in one UploadBatch
+const MAX_BATCH_BYTES: u64 = 32 * 1024 * 1024;
+/// How many images of a batch are processed at once
+const BATCH_PARALLELISM: usize = 4;
+/// Chunked uploads not finished within this time are discarded
+const UPLOAD_TTL: Duration = Duration::from_secs(600);
+
+/// Open subscriptions on this node, by username; each sender feeds one
+/// connection's `(seq, event)` stream
+type Subscribers = Arc<Mutex<HashMap<String, Vec<mpsc::UnboundedSender<(u64, ServerEvent)>>>>>;
+
+/// Upload request ids recently taken by some node
+///
+/// A broadcast upload must be processed once, but nodes that disagree on who
+/// is alive, e.g. during failover, can both accept it. Nodes tell each other
+/// which request ids they take, and back off from ids another node has.
+#[derive(Default)]
+struct Claims {
+    by_key: HashMap<String, Claim>,
+}
+
+struct Claim {
+    node_id: u32,
+    at: Instant,
+    /// The holder went ahead with the request, so no other node may take it
+    settled: bool,
+}
+
+impl Claims {
+    /// The node that has taken `key`, if any
+    fn holder(&mut self, key: &str) -> Option<u32> {
+        self.by_key.retain(|_, claim| claim.at.elapsed() < CLAIM_TTL);
+        self.by_key.get(key).map(|claim| claim.node_id)
+    }
+
+    /// Record that `node_id` takes `key`, returning the node that gets it
+    ///
+    /// If two nodes claim the same key, the one with the lower id wins on
+    /// every node, so at most one of them goes ahead. A settled key stays
+    /// with its holder.
+    fn claim(&mut self, key: &str, node_id: u32) -> u32 {
+        self.holder(key);
+        if let Some(claim) = self.by_key.get(key).filter(|claim| claim.settled) {
+            return claim.node_id;
+        }
+        let winner = self.by_key.get(key).map_or(node_id, |claim| claim.node_id.min(node_id));
+        self.by_key.insert(
+            key.to_string(),
+            Claim {
+                node_id: winner,
+                at: Instant::now(),
+                settled: false,
+            },
+        );
+        if self.by_key.len() > MAX_CLAIMS {
+            let oldest = self.by_key.iter().min_by_key(|(_, claim)| claim.at).map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.by_key.remove(&oldest);
+            }
+        }
+        winner
+    }
+
+    /// Record that a peer holds `key`, as it told us
+    fn yield_to(&mut self, key: &str, node_id: u32) {
+        self.claim(key, node_id);
+        if let Some(claim) = self.by_key.get_mut(key) {
+            claim.node_id = node_id;
+        }
+    }
+
+    /// Settle `key` with `node_id` if it holds it, returning the holder
+    fn settle(&mut self, key: &str, node_id: u32) -> u32 {
+        let winner = self.claim(key, node_id);
+        if winner == node_id {
+            if let Some(claim) = self.by_key.get_mut(key) {
+                claim.settled = true;
+            }
+        }
+        winner
+    }
+}
+
+/// Where a request reports how far it got; reports reach the client only if
+/// it reads Progress responses, and go nowhere otherwise
+#[derive(Clone, Default)]
+struct Progress(Option<mpsc::UnboundedSender<(u64, UploadStage)>>);
+
+impl Progress {
+    fn report(&self, bytes_received: u64, stage: UploadStage) {
+        if let Some(sender) = &self.0 {
+            let _ = sender.send((bytes_received, stage));
+        }
+    }
+}
+
+/// What an upload asked to be done to the image before it is encrypted,
+/// with the server's settings filled in where it did not say
+#[derive(Debug, Clone, Copy)]
+struct UploadOptions {
+    strip_metadata: bool,
+    compress: bool,
+}
+
+/// How uploads are spread over the cluster
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Routing {
+    /// Every node takes the broadcast uploads a hash of username and
+    /// filename assigns it
+    Hash,
+    /// Only the leader takes uploads, and hands each to the node its load
+    /// balancer picks
+    Leader,
+}
+
+impl Routing {
+    /// The `routing` setting called `name`
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "hash" => Some(Routing::Hash),
+            "leader" => Some(Routing::Leader),
+            _ => None,
+        }
+    }
+}
+
+/// How the leader picks the node an upload goes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Balancing {
+    RoundRobin,
+    /// The node with the fewest requests in flight, as last reported on its
+    /// heartbeats plus those handed to it since
+    LeastLoaded,
+}
+
+/// A chunked upload in progress on this node
+struct UploadSession {
+    username: String,
+    filename: String,
+    total_size: u64,
+    chunk_size: u64,
+    /// What the upload asked for, which chunked uploads cannot do: they are
+    /// stored as uploaded
+    options: UploadOptions,
+    received: HashSet<u64>,
+    started: Instant,
+}
+
+impl UploadSession {
+    fn chunk_count(&self) -> u64 {
+        self.total_size.div_ceil(self.chunk_size)
+    }
+
+    /// Length chunk `seq` must have: `chunk_size`, except for a shorter last chunk
+    fn chunk_len(&self, seq: u64) -> u64 {
+        self.chunk_size.min(self.total_size - seq * self.chunk_size)
+    }
+
+    fn missing(&self) -> Vec<u64> {
+        (0..self.chunk_count()).filter(|seq| !self.received.contains(seq)).collect()
+    }
+}
 
 struct ServerNode {
     id: u32,
     address: String,
+    settings: ElectionSettings,
     bully: Arc<BullyElection>,
-    load_balancer: Option<LoadBalancer>,
+    /// Only present while this node is the leader
+    load_balancer: Arc<RwLock<Option<LoadBalancer>>>,
+    /// Encrypted images this node processed
+    images: ImageStore,
+    /// Chunked uploads in progress, by upload id
+    uploads: Arc<Mutex<HashMap<String, UploadSession>>>,
+    /// Largest client, admin or internal frame accepted or sent
+    max_frame_len: usize,
+    /// Users registered in the cluster, as far as this node has synced
+    users: Arc<UserRegistry>,
+    tokens: TokenSigner,
+    /// Signs this node's internal messages and checks those of other nodes
+    peer_signer: PeerSigner,
+    /// Serve requests without a session token for any username, as before
+    /// registration existed
+    allow_anonymous: bool,
+    subscribers: Subscribers,
+    claims: Arc<Mutex<Claims>>,
+    quotas: Arc<Quotas>,
+    /// Cipher for images this node stores
+    scheme: EncryptionScheme,
+    /// Encrypts users' image keys in the user registry
+    key_wrap: SecretKey,
+    /// Users' image keys as unwrapped from the registry
+    key_cache: Arc<KeyCache>,
+    /// Serve and store images under the deprecated username keys
+    legacy_keys: bool,
+    /// Strip metadata from uploads that leave it to the server
+    strip_metadata: bool,
+    /// Compress uploads that leave it to the server before encrypting them
+    compress_storage: bool,
+    /// Hand clients their encrypted images hidden in a cover PNG
+    stego_output: bool,
+    /// The configured cover; without one, a plain cover is made to fit
+    stego_cover: Option<Arc<Vec<u8>>>,
+    /// Image key rotations by user, as far as this node got
+    rotations: Arc<Mutex<HashMap<String, KeyRotationStatus>>>,
+    routing: Routing,
+    balancing: Balancing,
+    /// Credential admin requests must carry; `None` refuses them all
+    admin_token: Option<Arc<String>>,
 }
 
 impl ServerNode {
-    fn new(id: u32, address: String) -> Self {
-        let bully = Arc::new(BullyElection::new(id, address.clone()));
+    fn new(id: u32, address: String, config: &Config, allow_anonymous: bool) -> Self {
+        let settings = config.election.clone();
+        let priority = config.get_priority(id);
+        let storage_dir = PathBuf::from(&config.storage_dir);
+        let heartbeat_mode = match settings.heartbeat_mode.as_str() {
+            "push" => HeartbeatMode::Push,
+            "pull" => HeartbeatMode::Pull,
+            other => {
+                eprintln!("Unknown heartbeat_mode '{}', using pull", other);
+                HeartbeatMode::Pull
+            }
+        };
+        let heartbeat_transport = match settings.heartbeat_transport.as_str() {
+            "udp" => TransportKind::Udp,
+            "tcp" => TransportKind::Tcp,
+            other => {
+                eprintln!("Unknown heartbeat_transport '{}', using tcp", other);
+                TransportKind::Tcp
+            }
+        };
+        let scheme = match EncryptionScheme::from_name(&config.encryption_scheme) {
+            Some(scheme) => scheme,
+            None => {
+                eprintln!("Unknown encryption_scheme '{}', using aes-256-gcm", config.encryption_scheme);
+                EncryptionScheme::Aes256Gcm
+            }
+        };
+        let routing = Routing::from_name(&config.routing).expect("unknown routing is refused at startup");
+        let balancing = match config.balancing.as_str() {
+            "round-robin" => Balancing::RoundRobin,
+            "least-loaded" => Balancing::LeastLoaded,
+            other => {
+                eprintln!("Unknown balancing '{}', using round-robin", other);
+                Balancing::RoundRobin
+            }
+        };
+        let stego_cover = match &config.stego_cover {
+            Some(path) if config.stego_output => match std::fs::read(path) {
+                Ok(cover) => Some(Arc::new(cover)),
+                Err(e) => {
+                    eprintln!("Cannot read stego_cover {}: {}, using plain covers", path, e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        let bully_config = BullyConfig {
+            heartbeat_interval: Duration::from_millis(settings.heartbeat_interval_ms),
+            heartbeat_timeout: Duration::from_millis(settings.heartbeat_timeout_ms),
+            election_timeout: Duration::from_millis(settings.election_timeout_ms),
+            answer_wait: Duration::from_millis(settings.answer_wait_ms),
+            coordinator_timeout: Duration::from_millis(settings.coordinator_timeout_ms),
+            heartbeat_mode,
+            failure_threshold: settings.failure_threshold,
+            heartbeat_transport,
+            udp_port_offset: settings.udp_port_offset,
+            election_jitter: Duration::from_millis(settings.election_jitter_ms),
+            cluster_secret: settings.cluster_secret.clone(),
+            prevote_backoff: Duration::from_millis(settings.prevote_backoff_ms),
+            priority,
+            reannounce_interval: Duration::from_millis(settings.reannounce_interval_ms),
+            coordinator_retries: settings.coordinator_retries,
+            coordinator_retry_backoff: Duration::from_millis(settings.coordinator_retry_backoff_ms),
+            leader_quorum_fraction: settings.leader_quorum_fraction,
+            leader_quorum_checks: settings.leader_quorum_checks,
+            election_retries: settings.election_retries,
+            election_retry_backoff: Duration::from_millis(settings.election_retry_backoff_ms),
+            quorum_mode: settings.quorum_mode,
+            state_path: settings
+                .state_dir
+                .as_ref()
+                .map(|dir| PathBuf::from(dir).join(format!("node{}.term", id))),
+            leader_state_path: settings
+                .state_dir
+                .as_ref()
+                .map(|dir| PathBuf::from(dir).join(format!("node{}.leader", id))),
+            adaptive_heartbeat: settings.adaptive_heartbeat,
+            rtt_multiplier: settings.rtt_multiplier,
+            min_heartbeat_timeout: Duration::from_millis(settings.min_heartbeat_timeout_ms),
+            max_heartbeat_timeout: Duration::from_millis(settings.max_heartbeat_timeout_ms),
+            min_heartbeat_interval: Duration::from_millis(settings.min_heartbeat_interval_ms),
+            max_heartbeat_interval: Duration::from_millis(settings.max_heartbeat_interval_ms),
+            accept_unknown_leaders: settings.accept_unknown_leaders,
+            rtt_history_len: settings.rtt_history_len,
+        };
+        let bully = Arc::new(BullyElection::new_with_config(id, address.clone(), bully_config));
+
+        let users = UserRegistry::load(storage_dir.join(format!("node{}-users.json", id)))
+            .expect("Failed to load the user registry");
+        let quotas = Quotas::load(storage_dir.join(format!("node{}-quotas.json", id)), config.quota_bytes)
+            .expect("Failed to load user quotas");
+        // Every node must sign with the same secret to accept each other's
+        // tokens, image keys and access headers
+        let secret = match &settings.cluster_secret {
+            Some(secret) => secret.as_bytes(),
+            None => {
+                eprintln!(
+                    "Node {}: WARNING: no cluster_secret, protecting tokens, image keys and internal messages with the built-in development key",
+                    id
+                );
+                DEV_TOKEN_SECRET
+            }
+        };
+        let (tokens, key_wrap, header_key) = (TokenSigner::new(secret), key_wrapping_key(secret), access_header_key(secret));
+        let peer_signer = PeerSigner::new(secret);
+        let catalog_key = catalog_key(secret);
 
         ServerNode {
             id,
             address: address.clone(),
+            settings,
             bully,
-            load_balancer: None,
+            load_balancer: Arc::new(RwLock::new(None)),
+            images: ImageStore::new(id, storage_dir.join(format!("node{}", id)), header_key, catalog_key),
+            uploads: Arc::new(Mutex::new(HashMap::new())),
+            max_frame_len: config.max_frame_bytes,
+            users: Arc::new(users),
+            tokens,
+            peer_signer,
+            allow_anonymous,
+            subscribers: Arc::new(Mutex::new(HashMap::new())),
+            claims: Arc::new(Mutex::new(Claims::default())),
+            quotas: Arc::new(quotas),
+            scheme,
+            key_wrap,
+            key_cache: Arc::new(KeyCache::new(
+                config.key_cache_capacity,
+                Duration::from_secs(config.key_cache_ttl_secs),
+            )),
+            legacy_keys: config.legacy_username_keys,
+            strip_metadata: config.strip_metadata,
+            compress_storage: config.compress_storage,
+            stego_output: config.stego_output,
+            stego_cover,
+            rotations: Arc::new(Mutex::new(HashMap::new())),
+            routing,
+            balancing,
+            admin_token: config.admin_token.clone().map(Arc::new),
         }
     }
 
-    async fn add_peer(&self, peer_id: u32, peer_address: String) {
-        self.bully.add_peer(peer_id, peer_address).await;
+    async fn add_peer(&self, peer_id: u32, peer_address: String, priority: u32) {
+        if let Err(e) = self.bully.add_peer(peer_id, peer_address, priority).await {
+            eprintln!("Node {}: Not adding peer: {}", self.id, e);
+        }
     }
 
-    async fn start(&mut self) {
-        println!("Starting Server Node {} on {}", self.id, self.address);
+    async fn start(&self, join_seed: Option<String>) {
+        println!("Starting Server Node {} on {}, encrypting with {}", self.id, self.address, self.scheme.name());
+
+        // Images must be under their tokens before any request looks for them
+        match self.images.migrate_names().await {
+            Ok(0) => {}
+            Ok(moved) => println!("Node {}: Moved {} images from their names to tokens", self.id, moved),
+            Err(e) => eprintln!("Node {}: Failed to move images to their tokens: {}", self.id, e),
+        }
 
         // Start listening
         let listener = TcpListener::bind(&self.address).await.unwrap();
         println!("Node {} listening on {}", self.id, self.address);
 
-        // Wait a bit for all nodes to start
-        sleep(Duration::from_secs(2)).await;
+        // Joining through a seed, or rejoining the leader remembered from
+        // before a restart, adopts the existing leader, no election needed
+        let joined = match &join_seed {
+            Some(seed) => {
+                println!("Node {}: Joining cluster via {}", self.id, seed);
+                match self.bully.join(seed).await {
+                    Ok(()) => true,
+                    Err(e) => {
+                        println!("Node {}: Cannot join via {} ({}), falling back to election", self.id, seed, e);
+                        false
+                    }
+                }
+            }
+            None => self.bully.resume_leader().await,
+        };
+
+        if !joined {
+            // Wait a bit for all nodes to start
+            sleep(Duration::from_millis(self.settings.startup_delay_ms)).await;
 
-        // Start election
-        println!("Node {}: Starting initial election", self.id);
-        self.bully.start_election().await;
+            // Start election; it keeps retrying until a majority is reachable,
+            // so run it in the background and start serving meanwhile
+            println!("Node {}: Starting initial election", self.id);
+            let bully_clone = Arc::clone(&self.bully);
+            tokio::spawn(async move {
+                bully_clone.start_election().await;
+            });
 
-        // Wait for election to complete
-        sleep(Duration::from_secs(3)).await;
+            // Wait for election to complete
+            sleep(Duration::from_millis(self.settings.election_settle_ms)).await;
+        }
 
         // Start leader monitoring (heartbeat)
         let bully_clone = Arc::clone(&self.bully);
-        bully_clone.start_leader_monitoring().await;
+        let monitor = bully_clone.start_leader_monitoring().await;
 
-        // Check if I'm the leader
-        if self.bully.is_leader().await {
-            println!("Node {}: I am the LEADER, initializing load balancer", self.id);
-            self.load_balancer = Some(LoadBalancer::new());
-        } else {
-            if let Some(leader_id) = self.bully.get_leader().await {
-                println!("Node {}: I am a WORKER, leader is Node {}", self.id, leader_id);
+        // Log when the leader starts missing heartbeats
+        let bully_clone = Arc::clone(&self.bully);
+        let node_id = self.id;
+        let interval = Duration::from_millis(self.settings.heartbeat_interval_ms);
+        tokio::spawn(async move {
+            loop {
+                sleep(interval).await;
+                let health = bully_clone.leader_health().await;
+                if health.is_suspected() && !health.is_dead() {
+                    if let Some(leader_id) = bully_clone.get_leader().await {
+                        println!("Node {}: Leader {} suspected ({}/{} heartbeats missed)",
+                            node_id, leader_id, health.consecutive_failures, health.threshold);
+                    }
+                }
             }
-        }
+        });
 
-        // Handle connections
-        loop {
-            match listener.accept().await {
-                Ok((stream, addr)) => {
-                    println!("Node {}: New connection from {}", self.id, addr);
-                    let node = self.clone_for_task();
-                    tokio::spawn(async move {
-                        node.handle_connection(stream).await;
-                    });
+        // Follow leadership changes for the lifetime of the node
+        let mut leader_rx = self.bully.subscribe();
+        let load_balancer = Arc::clone(&self.load_balancer);
+        let node = self.clone_for_task();
+        tokio::spawn(async move {
+            let mut health_checks = None;
+            loop {
+                let leader = *leader_rx.borrow_and_update();
+                node.deliver_to_all(&ServerEvent::LeaderChanged { leader });
+                let mut became_leader = false;
+                {
+                    let mut lb = load_balancer.write().await;
+                    if leader == Some(node_id) {
+                        if lb.is_none() {
+                            println!("Node {}: I am the LEADER, initializing load balancer", node_id);
+                            *lb = Some(LoadBalancer::new());
+                            became_leader = true;
+                        }
+                    } else {
+                        if lb.take().is_some() {
+                            println!("Node {}: No longer leader, dropping load balancer", node_id);
+                        }
+                        match leader {
+                            Some(leader_id) => {
+                                println!("Node {}: I am a WORKER, leader is Node {}", node_id, leader_id)
+                            }
+                            None => println!("Node {}: No leader known", node_id),
+                        }
+                    }
                 }
-                Err(e) => {
-                    eprintln!("Node {}: Error accepting connection: {}", self.id, e);
+                // Register the alive nodes now rather than on the next
+                // sync, so the first uploads are not all processed here
+                if became_leader {
+                    node.sync_load_balancer().await;
+                    health_checks = node.start_health_checks().await;
+                } else if leader != Some(node_id) {
+                    if let Some(checks) = health_checks.take() {
+                        checks.shutdown().await;
+                    }
+                }
+                if leader_rx.changed().await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        // Feed follower loads reported on heartbeats into the load balancer
+        let node = self.clone_for_task();
+        tokio::spawn(async move {
+            loop {
+                sleep(interval).await;
+                node.sync_load_balancer().await;
+            }
+        });
+
+        // Push this node's users to its peers, seal the catalog entries of
+        // those with a key, and keep pushing in case a push was missed; users
+        // registered while this node was down arrive with the peers' pushes
+        let node = self.clone_for_task();
+        tokio::spawn(async move {
+            node.sync_users().await;
+            node.seal_catalogs().await;
+            loop {
+                sleep(USER_SYNC_INTERVAL).await;
+                node.sync_users().await;
+            }
+        });
+
+        // Finish key rotations that were cut short, e.g. by a node being down
+        let node = self.clone_for_task();
+        tokio::spawn(async move {
+            loop {
+                sleep(USER_SYNC_INTERVAL).await;
+                if !node.bully.is_leader().await {
+                    continue;
+                }
+                for username in node.users.rotations_pending() {
+                    node.rotate_keys_everywhere(&username).await;
+                }
+            }
+        });
+
+        // Handle connections until Ctrl+C
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, addr)) => {
+                        println!("Node {}: New connection from {}", self.id, addr);
+                        let node = self.clone_for_task();
+                        tokio::spawn(async move {
+                            node.handle_connection(stream).await;
+                        });
+                    }
+                    Err(e) => {
+                        eprintln!("Node {}: Error accepting connection: {}", self.id, e);
+                    }
+                },
+                _ = tokio::signal::ctrl_c() => {
+                    println!("Node {}: Shutting down", self.id);
+                    // Hand over leadership now rather than waiting for heartbeats to fail
+                    self.bully.resign().await;
+                    monitor.shutdown().await;
+                    return;
                 }
             }
         }
@@ -90,179 +625,4902 @@ impl ServerNode {
         ServerNode {
             id: self.id,
             address: self.address.clone(),
+            settings: self.settings.clone(),
             bully: Arc::clone(&self.bully),
-            load_balancer: self.load_balancer.clone(),
+            load_balancer: Arc::clone(&self.load_balancer),
+            images: self.images.clone(),
+            uploads: Arc::clone(&self.uploads),
+            max_frame_len: self.max_frame_len,
+            users: Arc::clone(&self.users),
+            tokens: self.tokens.clone(),
+            peer_signer: self.peer_signer.clone(),
+            allow_anonymous: self.allow_anonymous,
+            subscribers: Arc::clone(&self.subscribers),
+            claims: Arc::clone(&self.claims),
+            quotas: Arc::clone(&self.quotas),
+            scheme: self.scheme,
+            key_wrap: self.key_wrap.clone(),
+            key_cache: Arc::clone(&self.key_cache),
+            legacy_keys: self.legacy_keys,
+            strip_metadata: self.strip_metadata,
+            compress_storage: self.compress_storage,
+            stego_output: self.stego_output,
+            stego_cover: self.stego_cover.clone(),
+            rotations: Arc::clone(&self.rotations),
+            routing: self.routing,
+            balancing: self.balancing,
+            admin_token: self.admin_token.clone(),
         }
     }
 
     async fn handle_connection(&self, mut stream: TcpStream) {
-        let mut reader = BufReader::new(&mut stream);
-        let mut line = String::new();
-
-        match reader.read_line(&mut line).await {
-            Ok(0) => return,
-            Ok(_) => {
-                // Try to parse as BullyMessage first
-                if let Ok(msg) = serde_json::from_str::<BullyMessage>(&line) {
-                    if let Some(response) = self.bully.handle_message(msg).await {
-                        let response_json = serde_json::to_string(&response).unwrap();
-                        let _ = stream.write_all(response_json.as_bytes()).await;
-                        let _ = stream.write_all(b"\n").await;
+        let (read_half, mut writer) = stream.split();
+        let mut reader = BufReader::new(read_half);
+
+        let first_byte = match reader.fill_buf().await {
+            Ok([]) => return,
+            Ok(buf) => buf[0],
+            Err(e) => {
+                eprintln!("Node {}: Error reading from stream: {}", self.id, e);
+                return;
+            }
+        };
+
+        // Clients and nodes from before framing send one JSON line
+        if is_line_start(first_byte) {
+            let mut line = String::new();
+            if let Err(e) = reader.read_line(&mut line).await {
+                eprintln!("Node {}: Error reading from stream: {}", self.id, e);
+                return;
+            }
+            let response_json = match self.answer_request(line.as_bytes()).await {
+                Some(response_json) => response_json,
+                None => {
+                    // Tell the sender rather than dropping the connection
+                    println!("Node {}: Unknown message format", self.id);
+                    let response = ServerResponse::UnsupportedVersion {
+                        server_version: PROTOCOL_VERSION,
+                    };
+                    serde_json::to_string(&response).unwrap()
+                }
+            };
+            let _ = writer.write_all(response_json.as_bytes()).await;
+            let _ = writer.write_all(b"\n").await;
+            return;
+        }
+
+        let frame = match read_frame(&mut reader, self.max_frame_len).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("Node {}: Rejected frame: {}", self.id, e);
+                return;
+            }
+        };
+
+        // Tagged requests are answered in the format they arrived in
+        if is_tagged(&frame) {
+            let response = match in_version(wire_version(&frame), || decode::<TaggedRequest>(&frame)) {
+                // Streams are answered with many frames
+                Ok((format, TaggedRequest::Client(RequestEnvelope {
+                    request_id,
+                    body: ClientRequest::StreamDownload {
+                        username,
+                        token,
+                        filename,
+                        from_seq,
+                    },
+                    ..
+                }))) => {
+                    self.bully.load.fetch_add(1, Ordering::Relaxed);
+                    let checked = validate_username("username", &username)
+                        .and_then(|()| validate_filename("filename", &filename))
+                        .map_err(bad_request)
+                        .and_then(|()| self.check_user(&request_id, &username, &token));
+                    let result = match checked {
+                        Ok(()) => {
+                            self.stream_download(&mut writer, format, &request_id, username, filename, from_seq)
+                                .await
+                        }
+                        Err(response) => self.send_response(&mut writer, format, &request_id, response).await,
+                    };
+                    self.bully.load.fetch_sub(1, Ordering::Relaxed);
+                    if let Err(e) = result {
+                        eprintln!("Node {} [{}]: Download stream failed: {}", self.id, request_id, e);
                     }
                     return;
                 }
-
-                // Try to parse as ClientRequest
-                if let Ok(request) = serde_json::from_str::<ClientRequest>(&line) {
-                    let response = self.handle_client_request(request).await;
-                    let response_json = serde_json::to_string(&response).unwrap();
-                    let _ = stream.write_all(response_json.as_bytes()).await;
-                    let _ = stream.write_all(b"\n").await;
+                Ok((format, TaggedRequest::Client(RequestEnvelope {
+                    request_id,
+                    body: ClientRequest::Subscribe {
+                        username,
+                        token,
+                        after_seq,
+                    },
+                    ..
+                }))) => {
+                    let checked = validate_username("username", &username)
+                        .map_err(bad_request)
+                        .and_then(|()| self.check_user(&request_id, &username, &token));
+                    let result = match checked {
+                        Ok(()) => {
+                            self.serve_subscription(&mut writer, format, &request_id, username, after_seq)
+                                .await
+                        }
+                        Err(response) => self.send_response(&mut writer, format, &request_id, response).await,
+                    };
+                    if let Err(e) = result {
+                        println!("Node {} [{}]: Subscription closed: {}", self.id, request_id, e);
+                    }
                     return;
                 }
-
-                println!("Node {}: Unknown message format", self.id);
+                Ok((format, TaggedRequest::Client(envelope))) if envelope.protocol_version >= PROGRESS_VERSION => {
+                    self.serve_with_progress(&mut writer, format, envelope).await;
+                    return;
+                }
+                Ok((format, request)) => self.answer_tagged(format, request).await,
+                Err(e) => self.reject_unreadable(&frame, e),
+            };
+            match response {
+                Ok(response) => {
+                    if let Err(e) = write_frame(&mut writer, &response, self.max_frame_len).await {
+                        eprintln!("Node {}: Failed to send response: {}", self.id, e);
+                    }
+                }
+                Err(e) => eprintln!("Node {}: Rejected frame: {}", self.id, e),
             }
-            Err(e) => {
-                eprintln!("Node {}: Error reading from stream: {}", self.id, e);
+            return;
+        }
+
+        match self.answer_request(&frame).await {
+            Some(response_json) => {
+                if let Err(e) = write_frame(&mut writer, response_json.as_bytes(), self.max_frame_len).await {
+                    eprintln!("Node {}: Failed to send response: {}", self.id, e);
+                }
             }
+            // Anything else is election traffic
+            None => self.serve_bully_frames(frame, &mut reader, &mut writer).await,
         }
     }
 
-    async fn handle_client_request(&self, request: ClientRequest) -> ServerResponse {
-        println!("Node {}: Received client request", self.id);
-
+    /// Answer a request from a tagged frame, encoding the response in `format`
+    async fn answer_tagged(&self, format: Format, request: TaggedRequest) -> Result<Vec<u8>, FrameError> {
         match request {
-            ClientRequest::UploadImage {
-                username,
-                image_data,
-                filename,
-            } => {
-                // Create a deterministic hash for this request (username + filename)
-                use std::collections::hash_map::DefaultHasher;
-                use std::hash::{Hash, Hasher};
-
-                let mut hasher = DefaultHasher::new();
-                username.hash(&mut hasher);
-                filename.hash(&mut hasher);
-                let request_hash = hasher.finish();
-
-                // Check which peers are alive
-                let alive_nodes = self.get_alive_nodes().await;
+            TaggedRequest::Client(envelope) => {
+                let version = envelope.protocol_version;
+                let response = self.serve_envelope(envelope, &Progress::default()).await;
+                in_version(version, || encode(format, &response))
+            }
+            TaggedRequest::Internal(signed) => {
+                let response = match self.peer_signer.open(signed, unix_now_ms()) {
+                    Ok(message) => self.handle_internal_message(message).await,
+                    Err(e) => {
+                        println!("Node {}: Refused internal message: {}", self.id, e);
+                        InternalMessage::ProcessingComplete {
+                            success: false,
+                            message: format!("internal message refused: {}", e),
+                        }
+                    }
+                };
+                encode(format, &response)
+            }
+        }
+    }
 
-                if alive_nodes.is_empty() {
-                    println!("Node {}: No alive nodes detected, processing as fallback", self.id);
-                    // Process anyway as last resort
-                } else {
-                    // Round-robin assignment based on request hash
-                    let assigned_index = (request_hash % alive_nodes.len() as u64) as usize;
-                    let assigned_node_id = alive_nodes[assigned_index];
+    /// Answer a client request, sending the Progress reports it makes ahead
+    /// of its response on the same connection
+    async fn serve_with_progress<W: AsyncWrite + Unpin>(&self, writer: &mut W, format: Format, envelope: RequestEnvelope) {
+        let request_id = envelope.request_id.clone();
+        let version = envelope.protocol_version;
+        let (sender, mut reports) = mpsc::unbounded_channel();
+        let progress = Progress(Some(sender));
+        let serve = self.serve_envelope(envelope, &progress);
+        tokio::pin!(serve);
 
-                    if assigned_node_id != self.id {
-                        println!("Node {}: Request assigned to Node {} (round-robin), rejecting",
-                            self.id, assigned_node_id);
-                        return ServerResponse::Error {
-                            message: format!("Request assigned to Node {}", assigned_node_id),
-                        };
+        let mut client_gone = false;
+        let response = loop {
+            tokio::select! {
+                response = &mut serve => break response,
+                Some((bytes_received, stage)) = reports.recv() => {
+                    // The request is still finished if the client stops listening
+                    if client_gone {
+                        continue;
                     }
-
-                    println!("Node {}: Assigned to me via load balancing (alive nodes: {:?})",
-                        self.id, alive_nodes);
+                    let report = ServerResponse::Progress {
+                        request_id: request_id.clone(),
+                        bytes_received,
+                        stage,
+                    };
+                    client_gone = self.send_response(writer, format, &request_id, report).await.is_err();
                 }
+            }
+        };
 
-                // Process the request
-                println!("Node {}: Processing image upload for user {} ({})",
-                    self.id, username, filename);
-
-                // Generate encryption key from username
-                let key = generate_key_from_username(&username);
+        let sent = match in_version(version, || encode(format, &response)) {
+            Ok(payload) => write_frame(writer, &payload, self.max_frame_len).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = sent {
+            eprintln!("Node {}: Failed to send response: {}", self.id, e);
+        }
+    }
 
-                // Encrypt the image data
-                let encrypted_data = encrypt_data(&image_data, &key);
+    /// Answer StreamDownload with DownloadBegin, the chunks from `from_seq` on
+    /// and DownloadEnd, reading the image from disk one chunk at a time
+    async fn stream_download<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+        format: Format,
+        request_id: &str,
+        username: String,
+        filename: String,
+        from_seq: u64,
+    ) -> Result<(), FrameError> {
+        if let Err(response) = self.check_assignment(request_id, &username, &filename).await {
+            return self.send_response(writer, format, request_id, response).await;
+        }
+        // A cover is only written whole, so hidden images go in one piece
+        let stored = if self.stego_output {
+            None
+        } else {
+            self.images.open(&username, &filename).await
+        };
+        let Some((mut file, len)) = stored else {
+            // Not stored here; whatever a peer has is sent in one piece
+            let response = self.download(request_id, username, filename).await;
+            let response = self.hide_image_data(request_id, response).await;
+            return self.send_response(writer, format, request_id, response).await;
+        };
+        // The whole image must pass its check before the first chunk goes out
+        if let Err(response) = self.check_stored_intact(request_id, &username, &filename).await {
+            return self.send_response(writer, format, request_id, response).await;
+        }
 
-                println!("Node {}: Image encrypted ({} bytes -> {} bytes)",
-                    self.id, image_data.len(), encrypted_data.len());
+        let metadata = self.images.load_metadata(&username, &filename, &self.user_keys(&username)).await;
+        let total_chunks = len.div_ceil(DOWNLOAD_CHUNK_SIZE as u64);
+        println!("Node {} [{}]: Streaming image {} for user {} ({} chunks, from chunk {})",
+            self.id, request_id, filename, username, total_chunks, from_seq);
+        let begin = ServerResponse::DownloadBegin { metadata: metadata.map(Box::new), total_chunks };
+        self.send_response(writer, format, request_id, begin).await?;
 
-                // Return encrypted image to client
-                ServerResponse::EncryptedImageData { data: encrypted_data }
+        // Skipped chunks are still read, so the hash covers the whole image
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; DOWNLOAD_CHUNK_SIZE];
+        for seq in 0..total_chunks {
+            let chunk_len = (len - seq * DOWNLOAD_CHUNK_SIZE as u64).min(DOWNLOAD_CHUNK_SIZE as u64) as usize;
+            file.read_exact(&mut buf[..chunk_len]).await?;
+            hasher.update(&buf[..chunk_len]);
+            if seq >= from_seq {
+                let chunk = ServerResponse::DownloadChunk {
+                    seq,
+                    data: buf[..chunk_len].to_vec(),
+                };
+                self.send_response(writer, format, request_id, chunk).await?;
             }
         }
+
+        let end = ServerResponse::DownloadEnd {
+            sha256: to_hex(&hasher.finalize()),
+        };
+        self.send_response(writer, format, request_id, end).await
     }
 
-    /// Check which peer nodes are alive by attempting to connect
-    async fn get_alive_nodes(&self) -> Vec<u32> {
-        let peers = self.bully.get_all_peers().await;
-        let mut alive = vec![];
+    /// Write one response frame of a stream
+    async fn send_response<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+        format: Format,
+        request_id: &str,
+        body: ServerResponse,
+    ) -> Result<(), FrameError> {
+        let payload = encode(format, &ResponseEnvelope {
+            request_id: request_id.to_string(),
+            body,
+            compression: None,
+        })?;
+        write_frame(writer, &payload, self.max_frame_len).await
+    }
 
-        // Always include myself if I can process requests
-        alive.push(self.id);
+    /// Answer a tagged request that does not parse with UnsupportedVersion if
+    /// it comes from another protocol version, otherwise pass on `error`
+    fn reject_unreadable(&self, frame: &[u8], error: FrameError) -> Result<Vec<u8>, FrameError> {
+        let Ok((format, TaggedProbe::Client(probe))) = decode::<TaggedProbe>(frame) else {
+            return Err(error);
+        };
+        if is_supported(probe.protocol_version) {
+            return Err(error);
+        }
+        println!("Node {} [{}]: Client speaks protocol version {}, this node {}",
+            self.id, probe.request_id, probe.protocol_version, PROTOCOL_VERSION);
+        encode(format, &ResponseEnvelope {
+            request_id: probe.request_id,
+            body: ServerResponse::UnsupportedVersion {
+                server_version: PROTOCOL_VERSION,
+            },
+            compression: None,
+        })
+    }
 
-        // Quick health check for each peer
-        for (peer_id, peer_addr) in peers {
-            if peer_id == self.id {
-                continue;
+    /// Handle a client request with an id, undoing and applying compression
+    /// of its image bytes
+    async fn serve_envelope(&self, envelope: RequestEnvelope, progress: &Progress) -> ResponseEnvelope {
+        let RequestEnvelope {
+            protocol_version,
+            request_id,
+            mut body,
+            compression,
+        } = envelope;
+        if !is_supported(protocol_version) {
+            println!("Node {} [{}]: Client speaks protocol version {}, this node {}",
+                self.id, request_id, protocol_version, PROTOCOL_VERSION);
+            return ResponseEnvelope {
+                request_id,
+                body: ServerResponse::UnsupportedVersion {
+                    server_version: PROTOCOL_VERSION,
+                },
+                compression: None,
+            };
+        }
+        if let Some(codec) = &compression {
+            if let Err(response) = self.decompress_request(&request_id, codec, &mut body) {
+                return ResponseEnvelope {
+                    request_id,
+                    body: response,
+                    compression: None,
+                };
             }
+        }
 
-            // Try to connect with short timeout
-            match tokio::time::timeout(
-                Duration::from_millis(100),
-                TcpStream::connect(&peer_addr)
-            ).await {
-                Ok(Ok(_)) => {
-                    alive.push(peer_id);
-                }
-                _ => {
-                    // Node is down or unreachable
+        let response = self.serve_client(&request_id, body, progress).await;
+        let mut response = self.hide_image_data(&request_id, response).await;
+
+        // Only clients that compress themselves get compressed answers
+        let mut response_compression = None;
+        if compression.is_some() {
+            if let Some(data) = response.image_data_mut() {
+                if let Some(compressed) = compress_if_smaller(data) {
+                    *data = compressed;
+                    response_compression = Some(Codec::Zstd);
                 }
             }
         }
-
-        alive.sort();
-        alive
+        ResponseEnvelope {
+            request_id,
+            body: response,
+            compression: response_compression,
+        }
     }
-}
 
-impl Clone for LoadBalancer {
-    fn clone(&self) -> Self {
-        LoadBalancer {
-            servers: Arc::clone(&self.servers),
-            next_index: Arc::clone(&self.next_index),
+    /// With `stego_output`, hide the encrypted image a response carries in a
+    /// cover PNG; the metadata's SHA-256 still covers the encrypted image
+    async fn hide_image_data(&self, request_id: &str, mut response: ServerResponse) -> ServerResponse {
+        if !self.stego_output {
+            return response;
+        }
+        let Some(data) = response.image_data_mut() else {
+            return response;
+        };
+        let payload = std::mem::take(data);
+        let cover = self.stego_cover.clone();
+        let hidden = tokio::task::spawn_blocking(move || match cover {
+            Some(cover) => stego::embed(&cover, &payload),
+            None => stego::default_cover(payload.len()).and_then(|cover| stego::embed(&cover, &payload)),
+        })
+        .await;
+        match hidden {
+            Ok(Ok(png)) => {
+                *data = png;
+                response
+            }
+            Ok(Err(e)) => {
+                eprintln!("Node {} [{}]: Cannot hide the image in the cover: {}", self.id, request_id, e);
+                ServerResponse::Error {
+                    message: format!("Cannot hide the image in the cover: {}", e),
+                    code: ErrorCode::Internal,
+                }
+            }
+            Err(e) => ServerResponse::Error {
+                message: format!("Hiding the image failed: {}", e),
+                code: ErrorCode::Internal,
+            },
         }
     }
-}
 
-#[tokio::main]
-async fn main() {
-    let args: Vec<String> = env::args().collect();
+    /// Replace a request's compressed image bytes with the original ones
+    fn decompress_request(&self, request_id: &str, codec: &Codec, body: &mut ClientRequest) -> Result<(), ServerResponse> {
+        if let Codec::Unknown(name) = codec {
+            println!("Node {} [{}]: Request uses unsupported codec {}", self.id, request_id, name);
+            return Err(ServerResponse::UnsupportedCodec { codec: name.clone() });
+        }
+        let Some(data) = body.image_data_mut() else {
+            return Ok(());
+        };
+        match decompress(codec, data, MAX_UPLOAD_SIZE as usize) {
+            Ok(original) => {
+                *data = original;
+                Ok(())
+            }
+            Err(e) => {
+                println!("Node {} [{}]: Cannot decompress request: {}", self.id, request_id, e);
+                Err(ServerResponse::DecompressFailed { message: e.to_string() })
+            }
+        }
+    }
 
-    if args.len() < 2 {
-        eprintln!("Usage: {} <node_id>", args[0]);
-        eprintln!("Example: {} 1", args[0]);
-        std::process::exit(1);
+    /// Handle a client request, counting it towards this node's load
+    async fn serve_client(&self, request_id: &str, request: ClientRequest, progress: &Progress) -> ServerResponse {
+        // Names end up in paths and hashes; refuse bad ones before anything else
+        if let Err(e) = validate_request(&request, MAX_UPLOAD_SIZE as usize) {
+            println!("Node {} [{}]: Refused invalid request: {}", self.id, request_id, e);
+            return bad_request(e);
+        }
+        self.bully.load.fetch_add(1, Ordering::Relaxed);
+        let response = self.handle_client_request(request_id, request, progress).await;
+        self.bully.load.fetch_sub(1, Ordering::Relaxed);
+        response
     }
 
-    let node_id: u32 = args[1].parse().expect("Node ID must be a number");
+    /// Answer an untagged JSON client or admin request, `None` if `payload`
+    /// is neither
+    ///
+    /// Internal messages are only taken signed, in tagged frames.
+    async fn answer_request(&self, payload: &[u8]) -> Option<String> {
+        // Try to parse as a client request with an id
+        if let Ok(envelope) = serde_json::from_slice::<RequestEnvelope>(payload) {
+            return serde_json::to_string(&self.serve_envelope(envelope, &Progress::default()).await).ok();
+        }
 
-    // Load configuration from config.toml
-    let config = Config::load("config.toml").expect("Failed to load config.toml");
+        // Try to parse as an admin request, which must carry the admin token
+        if let Ok(envelope) = serde_json::from_slice::<AdminEnvelope>(payload) {
+            let response = match self.check_admin_token(&envelope.admin_token) {
+                Ok(()) => self.handle_admin_request(envelope.request).await,
+                Err(response) => response,
+            };
+            return serde_json::to_string(&response).ok();
+        }
+        if serde_json::from_slice::<AdminRequest>(payload).is_ok() {
+            println!("Node {}: Refused admin request without an admin token", self.id);
+            let response = AdminResponse::Error {
+                message: "Admin requests must be sent as {\"admin_token\": ..., \"request\": ...}".to_string(),
+            };
+            return serde_json::to_string(&response).ok();
+        }
 
-    // Get this node's address from config
-    let address = config
-        .get_server_address(node_id)
-        .expect(&format!("Node {} not found in config.toml", node_id));
+        // Clients without request ids get a bare response
+        if let Ok(request) = serde_json::from_slice::<ClientRequest>(payload) {
+            let response = self.serve_client("-", request, &Progress::default()).await;
+            return serde_json::to_string(&response).ok();
+        }
 
-    println!("Node {} will bind to {}", node_id, address);
+        None
+    }
+
+    /// Answer bully frames, starting with `first`, until the peer hangs up
+    ///
+    /// Followers keep their heartbeat connection open, so this may serve many
+    /// frames on one connection.
+    async fn serve_bully_frames<R, W>(&self, first: Vec<u8>, reader: &mut R, writer: &mut W)
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut next = Some(first);
+        loop {
+            let frame = match next.take() {
+                Some(frame) => frame,
+                None => match read_frame(reader, MAX_FRAME_LEN).await {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => return,
+                    Err(e) => {
+                        eprintln!("Node {}: Bad bully frame: {}", self.id, e);
+                        return;
+                    }
+                },
+            };
+            let Ok(msg) = self.bully.decode(&frame) else {
+                return;
+            };
+            if let Some(response) = self.bully.handle_message(msg).await {
+                let Ok(response_json) = self.bully.encode(&response) else {
+                    return;
+                };
+                if write_frame(writer, response_json.as_bytes(), MAX_FRAME_LEN).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
 
-    let mut node = ServerNode::new(node_id, address);
+    /// Check the credential an admin request came with against `admin_token`
+    fn check_admin_token(&self, presented: &str) -> Result<(), AdminResponse> {
+        let Some(expected) = &self.admin_token else {
+            println!("Node {}: Refused admin request, no admin_token is configured", self.id);
+            return Err(AdminResponse::Error {
+                message: "Admin requests are disabled on this node; set admin_token to enable them".to_string(),
+            });
+        };
+        if !bool::from(expected.as_bytes().ct_eq(presented.as_bytes())) {
+            println!("Node {}: Refused admin request with a wrong admin token", self.id);
+            return Err(AdminResponse::Error {
+                message: "Wrong admin token".to_string(),
+            });
+        }
+        Ok(())
+    }
 
-    // Add peers from config
-    for peer_id in 1..=3 {
-        if peer_id != node_id {
-            if let Some(peer_address) = config.get_server_address(peer_id) {
-                node.add_peer(peer_id, peer_address).await;
+    async fn handle_admin_request(&self, request: AdminRequest) -> AdminResponse {
+        match request {
+            AdminRequest::RemovePeer { node_id } => {
+                if node_id == self.id {
+                    return AdminResponse::Error {
+                        message: "Cannot remove this node from itself".to_string(),
+                    };
+                }
+                if let Err(e) = self.bully.remove_peer(node_id).await {
+                    return AdminResponse::Error { message: e.to_string() };
+                }
+                if let Some(lb) = self.load_balancer.read().await.as_ref() {
+                    lb.unregister_server(node_id).await;
+                }
+                AdminResponse::Ok {
+                    message: format!("Node {} removed", node_id),
+                }
+            }
+            AdminRequest::UpdatePeer { node_id, address } => {
+                if let Err(e) = self.bully.update_peer(node_id, address.clone()).await {
+                    return AdminResponse::Error { message: e.to_string() };
+                }
+                if let Some(lb) = self.load_balancer.read().await.as_ref() {
+                    lb.update_server_address(node_id, address.clone()).await;
+                }
+                AdminResponse::Ok {
+                    message: format!("Node {} now at {}", node_id, address),
+                }
+            }
+            AdminRequest::TransferLeadership { node_id } => {
+                match self.bully.transfer_leadership(node_id).await {
+                    Ok(()) => AdminResponse::Ok {
+                        message: format!("Node {} is now the leader", node_id),
+                    },
+                    Err(e) => AdminResponse::Error { message: e.to_string() },
+                }
+            }
+            AdminRequest::ClusterView { refresh } => {
+                let peers = self
+                    .bully
+                    .cluster_view(refresh)
+                    .await
+                    .into_iter()
+                    .map(|p| PeerStatus {
+                        id: p.id,
+                        address: p.address,
+                        reachable: p.reachable,
+                        is_leader: p.is_leader,
+                        last_seen_secs: p.last_seen_secs,
+                    })
+                    .collect();
+                AdminResponse::ClusterView {
+                    node_id: self.id,
+                    leader: self.bully.get_leader().await,
+                    peers,
+                }
+            }
+            AdminRequest::SetQuota { username, quota_bytes } => {
+                if let Err(e) = validate_username("username", &username) {
+                    return AdminResponse::Error { message: e.to_string() };
+                }
+                if let Err(e) = self.quotas.set_limit(&username, quota_bytes) {
+                    return AdminResponse::Error {
+                        message: format!("Failed to save quotas: {}", e),
+                    };
+                }
+                let message = InternalMessage::SetQuota {
+                    username: username.clone(),
+                    quota_bytes,
+                };
+                let mut missed = Vec::new();
+                for peer in self.bully.get_all_peers().await {
+                    if peer.id == self.id {
+                        continue;
+                    }
+                    match self.ask_peer(&peer.address, &message).await {
+                        Some(InternalMessage::ProcessingComplete { success: true, .. }) => {}
+                        _ => missed.push(peer.id),
+                    }
+                }
+                let quota = match quota_bytes {
+                    Some(0) => "no quota".to_string(),
+                    Some(bytes) => format!("a quota of {} bytes", bytes),
+                    None => "the default quota".to_string(),
+                };
+                let message = if missed.is_empty() {
+                    format!("{} now has {}", username, quota)
+                } else {
+                    format!("{} now has {}, except on nodes {:?}; send it again once they are back", username, quota, missed)
+                };
+                AdminResponse::Ok { message }
+            }
+            AdminRequest::Status => {
+                let stats = self.bully.stats().await;
+                AdminResponse::Status(Box::new(NodeStatus {
+                    node_id: self.id,
+                    leader: self.bully.get_leader().await,
+                    term: self.bully.get_term().await,
+                    elections_started: stats.elections_started,
+                    elections_won: stats.elections_won,
+                    coordinators_received: stats.coordinators_received,
+                    heartbeat_failures: stats.heartbeat_failures,
+                    last_election_at_ms: stats
+                        .last_election_at
+                        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                        .map(|d| d.as_millis() as u64),
+                    last_election_duration_ms: stats
+                        .last_election_duration
+                        .map(|d| d.as_millis() as u64),
+                    leader_uptime_secs: stats.leader_uptime().map(|d| d.as_secs()),
+                    rtt_ewma_ms: stats.rtt_ewma.map(|d| d.as_millis() as u64),
+                    heartbeat_timeout_ms: stats.effective_heartbeat_timeout.as_millis() as u64,
+                    heartbeat_interval_ms: stats.effective_heartbeat_interval.as_millis() as u64,
+                    peer_liveness: self.bully.peer_liveness().await,
+                    election_status: format!("{:?}", self.bully.election_status().await),
+                    leader_history: self
+                        .bully
+                        .leader_history()
+                        .into_iter()
+                        .map(|t| LeaderTransitionInfo {
+                            at_ms: t
+                                .at
+                                .duration_since(std::time::UNIX_EPOCH)
+                                .map(|d| d.as_millis() as u64)
+                                .unwrap_or(0),
+                            old_leader: t.old_leader,
+                            new_leader: t.new_leader,
+                            reason: t.reason.as_str().to_string(),
+                        })
+                        .collect(),
+                    peer_latency: self
+                        .bully
+                        .latency_report()
+                        .into_iter()
+                        .map(|r| PeerLatency {
+                            peer_id: r.peer_id,
+                            samples: r.samples,
+                            p50_ms: r.p50.as_secs_f64() * 1000.0,
+                            p95_ms: r.p95.as_secs_f64() * 1000.0,
+                            max_ms: r.max.as_secs_f64() * 1000.0,
+                        })
+                        .collect(),
+                    degraded: self.bully.is_degraded().await,
+                    key_rotations: {
+                        let mut rotations: Vec<KeyRotationStatus> =
+                            self.rotations.lock().unwrap().values().cloned().collect();
+                        rotations.sort_by(|a, b| a.username.cmp(&b.username));
+                        rotations
+                    },
+                    announcement: {
+                        let status = self.bully.announcement_status().await;
+                        (status.term > 0).then(|| {
+                            let mut acked: Vec<u32> = status.acked.into_iter().collect();
+                            let mut unacked: Vec<u32> = status.unacked.into_iter().collect();
+                            acked.sort_unstable();
+                            unacked.sort_unstable();
+                            AnnouncementInfo {
+                                term: status.term,
+                                acked,
+                                unacked,
+                            }
+                        })
+                    },
+                }))
             }
         }
     }
 
-    node.start().await;
+    async fn handle_client_request(&self, request_id: &str, request: ClientRequest, progress: &Progress) -> ServerResponse {
+        println!("Node {} [{}]: Received client request", self.id, request_id);
+
+        if let Some((username, token)) = request.credentials() {
+            if let Err(response) = self.check_user(request_id, username, token) {
+                return response;
+            }
+        }
+
+        match request {
+            ClientRequest::Register { username, password } => {
+                if let Err(response) = self.check_leader(request_id).await {
+                    return response;
+                }
+                if password.is_empty() {
+                    return ServerResponse::Error {
+                        message: "Choose a non-empty password".to_string(),
+                        code: ErrorCode::BadRequest,
+                    };
+                }
+                let (name, secret) = (username.clone(), password.clone());
+                let registered = self
+                    .hashing_passwords(move |users| users.register(&name, &secret, unix_now()))
+                    .await
+                    .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+                match registered {
+                    Ok(true) => {
+                        println!("Node {} [{}]: Registered user {}", self.id, request_id, username);
+                        self.ensure_user_key(&username, &password).await;
+                        // Push the user out before answering, so the token and
+                        // image key are honored by whichever node the client
+                        // asks next
+                        self.sync_users().await;
+                        self.log_in(&username)
+                    }
+                    Ok(false) => ServerResponse::Error {
+                        message: format!("{} is already registered", username),
+                        code: ErrorCode::UserExists,
+                    },
+                    Err(e) => {
+                        eprintln!("Node {} [{}]: Failed to save the user registry: {}", self.id, request_id, e);
+                        ServerResponse::Error {
+                            message: format!("Failed to register {}", username),
+                            code: ErrorCode::Internal,
+                        }
+                    }
+                }
+            }
+            ClientRequest::Login { username, password } => {
+                if let Err(response) = self.check_leader(request_id).await {
+                    return response;
+                }
+                match self.check_password(&username, &password).await {
+                    PasswordCheck::Valid => {
+                        println!("Node {} [{}]: {} logged in", self.id, request_id, username);
+                        // Users from before Argon2id password hashes get one now
+                        let (name, secret) = (username.clone(), password.clone());
+                        let rehashed = match self.hashing_passwords(move |users| users.upgrade_password_hash(&name, &secret)).await {
+                            Ok(Ok(rehashed)) => rehashed,
+                            Ok(Err(e)) => {
+                                eprintln!("Node {} [{}]: Failed to save {}'s password hash: {}", self.id, request_id, username, e);
+                                false
+                            }
+                            Err(_) => false,
+                        };
+                        // Users from before password-derived keys get theirs
+                        // now, and their images are moved over to it
+                        let keyed = self.ensure_user_key(&username, &password).await;
+                        if rehashed || keyed {
+                            self.sync_users().await;
+                        }
+                        if keyed {
+                            self.upgrade_keys_in_background(&username);
+                        }
+                        self.log_in(&username)
+                    }
+                    PasswordCheck::UnknownUser => ServerResponse::Error {
+                        message: format!("{} is not registered", username),
+                        code: ErrorCode::UnknownUser,
+                    },
+                    PasswordCheck::WrongPassword => {
+                        println!("Node {} [{}]: Wrong password for {}", self.id, request_id, username);
+                        ServerResponse::Error {
+                            message: "Wrong password".to_string(),
+                            code: ErrorCode::WrongPassword,
+                        }
+                    }
+                }
+            }
+            ClientRequest::UploadImage {
+                username,
+                image_data,
+                filename,
+                sha256: expected,
+                strip_metadata,
+                compress,
+                ..
+            } => {
+                // The leader hands uploads out to workers, unless each node
+                // takes those assigned to it
+                let routed = match self.routing {
+                    Routing::Leader => self.check_leader(request_id).await,
+                    Routing::Hash => self.check_assignment(request_id, &username, &filename).await,
+                };
+                if let Err(response) = routed {
+                    return response;
+                }
+                if let Err(response) = self.claim_request(request_id).await {
+                    return response;
+                }
+                if !self.images.owns(&username, &filename) {
+                    return ServerResponse::Error {
+                        message: format!("Invalid filename {}", filename),
+                        code: ErrorCode::BadRequest,
+                    };
+                }
+                if let Err(response) = self.check_quota(request_id, &username, image_data.len() as u64).await {
+                    return response;
+                }
+                let image_data = match expected {
+                    Some(expected) => {
+                        let (image_data, digest) = digest_of(image_data).await;
+                        if digest != expected {
+                            println!("Node {} [{}]: Upload of {} failed its checksum", self.id, request_id, filename);
+                            return ServerResponse::ChecksumMismatch { filename };
+                        }
+                        image_data
+                    }
+                    None => image_data,
+                };
+
+                let options = self.upload_options(strip_metadata, compress);
+                match self.routing {
+                    Routing::Leader => {
+                        self.dispatch_upload(request_id, username, filename, image_data, options, progress).await
+                    }
+                    Routing::Hash => self.upload_here(request_id, username, filename, image_data, options, progress).await,
+                }
+            }
+            ClientRequest::UploadBatch {
+                username,
+                images,
+                strip_metadata,
+                compress,
+                ..
+            } => {
+                if let Err(response) = self.check_leader(request_id).await {
+                    return response;
+                }
+                if let Err(response) = self.claim_request(request_id).await {
+                    return response;
+                }
+                let total_bytes: u64 = images.iter().map(|(_, data)| data.len() as u64).sum();
+                if total_bytes > MAX_BATCH_BYTES {
+                    println!("Node {} [{}]: Batch of {} bytes is over the limit", self.id, request_id, total_bytes);
+                    return ServerResponse::BatchTooLarge {
+                        total_bytes,
+                        max_bytes: MAX_BATCH_BYTES,
+                    };
+                }
+                if let Err(response) = self.check_quota(request_id, &username, total_bytes).await {
+                    return response;
+                }
+
+                println!("Node {} [{}]: Processing batch of {} images for user {}",
+                    self.id, request_id, images.len(), username);
+                ServerResponse::BatchUploaded {
+                    results: self
+                        .upload_batch(request_id, username, images, self.upload_options(strip_metadata, compress))
+                        .await,
+                }
+            }
+            ClientRequest::BeginUpload {
+                username,
+                filename,
+                total_size,
+                chunk_size,
+                strip_metadata,
+                compress,
+                ..
+            } => {
+                if !self.images.owns(&username, &filename) {
+                    return ServerResponse::Error {
+                        message: format!("Invalid filename {}", filename),
+                        code: ErrorCode::BadRequest,
+                    };
+                }
+                if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE || total_size > MAX_UPLOAD_SIZE {
+                    return ServerResponse::Error {
+                        message: format!(
+                            "Uploads are limited to {} bytes in chunks of at most {} bytes",
+                            MAX_UPLOAD_SIZE, MAX_CHUNK_SIZE
+                        ),
+                        code: ErrorCode::BadRequest,
+                    };
+                }
+                if let Err(response) = self.check_assignment(request_id, &username, &filename).await {
+                    return response;
+                }
+                if let Err(response) = self.claim_request(request_id).await {
+                    return response;
+                }
+                if let Err(response) = self.check_quota(request_id, &username, total_size).await {
+                    return response;
+                }
+                self.discard_expired_uploads().await;
+
+                let upload_id = uuid::Uuid::new_v4().to_string();
+                if let Err(e) = self.images.begin_upload(&upload_id).await {
+                    eprintln!("Node {} [{}]: Failed to start upload: {}", self.id, request_id, e);
+                    return ServerResponse::Error {
+                        message: format!("Failed to start upload of {}", filename),
+                        code: ErrorCode::Internal,
+                    };
+                }
+                println!("Node {} [{}]: Started upload {} of {} for user {} ({} bytes)",
+                    self.id, request_id, upload_id, filename, username, total_size);
+                self.uploads.lock().unwrap().insert(
+                    upload_id.clone(),
+                    UploadSession {
+                        username,
+                        filename,
+                        total_size,
+                        chunk_size,
+                        options: self.upload_options(strip_metadata, compress),
+                        received: HashSet::new(),
+                        started: Instant::now(),
+                    },
+                );
+                ServerResponse::UploadStarted { upload_id }
+            }
+            ClientRequest::UploadChunk { upload_id, seq, data } => {
+                let (chunk_size, expected_len) = {
+                    let uploads = self.uploads.lock().unwrap();
+                    let Some(session) = uploads.get(&upload_id) else {
+                        return unknown_upload(&upload_id);
+                    };
+                    if seq >= session.chunk_count() {
+                        return ServerResponse::Error {
+                            message: format!("Chunk {} is past the end of upload {}", seq, upload_id),
+                            code: ErrorCode::BadRequest,
+                        };
+                    }
+                    (session.chunk_size, session.chunk_len(seq))
+                };
+                if data.len() as u64 != expected_len {
+                    return ServerResponse::Error {
+                        message: format!("Chunk {} should be {} bytes, got {}", seq, expected_len, data.len()),
+                        code: ErrorCode::BadRequest,
+                    };
+                }
+
+                if let Err(e) = self.images.write_chunk(&upload_id, seq * chunk_size, &data).await {
+                    eprintln!("Node {} [{}]: Failed to write chunk {} of {}: {}",
+                        self.id, request_id, seq, upload_id, e);
+                    return ServerResponse::Error {
+                        message: format!("Failed to store chunk {}", seq),
+                        code: ErrorCode::Internal,
+                    };
+                }
+                match self.uploads.lock().unwrap().get_mut(&upload_id) {
+                    Some(session) => {
+                        session.received.insert(seq);
+                    }
+                    None => return unknown_upload(&upload_id),
+                }
+                ServerResponse::ChunkReceived { upload_id, seq }
+            }
+            ClientRequest::FinishUpload { upload_id, sha256 } => {
+                let session = {
+                    let mut uploads = self.uploads.lock().unwrap();
+                    let Some(session) = uploads.get(&upload_id) else {
+                        return unknown_upload(&upload_id);
+                    };
+                    let missing = session.missing();
+                    if !missing.is_empty() {
+                        println!("Node {} [{}]: Upload {} is missing chunks {:?}",
+                            self.id, request_id, upload_id, missing);
+                        return ServerResponse::MissingChunks { upload_id, missing };
+                    }
+                    uploads.remove(&upload_id).unwrap()
+                };
+
+                let hashed = self
+                    .images
+                    .hash_upload(&upload_id, |bytes| progress.report(bytes, UploadStage::Receiving))
+                    .await;
+                let (content_id, original_size) = match hashed {
+                    Ok((digest, _)) if !digest.eq_ignore_ascii_case(&sha256) => {
+                        println!("Node {} [{}]: Upload {} failed its checksum", self.id, request_id, upload_id);
+                        self.images.discard_upload(&upload_id).await;
+                        return ServerResponse::ChecksumMismatch {
+                            filename: session.filename,
+                        };
+                    }
+                    Ok(hashed) => hashed,
+                    Err(e) => {
+                        eprintln!("Node {} [{}]: Failed to assemble upload {}: {}",
+                            self.id, request_id, upload_id, e);
+                        self.images.discard_upload(&upload_id).await;
+                        return ServerResponse::Error {
+                            message: format!("Failed to assemble {}", session.filename),
+                            code: ErrorCode::Internal,
+                        };
+                    }
+                };
+
+                let response = self
+                    .process_assembled_upload(request_id, &upload_id, &session, &content_id, original_size, progress)
+                    .await;
+                self.images.discard_upload(&upload_id).await;
+                if matches!(response, ServerResponse::EncryptedImageData { .. }) {
+                    self.drop_stale_copies(&session.username, &session.filename, self.id);
+                    self.usage_changed(&session.username).await;
+                }
+                response
+            }
+            ClientRequest::DownloadImage { username, image, .. } => {
+                let filename = match self.resolve_image(request_id, &username, image).await {
+                    Ok(filename) => filename,
+                    Err(response) => return response,
+                };
+
+                self.download(request_id, username, filename).await
+            }
+            ClientRequest::GetThumbnail { username, image, .. } => {
+                let filename = match self.resolve_image(request_id, &username, image).await {
+                    Ok(filename) => filename,
+                    Err(response) => return response,
+                };
+                self.thumbnail(request_id, username, filename).await
+            }
+            ClientRequest::RegisterPublicKey { username, public_key, .. } => {
+                // Registry changes go through the leader, like passwords
+                if let Err(response) = self.check_leader(request_id).await {
+                    return response;
+                }
+                if !is_valid_public_key(&public_key) {
+                    return ServerResponse::Error {
+                        message: "Not a usable X25519 public key".to_string(),
+                        code: ErrorCode::BadRequest,
+                    };
+                }
+                match self.users.set_public_key(&username, &public_key, unix_now_ms()) {
+                    Ok(true) => {
+                        println!("Node {} [{}]: {} registered a public key", self.id, request_id, username);
+                        // Views pick it up on whichever node stores the image
+                        self.sync_users().await;
+                        ServerResponse::PublicKeyRegistered { username }
+                    }
+                    Ok(false) => ServerResponse::Error {
+                        message: format!("{} is not registered", username),
+                        code: ErrorCode::UnknownUser,
+                    },
+                    Err(e) => {
+                        eprintln!("Node {} [{}]: Failed to save {}'s public key: {}", self.id, request_id, username, e);
+                        ServerResponse::Error {
+                            message: "Failed to register the public key".to_string(),
+                            code: ErrorCode::Internal,
+                        }
+                    }
+                }
+            }
+            // Without a stream to write to, the image is sent in one piece
+            ClientRequest::StreamDownload { username, filename, .. } => {
+                if let Err(response) = self.check_assignment(request_id, &username, &filename).await {
+                    return response;
+                }
+                self.download(request_id, username, filename).await
+            }
+            // Subscriptions hold their connection open and are served before
+            // requests reach here
+            ClientRequest::Subscribe { .. } => ServerResponse::Error {
+                message: "Subscribe needs a connection of its own".to_string(),
+                code: ErrorCode::BadRequest,
+            },
+            ClientRequest::DeleteImage { username, image, .. } => {
+                let filename = match self.resolve_image(request_id, &username, image).await {
+                    Ok(filename) => filename,
+                    Err(response) => return response,
+                };
+                // Names are per-user directories; anything reaching outside
+                // the user's own is someone else's file
+                if !self.images.owns(&username, &filename) {
+                    println!("Node {} [{}]: {} may not delete {}", self.id, request_id, username, filename);
+                    return ServerResponse::Forbidden {
+                        message: format!("{} is not one of {}'s images", filename, username),
+                    };
+                }
+
+                println!("Node {} [{}]: Deleting image {} for user {}", self.id, request_id, filename, username);
+                let mut deleted = match self.images.delete(&username, &filename).await {
+                    Ok(deleted) => deleted,
+                    Err(e) => {
+                        eprintln!("Node {} [{}]: Failed to delete {}: {}", self.id, request_id, filename, e);
+                        return ServerResponse::Error {
+                            message: format!("Failed to delete {}", filename),
+                            code: ErrorCode::Internal,
+                        };
+                    }
+                };
+                // Copies may live on other nodes, e.g. after membership changed
+                deleted |= self.delete_replicas(&username, &filename).await;
+
+                if deleted {
+                    self.usage_changed(&username).await;
+                    ServerResponse::Deleted { filename }
+                } else {
+                    ServerResponse::NotFound { filename }
+                }
+            }
+            ClientRequest::UpdateImageMeta {
+                username,
+                filename,
+                new_filename,
+                tags,
+                caption,
+                ..
+            } => {
+                let renames_outside = new_filename.as_ref().is_some_and(|name| !self.images.owns(&username, name));
+                if !self.images.owns(&username, &filename) || renames_outside {
+                    return ServerResponse::Forbidden {
+                        message: format!("{} is not one of {}'s images", filename, username),
+                    };
+                }
+                if new_filename.is_none() && tags.is_none() && caption.is_none() {
+                    return ServerResponse::Error {
+                        message: "Give a new name, tags or a caption".to_string(),
+                        code: ErrorCode::BadRequest,
+                    };
+                }
+                if let Err(response) = self.check_assignment(request_id, &username, &filename).await {
+                    return response;
+                }
+                // Check every node before renaming any copy, so a collision
+                // never leaves the copies under different names
+                if let Some(new_name) = new_filename.as_ref().filter(|&name| *name != filename) {
+                    if self.image_exists(&username, new_name).await {
+                        return ServerResponse::Error {
+                            message: format!("{} already has an image named {}", username, new_name),
+                            code: ErrorCode::NameTaken,
+                        };
+                    }
+                }
+
+                println!("Node {} [{}]: Updating {} for user {}", self.id, request_id, filename, username);
+                let update = InternalMessage::UpdateImageMeta {
+                    username: username.clone(),
+                    filename: filename.clone(),
+                    new_filename: new_filename.clone(),
+                    tags,
+                    caption,
+                };
+                let (mut found, mut updated) = (false, None);
+                match self.apply_meta_update(update.clone()).await {
+                    InternalMessage::MetaUpdated { metadata } => (found, updated) = (true, metadata),
+                    InternalMessage::ImageNotFound => {}
+                    InternalMessage::NameTaken => {
+                        return ServerResponse::Error {
+                            message: format!("{} already has an image named {}", username, new_filename.unwrap_or_default()),
+                            code: ErrorCode::NameTaken,
+                        };
+                    }
+                    answer => {
+                        eprintln!("Node {} [{}]: Failed to update {}: {:?}", self.id, request_id, filename, answer);
+                        let reason = match answer {
+                            InternalMessage::ProcessingComplete { message, .. } => message,
+                            _ => "unexpected answer".to_string(),
+                        };
+                        return ServerResponse::Error {
+                            message: format!("Cannot update {}: {}", filename, reason),
+                            code: ErrorCode::Internal,
+                        };
+                    }
+                }
+                // Copies may live on other nodes, e.g. after membership changed
+                for peer in self.bully.get_all_peers().await {
+                    if peer.id == self.id {
+                        continue;
+                    }
+                    match self.ask_peer(&peer.address, &update).await {
+                        Some(InternalMessage::MetaUpdated { metadata }) => {
+                            found = true;
+                            updated = updated.or(metadata);
+                        }
+                        Some(InternalMessage::ImageNotFound) => {}
+                        answer => println!("Node {} [{}]: Could not update {} on Node {}: {:?}",
+                            self.id, request_id, filename, peer.id, answer),
+                    }
+                }
+
+                if found {
+                    ServerResponse::ImageUpdated {
+                        filename: new_filename.unwrap_or(filename),
+                        metadata: updated.map(Box::new),
+                    }
+                } else {
+                    ServerResponse::NotFound { filename }
+                }
+            }
+            ClientRequest::ShareImage {
+                owner,
+                image,
+                with_user,
+                allowed_views,
+                ..
+            } => {
+                if allowed_views == 0 || with_user.is_empty() || with_user == owner {
+                    return ServerResponse::Error {
+                        message: "Share with another user and at least one view".to_string(),
+                        code: ErrorCode::BadRequest,
+                    };
+                }
+                let filename = match self.resolve_image(request_id, &owner, image).await {
+                    Ok(filename) => filename,
+                    Err(response) => return response,
+                };
+                if !self.images.owns(&owner, &filename) {
+                    return ServerResponse::Forbidden {
+                        message: format!("{} is not one of {}'s images", filename, owner),
+                    };
+                }
+
+                println!("Node {} [{}]: {} shares {} with {} ({} views)",
+                    self.id, request_id, owner, filename, with_user, allowed_views);
+                // The grant lives next to the image so every node routes
+                // views to the same record
+                let granted = match self.grant_access(&owner, &filename, &with_user, allowed_views).await {
+                    Ok(true) => true,
+                    Ok(false) => {
+                        let request = InternalMessage::GrantAccess {
+                            owner: owner.clone(),
+                            filename: filename.clone(),
+                            with_user: with_user.clone(),
+                            allowed_views,
+                        };
+                        self.ask_storing_peer(&request).await.is_some()
+                    }
+                    Err(e) => {
+                        eprintln!("Node {} [{}]: Failed to record share of {}: {}", self.id, request_id, filename, e);
+                        return ServerResponse::Error {
+                            message: format!("Failed to share {}", filename),
+                            code: ErrorCode::Internal,
+                        };
+                    }
+                };
+
+                if granted {
+                    self.push_event(&with_user, ServerEvent::QuotaChanged {
+                        owner,
+                        filename: filename.clone(),
+                        allowed_views,
+                        used_views: 0,
+                    });
+                    ServerResponse::Shared { filename, with_user, allowed_views }
+                } else {
+                    ServerResponse::NotFound { filename }
+                }
+            }
+            ClientRequest::ViewSharedImage {
+                viewer,
+                owner,
+                filename,
+                ..
+            } => {
+                if let Err(response) = self.check_assignment(request_id, &owner, &filename).await {
+                    return response;
+                }
+
+                println!("Node {} [{}]: {} views {}'s image {}", self.id, request_id, viewer, owner, filename);
+                let mut answer = self.consume_view(&viewer, &owner, &filename).await;
+                // Where the image and its access record are, None for here
+                let mut stored_at = None;
+                if matches!(answer, InternalMessage::ImageNotFound) {
+                    let request = InternalMessage::ConsumeView {
+                        viewer: viewer.clone(),
+                        owner: owner.clone(),
+                        filename: filename.clone(),
+                    };
+                    answer = match self.ask_storing_peer(&request).await {
+                        Some((address, answer)) => {
+                            stored_at = Some(address);
+                            answer
+                        }
+                        None => InternalMessage::ImageNotFound,
+                    };
+                }
+
+                match answer {
+                    InternalMessage::ViewGranted {
+                        data,
+                        remaining_views,
+                        wrapped_key,
+                    } => {
+                        // Hand the viewer a copy only their key opens: the
+                        // image as stored if its data key was wrapped to
+                        // their public key, else one re-encrypted here
+                        let data = match wrapped_key {
+                            Some(_) => data,
+                            None => match self.reencrypt_for_viewer(request_id, &data, &owner, &viewer, &filename).await {
+                                Ok(data) => data,
+                                Err(response) => return response,
+                            },
+                        };
+                        let notice = ViewNotice {
+                            viewer: viewer.clone(),
+                            filename: filename.clone(),
+                            timestamp: unix_now(),
+                            remaining_views,
+                            seq: unix_now_ms(),
+                        };
+                        let node = self.clone_for_task();
+                        let notified = owner.clone();
+                        tokio::spawn(async move {
+                            node.notify_view(stored_at, notified, notice).await;
+                        });
+
+                        match wrapped_key {
+                            Some(wrapped_key) => ServerResponse::SealedSharedImage {
+                                owner,
+                                filename,
+                                data,
+                                wrapped_key,
+                                remaining_views,
+                            },
+                            None => ServerResponse::SharedImageData { owner, filename, data, remaining_views },
+                        }
+                    }
+                    InternalMessage::NotShared => ServerResponse::Forbidden {
+                        message: format!("{} has not shared {} with {}", owner, filename, viewer),
+                    },
+                    InternalMessage::QuotaExhausted => ServerResponse::QuotaExhausted { filename },
+                    InternalMessage::ProcessingComplete { message, .. } => ServerResponse::Error {
+                        message,
+                        code: ErrorCode::Internal,
+                    },
+                    _ => ServerResponse::NotFound { filename },
+                }
+            }
+            ClientRequest::UpdateAccess {
+                owner,
+                filename,
+                with_user,
+                new_allowed_views,
+                ..
+            } => {
+                if !self.images.owns(&owner, &filename) {
+                    return ServerResponse::Forbidden {
+                        message: format!("{} is not one of {}'s images", filename, owner),
+                    };
+                }
+                if let Err(response) = self.check_assignment(request_id, &owner, &filename).await {
+                    return response;
+                }
+
+                println!("Node {} [{}]: {} sets {}'s views of {} to {}",
+                    self.id, request_id, owner, with_user, filename, new_allowed_views);
+                let local = match self
+                    .images
+                    .update_access(&owner, &filename, &with_user, new_allowed_views)
+                    .await
+                {
+                    Ok(grant) => grant.map(|g| (g.allowed_views, g.used_views)),
+                    Err(e) => {
+                        eprintln!("Node {} [{}]: Failed to update access to {}: {}", self.id, request_id, filename, e);
+                        return ServerResponse::Error {
+                            message: format!("Failed to update access to {}", filename),
+                            code: ErrorCode::Internal,
+                        };
+                    }
+                };
+                // Every node holding a copy gets the update, so a view served
+                // from any of them sees the new quota
+                let remote = self
+                    .update_access_on_peers(&owner, &filename, &with_user, new_allowed_views)
+                    .await;
+
+                match local.or(remote) {
+                    Some((allowed_views, used_views)) => {
+                        self.push_event(&with_user, ServerEvent::QuotaChanged {
+                            owner,
+                            filename: filename.clone(),
+                            allowed_views,
+                            used_views,
+                        });
+                        ServerResponse::AccessUpdated {
+                            filename,
+                            with_user,
+                            allowed_views,
+                            used_views,
+                        }
+                    }
+                    None => ServerResponse::NotFound { filename },
+                }
+            }
+            ClientRequest::ListImages {
+                username,
+                token,
+                offset,
+                limit,
+                sort_by,
+                descending,
+                sealed_names,
+            } => {
+                // The leader answers for the whole cluster
+                if let Err(response) = self.check_leader(request_id).await {
+                    return response;
+                }
+
+                // Names are only opened for the user who proved who they are
+                let sealed = sealed_names || token.is_empty();
+                let keys = if sealed { Vec::new() } else { self.user_keys(&username) };
+                let mut images = self.images.list(&username, &keys).await;
+                images.extend(self.list_images_from_peers(&username, sealed).await);
+                // Sort the whole cluster's listing first so pages never overlap
+                images.sort_by(|a, b| {
+                    let order = match sort_by {
+                        ImageSort::Name => std::cmp::Ordering::Equal,
+                        ImageSort::UploadedAt => a.uploaded_at_unix.cmp(&b.uploaded_at_unix),
+                        ImageSort::Size => a.size_bytes.cmp(&b.size_bytes),
+                    };
+                    let order = order.then(a.filename.cmp(&b.filename)).then(a.node_id.cmp(&b.node_id));
+                    if descending { order.reverse() } else { order }
+                });
+
+                let total_count = images.len() as u64;
+                let limit = if limit == 0 { MAX_LIST_LIMIT } else { limit.min(MAX_LIST_LIMIT) };
+                let start = offset.min(total_count) as usize;
+                let end = (start + limit as usize).min(images.len());
+                let images: Vec<ImageEntry> = images.drain(start..end).collect();
+                let has_more = (end as u64) < total_count;
+                println!("Node {} [{}]: Listing {} of {} images for user {} from offset {}",
+                    self.id, request_id, images.len(), total_count, username, start);
+                ServerResponse::ImageList { images, total_count, has_more }
+            }
+            ClientRequest::GetQuota { username, .. } => {
+                if let Err(response) = self.check_leader(request_id).await {
+                    return response;
+                }
+                ServerResponse::Quota {
+                    used_bytes: self.cluster_usage(&username).await,
+                    limit_bytes: self.quotas.limit(&username),
+                }
+            }
+            ClientRequest::ChangePassword {
+                username,
+                old_password,
+                new_password,
+                ..
+            } => {
+                if let Err(response) = self.check_leader(request_id).await {
+                    return response;
+                }
+                if new_password.is_empty() {
+                    return ServerResponse::Error {
+                        message: "Choose a non-empty password".to_string(),
+                        code: ErrorCode::BadRequest,
+                    };
+                }
+                match self.check_password(&username, &old_password).await {
+                    PasswordCheck::Valid => {}
+                    PasswordCheck::UnknownUser => {
+                        return ServerResponse::Error {
+                            message: format!("{} is not registered", username),
+                            code: ErrorCode::UnknownUser,
+                        }
+                    }
+                    PasswordCheck::WrongPassword => {
+                        println!("Node {} [{}]: Wrong password for {}", self.id, request_id, username);
+                        return ServerResponse::Error {
+                            message: "Wrong password".to_string(),
+                            code: ErrorCode::WrongPassword,
+                        };
+                    }
+                }
+                // Users who have not logged in since password-derived keys
+                // need one before it can be replaced
+                if self.ensure_user_key(&username, &old_password).await {
+                    self.upgrade_keys_in_background(&username);
+                }
+
+                let key_salt = to_hex(&rand::random::<[u8; 16]>());
+                let (name, password, salt) = (username.clone(), new_password.clone(), key_salt.clone());
+                let derived = tokio::task::spawn_blocking(move || derive_user_key(&name, &password, salt.as_bytes())).await;
+                let changed = match derived {
+                    Ok(new_key) => {
+                        let wrapped = wrap_key(&self.key_wrap, &username, &new_key);
+                        let (name, password) = (username.clone(), new_password.clone());
+                        let changed = self
+                            .hashing_passwords(move |users| users.change_password(&name, &password, &key_salt, &wrapped))
+                            .await
+                            .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+                        self.key_cache.invalidate(&username);
+                        changed
+                    }
+                    Err(e) => Err(std::io::Error::other(e)),
+                };
+                match changed {
+                    Ok(Some(generation)) => {
+                        println!("Node {} [{}]: {} changed their password, image key generation {}",
+                            self.id, request_id, username, generation);
+                        // Every node must know the new key before images move to it
+                        self.sync_users().await;
+                        self.rotate_keys_in_background(&username);
+                        self.log_in(&username)
+                    }
+                    Ok(None) => ServerResponse::Error {
+                        message: format!("{} has no image key to replace", username),
+                        code: ErrorCode::Internal,
+                    },
+                    Err(e) => {
+                        eprintln!("Node {} [{}]: Failed to change {}'s password: {}", self.id, request_id, username, e);
+                        ServerResponse::Error {
+                            message: "Failed to change the password".to_string(),
+                            code: ErrorCode::Internal,
+                        }
+                    }
+                }
+            }
+            ClientRequest::ClusterStatus => {
+                // Answered from this node's own view; the probe is bounded,
+                // so unreachable peers cannot hold up the answer
+                let loads = self.bully.peer_loads();
+                let mut nodes: Vec<ClusterNodeStatus> = self
+                    .bully
+                    .cluster_view(true)
+                    .await
+                    .into_iter()
+                    .filter(|peer| peer.id != self.id)
+                    .map(|peer| ClusterNodeStatus {
+                        current_load: loads.get(&peer.id).copied().flatten(),
+                        id: peer.id,
+                        address: peer.address,
+                        reachable: peer.reachable,
+                        is_self: false,
+                    })
+                    .collect();
+                nodes.push(ClusterNodeStatus {
+                    id: self.id,
+                    address: self.address.clone(),
+                    reachable: true,
+                    current_load: Some(self.bully.load.load(Ordering::Relaxed)),
+                    is_self: true,
+                });
+                nodes.sort_by_key(|node| node.id);
+                ServerResponse::ClusterStatus {
+                    leader_id: self.bully.get_leader().await,
+                    nodes,
+                }
+            }
+            ClientRequest::FetchNotifications { username, .. } => {
+                // The leader collects the queues of every node
+                if let Err(response) = self.check_leader(request_id).await {
+                    return response;
+                }
+
+                let mut notifications = self.take_notifications(&username).await;
+                let request = InternalMessage::FetchNotifications {
+                    username: username.clone(),
+                };
+                for peer in self.bully.get_all_peers().await {
+                    if peer.id == self.id {
+                        continue;
+                    }
+                    // Views queued on an unreachable node are fetched next time
+                    match self.ask_peer(&peer.address, &request).await {
+                        Some(InternalMessage::Notifications { notifications: queued }) => notifications.extend(queued),
+                        _ => println!("Node {} [{}]: Could not fetch notifications from Node {}", self.id, request_id, peer.id),
+                    }
+                }
+                notifications.sort_by_key(|notice| notice.timestamp);
+                println!("Node {} [{}]: {} notifications for user {}", self.id, request_id, notifications.len(), username);
+                ServerResponse::Notifications { notifications }
+            }
+        }
+    }
+
+    /// Drop chunked uploads that were never finished
+    async fn discard_expired_uploads(&self) {
+        let expired: Vec<String> = {
+            let mut uploads = self.uploads.lock().unwrap();
+            let expired = uploads
+                .iter()
+                .filter(|(_, session)| session.started.elapsed() > UPLOAD_TTL)
+                .map(|(id, _)| id.clone())
+                .collect::<Vec<_>>();
+            for id in &expired {
+                uploads.remove(id);
+            }
+            expired
+        };
+        for id in expired {
+            println!("Node {}: Discarding abandoned upload {}", self.id, id);
+            self.images.discard_upload(&id).await;
+        }
+    }
+
+    /// An upload's options, the server's settings where it leaves them open
+    fn upload_options(&self, strip_metadata: Option<bool>, compress: Option<bool>) -> UploadOptions {
+        UploadOptions {
+            strip_metadata: strip_metadata.unwrap_or(self.strip_metadata),
+            compress: compress.unwrap_or(self.compress_storage),
+        }
+    }
+
+    /// Encrypt an uploaded image, store it with its metadata and answer the client
+    ///
+    /// With `strip_metadata`, the image's EXIF and such are removed first;
+    /// an image they cannot be removed from is stored as it is. With
+    /// `compress`, it is compressed before it is encrypted unless that
+    /// hardly saves anything.
+    async fn process_upload(
+        &self,
+        request_id: &str,
+        username: String,
+        filename: String,
+        image_data: Vec<u8>,
+        options: UploadOptions,
+        progress: &Progress,
+    ) -> ServerResponse {
+        // Process the request
+        println!("Node {} [{}]: Processing image upload for user {} ({})",
+            self.id, request_id, username, filename);
+        let original_size = image_data.len() as u64;
+        progress.report(original_size, UploadStage::Encrypting);
+        let image_data = Zeroizing::new(image_data);
+        let (content_id, image_data, metadata_strip, thumbnail) = blocking(move || {
+            let content_id = sha256_hex(&image_data);
+            let (image_data, metadata_strip) = match options.strip_metadata {
+                true => match metastrip::strip(&image_data) {
+                    Ok(stripped) => {
+                        let bytes_removed = (image_data.len() - stripped.len()) as u64;
+                        (Zeroizing::new(stripped), Some(MetadataStrip::Stripped { bytes_removed }))
+                    }
+                    Err(e) => (image_data, Some(MetadataStrip::NotStripped { reason: e.to_string() })),
+                },
+                false => (image_data, None),
+            };
+            let thumbnail = thumbnail::make(&image_data).map(Zeroizing::new);
+            (content_id, image_data, metadata_strip, thumbnail)
+        })
+        .await;
+        match &metadata_strip {
+            Some(MetadataStrip::Stripped { bytes_removed }) => println!("Node {} [{}]: Stripped {} bytes of metadata from {}",
+                self.id, request_id, bytes_removed, filename),
+            Some(MetadataStrip::NotStripped { reason }) => println!("Node {} [{}]: Storing {} with its metadata: {}",
+                self.id, request_id, filename, reason),
+            None => {}
+        }
+
+        // Encrypt the image data, bound to its owner and name
+        let sealed = match options.compress {
+            true => {
+                let sealed = self.seal_compressed(image_data, &username, &filename).await;
+                sealed.map(|(data, compression)| (data, Some(compression)))
+            }
+            false => self.seal(image_data, &username, &filename).await.map(|data| (data, None)),
+        };
+        let (encrypted_data, storage_compression) = match sealed {
+            Ok(sealed) => sealed,
+            Err(message) => {
+                eprintln!("Node {} [{}]: Not storing {}: {}", self.id, request_id, filename, message);
+                return ServerResponse::Error {
+                    message,
+                    code: ErrorCode::Internal,
+                };
+            }
+        };
+
+        match &storage_compression {
+            Some(StorageCompression::Zstd { compressed_size }) => println!("Node {} [{}]: Compressed {} to {} bytes",
+                self.id, request_id, filename, compressed_size),
+            Some(StorageCompression::Skipped { reason }) => println!("Node {} [{}]: Storing {} uncompressed: {}",
+                self.id, request_id, filename, reason),
+            None => {}
+        }
+        println!("Node {} [{}]: Image encrypted ({} bytes -> {} bytes)",
+            self.id, request_id, original_size, encrypted_data.len());
+
+        progress.report(original_size, UploadStage::Storing);
+        let (encrypted_data, content_sha256) = digest_of(encrypted_data).await;
+        let mut metadata = self.new_metadata(&filename, original_size, content_id, encrypted_data.len() as u64, content_sha256);
+
+        // Keep a copy so the image can be downloaded later
+        match self.images.store(&username, &filename, &encrypted_data).await {
+            Ok(path) => println!("Node {} [{}]: Stored image at {}", self.id, request_id, path.display()),
+            Err(e) => {
+                eprintln!("Node {} [{}]: Failed to store image: {}", self.id, request_id, e);
+                return ServerResponse::Error {
+                    message: format!("Failed to store {}", filename),
+                    code: ErrorCode::Internal,
+                };
+            }
+        }
+        metadata.thumbnail_error = self.store_thumbnail(request_id, &username, &filename, thumbnail).await;
+        metadata.metadata_strip = metadata_strip;
+        metadata.storage_compression = storage_compression;
+        if let Err(e) = self.images.store_metadata(&username, &metadata, &self.user_keys(&username)).await {
+            eprintln!("Node {} [{}]: Failed to store image metadata: {}", self.id, request_id, e);
+        }
+
+        // Return encrypted image to client
+        ServerResponse::EncryptedImageData {
+            metadata: Some(Box::new(metadata)),
+            data: encrypted_data,
+        }
+    }
+
+    /// `process_upload` for a chunked upload assembled on disk
+    ///
+    /// The image is encrypted a chunk at a time from the upload's file into
+    /// a file that is then moved into place, so it is never in memory
+    /// unencrypted. The stored image is read back once for the response.
+    async fn process_assembled_upload(
+        &self,
+        request_id: &str,
+        upload_id: &str,
+        session: &UploadSession,
+        content_id: &str,
+        original_size: u64,
+        progress: &Progress,
+    ) -> ServerResponse {
+        let (username, filename) = (&session.username, &session.filename);
+        println!("Node {} [{}]: Processing image upload for user {} ({})",
+            self.id, request_id, username, filename);
+        progress.report(original_size, UploadStage::Encrypting);
+        let user_key = match self.sealing_key(username) {
+            Ok(user_key) => user_key,
+            Err(message) => {
+                eprintln!("Node {} [{}]: Not storing {}: {}", self.id, request_id, filename, message);
+                return ServerResponse::Error {
+                    message,
+                    code: ErrorCode::Internal,
+                };
+            }
+        };
+
+        let thumbnail = self.assembled_thumbnail(upload_id, original_size).await;
+        let sealed = async {
+            let upload = self.images.open_upload(upload_id).await?;
+            let sealed = tokio::io::BufWriter::new(self.images.create_sealed_upload(upload_id).await?);
+            encrypt_stream(self.scheme, user_key.as_ref(), username, filename, upload, sealed).await
+        };
+        let encrypted_size = match sealed.await {
+            Ok(encrypted_size) => encrypted_size,
+            Err(e) => {
+                eprintln!("Node {} [{}]: Failed to encrypt upload {}: {}", self.id, request_id, upload_id, e);
+                return ServerResponse::Error {
+                    message: format!("Failed to encrypt {}", filename),
+                    code: ErrorCode::Internal,
+                };
+            }
+        };
+        println!("Node {} [{}]: Image encrypted ({} bytes -> {} bytes)",
+            self.id, request_id, original_size, encrypted_size);
+
+        progress.report(original_size, UploadStage::Storing);
+        let stored = match self.images.store_sealed_upload(upload_id, username, filename).await {
+            Ok(path) => self.images.load(username, filename).await.map(|data| (path, data)),
+            Err(e) => {
+                eprintln!("Node {} [{}]: Failed to store image: {}", self.id, request_id, e);
+                None
+            }
+        };
+        let Some((path, encrypted_data)) = stored else {
+            return ServerResponse::Error {
+                message: format!("Failed to store {}", filename),
+                code: ErrorCode::Internal,
+            };
+        };
+        println!("Node {} [{}]: Stored image at {}", self.id, request_id, path.display());
+        let (encrypted_data, content_sha256) = digest_of(encrypted_data).await;
+        let mut metadata =
+            self.new_metadata(filename, original_size, content_id.to_string(), encrypted_data.len() as u64, content_sha256);
+        metadata.thumbnail_error = self.store_thumbnail(request_id, username, filename, thumbnail).await;
+        if session.options.strip_metadata {
+            println!("Node {} [{}]: Storing {} with its metadata: it was uploaded in chunks",
+                self.id, request_id, filename);
+            metadata.metadata_strip = Some(MetadataStrip::NotStripped {
+                reason: "chunked uploads are stored as uploaded".to_string(),
+            });
+        }
+        if session.options.compress {
+            println!("Node {} [{}]: Storing {} uncompressed: it was uploaded in chunks",
+                self.id, request_id, filename);
+            metadata.storage_compression = Some(StorageCompression::Skipped {
+                reason: "chunked uploads are stored uncompressed".to_string(),
+            });
+        }
+        if let Err(e) = self.images.store_metadata(username, &metadata, &self.user_keys(username)).await {
+            eprintln!("Node {} [{}]: Failed to store image metadata: {}", self.id, request_id, e);
+        }
+
+        ServerResponse::EncryptedImageData {
+            metadata: Some(Box::new(metadata)),
+            data: encrypted_data,
+        }
+    }
+
+    /// `thumbnail::make` for a chunked upload assembled on disk, read into
+    /// memory only if it is small enough to make one of
+    async fn assembled_thumbnail(&self, upload_id: &str, original_size: u64) -> Result<Zeroizing<Vec<u8>>, ThumbnailError> {
+        if original_size > thumbnail::MAX_SOURCE_LEN {
+            return Err(ThumbnailError::TooLarge(original_size));
+        }
+        let mut image_data = Zeroizing::new(Vec::with_capacity(original_size as usize));
+        self.images.open_upload(upload_id).await?.read_to_end(&mut image_data).await?;
+        blocking(move || thumbnail::make(&image_data).map(Zeroizing::new)).await
+    }
+
+    /// Seal and store a just uploaded image's thumbnail, dropping any from an
+    /// image stored under its name before, returning why there is none
+    ///
+    /// A thumbnail is only a preview: failing to make one does not fail the
+    /// upload.
+    async fn store_thumbnail(
+        &self,
+        request_id: &str,
+        username: &str,
+        filename: &str,
+        thumbnail: Result<Zeroizing<Vec<u8>>, ThumbnailError>,
+    ) -> Option<String> {
+        let stored = match thumbnail {
+            Ok(thumbnail) => match self.seal(thumbnail, username, &thumbnail::sealed_name(filename)).await {
+                Ok(sealed) => self.images.store_thumbnail(username, filename, &sealed).await.map_err(|e| e.to_string()),
+                Err(message) => Err(message),
+            },
+            Err(e) => Err(e.to_string()),
+        };
+        let reason = stored.err()?;
+        println!("Node {} [{}]: No thumbnail of {}: {}", self.id, request_id, filename, reason);
+        if let Err(e) = self.images.delete_thumbnail(username, filename).await {
+            eprintln!("Node {} [{}]: Failed to drop the old thumbnail of {}: {}", self.id, request_id, filename, e);
+        }
+        Some(reason)
+    }
+
+    /// Metadata for an image just encrypted on this node
+    fn new_metadata(
+        &self,
+        filename: &str,
+        original_size: u64,
+        content_id: String,
+        encrypted_size: u64,
+        content_sha256: [u8; 32],
+    ) -> ImageMetadata {
+        ImageMetadata {
+            filename: filename.to_string(),
+            original_size,
+            encrypted_size,
+            processed_by_node: self.id,
+            uploaded_at_unix: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            content_sha256: to_hex(&content_sha256),
+            tags: Vec::new(),
+            caption: None,
+            content_id: Some(content_id),
+            thumbnail_error: None,
+            metadata_strip: None,
+            storage_compression: None,
+        }
+    }
+
+    /// Leader only: have the node picked by the load balancer process an
+    /// upload, moving on to the next one if it fails or does not answer
+    ///
+    /// Nodes are picked round-robin or by load, as `balancing` says.
+    ///
+    /// Copies of an earlier upload under the same name are dropped from the
+    /// other nodes, so a download cannot find a stale one. Without a load
+    /// balancer, or once every node failed, this node processes it itself.
+    async fn dispatch_upload(
+        &self,
+        request_id: &str,
+        username: String,
+        filename: String,
+        image_data: Vec<u8>,
+        options: UploadOptions,
+        progress: &Progress,
+    ) -> ServerResponse {
+        let mut tried = HashSet::new();
+        loop {
+            let next = match self.load_balancer.read().await.as_ref() {
+                // Round-robin comes back to the first node once all were tried
+                Some(lb) => match self.balancing {
+                    Balancing::RoundRobin => lb.get_next_server().await.filter(|(node_id, _)| !tried.contains(node_id)),
+                    Balancing::LeastLoaded => lb.get_least_loaded_server(&tried).await,
+                },
+                None => None,
+            };
+            let Some((node_id, address)) = next else {
+                break;
+            };
+            tried.insert(node_id);
+            if node_id == self.id {
+                break;
+            }
+
+            println!("Node {} [{}]: Forwarding upload of {} to Node {}", self.id, request_id, filename, node_id);
+            let request = InternalMessage::ProcessImage {
+                username: username.clone(),
+                image_data: image_data.clone(),
+                filename: filename.clone(),
+                strip_metadata: options.strip_metadata,
+                compress: options.compress,
+            };
+            progress.report(image_data.len() as u64, UploadStage::Replicating);
+            // Uploads handed out between two load reports must not all go
+            // to the node that reported the least
+            if let Some(lb) = self.load_balancer.read().await.as_ref() {
+                lb.record_dispatch(node_id).await;
+            }
+            let answer = self.ask_peer_within(&address, &request, PROCESS_IMAGE_TIMEOUT).await;
+            if let Some(lb) = self.load_balancer.read().await.as_ref() {
+                lb.record_completion(node_id).await;
+            }
+            match answer {
+                Some(InternalMessage::ImageData { metadata, data }) => {
+                    self.drop_stale_copies(&username, &filename, node_id);
+                    self.quotas.charge(&username, data.len() as u64);
+                    return ServerResponse::EncryptedImageData { metadata: metadata.map(Box::new), data };
+                }
+                Some(InternalMessage::ProcessingComplete { message, .. }) => {
+                    println!("Node {} [{}]: Node {} failed to process {}: {}",
+                        self.id, request_id, node_id, filename, message);
+                }
+                _ => println!("Node {} [{}]: Node {} did not answer, trying the next node",
+                    self.id, request_id, node_id),
+            }
+        }
+
+        self.upload_here(request_id, username, filename, image_data, options, progress).await
+    }
+
+    /// Process an upload on this node, dropping copies of an earlier upload
+    /// under the same name from the others
+    async fn upload_here(
+        &self,
+        request_id: &str,
+        username: String,
+        filename: String,
+        image_data: Vec<u8>,
+        options: UploadOptions,
+        progress: &Progress,
+    ) -> ServerResponse {
+        let response = self
+            .process_upload(request_id, username.clone(), filename.clone(), image_data, options, progress)
+            .await;
+        if let ServerResponse::EncryptedImageData { data, .. } = &response {
+            self.drop_stale_copies(&username, &filename, self.id);
+            self.quotas.charge(&username, data.len() as u64);
+        }
+        response
+    }
+
+    /// Leader only: dispatch the images of a batch, at most
+    /// `BATCH_PARALLELISM` at a time, returning their results in order
+    async fn upload_batch(
+        &self,
+        request_id: &str,
+        username: String,
+        images: Vec<(String, Vec<u8>)>,
+        options: UploadOptions,
+    ) -> Vec<BatchResult> {
+        let permits = Arc::new(tokio::sync::Semaphore::new(BATCH_PARALLELISM));
+        let mut seen = HashSet::new();
+        let mut uploads = tokio::task::JoinSet::new();
+        for (index, (filename, data)) in images.into_iter().enumerate() {
+            let failed = if !self.images.owns(&username, &filename) {
+                Some(format!("Invalid filename {}", filename))
+            } else if !seen.insert(filename.clone()) {
+                // Two uploads of one name would race to be the stored copy
+                Some(format!("{} appears more than once in the batch", filename))
+            } else {
+                None
+            };
+            let node = self.clone_for_task();
+            let permits = Arc::clone(&permits);
+            let request_id = request_id.to_string();
+            let username = username.clone();
+            uploads.spawn(async move {
+                if let Some(message) = failed {
+                    return (index, BatchResult {
+                        filename,
+                        outcome: BatchOutcome::Failed { message },
+                    });
+                }
+                let _permit = permits.acquire_owned().await;
+                let outcome = match node
+                    .dispatch_upload(&request_id, username, filename.clone(), data, options, &Progress::default())
+                    .await
+                {
+                    ServerResponse::EncryptedImageData { metadata, .. } => BatchOutcome::Uploaded { metadata: metadata.map(|m| *m) },
+                    ServerResponse::Error { message, .. } => BatchOutcome::Failed { message },
+                    _ => BatchOutcome::Failed {
+                        message: "unexpected processing result".to_string(),
+                    },
+                };
+                (index, BatchResult { filename, outcome })
+            });
+        }
+
+        let mut results = Vec::new();
+        while let Some(result) = uploads.join_next().await {
+            if let Ok(result) = result {
+                results.push(result);
+            }
+        }
+        results.sort_by_key(|(index, _)| *index);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+
+    /// In the background, delete an image from every node but `keep_on`
+    fn drop_stale_copies(&self, username: &str, filename: &str, keep_on: u32) {
+        let node = self.clone_for_task();
+        let username = username.to_string();
+        let filename = filename.to_string();
+        tokio::spawn(async move {
+            if keep_on != node.id {
+                if let Err(e) = node.images.delete(&username, &filename).await {
+                    eprintln!("Node {}: Failed to delete stale copy of {}: {}", node.id, filename, e);
+                }
+            }
+            let request = InternalMessage::DeleteReplica {
+                username,
+                filename: filename.clone(),
+            };
+            for peer in node.bully.get_all_peers().await {
+                if peer.id != keep_on && node.ask_peer(&peer.address, &request).await.is_none() {
+                    println!("Node {}: Could not reach Node {} to delete stale copies of {}", node.id, peer.id, filename);
+                }
+            }
+        });
+    }
+
+    /// Answer a download on the assigned node with the whole image
+    async fn download(&self, request_id: &str, username: String, filename: String) -> ServerResponse {
+        println!("Node {} [{}]: Looking up image {} for user {}", self.id, request_id, filename, username);
+        if let Some(data) = self.images.load(&username, &filename).await {
+            let data = match self.check_intact(request_id, &username, &filename, data).await {
+                Ok(data) => data,
+                Err(response) => return response,
+            };
+            let metadata = self.images.load_metadata(&username, &filename, &self.user_keys(&username)).await;
+            return ServerResponse::ImageData { metadata: metadata.map(Box::new), data, filename };
+        }
+
+        // Another node may have processed the upload, e.g. before the
+        // set of alive nodes changed
+        match self.fetch_image_from_peers(&username, &filename).await {
+            Some((metadata, data)) => match self.check_intact(request_id, &username, &filename, data).await {
+                Ok(data) => ServerResponse::ImageData { metadata: metadata.map(Box::new), data, filename },
+                Err(response) => response,
+            },
+            None => {
+                println!("Node {} [{}]: Image {} not found in the cluster", self.id, request_id, filename);
+                ServerResponse::NotFound { filename }
+            }
+        }
+    }
+
+    /// Answer GetThumbnail on the assigned node with the image's thumbnail,
+    /// from whichever node stores the image
+    async fn thumbnail(&self, request_id: &str, username: String, filename: String) -> ServerResponse {
+        let answer = match self.local_thumbnail(&username, &filename).await {
+            InternalMessage::ImageNotFound => {
+                let request = InternalMessage::RetrieveThumbnail {
+                    username: username.clone(),
+                    filename: filename.clone(),
+                };
+                match self.ask_storing_peer(&request).await {
+                    Some((_, answer)) => answer,
+                    None => InternalMessage::ImageNotFound,
+                }
+            }
+            answer => answer,
+        };
+        match answer {
+            InternalMessage::ThumbnailData { data } => {
+                println!("Node {} [{}]: Sending the thumbnail of {}'s {}", self.id, request_id, username, filename);
+                ServerResponse::Thumbnail { filename, data }
+            }
+            InternalMessage::NoThumbnail { reason } => ServerResponse::Error {
+                message: match reason {
+                    Some(reason) => format!("{} has no thumbnail: {}", filename, reason),
+                    None => format!("{} has no thumbnail", filename),
+                },
+                code: ErrorCode::NotFound,
+            },
+            _ => {
+                println!("Node {} [{}]: Image {} not found in the cluster", self.id, request_id, filename);
+                ServerResponse::NotFound { filename }
+            }
+        }
+    }
+
+    /// Answer RetrieveThumbnail for an image stored on this node
+    async fn local_thumbnail(&self, username: &str, filename: &str) -> InternalMessage {
+        if let Some(data) = self.images.load_thumbnail(username, filename).await {
+            return InternalMessage::ThumbnailData { data };
+        }
+        if !self.images.contains(username, filename).await {
+            return InternalMessage::ImageNotFound;
+        }
+        let metadata = self.images.load_metadata(username, filename, &self.user_keys(username)).await;
+        InternalMessage::NoThumbnail {
+            reason: metadata.and_then(|metadata| metadata.thumbnail_error),
+        }
+    }
+
+    /// Encrypt a copy of `owner`'s image for `viewer`, without it ever being
+    /// whole in the clear, returning the response to send back on failure
+    async fn reencrypt_for_viewer(
+        &self,
+        request_id: &str,
+        data: &[u8],
+        owner: &str,
+        viewer: &str,
+        filename: &str,
+    ) -> Result<Vec<u8>, ServerResponse> {
+        let viewer_key = self.sealing_key(viewer).map_err(|message| ServerResponse::Error {
+            message,
+            code: ErrorCode::Internal,
+        })?;
+        let result = if !self.legacy_keys && uses_username_key(data) {
+            Err(StreamError::Crypto(CryptoError::NoKey))
+        } else {
+            let owner_keys = self.user_keys(owner);
+            reencrypt_for(self.scheme, data, &owner_keys, owner, viewer_key.as_ref(), viewer, filename).await
+        };
+        result.map_err(|e| {
+            eprintln!("Node {} [{}]: Cannot decrypt {}'s {}: {}", self.id, request_id, owner, filename, e);
+            let code = match e {
+                StreamError::Crypto(
+                    CryptoError::Truncated | CryptoError::Tampered | CryptoError::Decompress | CryptoError::Format(_),
+                ) => ErrorCode::Corrupted,
+                StreamError::Crypto(CryptoError::NoKey | CryptoError::NoDataKey | CryptoError::InvalidPublicKey)
+                | StreamError::Io(_) => ErrorCode::Internal,
+            };
+            ServerResponse::Error {
+                message: format!("{} is damaged: {}", filename, e),
+                code,
+            }
+        })
+    }
+
+    /// Check that a stored image passes its integrity check before it is
+    /// served, returning the response to send back if not
+    ///
+    /// Images this node has no key for, and CTR images from before they were
+    /// tagged, cannot be checked and pass. The image is handed back to serve.
+    async fn check_intact(
+        &self,
+        request_id: &str,
+        username: &str,
+        filename: &str,
+        data: Vec<u8>,
+    ) -> Result<Vec<u8>, ServerResponse> {
+        if !is_authenticated(&data) {
+            println!("Node {} [{}]: {}'s {} predates integrity checks; serving it unchecked",
+                self.id, request_id, username, filename);
+        }
+        let (open, name) = (self.opener(username), filename.to_string());
+        let (data, opened) = blocking(move || {
+            let opened = open(&data, &name);
+            (data, opened)
+        })
+        .await;
+        match opened {
+            Ok(_) | Err(CryptoError::NoKey) => Ok(data),
+            Err(e) => Err(self.corrupted(request_id, username, filename, e)),
+        }
+    }
+
+    /// `check_intact` for an image stored here, decrypted from disk a chunk
+    /// at a time rather than loaded whole
+    async fn check_stored_intact(&self, request_id: &str, username: &str, filename: &str) -> Result<(), ServerResponse> {
+        let Some((file, _)) = self.images.open(username, filename).await else {
+            return Ok(());
+        };
+        let keys = self.user_keys(username);
+        match decrypt_stream(&keys, username, filename, tokio::io::BufReader::new(file), tokio::io::sink()).await {
+            Ok(_) | Err(StreamError::Crypto(CryptoError::NoKey)) => Ok(()),
+            Err(StreamError::Crypto(e)) => Err(self.corrupted(request_id, username, filename, e)),
+            // Reading it again to send it fails the same way
+            Err(StreamError::Io(e)) => {
+                eprintln!("Node {} [{}]: Could not check {}'s {}: {}", self.id, request_id, username, filename, e);
+                Ok(())
+            }
+        }
+    }
+
+    fn corrupted(&self, request_id: &str, username: &str, filename: &str, e: CryptoError) -> ServerResponse {
+        eprintln!("Node {} [{}]: Not serving {}'s {}: {}", self.id, request_id, username, filename, e);
+        ServerResponse::Error {
+            message: format!("{} is corrupted: {}", filename, e),
+            code: ErrorCode::Corrupted,
+        }
+    }
+
+    /// Check that a request comes from a registered user with a valid session
+    /// token, returning the response to send back if not
+    ///
+    /// With `--allow-anonymous`, requests without a token are served for any
+    /// username.
+    fn check_user(&self, request_id: &str, username: &str, token: &str) -> Result<(), ServerResponse> {
+        if self.allow_anonymous && token.is_empty() {
+            return Ok(());
+        }
+        if let Err(e) = self.tokens.verify(token, username, unix_now()) {
+            println!("Node {} [{}]: Refused token for {}: {}", self.id, request_id, username, e);
+            let code = match e {
+                TokenError::Invalid => ErrorCode::InvalidToken,
+                TokenError::Expired => ErrorCode::TokenExpired,
+                TokenError::WrongUser => ErrorCode::TokenMismatch,
+            };
+            return Err(ServerResponse::Error {
+                code,
+                message: e.to_string(),
+            });
+        }
+        if !self.users.contains(username) {
+            println!("Node {} [{}]: {} is not registered here", self.id, request_id, username);
+            return Err(ServerResponse::Error {
+                message: format!("{} is not registered", username),
+                code: ErrorCode::UnknownUser,
+            });
+        }
+        Ok(())
+    }
+
+    /// Take a broadcast upload for this node, unless another node already
+    /// has it; returns the response to send back if not
+    ///
+    /// The request id is the key. Peers that do not answer in time are left
+    /// out, so a partitioned node can still take requests.
+    async fn claim_request(&self, request_id: &str) -> Result<(), ServerResponse> {
+        let declined = |holder: u32| {
+            println!("Node {} [{}]: Node {} took this request, declining", self.id, request_id, holder);
+            Err(ServerResponse::Error {
+                message: format!("Node {} is handling this request", holder),
+                code: ErrorCode::NotAssigned,
+            })
+        };
+        {
+            let mut claims = self.claims.lock().unwrap();
+            match claims.holder(request_id) {
+                // Our own claim, e.g. the client is retrying
+                Some(holder) if holder == self.id => return Ok(()),
+                Some(holder) => return declined(holder),
+                None => claims.claim(request_id, self.id),
+            };
+        }
+
+        let message = InternalMessage::Processed {
+            key: request_id.to_string(),
+            node_id: self.id,
+        };
+        let mut answers = tokio::task::JoinSet::new();
+        for peer in self.bully.get_all_peers().await {
+            if peer.id == self.id {
+                continue;
+            }
+            let node = self.clone_for_task();
+            let message = message.clone();
+            answers.spawn(async move { node.ask_peer_within(&peer.address, &message, PING_TIMEOUT).await });
+        }
+        let mut taken_by = None;
+        while let Some(answer) = answers.join_next().await {
+            if let Ok(Some(InternalMessage::ClaimedBy { node_id })) = answer {
+                if node_id != self.id {
+                    taken_by = Some(node_id);
+                }
+            }
+        }
+        let mut claims = self.claims.lock().unwrap();
+        if let Some(holder) = taken_by {
+            claims.yield_to(request_id, holder);
+            return declined(holder);
+        }
+        // A peer may have claimed it here while we were asking the others
+        let winner = claims.settle(request_id, self.id);
+        if winner != self.id {
+            return declined(winner);
+        }
+        Ok(())
+    }
+
+    /// Issue a session token to a user who just registered or logged in
+    fn log_in(&self, username: &str) -> ServerResponse {
+        let (token, expires_at_unix) = self.tokens.issue(username, unix_now());
+        ServerResponse::LoggedIn {
+            token,
+            expires_at_unix,
+            key_salt: self.users.key_salt(username).unwrap_or_default(),
+        }
+    }
+
+    /// Check that this node is the leader, which answers some requests for the
+    /// whole cluster; returns the response to send back if it is not
+    async fn check_leader(&self, request_id: &str) -> Result<(), ServerResponse> {
+        if !self.bully.cluster_healthy().await {
+            println!("Node {} [{}]: No live leader, asking client to retry", self.id, request_id);
+            return Err(ServerResponse::Error {
+                message: "cluster re-electing, retry".to_string(),
+                code: ErrorCode::Retriable,
+            });
+        }
+        if !self.bully.is_leader().await {
+            // Clients send to every node, so pointing them at the leader is
+            // enough; proxying would hand it the request twice
+            let message = match self.bully.get_leader().await {
+                Some(leader_id) => format!("Node {} is not the leader, Node {} is", self.id, leader_id),
+                None => format!("Node {} is not the leader", self.id),
+            };
+            return Err(ServerResponse::Error {
+                message,
+                code: ErrorCode::NotAssigned,
+            });
+        }
+        Ok(())
+    }
+
+    /// Decide whether this node serves a client request for `username`/`filename`
+    ///
+    /// Requests are spread round-robin by a hash of username and filename over
+    /// the alive nodes, so an upload and its later download land on the same
+    /// node while membership is unchanged. Returns the response to send back
+    /// if another node is responsible or the cluster cannot take work.
+    async fn check_assignment(&self, request_id: &str, username: &str, filename: &str) -> Result<(), ServerResponse> {
+        // Create a deterministic hash for this request (username + filename)
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        let mut hasher = DefaultHasher::new();
+        username.hash(&mut hasher);
+        filename.hash(&mut hasher);
+        let request_hash = hasher.finish();
+
+        // Assignments made without a live leader could overlap with the
+        // new leader's view once it is elected
+        if !self.bully.cluster_healthy().await {
+            println!("Node {} [{}]: No live leader, asking client to retry", self.id, request_id);
+            return Err(ServerResponse::Error {
+                message: "cluster re-electing, retry".to_string(),
+                code: ErrorCode::Retriable,
+            });
+        }
+
+        // Check which peers are alive
+        let alive_nodes = self.get_alive_nodes().await;
+
+        if alive_nodes.is_empty() {
+            println!("Node {} [{}]: No alive nodes detected, processing as fallback", self.id, request_id);
+            // Process anyway as last resort
+            return Ok(());
+        }
+
+        // Round-robin assignment based on request hash
+        let assigned_index = (request_hash % alive_nodes.len() as u64) as usize;
+        let assigned_node_id = alive_nodes[assigned_index];
+
+        if assigned_node_id != self.id {
+            println!("Node {} [{}]: Request assigned to Node {} (round-robin), rejecting",
+                self.id, request_id, assigned_node_id);
+            return Err(ServerResponse::Error {
+                message: format!("Request assigned to Node {}", assigned_node_id),
+                code: ErrorCode::NotAssigned,
+            });
+        }
+
+        println!("Node {} [{}]: Assigned to me via load balancing (alive nodes: {:?})",
+            self.id, request_id, alive_nodes);
+        Ok(())
+    }
+
+    /// `check_assignment` for a request naming one of `username`'s images,
+    /// returning the image's filename
+    ///
+    /// Requests naming a content id are assigned by the id, and the assigned
+    /// node looks it up among the user's images on every node; of several
+    /// with the same content, the first uploaded is meant.
+    async fn resolve_image(&self, request_id: &str, username: &str, image: ImageSelector) -> Result<String, ServerResponse> {
+        let content_id = match image {
+            ImageSelector::Name(filename) => {
+                self.check_assignment(request_id, username, &filename).await?;
+                return Ok(filename);
+            }
+            ImageSelector::ContentId(content_id) => content_id,
+        };
+        self.check_assignment(request_id, username, &content_id).await?;
+        let mut images = self.images.list(username, &self.user_keys(username)).await;
+        images.extend(self.list_images_from_peers(username, false).await);
+        let found = images
+            .into_iter()
+            .filter(|image| image.content_id.as_ref() == Some(&content_id))
+            .min_by(|a, b| (a.uploaded_at_unix, &a.filename).cmp(&(b.uploaded_at_unix, &b.filename)));
+        match found {
+            Some(image) => {
+                println!("Node {} [{}]: Content id {} is {}'s {}", self.id, request_id, content_id, username, image.filename);
+                Ok(image.filename)
+            }
+            None => Err(ServerResponse::NotFound {
+                filename: ImageSelector::ContentId(content_id).to_string(),
+            }),
+        }
+    }
+
+    /// Send one InternalMessage to a peer and read its answer
+    async fn ask_peer(&self, address: &str, message: &InternalMessage) -> Option<InternalMessage> {
+        self.ask_peer_within(address, message, PEER_REQUEST_TIMEOUT).await
+    }
+
+    /// Like `ask_peer`, waiting up to `timeout` for the answer
+    async fn ask_peer_within(&self, address: &str, message: &InternalMessage, timeout: Duration) -> Option<InternalMessage> {
+        let signed = self.peer_signer.sign(message, unix_now_ms());
+        let request = encode(Format::Bincode, &TaggedRequest::Internal(signed)).ok()?;
+        let exchange = async {
+            let mut stream = TcpStream::connect(address).await?;
+            write_frame(&mut stream, &request, self.max_frame_len).await?;
+            read_frame(&mut stream, self.max_frame_len).await
+        };
+        let frame = tokio::time::timeout(timeout, exchange).await.ok()?.ok()??;
+        decode(&frame).ok().map(|(_, answer)| answer)
+    }
+
+    /// Ask every peer for an image this node does not store
+    async fn fetch_image_from_peers(
+        &self,
+        username: &str,
+        filename: &str,
+    ) -> Option<(Option<ImageMetadata>, Vec<u8>)> {
+        let request = InternalMessage::RetrieveImage {
+            username: username.to_string(),
+            filename: filename.to_string(),
+        };
+        for peer in self.bully.get_all_peers().await {
+            if let Some(InternalMessage::ImageData { metadata, data }) =
+                self.ask_peer(&peer.address, &request).await
+            {
+                println!("Node {}: Fetched image {} from Node {}", self.id, filename, peer.id);
+                return Some((metadata, data));
+            }
+        }
+        None
+    }
+
+    /// Send a request about one image to every peer until one answers for it
+    ///
+    /// Peers that do not store the image answer ImageNotFound or an
+    /// unsuccessful ProcessingComplete and are skipped. The answer comes with
+    /// the address of the peer that gave it.
+    async fn ask_storing_peer(&self, request: &InternalMessage) -> Option<(String, InternalMessage)> {
+        for peer in self.bully.get_all_peers().await {
+            match self.ask_peer(&peer.address, request).await {
+                None
+                | Some(InternalMessage::ImageNotFound)
+                | Some(InternalMessage::ProcessingComplete { success: false, .. }) => continue,
+                Some(answer) => return Some((peer.address, answer)),
+            }
+        }
+        None
+    }
+
+    /// Queue a view for the owner on the node storing the image (`None` for
+    /// this one), keeping it here if that node cannot be reached, and push
+    /// it to the owner's subscriptions
+    async fn notify_view(&self, stored_at: Option<String>, owner: String, notice: ViewNotice) {
+        self.push_event(&owner, ServerEvent::Viewed(notice.clone()));
+        let notification = InternalMessage::ViewNotification {
+            owner,
+            viewer: notice.viewer,
+            filename: notice.filename,
+            timestamp: notice.timestamp,
+            remaining_views: notice.remaining_views,
+            seq: notice.seq,
+        };
+        if let Some(address) = stored_at {
+            if let Some(InternalMessage::ProcessingComplete { success: true, .. }) =
+                self.ask_peer(&address, &notification).await
+            {
+                return;
+            }
+            println!("Node {}: Could not notify {}, keeping the notification here", self.id, address);
+        }
+        self.handle_internal_message(notification).await;
+    }
+
+    /// Stream events for `username` until the connection fails, after
+    /// replaying the views queued since `after_seq`
+    async fn serve_subscription<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+        format: Format,
+        request_id: &str,
+        username: String,
+        after_seq: u64,
+    ) -> Result<(), FrameError> {
+        // Register before replaying, so nothing slips in between
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        self.subscribers
+            .lock()
+            .unwrap()
+            .entry(username.clone())
+            .or_default()
+            .push(tx);
+        println!("Node {} [{}]: {} subscribed", self.id, request_id, username);
+        self.send_response(writer, format, request_id, ServerResponse::Keepalive).await?;
+
+        if after_seq > 0 {
+            let mut missed = self.images.peek_notifications(&username, after_seq).await;
+            let request = InternalMessage::PeekNotifications {
+                username: username.clone(),
+                after_seq,
+            };
+            for peer in self.bully.get_all_peers().await {
+                if peer.id == self.id {
+                    continue;
+                }
+                if let Some(InternalMessage::Notifications { notifications }) = self.ask_peer(&peer.address, &request).await {
+                    missed.extend(notifications);
+                }
+            }
+            missed.sort_by_key(|notice| notice.seq);
+            println!("Node {} [{}]: Replaying {} views to {}", self.id, request_id, missed.len(), username);
+            for notice in missed {
+                let event = ServerResponse::Event {
+                    seq: notice.seq,
+                    event: ServerEvent::Viewed(notice),
+                };
+                self.send_response(writer, format, request_id, event).await?;
+            }
+        }
+
+        // The sender is dropped from `subscribers` on the first event after
+        // this returns, once its receiver is gone
+        loop {
+            let response = match tokio::time::timeout(SUBSCRIPTION_KEEPALIVE, rx.recv()).await {
+                Ok(Some((seq, event))) => ServerResponse::Event { seq, event },
+                Ok(None) => return Ok(()),
+                Err(_) => ServerResponse::Keepalive,
+            };
+            self.send_response(writer, format, request_id, response).await?;
+        }
+    }
+
+    /// Hand an event to this node's subscriptions for `username`, dropping
+    /// those whose connection is gone
+    fn deliver(&self, username: &str, seq: u64, event: &ServerEvent) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some(senders) = subscribers.get_mut(username) {
+            senders.retain(|tx| tx.send((seq, event.clone())).is_ok());
+            if senders.is_empty() {
+                subscribers.remove(username);
+            }
+        }
+    }
+
+    /// Hand an event to every subscription on this node
+    fn deliver_to_all(&self, event: &ServerEvent) {
+        let seq = unix_now_ms();
+        let mut subscribers = self.subscribers.lock().unwrap();
+        for senders in subscribers.values_mut() {
+            senders.retain(|tx| tx.send((seq, event.clone())).is_ok());
+        }
+        subscribers.retain(|_, senders| !senders.is_empty());
+    }
+
+    /// Deliver an event to `username`'s subscriptions here and, in the
+    /// background, on every peer, since the user may be subscribed anywhere
+    fn push_event(&self, username: &str, event: ServerEvent) {
+        let seq = match &event {
+            ServerEvent::Viewed(notice) => notice.seq,
+            _ => unix_now_ms(),
+        };
+        self.deliver(username, seq, &event);
+        let request = InternalMessage::PushEvent {
+            username: username.to_string(),
+            seq,
+            event,
+        };
+        let node = self.clone_for_task();
+        tokio::spawn(async move {
+            for peer in node.bully.get_all_peers().await {
+                if peer.id != node.id {
+                    node.ask_peer(&peer.address, &request).await;
+                }
+            }
+        });
+    }
+
+    /// Take this node's queued views of `username`'s images
+    async fn take_notifications(&self, username: &str) -> Vec<ViewNotice> {
+        match self.images.take_notifications(username).await {
+            Ok(notifications) => notifications,
+            Err(e) => {
+                eprintln!("Node {}: Failed to read notifications for {}: {}", self.id, username, e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Apply a quota change on every peer storing the image, returning the
+    /// first resulting `(allowed_views, used_views)`
+    async fn update_access_on_peers(
+        &self,
+        owner: &str,
+        filename: &str,
+        with_user: &str,
+        allowed_views: u32,
+    ) -> Option<(u32, u32)> {
+        let request = InternalMessage::UpdateAccess {
+            owner: owner.to_string(),
+            filename: filename.to_string(),
+            with_user: with_user.to_string(),
+            allowed_views,
+        };
+        let mut updated = None;
+        for peer in self.bully.get_all_peers().await {
+            match self.ask_peer(&peer.address, &request).await {
+                Some(InternalMessage::AccessUpdated { allowed_views, used_views }) => {
+                    updated = updated.or(Some((allowed_views, used_views)));
+                }
+                Some(_) => {}
+                None => println!("Node {}: Could not reach Node {} to update access to {}", self.id, peer.id, filename),
+            }
+        }
+        updated
+    }
+
+    /// Spend one of `viewer`'s views of an image stored on this node
+    async fn consume_view(&self, viewer: &str, owner: &str, filename: &str) -> InternalMessage {
+        match self.images.consume_view(owner, filename, viewer).await {
+            Ok(ViewCheck::Granted {
+                data,
+                remaining,
+                share_key,
+            }) => {
+                let current = self.share_key(owner, filename, viewer, &data, share_key.clone());
+                if current != share_key {
+                    if let Err(e) = self.images.set_share_key(owner, filename, viewer, current.clone()).await {
+                        eprintln!("Node {}: Failed to keep the key wrapped for {} to {}: {}", self.id, viewer, filename, e);
+                    }
+                }
+                InternalMessage::ViewGranted {
+                    data,
+                    remaining_views: remaining,
+                    wrapped_key: current.map(|share_key| share_key.wrapped_key),
+                }
+            }
+            Ok(ViewCheck::NotShared) => InternalMessage::NotShared,
+            Ok(ViewCheck::QuotaExhausted) => InternalMessage::QuotaExhausted,
+            Ok(ViewCheck::NotStored) => InternalMessage::ImageNotFound,
+            Err(e) => {
+                eprintln!("Node {}: Failed to update access record for {}: {}", self.id, filename, e);
+                InternalMessage::ProcessingComplete {
+                    success: false,
+                    message: format!("Failed to view {}", filename),
+                }
+            }
+        }
+    }
+
+    /// `grant_access` of the image store, with the image's data key wrapped
+    /// to the viewer's public key if they registered one
+    async fn grant_access(&self, owner: &str, filename: &str, viewer: &str, allowed_views: u32) -> std::io::Result<bool> {
+        let share_key = match self.images.load(owner, filename).await {
+            Some(stored) => self.share_key(owner, filename, viewer, &stored, None),
+            None => None,
+        };
+        self.images.grant_access(owner, filename, viewer, allowed_views, share_key).await
+    }
+
+    /// The data key of an image stored here as `stored`, wrapped to
+    /// `viewer`'s public key: `existing` if that is still wrapped to their
+    /// current key for the data key the image is under, else wrapped anew
+    ///
+    /// `None` if the viewer registered no public key or the image has no
+    /// data key of its own; such views are re-encrypted for the viewer.
+    fn share_key(&self, owner: &str, filename: &str, viewer: &str, stored: &[u8], existing: Option<ShareKey>) -> Option<ShareKey> {
+        let public_key = self
+            .users
+            .public_key(viewer)
+            .or_else(|| existing.as_ref().map(|share_key| share_key.public_key))?;
+        let data_key_id = data_key_id(stored)?;
+        if let Some(existing) = existing.filter(|k| k.public_key == public_key && k.data_key_id == data_key_id) {
+            return Some(existing);
+        }
+        let wrapped = image_data_key(stored, &self.user_keys(owner), owner, filename)
+            .and_then(|data_key| wrap_for_viewer(&data_key, &public_key, owner, filename, viewer));
+        match wrapped {
+            Ok(wrapped_key) => Some(ShareKey {
+                public_key,
+                wrapped_key,
+                data_key_id,
+            }),
+            Err(e) => {
+                eprintln!("Node {}: Cannot wrap the key of {}'s {} for {}: {}", self.id, owner, filename, viewer, e);
+                None
+            }
+        }
+    }
+
+    /// Tell every peer to drop its copy of an image, returning whether any had one
+    async fn delete_replicas(&self, username: &str, filename: &str) -> bool {
+        let request = InternalMessage::DeleteReplica {
+            username: username.to_string(),
+            filename: filename.to_string(),
+        };
+        let mut deleted = false;
+        for peer in self.bully.get_all_peers().await {
+            match self.ask_peer(&peer.address, &request).await {
+                Some(InternalMessage::ProcessingComplete { success, .. }) => deleted |= success,
+                _ => println!("Node {}: Could not reach Node {} to delete {}", self.id, peer.id, filename),
+            }
+        }
+        deleted
+    }
+
+    /// Apply an UpdateImageMeta to this node's copy, answering as for a peer
+    async fn apply_meta_update(&self, update: InternalMessage) -> InternalMessage {
+        let InternalMessage::UpdateImageMeta {
+            username,
+            filename,
+            new_filename,
+            tags,
+            caption,
+        } = update
+        else {
+            return InternalMessage::ProcessingComplete {
+                success: false,
+                message: "not a metadata update".to_string(),
+            };
+        };
+        let keys = self.user_keys(&username);
+        match self.images.update_meta(&username, &filename, new_filename.as_deref(), tags, caption, &keys).await {
+            Ok(MetaUpdate::Updated(metadata)) => {
+                let metadata = metadata.map(|m| *m);
+                match new_filename.filter(|name| *name != filename) {
+                    Some(new_filename) => match self.reseal(&username, &filename, &new_filename, metadata).await {
+                        Ok(metadata) => InternalMessage::MetaUpdated { metadata },
+                        Err(message) => InternalMessage::ProcessingComplete { success: false, message },
+                    },
+                    None => InternalMessage::MetaUpdated { metadata },
+                }
+            }
+            Ok(MetaUpdate::NotStored) => InternalMessage::ImageNotFound,
+            Ok(MetaUpdate::NameTaken) => InternalMessage::NameTaken,
+            Ok(MetaUpdate::NoMetadata) => InternalMessage::ProcessingComplete {
+                success: false,
+                message: "image was stored without metadata".to_string(),
+            },
+            Err(e) => InternalMessage::ProcessingComplete {
+                success: false,
+                message: e.to_string(),
+            },
+        }
+    }
+
+    /// Seal a just renamed image again under its new name, which its
+    /// associated data includes, returning its updated metadata
+    async fn reseal(
+        &self,
+        username: &str,
+        old_filename: &str,
+        new_filename: &str,
+        metadata: Option<ImageMetadata>,
+    ) -> Result<Option<ImageMetadata>, String> {
+        let data = self
+            .images
+            .load(username, new_filename)
+            .await
+            .ok_or_else(|| format!("{} is gone", new_filename))?;
+        let compressed = is_compressed(&data);
+        let plain = self
+            .open(data, username, old_filename)
+            .await
+            .map_err(|e| format!("{} is damaged: {}", old_filename, e))?;
+        let sealed = match compressed {
+            true => self.seal_compressed(plain, username, new_filename).await?.0,
+            false => self.seal(plain, username, new_filename).await?,
+        };
+        let metadata = self.replace_sealed(username, new_filename, &sealed, metadata).await?;
+        if let Some(thumbnail) = self.images.load_thumbnail(username, new_filename).await {
+            self.reseal_thumbnail(username, old_filename, new_filename, thumbnail).await;
+        }
+        Ok(metadata)
+    }
+
+    /// Seal an image's thumbnail again under `username`'s current key for
+    /// `filename`, having been sealed for `old_filename`
+    ///
+    /// One that cannot be opened or stored is dropped rather than left under
+    /// a name or key it no longer opens with; it is only a preview.
+    async fn reseal_thumbnail(&self, username: &str, old_filename: &str, filename: &str, thumbnail: Vec<u8>) {
+        let resealed = match self.open(thumbnail, username, &thumbnail::sealed_name(old_filename)).await {
+            Ok(plain) => self.seal(plain, username, &thumbnail::sealed_name(filename)).await,
+            Err(e) => Err(e.to_string()),
+        };
+        let stored = match resealed {
+            Ok(sealed) => self.images.store_thumbnail(username, filename, &sealed).await.map_err(|e| e.to_string()),
+            Err(message) => Err(message),
+        };
+        if let Err(message) = stored {
+            eprintln!("Node {}: Dropping the thumbnail of {}'s {}: {}", self.id, username, filename, message);
+            if let Err(e) = self.images.delete_thumbnail(username, filename).await {
+                eprintln!("Node {}: Failed to drop the thumbnail of {}'s {}: {}", self.id, username, filename, e);
+            }
+        }
+    }
+
+    /// Store `sealed` over an image, updating its metadata to match
+    async fn replace_sealed(
+        &self,
+        username: &str,
+        filename: &str,
+        sealed: &[u8],
+        metadata: Option<ImageMetadata>,
+    ) -> Result<Option<ImageMetadata>, String> {
+        self.images.store(username, filename, sealed).await.map_err(|e| e.to_string())?;
+        self.record_sealed(username, sealed, metadata).await
+    }
+
+    /// Bring an image's metadata in line with `sealed`, now stored for it
+    async fn record_sealed(
+        &self,
+        username: &str,
+        sealed: &[u8],
+        metadata: Option<ImageMetadata>,
+    ) -> Result<Option<ImageMetadata>, String> {
+        let Some(mut metadata) = metadata else {
+            return Ok(None);
+        };
+        metadata.encrypted_size = sealed.len() as u64;
+        metadata.content_sha256 = sha256_hex(sealed);
+        self.images.store_metadata(username, &metadata, &self.user_keys(username)).await.map_err(|e| e.to_string())?;
+        Ok(Some(metadata))
+    }
+
+    /// `username`'s current image key, if they have logged in since
+    /// password-derived keys were introduced
+    fn user_key(&self, username: &str) -> Option<UserKey> {
+        let (generation, _) = self.users.retired_keys(username)?;
+        self.user_keys(username).into_iter().find(|key| key.generation == generation)
+    }
+
+    /// `username`'s current image key followed by the earlier ones that
+    /// images may still be under, unwrapped only when not already cached
+    fn user_keys(&self, username: &str) -> Vec<UserKey> {
+        self.key_cache.get_or_insert_with(username, || {
+            let Some((generation, retired)) = self.users.retired_keys(username) else {
+                return Vec::new();
+            };
+            let current = self.users.wrapped_key(username).map(|wrapped| (generation, wrapped));
+            current
+                .iter()
+                .chain(retired.iter())
+                .filter_map(|(generation, wrapped)| self.unwrap_user_key(username, *generation, wrapped))
+                .collect()
+        })
+    }
+
+    fn unwrap_user_key(&self, username: &str, generation: u32, wrapped: &[u8]) -> Option<UserKey> {
+        match unwrap_key(&self.key_wrap, username, wrapped) {
+            Ok(key) => Some(UserKey { key, generation }),
+            Err(e) => {
+                eprintln!("Node {}: Cannot open {}'s image key {}: {}", self.id, username, generation, e);
+                None
+            }
+        }
+    }
+
+    /// Encrypt an image for `username` under their key, or the deprecated
+    /// username key if they have none and that is still allowed, on a
+    /// blocking thread; `data` is wiped once sealed
+    async fn seal(&self, data: Zeroizing<Vec<u8>>, username: &str, filename: &str) -> Result<Vec<u8>, String> {
+        let user_key = self.sealing_key(username)?;
+        let (scheme, username, filename) = (self.scheme, username.to_string(), filename.to_string());
+        Ok(blocking(move || seal_image(scheme, user_key.as_ref(), &data, &username, &filename)).await)
+    }
+
+    /// `seal` for an image compressed first, returning what compressing did
+    async fn seal_compressed(
+        &self,
+        data: Zeroizing<Vec<u8>>,
+        username: &str,
+        filename: &str,
+    ) -> Result<(Vec<u8>, StorageCompression), String> {
+        let user_key = self.sealing_key(username)?;
+        let (scheme, username, filename) = (self.scheme, username.to_string(), filename.to_string());
+        let (sealed, outcome) =
+            blocking(move || seal_compressed(scheme, user_key.as_ref(), &data, &username, &filename)).await;
+        let compression = match outcome {
+            CompressionOutcome::Compressed { compressed_len } => StorageCompression::Zstd {
+                compressed_size: compressed_len as u64,
+            },
+            CompressionOutcome::Skipped { ratio } => StorageCompression::Skipped {
+                reason: format!("compresses only to {:.0}% of its size", ratio * 100.0),
+            },
+        };
+        Ok((sealed, compression))
+    }
+
+    /// The key `seal` uses for `username`, `None` for the username key
+    fn sealing_key(&self, username: &str) -> Result<Option<UserKey>, String> {
+        let user_key = self.user_key(username);
+        if user_key.is_none() && !self.legacy_keys {
+            return Err(format!("{} has no image key yet; log in again to create one", username));
+        }
+        Ok(user_key)
+    }
+
+    /// Decrypt an image stored for `username` on a blocking thread, refusing
+    /// ones under the deprecated username key unless they are still allowed;
+    /// the image is wiped once dropped
+    async fn open(&self, data: Vec<u8>, username: &str, filename: &str) -> Result<Zeroizing<Vec<u8>>, CryptoError> {
+        let (open, filename) = (self.opener(username), filename.to_string());
+        blocking(move || open(&data, &filename)).await
+    }
+
+    /// `open` for `username`'s images as a function holding what it needs,
+    /// to run off the runtime's threads
+    fn opener(&self, username: &str) -> impl Fn(&[u8], &str) -> Result<Zeroizing<Vec<u8>>, CryptoError> + Send + 'static {
+        let (keys, legacy_keys, username) = (self.user_keys(username), self.legacy_keys, username.to_string());
+        move |data, filename| {
+            if !legacy_keys && uses_username_key(data) {
+                return Err(CryptoError::NoKey);
+            }
+            open_image(data, &keys, &username, filename).map(Zeroizing::new)
+        }
+    }
+
+    /// Run a registry call that hashes a password, off the runtime's threads
+    async fn hashing_passwords<T: Send + 'static>(
+        &self,
+        work: impl FnOnce(&UserRegistry) -> T + Send + 'static,
+    ) -> Result<T, tokio::task::JoinError> {
+        let users = Arc::clone(&self.users);
+        tokio::task::spawn_blocking(move || work(&users)).await
+    }
+
+    async fn check_password(&self, username: &str, password: &str) -> PasswordCheck {
+        let (name, password) = (username.to_string(), password.to_string());
+        self.hashing_passwords(move |users| users.check_password(&name, &password))
+            .await
+            .unwrap_or(PasswordCheck::WrongPassword)
+    }
+
+    /// Give `username` an image key derived from their password if they have
+    /// none, returning whether one was created
+    async fn ensure_user_key(&self, username: &str, password: &str) -> bool {
+        if self.users.wrapped_key(username).is_some() {
+            return false;
+        }
+        let Some(salt) = self.users.key_salt(username) else {
+            return false;
+        };
+        // Argon2 is slow on purpose; keep it off the runtime's threads
+        let (name, password) = (username.to_string(), password.to_string());
+        let Ok(user_key) = tokio::task::spawn_blocking(move || derive_user_key(&name, &password, salt.as_bytes())).await
+        else {
+            return false;
+        };
+        let wrapped = wrap_key(&self.key_wrap, username, &user_key);
+        match self.users.set_wrapped_key(username, &wrapped) {
+            Ok(created) => {
+                self.key_cache.invalidate(username);
+                created
+            }
+            Err(e) => {
+                eprintln!("Node {}: Failed to save {}'s image key: {}", self.id, username, e);
+                false
+            }
+        }
+    }
+
+    /// Re-encrypt `username`'s images under their new key on every node,
+    /// without holding up their login
+    fn upgrade_keys_in_background(&self, username: &str) {
+        let node = self.clone_for_task();
+        let username = username.to_string();
+        tokio::spawn(async move {
+            let mut upgraded = node.upgrade_local_keys(&username).await;
+            let message = InternalMessage::UpgradeKeys {
+                username: username.clone(),
+            };
+            for peer in node.bully.get_all_peers().await {
+                if peer.id == node.id {
+                    continue;
+                }
+                match node.ask_peer_within(&peer.address, &message, PROCESS_IMAGE_TIMEOUT).await {
+                    Some(InternalMessage::KeysUpgraded { images }) => upgraded += images,
+                    _ => println!("Node {}: Node {} did not upgrade {}'s images", node.id, peer.id, username),
+                }
+            }
+            if upgraded > 0 {
+                println!("Node {}: Re-encrypted {} of {}'s images under their own key", node.id, upgraded, username);
+            }
+        });
+    }
+
+    /// Re-encrypt the images this node stores for `username` that are still
+    /// under the deprecated username key, and seal their catalog entries kept
+    /// in the clear, returning how many images were re-encrypted
+    async fn upgrade_local_keys(&self, username: &str) -> u32 {
+        let Some(user_key) = self.user_key(username) else {
+            return 0;
+        };
+        if let Err(e) = self.images.seal_catalog(username, &self.user_keys(username)).await {
+            eprintln!("Node {}: Failed to seal {}'s catalog: {}", self.id, username, e);
+        }
+        let mut upgraded = 0;
+        for image in self.images.list(username, &self.user_keys(username)).await {
+            if let Some(thumbnail) = self.images.load_thumbnail(username, &image.filename).await {
+                if uses_username_key(&thumbnail) {
+                    self.reseal_thumbnail(username, &image.filename, &image.filename, thumbnail).await;
+                }
+            }
+            let Some(data) = self.images.load(username, &image.filename).await else {
+                continue;
+            };
+            if !uses_username_key(&data) {
+                continue;
+            }
+            let (scheme, user_key) = (self.scheme, user_key.clone());
+            let (name, filename) = (username.to_string(), image.filename.clone());
+            let sealed = blocking(move || {
+                let plain = Zeroizing::new(open_image(&data, &[], &name, &filename)?);
+                match is_compressed(&data) {
+                    true => Ok::<_, CryptoError>(seal_compressed(scheme, Some(&user_key), &plain, &name, &filename).0),
+                    false => Ok(seal_image(scheme, Some(&user_key), &plain, &name, &filename)),
+                }
+            })
+            .await;
+            let sealed = match sealed {
+                Ok(sealed) => sealed,
+                Err(e) => {
+                    eprintln!("Node {}: Not upgrading {}'s {}: {}", self.id, username, image.filename, e);
+                    continue;
+                }
+            };
+            let metadata = self.images.load_metadata(username, &image.filename, &self.user_keys(username)).await;
+            match self.replace_sealed(username, &image.filename, &sealed, metadata).await {
+                Ok(_) => upgraded += 1,
+                Err(e) => eprintln!("Node {}: Failed to upgrade {}'s {}: {}", self.id, username, image.filename, e),
+            }
+        }
+        upgraded
+    }
+
+    /// Move `username`'s images to their current key on every node, without
+    /// holding up the password change
+    fn rotate_keys_in_background(&self, username: &str) {
+        let node = self.clone_for_task();
+        let username = username.to_string();
+        tokio::spawn(async move {
+            node.rotate_keys_everywhere(&username).await;
+        });
+    }
+
+    /// Leader only: have every node move `username`'s images to their
+    /// current key, then retire the earlier keys if no node has any left
+    ///
+    /// A node that cannot be reached keeps the earlier keys around; the
+    /// rotation is tried again every `USER_SYNC_INTERVAL` until it is done.
+    async fn rotate_keys_everywhere(&self, username: &str) {
+        let (generation, mut remaining) = self.rotate_local_keys(username).await;
+        let message = InternalMessage::RotateKeys {
+            username: username.to_string(),
+        };
+        let mut passes = tokio::task::JoinSet::new();
+        for peer in self.bully.get_all_peers().await {
+            if peer.id == self.id {
+                continue;
+            }
+            let node = self.clone_for_task();
+            let message = message.clone();
+            passes.spawn(async move {
+                let answer = node.ask_peer_within(&peer.address, &message, PROCESS_IMAGE_TIMEOUT).await;
+                (peer.id, answer)
+            });
+        }
+        let mut complete = true;
+        while let Some(Ok((peer_id, answer))) = passes.join_next().await {
+            match answer {
+                Some(InternalMessage::RotationProgress {
+                    generation: peer_generation,
+                    remaining: peer_remaining,
+                }) if peer_generation == generation => remaining += peer_remaining,
+                _ => {
+                    println!("Node {}: Node {} did not rotate {}'s images", self.id, peer_id, username);
+                    complete = false;
+                }
+            }
+        }
+        if !complete || remaining > 0 {
+            return;
+        }
+        match self.users.finish_rotation(username, generation) {
+            Ok(true) => {
+                self.key_cache.invalidate(username);
+                println!("Node {}: Every image of {} is under key generation {}", self.id, username, generation);
+                self.sync_users().await;
+            }
+            Ok(false) => {}
+            Err(e) => eprintln!("Node {}: Failed to retire {}'s old image keys: {}", self.id, username, e),
+        }
+    }
+
+    /// Move the images this node stores for `username` from their retired
+    /// keys to the current one, returning that key's generation and how many
+    /// images are still under another
+    ///
+    /// Images name the generation they were sealed under, so a pass that is
+    /// cut short is resumed by running another; images already moved are
+    /// skipped. An image replaced by an upload during the pass is left as the
+    /// upload stored it and counted as remaining, to be checked next pass.
+    async fn rotate_local_keys(&self, username: &str) -> (u32, u32) {
+        let keys = self.user_keys(username);
+        let Some(current) = keys.first().cloned() else {
+            return (0, 0);
+        };
+        {
+            let mut rotations = self.rotations.lock().unwrap();
+            if let Some(status) = rotations.get(username) {
+                if status.running && status.generation == current.generation {
+                    return (current.generation, status.remaining.max(1));
+                }
+            }
+            rotations.insert(
+                username.to_string(),
+                KeyRotationStatus {
+                    username: username.to_string(),
+                    generation: current.generation,
+                    rotated: 0,
+                    remaining: 0,
+                    failed: 0,
+                    running: true,
+                },
+            );
+        }
+
+        let (mut rotated, mut remaining, mut failed) = (0, 0, 0);
+        for image in self.images.list(username, &self.user_keys(username)).await {
+            // Thumbnails are moved along, but not counted: one that cannot
+            // be is dropped, so it never holds up retiring the old keys
+            if let Some(thumbnail) = self.images.load_thumbnail(username, &image.filename).await {
+                if key_generation(&thumbnail).is_some_and(|g| g != current.generation) {
+                    self.reseal_thumbnail(username, &image.filename, &image.filename, thumbnail).await;
+                }
+            }
+            let Some(data) = self.images.load(username, &image.filename).await else {
+                continue;
+            };
+            // Images under the username key are moved by `upgrade_local_keys`
+            let Some(generation) = key_generation(&data).filter(|g| *g != current.generation) else {
+                continue;
+            };
+            let (data, sealed) = match keys.iter().find(|key| key.generation == generation).cloned() {
+                Some(old_key) => {
+                    let (current, name, filename) = (current.clone(), username.to_string(), image.filename.clone());
+                    blocking(move || {
+                        let sealed = rotate_user_key(&data, &old_key, &current, &name, &filename);
+                        (data, sealed.map_err(|e| e.to_string()))
+                    })
+                    .await
+                }
+                None => (data, Err(format!("key generation {} is gone", generation))),
+            };
+            let outcome = match sealed {
+                Ok(sealed) => match self.images.replace(username, &image.filename, &data, &sealed).await {
+                    Ok(true) => {
+                        let metadata = self.images.load_metadata(username, &image.filename, &self.user_keys(username)).await;
+                        self.record_sealed(username, &sealed, metadata).await.map(|_| true)
+                    }
+                    Ok(false) => Ok(false),
+                    Err(e) => Err(e.to_string()),
+                },
+                Err(e) => Err(e),
+            };
+            match outcome {
+                Ok(true) => rotated += 1,
+                Ok(false) => remaining += 1,
+                Err(e) => {
+                    eprintln!("Node {}: Failed to rotate {}'s {}: {}", self.id, username, image.filename, e);
+                    remaining += 1;
+                    failed += 1;
+                }
+            }
+            if let Some(status) = self.rotations.lock().unwrap().get_mut(username) {
+                status.rotated = rotated;
+            }
+        }
+        // Catalog entries hold up retiring a key like the images do
+        match self.images.seal_catalog(username, &keys).await {
+            Ok(left) => remaining += left,
+            Err(e) => {
+                eprintln!("Node {}: Failed to seal {}'s catalog under key generation {}: {}",
+                    self.id, username, current.generation, e);
+                remaining += 1;
+                failed += 1;
+            }
+        }
+
+        if let Some(status) = self.rotations.lock().unwrap().get_mut(username) {
+            *status = KeyRotationStatus {
+                username: username.to_string(),
+                generation: current.generation,
+                rotated,
+                remaining,
+                failed,
+                running: false,
+            };
+        }
+        if rotated > 0 {
+            println!("Node {}: Moved {} of {}'s images to key generation {}", self.id, rotated, username, current.generation);
+        }
+        (current.generation, remaining)
+    }
+
+    /// Seal the catalog entries of every user with a key that are still in
+    /// the clear or under an earlier key, e.g. after `migrate_names`
+    async fn seal_catalogs(&self) {
+        for username in self.users.snapshot().into_keys() {
+            match self.images.seal_catalog(&username, &self.user_keys(&username)).await {
+                Ok(0) => {}
+                Ok(left) => println!("Node {}: {} of {}'s catalog entries are under a key no longer known", self.id, left, username),
+                Err(e) => eprintln!("Node {}: Failed to seal {}'s catalog: {}", self.id, username, e),
+            }
+        }
+    }
+
+    /// Whether any node stores an image of `username` named `filename`
+    async fn image_exists(&self, username: &str, filename: &str) -> bool {
+        self.images.contains(username, filename).await
+            || self
+                .list_images_from_peers(username, false)
+                .await
+                .iter()
+                .any(|image| image.filename == filename)
+    }
+
+    /// Collect every peer's images for `username`, with their catalog entries
+    /// left sealed if `sealed`; unreachable peers are skipped
+    async fn list_images_from_peers(&self, username: &str, sealed: bool) -> Vec<ImageEntry> {
+        let request = InternalMessage::ListImages {
+            username: username.to_string(),
+            sealed,
+        };
+        let mut images = Vec::new();
+        for peer in self.bully.get_all_peers().await {
+            match self.ask_peer(&peer.address, &request).await {
+                Some(InternalMessage::ImageList { images: peer_images }) => images.extend(peer_images),
+                _ => println!("Node {}: Could not list images on Node {}", self.id, peer.id),
+            }
+        }
+        images
+    }
+
+    /// Bytes this node stores for `username`
+    async fn stored_bytes(&self, username: &str) -> u64 {
+        self.images.list(username, &self.user_keys(username)).await.iter().map(|image| image.size_bytes).sum()
+    }
+
+    /// Leader only: the bytes `username` stores across the cluster
+    ///
+    /// The count is kept up to date as uploads pass through the leader, and
+    /// taken again from every node once it is `QUOTA_RECOUNT_INTERVAL` old or
+    /// a node reports a change. Unreachable nodes count as storing nothing.
+    async fn cluster_usage(&self, username: &str) -> u64 {
+        if let Some(bytes) = self.quotas.usage(username, QUOTA_RECOUNT_INTERVAL) {
+            return bytes;
+        }
+        let message = InternalMessage::StorageUsage {
+            username: username.to_string(),
+        };
+        let mut counts = tokio::task::JoinSet::new();
+        for peer in self.bully.get_all_peers().await {
+            if peer.id == self.id {
+                continue;
+            }
+            let node = self.clone_for_task();
+            let message = message.clone();
+            counts.spawn(async move { node.ask_peer(&peer.address, &message).await });
+        }
+        let mut bytes = self.stored_bytes(username).await;
+        while let Some(answer) = counts.join_next().await {
+            if let Ok(Some(InternalMessage::StoredBytes { bytes: peer_bytes })) = answer {
+                bytes += peer_bytes;
+            }
+        }
+        self.quotas.record_usage(username, bytes);
+        bytes
+    }
+
+    /// The leader's count of the bytes `username` stores, reused for
+    /// `QUOTA_CACHE_TTL`; `None` if the leader cannot be asked
+    async fn leader_usage(&self, username: &str) -> Option<u64> {
+        if let Some(bytes) = self.quotas.usage(username, QUOTA_CACHE_TTL) {
+            return Some(bytes);
+        }
+        let leader = self.bully.get_peer(self.bully.get_leader().await?).await?;
+        let message = InternalMessage::QuotaCheck {
+            username: username.to_string(),
+        };
+        match self.ask_peer(&leader.address, &message).await {
+            Some(InternalMessage::StoredBytes { bytes }) => {
+                self.quotas.record_usage(username, bytes);
+                Some(bytes)
+            }
+            _ => None,
+        }
+    }
+
+    /// Check that storing `incoming` more bytes keeps `username` within their
+    /// quota, returning the response to send back if not
+    ///
+    /// Other nodes than the leader check against the leader's count, which
+    /// may miss uploads still in flight, so they let a user go up to
+    /// `QUOTA_OVERSHOOT` past the quota. If the leader cannot be asked, the
+    /// upload goes ahead.
+    async fn check_quota(&self, request_id: &str, username: &str, incoming: u64) -> Result<(), ServerResponse> {
+        let Some(limit) = self.quotas.limit(username) else {
+            return Ok(());
+        };
+        let (used, tolerance) = if self.bully.is_leader().await {
+            (self.cluster_usage(username).await, 0)
+        } else {
+            match self.leader_usage(username).await {
+                Some(used) => (used, QUOTA_OVERSHOOT),
+                None => {
+                    println!("Node {} [{}]: Cannot ask the leader for {}'s usage, not checking the quota",
+                        self.id, request_id, username);
+                    return Ok(());
+                }
+            }
+        };
+        if used + incoming > limit + tolerance {
+            println!("Node {} [{}]: {} bytes more would put {} over their quota ({} of {} bytes used)",
+                self.id, request_id, incoming, username, used, limit);
+            return Err(ServerResponse::Error {
+                message: format!("Quota exceeded: {} of {} bytes used, this upload needs {} more", used, limit, incoming),
+                code: ErrorCode::QuotaExceeded { used, limit },
+            });
+        }
+        Ok(())
+    }
+
+    /// Have the leader count `username`'s stored bytes again after they
+    /// changed on this node outside its view, e.g. through a delete
+    async fn usage_changed(&self, username: &str) {
+        self.quotas.forget_usage(username);
+        if self.bully.is_leader().await {
+            return;
+        }
+        let Some(leader_id) = self.bully.get_leader().await else {
+            return;
+        };
+        let Some(leader) = self.bully.get_peer(leader_id).await else {
+            return;
+        };
+        let message = InternalMessage::UsageChanged {
+            username: username.to_string(),
+        };
+        if self.ask_peer(&leader.address, &message).await.is_none() {
+            println!("Node {}: Could not tell Node {} that {}'s usage changed", self.id, leader_id, username);
+        }
+    }
+
+    /// Answer a request from another node
+    async fn handle_internal_message(&self, message: InternalMessage) -> InternalMessage {
+        match message {
+            InternalMessage::RetrieveImage { username, filename } => {
+                match self.images.load(&username, &filename).await {
+                    Some(data) => InternalMessage::ImageData {
+                        metadata: self.images.load_metadata(&username, &filename, &self.user_keys(&username)).await,
+                        data,
+                    },
+                    None => InternalMessage::ImageNotFound,
+                }
+            }
+            InternalMessage::ListImages { username, sealed } => {
+                let keys = if sealed { Vec::new() } else { self.user_keys(&username) };
+                InternalMessage::ImageList {
+                    images: self.images.list(&username, &keys).await,
+                }
+            }
+            InternalMessage::RetrieveThumbnail { username, filename } => self.local_thumbnail(&username, &filename).await,
+            InternalMessage::UpdateImageMeta { .. } => self.apply_meta_update(message).await,
+            InternalMessage::ViewNotification {
+                owner,
+                viewer,
+                filename,
+                timestamp,
+                remaining_views,
+                seq,
+            } => {
+                let notice = ViewNotice {
+                    viewer,
+                    filename,
+                    timestamp,
+                    remaining_views,
+                    seq,
+                };
+                match self.images.push_notification(&owner, notice).await {
+                    Ok(()) => InternalMessage::ProcessingComplete {
+                        success: true,
+                        message: "queued".to_string(),
+                    },
+                    Err(e) => InternalMessage::ProcessingComplete {
+                        success: false,
+                        message: e.to_string(),
+                    },
+                }
+            }
+            InternalMessage::FetchNotifications { username } => InternalMessage::Notifications {
+                notifications: self.take_notifications(&username).await,
+            },
+            InternalMessage::PeekNotifications { username, after_seq } => InternalMessage::Notifications {
+                notifications: self.images.peek_notifications(&username, after_seq).await,
+            },
+            InternalMessage::Processed { key, node_id } => InternalMessage::ClaimedBy {
+                node_id: self.claims.lock().unwrap().claim(&key, node_id),
+            },
+            InternalMessage::PushEvent { username, seq, event } => {
+                self.deliver(&username, seq, &event);
+                InternalMessage::ProcessingComplete {
+                    success: true,
+                    message: "delivered".to_string(),
+                }
+            }
+            InternalMessage::DeleteReplica { username, filename } => {
+                match self.images.delete(&username, &filename).await {
+                    Ok(success) => InternalMessage::ProcessingComplete {
+                        success,
+                        message: if success { "deleted" } else { "not stored here" }.to_string(),
+                    },
+                    Err(e) => InternalMessage::ProcessingComplete {
+                        success: false,
+                        message: e.to_string(),
+                    },
+                }
+            }
+            InternalMessage::GrantAccess {
+                owner,
+                filename,
+                with_user,
+                allowed_views,
+            } => match self.grant_access(&owner, &filename, &with_user, allowed_views).await {
+                Ok(success) => InternalMessage::ProcessingComplete {
+                    success,
+                    message: if success { "shared" } else { "not stored here" }.to_string(),
+                },
+                Err(e) => InternalMessage::ProcessingComplete {
+                    success: false,
+                    message: e.to_string(),
+                },
+            },
+            InternalMessage::UpdateAccess {
+                owner,
+                filename,
+                with_user,
+                allowed_views,
+            } => match self.images.update_access(&owner, &filename, &with_user, allowed_views).await {
+                Ok(Some(grant)) => InternalMessage::AccessUpdated {
+                    allowed_views: grant.allowed_views,
+                    used_views: grant.used_views,
+                },
+                Ok(None) => InternalMessage::ImageNotFound,
+                Err(e) => InternalMessage::ProcessingComplete {
+                    success: false,
+                    message: e.to_string(),
+                },
+            },
+            InternalMessage::ConsumeView { viewer, owner, filename } => {
+                self.consume_view(&viewer, &owner, &filename).await
+            }
+            InternalMessage::ProcessImage {
+                username,
+                image_data,
+                filename,
+                strip_metadata,
+                compress,
+            } => {
+                self.bully.load.fetch_add(1, Ordering::Relaxed);
+                let options = UploadOptions { strip_metadata, compress };
+                let response = self
+                    .process_upload("forwarded", username, filename, image_data, options, &Progress::default())
+                    .await;
+                self.bully.load.fetch_sub(1, Ordering::Relaxed);
+                match response {
+                    ServerResponse::EncryptedImageData { metadata, data } => InternalMessage::ImageData { metadata: metadata.map(|m| *m), data },
+                    ServerResponse::Error { message, .. } => InternalMessage::ProcessingComplete {
+                        success: false,
+                        message,
+                    },
+                    _ => InternalMessage::ProcessingComplete {
+                        success: false,
+                        message: "unexpected processing result".to_string(),
+                    },
+                }
+            }
+            InternalMessage::StorageUsage { username } => InternalMessage::StoredBytes {
+                bytes: self.stored_bytes(&username).await,
+            },
+            InternalMessage::QuotaCheck { username } => InternalMessage::StoredBytes {
+                bytes: self.cluster_usage(&username).await,
+            },
+            InternalMessage::UpgradeKeys { username } => InternalMessage::KeysUpgraded {
+                images: self.upgrade_local_keys(&username).await,
+            },
+            InternalMessage::RotateKeys { username } => {
+                let (generation, remaining) = self.rotate_local_keys(&username).await;
+                InternalMessage::RotationProgress { generation, remaining }
+            }
+            InternalMessage::UsageChanged { username } => {
+                self.quotas.forget_usage(&username);
+                InternalMessage::ProcessingComplete {
+                    success: true,
+                    message: "usage forgotten".to_string(),
+                }
+            }
+            InternalMessage::SetQuota { username, quota_bytes } => match self.quotas.set_limit(&username, quota_bytes) {
+                Ok(()) => InternalMessage::ProcessingComplete {
+                    success: true,
+                    message: "quota set".to_string(),
+                },
+                Err(e) => InternalMessage::ProcessingComplete {
+                    success: false,
+                    message: e.to_string(),
+                },
+            },
+            InternalMessage::Ping => InternalMessage::Pong,
+            InternalMessage::UserSync { users } => {
+                self.merge_users(users);
+                InternalMessage::ProcessingComplete {
+                    success: true,
+                    message: "users merged".to_string(),
+                }
+            }
+            _ => InternalMessage::ProcessingComplete {
+                success: false,
+                message: "unsupported internal message".to_string(),
+            },
+        }
+    }
+
+    /// Leader only: register every node with the load balancer and refresh
+    /// its load from the latest heartbeats
+    ///
+    /// A node whose load is unknown or stale counts as fully loaded.
+    async fn sync_load_balancer(&self) {
+        let lb_guard = self.load_balancer.read().await;
+        let Some(lb) = lb_guard.as_ref() else {
+            return;
+        };
+
+        let registered: Vec<u32> = lb.servers.read().await.keys().copied().collect();
+        if !registered.contains(&self.id) {
+            lb.register_server(self.id, self.address.clone()).await;
+        }
+        lb.update_server_load(self.id, self.bully.load.load(Ordering::Relaxed) as usize).await;
+
+        let loads = self.bully.peer_loads();
+        for peer in self.bully.get_all_peers().await {
+            if !registered.contains(&peer.id) {
+                lb.register_server(peer.id, peer.address.clone()).await;
+            }
+            let load = match loads.get(&peer.id) {
+                Some(Some(load)) => *load as usize,
+                _ => usize::MAX,
+            };
+            lb.update_server_load(peer.id, load).await;
+        }
+    }
+
+    /// Leader only: have the load balancer ping its nodes every heartbeat
+    /// interval and stop handing uploads to those that stop answering,
+    /// logging every node it takes out or back in
+    async fn start_health_checks(&self) -> Option<HealthChecks> {
+        let lb_guard = self.load_balancer.read().await;
+        let (health_checks, mut events) = lb_guard.as_ref()?.start_health_checks(
+            Duration::from_millis(self.settings.heartbeat_interval_ms),
+            PING_TIMEOUT,
+            self.peer_signer.clone(),
+        );
+        let node_id = self.id;
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                match event {
+                    HealthEvent::Ejected { server_id, address } => println!(
+                        "Node {}: Node {} at {} missed {} pings, no longer handing it uploads",
+                        node_id, server_id, address, FAILURES_TO_EJECT
+                    ),
+                    HealthEvent::Readmitted { server_id, address } => println!(
+                        "Node {}: Node {} at {} answers pings again, handing it uploads",
+                        node_id, server_id, address
+                    ),
+                }
+            }
+        });
+        Some(health_checks)
+    }
+
+    /// Check which peer nodes are alive by pinging them
+    async fn get_alive_nodes(&self) -> Vec<u32> {
+        // Always include myself if I can process requests
+        let mut alive = vec![self.id];
+        alive.extend(self.ping_peers().await.into_iter().filter(|&(_, up)| up).map(|(id, _)| id));
+        alive.sort();
+        alive
+    }
+
+    /// Push this node's user registry to every peer, which merges it into its own
+    ///
+    /// Peers only acknowledge the push: a registry never goes back to
+    /// whoever sent one, so sending UserSync does not reveal any users.
+    async fn sync_users(&self) {
+        let message = InternalMessage::UserSync {
+            users: self.users.snapshot(),
+        };
+        let mut syncs = tokio::task::JoinSet::new();
+        for peer in self.bully.get_all_peers().await {
+            if peer.id == self.id {
+                continue;
+            }
+            let node = self.clone_for_task();
+            let message = message.clone();
+            syncs.spawn(async move { node.ask_peer(&peer.address, &message).await });
+        }
+        while syncs.join_next().await.is_some() {}
+    }
+
+    fn merge_users(&self, users: HashMap<String, UserRecord>) {
+        match self.users.merge(users) {
+            Ok(0) => {}
+            Ok(learned) => {
+                // The merge does not say whose keys changed
+                self.key_cache.clear();
+                println!("Node {}: Learned {} users from a peer", self.id, learned);
+            }
+            Err(e) => eprintln!("Node {}: Failed to save the user registry: {}", self.id, e),
+        }
+    }
+
+    /// Ping every peer at once, returning whether each answered with Pong
+    ///
+    /// A node that accepts the connection but cannot answer within
+    /// `PING_TIMEOUT`, e.g. because it is overloaded or stuck, counts as down.
+    async fn ping_peers(&self) -> Vec<(u32, bool)> {
+        let mut pings = tokio::task::JoinSet::new();
+        for peer in self.bully.get_all_peers().await {
+            if peer.id == self.id {
+                continue;
+            }
+            let node = self.clone_for_task();
+            pings.spawn(async move {
+                let answer = node.ask_peer_within(&peer.address, &InternalMessage::Ping, PING_TIMEOUT).await;
+                (peer.id, matches!(answer, Some(InternalMessage::Pong)))
+            });
+        }
+
+        let mut results = Vec::new();
+        while let Some(result) = pings.join_next().await {
+            if let Ok(result) = result {
+                results.push(result);
+            }
+        }
+        results
+    }
+}
+
+impl Clone for LoadBalancer {
+    fn clone(&self) -> Self {
+        LoadBalancer {
+            servers: Arc::clone(&self.servers),
+            next_index: Arc::clone(&self.next_index),
+        }
+    }
+}
+
+/// Run CPU-heavy work, such as encrypting a large image, on a blocking
+/// thread, so the runtime's threads stay free to answer heartbeats and
+/// elections meanwhile
+async fn blocking<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> T {
+    match tokio::task::spawn_blocking(work).await {
+        Ok(result) => result,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// SHA-256 of `data`, hashed on a blocking thread, and `data` handed back
+async fn digest_of(data: Vec<u8>) -> (Vec<u8>, [u8; 32]) {
+    blocking(move || {
+        let digest = sha256(&data);
+        (data, digest)
+    })
+    .await
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The response to a request that failed validation
+fn bad_request(e: ValidationError) -> ServerResponse {
+    ServerResponse::Error {
+        message: e.to_string(),
+        code: ErrorCode::BadRequest,
+    }
+}
+
+fn unix_now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn unknown_upload(upload_id: &str) -> ServerResponse {
+    ServerResponse::Error {
+        message: format!("Unknown upload {}, start it again", upload_id),
+        code: ErrorCode::NotFound,
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt::init();
+
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() < 2 {
+        eprintln!(
+            "Usage: {} <node_id> [--listen <address>] [--join <seed_address>] [--allow-anonymous] [--insecure-dev-secret]",
+            args[0]
+        );
+        eprintln!("Example: {} 1", args[0]);
+        eprintln!("Example: {} 4 --listen 127.0.0.1:8004 --join 127.0.0.1:8001", args[0]);
+        std::process::exit(1);
+    }
+
+    let node_id: u32 = args[1].parse().expect("Node ID must be a number");
+
+    let mut listen_address = None;
+    let mut join_seed = None;
+    let mut allow_anonymous = false;
+    let mut insecure_dev_secret = false;
+    let mut rest = args[2..].iter();
+    while let Some(flag) = rest.next() {
+        match flag.as_str() {
+            "--listen" => listen_address = rest.next().cloned(),
+            "--join" => join_seed = rest.next().cloned(),
+            "--allow-anonymous" => allow_anonymous = true,
+            "--insecure-dev-secret" => insecure_dev_secret = true,
+            other => {
+                eprintln!("Unknown argument: {}", other);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Load configuration from config.toml
+    let config = Config::load("config.toml").expect("Failed to load config.toml");
+    if config.election.cluster_secret.is_none() && !insecure_dev_secret {
+        eprintln!("No cluster_secret in config.toml; set one, the same on every node.");
+        eprintln!("To try the cluster out locally with a built-in key instead, pass --insecure-dev-secret.");
+        std::process::exit(1);
+    }
+    if Routing::from_name(&config.routing).is_none() {
+        eprintln!("Unknown routing '{}' in config.toml; use \"hash\" or \"leader\".", config.routing);
+        std::process::exit(1);
+    }
+
+    // Get this node's address from the command line or config
+    let address = listen_address
+        .or_else(|| config.get_server_address(node_id))
+        .unwrap_or_else(|| panic!("Node {} not found in config.toml", node_id));
+
+    println!("Node {} will bind to {}", node_id, address);
+
+    let node = ServerNode::new(node_id, address, &config, allow_anonymous);
+
+    // Add peers from config
+    for peer_id in 1..=3 {
+        if peer_id != node_id {
+            if let Some(peer_address) = config.get_server_address(peer_id) {
+                node.add_peer(peer_id, peer_address, config.get_priority(peer_id)).await;
+            }
+        }
+    }
+
+    node.start(join_seed).await;
+}
+
+/// Protocol version a tagged client request was written in, this node's own
+/// for anything else
+fn wire_version(frame: &[u8]) -> u16 {
+    match decode::<TaggedProbe>(frame) {
+        Ok((_, TaggedProbe::Client(probe))) => probe.protocol_version,
+        _ => PROTOCOL_VERSION,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::PEER_MESSAGE_MAX_AGE_MS;
+    use crate::encryption::open_metadata;
+    use crate::protocol::SignedInternal;
+    use std::sync::atomic::AtomicUsize;
+
+    const SECRET: &str = "test cluster secret";
+
+    /// A node storing under a directory of its own, removed when the test ends
+    struct TestNode {
+        node: ServerNode,
+        dir: PathBuf,
+    }
+
+    impl TestNode {
+        fn new(id: u32) -> Self {
+            let dir = std::env::temp_dir().join(format!("distinsta-node-{}", uuid::Uuid::new_v4()));
+            let config: Config = toml::from_str(&format!(
+                "storage_dir = {:?}\n[servers]\nnode{} = \"127.0.0.1:0\"\n[election]\ncluster_secret = {:?}\n",
+                dir.to_string_lossy(),
+                id,
+                SECRET
+            ))
+            .unwrap();
+            TestNode {
+                node: ServerNode::new(id, "127.0.0.1:0".to_string(), &config, false),
+                dir,
+            }
+        }
+    }
+
+    impl std::ops::Deref for TestNode {
+        type Target = ServerNode;
+
+        fn deref(&self) -> &ServerNode {
+            &self.node
+        }
+    }
+
+    impl Drop for TestNode {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Nodes of one cluster serving on local ports, their storage and
+    /// election state under one directory removed when the test ends
+    struct TestCluster {
+        nodes: Vec<ServerNode>,
+        tasks: Vec<tokio::task::JoinHandle<()>>,
+        dir: PathBuf,
+    }
+
+    impl TestCluster {
+        /// Start `size` nodes with `settings` added to their config, and wait
+        /// until they agree on a leader
+        async fn start(size: u32, settings: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("distinsta-cluster-{}", uuid::Uuid::new_v4()));
+            let mut addresses = Vec::new();
+            for _ in 0..size {
+                let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+                addresses.push(listener.local_addr().unwrap().to_string());
+            }
+            let servers: String = (1..=size).map(|id| format!("node{} = {:?}\n", id, addresses[id as usize - 1])).collect();
+            let config: Config = toml::from_str(&format!(
+                "storage_dir = {storage:?}\n{settings}\n[servers]\n{servers}[election]\ncluster_secret = {SECRET:?}\n\
+                 state_dir = {state:?}\nstartup_delay_ms = 50\nelection_settle_ms = 200\n\
+                 heartbeat_interval_ms = 200\nelection_timeout_ms = 500\ncoordinator_timeout_ms = 1000\n",
+                storage = dir.join("storage").to_string_lossy(),
+                state = dir.join("state").to_string_lossy(),
+            ))
+            .unwrap();
+
+            let mut nodes = Vec::new();
+            for id in 1..=size {
+                let node = ServerNode::new(id, addresses[id as usize - 1].clone(), &config, false);
+                for peer in (1..=size).filter(|&peer| peer != id) {
+                    node.add_peer(peer, addresses[peer as usize - 1].clone(), 0).await;
+                }
+                nodes.push(node);
+            }
+            let tasks = nodes
+                .iter()
+                .map(|node| {
+                    let node = node.clone_for_task();
+                    tokio::spawn(async move { node.start(None).await })
+                })
+                .collect();
+            let cluster = TestCluster { nodes, tasks, dir };
+
+            let deadline = Instant::now() + Duration::from_secs(15);
+            while cluster.leader().await.is_none() {
+                assert!(Instant::now() < deadline, "no leader elected");
+                sleep(Duration::from_millis(50)).await;
+            }
+            cluster
+        }
+
+        /// The leader every node agrees on
+        async fn leader(&self) -> Option<u32> {
+            let mut leaders = HashSet::new();
+            for node in &self.nodes {
+                leaders.insert(node.bully.get_leader().await?);
+            }
+            match leaders.len() {
+                1 => leaders.into_iter().next(),
+                _ => None,
+            }
+        }
+
+        fn node(&self, id: u32) -> &ServerNode {
+            &self.nodes[id as usize - 1]
+        }
+    }
+
+    impl Drop for TestCluster {
+        fn drop(&mut self) {
+            for task in &self.tasks {
+                task.abort();
+            }
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Send `body` to the node at `address` as a client would, returning its
+    /// answer past any progress reports
+    async fn ask_node(address: &str, body: ClientRequest) -> ServerResponse {
+        let request_id = uuid::Uuid::new_v4().to_string();
+        let envelope = RequestEnvelope {
+            protocol_version: PROTOCOL_VERSION,
+            request_id: request_id.clone(),
+            body,
+            compression: None,
+        };
+        let payload = encode(Format::Bincode, &TaggedRequest::Client(envelope)).unwrap();
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        write_frame(&mut stream, &payload, MAX_FRAME_LEN).await.unwrap();
+        loop {
+            let frame = read_frame(&mut stream, MAX_FRAME_LEN).await.unwrap().expect("node closed the connection");
+            let (_, envelope) = decode::<ResponseEnvelope>(&frame).unwrap();
+            assert_eq!(envelope.request_id, request_id);
+            if !matches!(envelope.body, ServerResponse::Progress { .. }) {
+                return envelope.body;
+            }
+        }
+    }
+
+    fn test_png() -> Vec<u8> {
+        let mut png = std::io::Cursor::new(Vec::new());
+        image::RgbImage::from_pixel(4, 4, image::Rgb([200, 30, 30]))
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        png.into_inner()
+    }
+
+    /// A registry entry for `username` as another node would push it
+    fn pushed_user(username: &str) -> HashMap<String, UserRecord> {
+        let peer = TestNode::new(9);
+        peer.users.register(username, "hunter2", 1).unwrap();
+        peer.users.snapshot()
+    }
+
+    async fn answer_internal(node: &ServerNode, signed: SignedInternal) -> InternalMessage {
+        let payload = node.answer_tagged(Format::Bincode, TaggedRequest::Internal(signed)).await.unwrap();
+        decode::<InternalMessage>(&payload).unwrap().1
+    }
+
+    #[tokio::test]
+    async fn user_sync_is_acknowledged_without_the_registry() {
+        let node = TestNode::new(1);
+        node.users.register("alice", "hunter2", 1).unwrap();
+
+        let push = InternalMessage::UserSync { users: pushed_user("bob") };
+        let signed = PeerSigner::new(SECRET.as_bytes()).sign(&push, unix_now_ms());
+        let answer = answer_internal(&node, signed).await;
+        assert!(matches!(answer, InternalMessage::ProcessingComplete { success: true, .. }), "{:?}", answer);
+        assert!(!format!("{:?}", answer).contains("alice"));
+        assert!(node.users.contains("bob"));
+    }
+
+    #[tokio::test]
+    async fn forged_user_sync_is_refused() {
+        let node = TestNode::new(1);
+        let push = InternalMessage::UserSync { users: pushed_user("alice") };
+
+        let forged = PeerSigner::new(b"guessed secret").sign(&push, unix_now_ms());
+        let answer = answer_internal(&node, forged).await;
+        assert!(matches!(answer, InternalMessage::ProcessingComplete { success: false, .. }), "{:?}", answer);
+        assert!(!node.users.contains("alice"));
+
+        // Nor can a signed push be replayed once it is stale
+        let stale = unix_now_ms() - PEER_MESSAGE_MAX_AGE_MS - 1_000;
+        let replayed = PeerSigner::new(SECRET.as_bytes()).sign(&push, stale);
+        let answer = answer_internal(&node, replayed).await;
+        assert!(matches!(answer, InternalMessage::ProcessingComplete { success: false, .. }), "{:?}", answer);
+        assert!(!node.users.contains("alice"));
+    }
+
+    #[tokio::test]
+    async fn pushed_user_does_not_replace_an_earlier_registration() {
+        let node = TestNode::new(1);
+        node.users.register("alice", "hunter2", 1).unwrap();
+        let mut takeover = pushed_user("alice");
+        takeover.get_mut("alice").unwrap().created_at_unix = 2;
+
+        let signed = PeerSigner::new(SECRET.as_bytes()).sign(&InternalMessage::UserSync { users: takeover }, unix_now_ms());
+        answer_internal(&node, signed).await;
+        assert_eq!(node.users.check_password("alice", "hunter2"), PasswordCheck::Valid);
+        assert_eq!(node.users.snapshot()["alice"].created_at_unix, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_views_cannot_share_the_last_credit() {
+        let node = Arc::new(TestNode::new(1));
+        node.images.store("alice", "cat.png", b"encrypted cat").await.unwrap();
+        assert!(node.grant_access("alice", "cat.png", "bob", 2).await.unwrap());
+        assert!(matches!(
+            node.consume_view("bob", "alice", "cat.png").await,
+            InternalMessage::ViewGranted { remaining_views: 1, .. }
+        ));
+
+        let mut views = tokio::task::JoinSet::new();
+        for _ in 0..8 {
+            let node = Arc::clone(&node);
+            views.spawn(async move { node.consume_view("bob", "alice", "cat.png").await });
+        }
+        let (mut granted, mut exhausted) = (0, 0);
+        while let Some(answer) = views.join_next().await {
+            match answer.unwrap() {
+                InternalMessage::ViewGranted { data, remaining_views, .. } => {
+                    assert_eq!((data.as_slice(), remaining_views), (&b"encrypted cat"[..], 0));
+                    granted += 1;
+                }
+                InternalMessage::QuotaExhausted => exhausted += 1,
+                other => panic!("unexpected answer {:?}", other),
+            }
+        }
+        assert_eq!((granted, exhausted), (1, 7));
+    }
+
+    #[tokio::test]
+    async fn views_are_spent_only_by_the_viewer_granted_them() {
+        let node = TestNode::new(1);
+        node.images.store("alice", "cat.png", b"encrypted cat").await.unwrap();
+        node.grant_access("alice", "cat.png", "bob", 1).await.unwrap();
+
+        assert!(matches!(node.consume_view("carol", "alice", "cat.png").await, InternalMessage::NotShared));
+        assert!(matches!(node.consume_view("bob", "alice", "dog.png").await, InternalMessage::ImageNotFound));
+        assert!(matches!(node.consume_view("bob", "alice", "cat.png").await, InternalMessage::ViewGranted { .. }));
+        assert!(matches!(node.consume_view("bob", "alice", "cat.png").await, InternalMessage::QuotaExhausted));
+    }
+
+    #[test]
+    fn routing_defaults_to_hash_and_refuses_unknown_names() {
+        let config: Config = toml::from_str("[servers]\nnode1 = \"127.0.0.1:8001\"\n").unwrap();
+        assert_eq!(Routing::from_name(&config.routing), Some(Routing::Hash));
+        assert_eq!(Routing::from_name("leader"), Some(Routing::Leader));
+        assert_eq!(Routing::from_name("Leader"), None);
+        assert_eq!(Routing::from_name("round-robin"), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn leader_routing_has_workers_encrypt_uploads_sent_to_the_leader() {
+        let cluster = TestCluster::start(3, "routing = \"leader\"").await;
+        let leader = cluster.leader().await.unwrap();
+        let leader_address = cluster.node(leader).address.clone();
+        let workers: Vec<u32> = (1..=3).filter(|&id| id != leader).collect();
+
+        let register = ClientRequest::Register {
+            username: "alice".to_string(),
+            password: "hunter2".to_string(),
+        };
+        let ServerResponse::LoggedIn { token, .. } = ask_node(&leader_address, register).await else {
+            panic!("registration failed");
+        };
+
+        let image = test_png();
+        let upload = |filename: String| ClientRequest::UploadImage {
+            username: "alice".to_string(),
+            token: token.clone(),
+            image_data: image.clone(),
+            filename,
+            sha256: None,
+            strip_metadata: None,
+            compress: None,
+        };
+
+        // Workers point uploads at the leader instead of taking them
+        for &worker in &workers {
+            let answer = ask_node(&cluster.node(worker).address, upload("refused.png".to_string())).await;
+            assert!(matches!(answer, ServerResponse::Error { code: ErrorCode::NotAssigned, .. }), "{:?}", answer);
+        }
+
+        let mut processed_by = HashMap::new();
+        for i in 0..6 {
+            let filename = format!("cat{}.png", i);
+            match ask_node(&leader_address, upload(filename.clone())).await {
+                ServerResponse::EncryptedImageData { metadata: Some(metadata), data } => {
+                    assert!(!data.is_empty());
+                    processed_by.insert(filename, metadata.processed_by_node);
+                }
+                other => panic!("upload of {} failed: {:?}", filename, other),
+            }
+        }
+
+        // Every worker encrypted and stored some of them
+        for &worker in &workers {
+            let stored: Vec<_> = processed_by.iter().filter(|&(_, &node)| node == worker).collect();
+            assert!(!stored.is_empty(), "node {} processed none of {:?}", worker, processed_by);
+            for (filename, _) in stored {
+                assert!(cluster.node(worker).images.contains("alice", filename).await);
+                assert!(!cluster.node(leader).images.contains("alice", filename).await);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn uploads_without_a_live_leader_ask_for_a_retry() {
+        let node = TestNode::new(1);
+        node.bully.add_peer(2, "127.0.0.1:1".to_string(), 0).await.unwrap();
+        node.users.register("alice", "hunter2", 1).unwrap();
+        let (token, _) = node.tokens.issue("alice", unix_now());
+        let upload = || ClientRequest::UploadImage {
+            username: "alice".to_string(),
+            token: token.clone(),
+            image_data: test_png(),
+            filename: "cat.png".to_string(),
+            sha256: None,
+            strip_metadata: None,
+            compress: None,
+        };
+
+        // Before any leader is known, then after the leader missed a heartbeat
+        let answer = node.handle_client_request("before", upload(), &Progress::default()).await;
+        assert!(matches!(answer, ServerResponse::Error { code: ErrorCode::Retriable, .. }), "{:?}", answer);
+        node.bully.set_leader(2, 1).await;
+        *node.bully.leader_failures.write().await = 1;
+        let answer = node.handle_client_request("failover", upload(), &Progress::default()).await;
+        assert!(matches!(answer, ServerResponse::Error { code: ErrorCode::Retriable, .. }), "{:?}", answer);
+
+        assert!(!node.images.contains("alice", "cat.png").await);
+    }
+
+    /// A lone node leading itself, with alice registered, and her token
+    async fn serving_node() -> (TestNode, String) {
+        let node = TestNode::new(1);
+        node.bully.set_leader(1, 1).await;
+        node.users.register("alice", "hunter2", 1).unwrap();
+        let (token, _) = node.tokens.issue("alice", unix_now());
+        (node, token)
+    }
+
+    fn upload_of(token: &str, filename: &str, image_data: Vec<u8>) -> ClientRequest {
+        ClientRequest::UploadImage {
+            username: "alice".to_string(),
+            token: token.to_string(),
+            sha256: Some(sha256(&image_data)),
+            image_data,
+            filename: filename.to_string(),
+            strip_metadata: None,
+            compress: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn upload_changed_on_the_way_in_is_refused() {
+        let (node, token) = serving_node().await;
+        let mut upload = upload_of(&token, "cat.png", test_png());
+        upload.image_data_mut().unwrap()[20] ^= 1;
+
+        let answer = node.handle_client_request("r1", upload, &Progress::default()).await;
+        assert!(matches!(&answer, ServerResponse::ChecksumMismatch { filename } if filename == "cat.png"), "{:?}", answer);
+        assert!(!node.images.contains("alice", "cat.png").await);
+    }
+
+    #[tokio::test]
+    async fn upload_answer_carries_the_checksum_of_what_was_stored() {
+        let (node, token) = serving_node().await;
+        let upload = upload_of(&token, "cat.png", test_png());
+        let ServerResponse::EncryptedImageData { metadata: Some(metadata), data } =
+            node.handle_client_request("r1", upload, &Progress::default()).await
+        else {
+            panic!("upload failed");
+        };
+        assert_eq!(metadata.content_sha256, sha256_hex(&data));
+        assert_eq!(metadata.content_id, Some(sha256_hex(&test_png())));
+    }
+
+    /// A local port that accepts connections and then never says a word
+    async fn silent_peer() -> (String, tokio::task::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let task = tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+        (address, task)
+    }
+
+    #[tokio::test]
+    async fn ping_is_answered_with_pong() {
+        let node = TestNode::new(1);
+        let ping = PeerSigner::new(SECRET.as_bytes()).sign(&InternalMessage::Ping, unix_now_ms());
+        assert!(matches!(answer_internal(&node, ping).await, InternalMessage::Pong));
+    }
+
+    #[tokio::test]
+    async fn peer_that_accepts_but_never_pongs_is_down() {
+        let node = TestNode::new(1);
+        let (silent, listener) = silent_peer().await;
+        node.add_peer(2, silent, 0).await;
+
+        let started = Instant::now();
+        assert_eq!(node.ping_peers().await, vec![(2, false)]);
+        assert!(started.elapsed() < PING_TIMEOUT + Duration::from_millis(200), "took {:?}", started.elapsed());
+        assert_eq!(node.get_alive_nodes().await, vec![1]);
+        listener.abort();
+    }
+
+    #[tokio::test]
+    async fn only_peers_that_pong_are_alive() {
+        let cluster = TestCluster::start(2, "").await;
+        let node = &cluster.nodes[0];
+        let (silent, listener) = silent_peer().await;
+        node.add_peer(3, silent, 0).await;
+        // Nothing listens on port 1, so the connection is refused
+        node.add_peer(4, "127.0.0.1:1".to_string(), 0).await;
+
+        let started = Instant::now();
+        assert_eq!(node.get_alive_nodes().await, vec![1, 2]);
+        // Peers are pinged at once, so the silent one costs a single timeout
+        assert!(started.elapsed() < PING_TIMEOUT + Duration::from_millis(200), "took {:?}", started.elapsed());
+        listener.abort();
+    }
+
+    /// A filename the hash routing gives to the first of `alive` nodes
+    fn filename_routed_first(username: &str, alive: u64) -> String {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+        (0..)
+            .map(|n| format!("cat{}.png", n))
+            .find(|filename| {
+                let mut hasher = DefaultHasher::new();
+                username.hash(&mut hasher);
+                filename.hash(&mut hasher);
+                hasher.finish().is_multiple_of(alive)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn lower_node_wins_a_key_claimed_at_once() {
+        let mut claims = Claims::default();
+        assert_eq!(claims.claim("r1", 2), 2);
+        assert_eq!(claims.claim("r1", 1), 1);
+        assert_eq!(claims.claim("r1", 3), 1);
+        assert_eq!(claims.holder("r1"), Some(1));
+        assert_eq!(claims.holder("r2"), None);
+    }
+
+    #[test]
+    fn oldest_claims_are_forgotten_first() {
+        let mut claims = Claims::default();
+        for n in 0..=MAX_CLAIMS {
+            claims.claim(&format!("r{}", n), 1);
+        }
+        assert_eq!(claims.holder("r0"), None);
+        assert_eq!(claims.holder("r1"), Some(1));
+        assert_eq!(claims.holder(&format!("r{}", MAX_CLAIMS)), Some(1));
+    }
+
+    /// Serve `node` on a local port, as `start` would without its elections
+    async fn serve(node: &ServerNode) -> (String, tokio::task::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let node = node.clone_for_task();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let node = node.clone_for_task();
+                tokio::spawn(async move { node.handle_connection(stream).await });
+            }
+        });
+        (address, task)
+    }
+
+    #[tokio::test]
+    async fn broadcast_upload_is_processed_once_when_nodes_disagree_on_who_is_alive() {
+        // Node 2 knows no peers, so it takes every upload itself and has
+        // nobody to tell, while node 1 sees both nodes and takes the uploads
+        // routed to it
+        let nodes = [TestNode::new(1), TestNode::new(2)];
+        let (address, server) = serve(&nodes[1]).await;
+        nodes[0].add_peer(2, address, 0).await;
+        for (node, id) in nodes.iter().zip(1..) {
+            node.bully.set_leader(id, 1).await;
+            node.users.register("alice", "hunter2", 1).unwrap();
+        }
+        let (token, _) = nodes[0].tokens.issue("alice", unix_now());
+        let upload = upload_of(&token, &filename_routed_first("alice", 2), test_png());
+
+        let mut stored = 0;
+        for node in nodes.iter().rev() {
+            match node.handle_client_request("r1", upload.clone(), &Progress::default()).await {
+                ServerResponse::EncryptedImageData { .. } => stored += 1,
+                ServerResponse::Error { code: ErrorCode::NotAssigned, .. } => {}
+                other => panic!("unexpected answer {:?}", other),
+            }
+        }
+        assert_eq!(stored, 1);
+        server.abort();
+    }
+
+    #[test]
+    fn settled_key_stays_with_its_holder() {
+        let mut claims = Claims::default();
+        assert_eq!(claims.settle("r1", 2), 2);
+        assert_eq!(claims.claim("r1", 1), 2);
+        // A lower node that claimed first keeps the key from settling
+        claims.claim("r2", 1);
+        assert_eq!(claims.settle("r2", 2), 1);
+    }
+
+    #[tokio::test]
+    async fn invalid_names_are_bad_requests_naming_the_field() {
+        let (node, token) = serving_node().await;
+        let cases = [
+            (upload_of(&token, "../../etc/passwd", test_png()), "filename"),
+            (upload_of(&token, "cat.png", Vec::new()), "image_data"),
+            (
+                ClientRequest::Register {
+                    username: "a".repeat(10 * 1024),
+                    password: "hunter2".to_string(),
+                },
+                "username",
+            ),
+        ];
+        for (request, field) in cases {
+            match node.serve_client("r1", request, &Progress::default()).await {
+                ServerResponse::Error { message, code: ErrorCode::BadRequest } => {
+                    assert!(message.starts_with(field), "{}", message)
+                }
+                other => panic!("{} was not refused: {:?}", field, other),
+            }
+        }
+        assert!(!node.images.contains("alice", "cat.png").await);
+    }
+
+    #[tokio::test]
+    async fn downloads_of_damaged_ctr_images_are_refused_as_corrupted() {
+        let (mut node, token) = serving_node().await;
+        node.node.scheme = EncryptionScheme::Aes128Ctr;
+        let upload = upload_of(&token, "cat.png", test_png());
+        assert!(matches!(
+            node.handle_client_request("r1", upload, &Progress::default()).await,
+            ServerResponse::EncryptedImageData { .. }
+        ));
+        let stored = node.images.load("alice", "cat.png").await.unwrap();
+        assert!(is_authenticated(&stored));
+        let download = || ClientRequest::DownloadImage {
+            username: "alice".to_string(),
+            token: token.clone(),
+            image: ImageSelector::Name("cat.png".to_string()),
+        };
+
+        // The middle of the ciphertext and the last byte of the HMAC
+        for offset in [stored.len() / 2, stored.len() - 1] {
+            let mut damaged = stored.clone();
+            damaged[offset] ^= 1;
+            node.images.store("alice", "cat.png", &damaged).await.unwrap();
+            match node.handle_client_request("r2", download(), &Progress::default()).await {
+                ServerResponse::Error { code: ErrorCode::Corrupted, message } => assert!(message.contains("cat.png"), "{}", message),
+                other => panic!("damaged at {} was served: {:?}", offset, other),
+            }
+        }
+        node.images.store("alice", "cat.png", &stored).await.unwrap();
+        let answer = node.handle_client_request("r3", download(), &Progress::default()).await;
+        assert!(matches!(answer, ServerResponse::ImageData { .. }), "{:?}", answer);
+    }
+
+    #[tokio::test]
+    async fn chunked_uploads_are_encrypted_from_disk_to_disk() {
+        let (node, token) = serving_node().await;
+        let image: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let chunk_size = 64 * 1024;
+        let begin = ClientRequest::BeginUpload {
+            username: "alice".to_string(),
+            token,
+            filename: "big.png".to_string(),
+            total_size: image.len() as u64,
+            chunk_size: chunk_size as u64,
+            strip_metadata: None,
+            compress: None,
+        };
+        let ServerResponse::UploadStarted { upload_id } = node.handle_client_request("r1", begin, &Progress::default()).await
+        else {
+            panic!("upload not started");
+        };
+        for (seq, data) in image.chunks(chunk_size).enumerate() {
+            let chunk = ClientRequest::UploadChunk {
+                upload_id: upload_id.clone(),
+                seq: seq as u64,
+                data: data.to_vec(),
+            };
+            let answer = node.handle_client_request("r1", chunk, &Progress::default()).await;
+            assert!(matches!(answer, ServerResponse::ChunkReceived { .. }), "{:?}", answer);
+        }
+        let finish = ClientRequest::FinishUpload {
+            upload_id,
+            sha256: sha256_hex(&image),
+        };
+        let answer = node.handle_client_request("r1", finish, &Progress::default()).await;
+        assert!(matches!(answer, ServerResponse::EncryptedImageData { .. }), "{:?}", answer);
+
+        let stored = node.images.load("alice", "big.png").await.unwrap();
+        assert_eq!(open_image(&stored, &node.user_keys("alice"), "alice", "big.png").unwrap(), image);
+    }
+
+    #[tokio::test]
+    async fn heartbeats_are_answered_while_a_large_upload_is_encrypted() {
+        // Tests run on one thread, so encrypting on it would hold up the
+        // heartbeats until the upload is done
+        let (mut node, token) = serving_node().await;
+        node.node.scheme = EncryptionScheme::Aes128Ctr;
+        let image: Vec<u8> = (0..12 << 20).map(|i: u32| (i % 251) as u8).collect();
+        let upload = upload_of(&token, "big.png", image);
+
+        let slowest = Arc::new(Mutex::new(Duration::ZERO));
+        let heartbeats = tokio::spawn({
+            let (bully, slowest) = (Arc::clone(&node.bully), Arc::clone(&slowest));
+            async move {
+                let mut ticker = tokio::time::interval(Duration::from_millis(20));
+                ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                loop {
+                    let due = ticker.tick().await;
+                    let heartbeat = bully::BullyMessage::Heartbeat {
+                        from_id: 2,
+                        address: None,
+                        load: 0,
+                    };
+                    assert!(bully.handle_message(heartbeat).await.is_some());
+                    let mut slowest = slowest.lock().unwrap();
+                    *slowest = (*slowest).max(due.elapsed());
+                }
+            }
+        });
+        let started = Instant::now();
+        let answer = node.handle_client_request("r1", upload, &Progress::default()).await;
+        assert!(matches!(answer, ServerResponse::EncryptedImageData { .. }), "{:?}", answer);
+        heartbeats.abort();
+
+        let slowest = *slowest.lock().unwrap();
+        let timeout = Duration::from_millis(node.settings.heartbeat_timeout_ms);
+        assert!(slowest < timeout / 4, "a heartbeat waited {:?} during an upload of {:?}", slowest, started.elapsed());
+    }
+
+    /// Every file under `dir`, however deep
+    fn files_under(dir: &std::path::Path) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                files.extend(files_under(&path));
+            } else {
+                files.push(path);
+            }
+        }
+        files
+    }
+
+    #[tokio::test]
+    async fn shared_views_reach_the_viewer_under_their_own_key() {
+        let (mut node, alice) = serving_node().await;
+        node.node.legacy_keys = false;
+        node.users.register("bob", "hunter3", 1).unwrap();
+        let (bob, _) = node.tokens.issue("bob", unix_now());
+        assert!(node.ensure_user_key("alice", "hunter2").await);
+        assert!(node.ensure_user_key("bob", "hunter3").await);
+        let upload = upload_of(&alice, "cat.png", test_png());
+        assert!(matches!(
+            node.handle_client_request("r1", upload, &Progress::default()).await,
+            ServerResponse::EncryptedImageData { .. }
+        ));
+        let share = ClientRequest::ShareImage {
+            owner: "alice".to_string(),
+            token: alice,
+            image: ImageSelector::Name("cat.png".to_string()),
+            with_user: "bob".to_string(),
+            allowed_views: 1,
+        };
+        let answer = node.handle_client_request("r2", share, &Progress::default()).await;
+        assert!(matches!(answer, ServerResponse::Shared { .. }), "{:?}", answer);
+
+        let view = ClientRequest::ViewSharedImage {
+            viewer: "bob".to_string(),
+            token: bob,
+            owner: "alice".to_string(),
+            filename: "cat.png".to_string(),
+        };
+        let ServerResponse::SharedImageData { data, remaining_views: 0, .. } =
+            node.handle_client_request("r3", view, &Progress::default()).await
+        else {
+            panic!("view refused");
+        };
+        assert_eq!(open_image(&data, &node.user_keys("bob"), "bob", "cat.png").unwrap(), test_png());
+        assert!(open_image(&data, &node.user_keys("alice"), "alice", "cat.png").is_err());
+        assert!(open_image(&data, &node.user_keys("alice"), "bob", "cat.png").is_err());
+
+        let png = test_png();
+        for file in files_under(&node.dir) {
+            let stored = std::fs::read(&file).unwrap();
+            assert!(!stored.windows(png.len()).any(|window| window == png), "{} holds the image in the clear", file.display());
+        }
+    }
+
+    fn download_of(token: &str, image: ImageSelector) -> ClientRequest {
+        ClientRequest::DownloadImage {
+            username: "alice".to_string(),
+            token: token.to_string(),
+            image,
+        }
+    }
+
+    #[tokio::test]
+    async fn identical_uploads_share_a_content_id() {
+        let (node, token) = serving_node().await;
+        let mut ids = Vec::new();
+        for (request_id, filename) in [("r1", "cat.png"), ("r2", "copy.png")] {
+            let upload = upload_of(&token, filename, test_png());
+            let ServerResponse::EncryptedImageData { metadata: Some(metadata), .. } =
+                node.handle_client_request(request_id, upload, &Progress::default()).await
+            else {
+                panic!("upload of {} failed", filename);
+            };
+            ids.push(metadata.content_id);
+        }
+        assert_eq!(ids[0], ids[1]);
+        assert_eq!(ids[0], Some(sha256_hex(&test_png())));
+    }
+
+    #[tokio::test]
+    async fn selectors_resolve_to_filenames() {
+        let (node, token) = serving_node().await;
+        for (request_id, filename) in [("r1", "first.png"), ("r2", "second.png")] {
+            let upload = upload_of(&token, filename, test_png());
+            node.handle_client_request(request_id, upload, &Progress::default()).await;
+            // Upload times are kept in seconds
+            tokio::time::sleep(Duration::from_millis(1100)).await;
+        }
+        let id = sha256_hex(&test_png());
+
+        let by_name = node.resolve_image("r3", "alice", ImageSelector::Name("second.png".to_string())).await;
+        assert_eq!(by_name.unwrap(), "second.png");
+        let by_id = node.resolve_image("r4", "alice", ImageSelector::ContentId(id.clone())).await;
+        assert_eq!(by_id.unwrap(), "first.png");
+
+        let unknown = "0".repeat(64);
+        let answer = node.resolve_image("r5", "alice", ImageSelector::ContentId(unknown.clone())).await;
+        assert!(matches!(&answer, Err(ServerResponse::NotFound { filename }) if *filename == format!("id:{}", unknown)), "{:?}", answer);
+        // Another user's images are never found by id
+        node.users.register("bob", "hunter3", 1).unwrap();
+        let answer = node.resolve_image("r6", "bob", ImageSelector::ContentId(id)).await;
+        assert!(matches!(answer, Err(ServerResponse::NotFound { .. })), "{:?}", answer);
+    }
+
+    #[tokio::test]
+    async fn renamed_images_download_and_delete_by_content_id() {
+        let (node, token) = serving_node().await;
+        let upload = upload_of(&token, "cat.png", test_png());
+        node.handle_client_request("r1", upload, &Progress::default()).await;
+        let rename = ClientRequest::UpdateImageMeta {
+            username: "alice".to_string(),
+            token: token.clone(),
+            filename: "cat.png".to_string(),
+            new_filename: Some("dog.png".to_string()),
+            tags: None,
+            caption: None,
+        };
+        node.handle_client_request("r2", rename, &Progress::default()).await;
+        let id = ImageSelector::ContentId(sha256_hex(&test_png()));
+
+        let by_old_name = download_of(&token, ImageSelector::Name("cat.png".to_string()));
+        let answer = node.handle_client_request("r3", by_old_name, &Progress::default()).await;
+        assert!(matches!(answer, ServerResponse::NotFound { .. }), "{:?}", answer);
+        let answer = node.handle_client_request("r4", download_of(&token, id.clone()), &Progress::default()).await;
+        let ServerResponse::ImageData { data, filename, .. } = answer else {
+            panic!("download by id failed: {:?}", answer);
+        };
+        assert_eq!(filename, "dog.png");
+        assert_eq!(*node.open(data, "alice", "dog.png").await.unwrap(), test_png());
+
+        let delete = ClientRequest::DeleteImage {
+            username: "alice".to_string(),
+            token: token.clone(),
+            image: id.clone(),
+        };
+        let answer = node.handle_client_request("r5", delete, &Progress::default()).await;
+        assert!(matches!(&answer, ServerResponse::Deleted { filename } if filename == "dog.png"), "{:?}", answer);
+        let answer = node.handle_client_request("r6", download_of(&token, id), &Progress::default()).await;
+        assert!(matches!(answer, ServerResponse::NotFound { .. }), "{:?}", answer);
+    }
+
+    /// An upload of testdata/metastrip/gps.jpg, whose EXIF holds a GPS
+    /// latitude, as `filename`
+    fn gps_upload(token: &str, filename: &str, strip_metadata: Option<bool>) -> ClientRequest {
+        let mut upload = upload_of(token, filename, include_bytes!("../testdata/metastrip/gps.jpg").to_vec());
+        if let ClientRequest::UploadImage { strip_metadata: strip, .. } = &mut upload {
+            *strip = strip_metadata;
+        }
+        upload
+    }
+
+    /// `username`'s stored `filename`, decrypted
+    async fn stored_plaintext(node: &ServerNode, username: &str, filename: &str) -> Vec<u8> {
+        let data = node.images.load(username, filename).await.unwrap();
+        node.open(data, username, filename).await.unwrap().to_vec()
+    }
+
+    #[tokio::test]
+    async fn gps_exif_is_gone_from_stored_images_when_stripping() {
+        let (mut node, token) = serving_node().await;
+        let has_exif = |data: &[u8]| data.windows(6).any(|window| window == b"Exif\0\0");
+
+        let answer = node.handle_client_request("r1", gps_upload(&token, "kept.jpg", None), &Progress::default()).await;
+        let ServerResponse::EncryptedImageData { metadata: Some(metadata), .. } = answer else {
+            panic!("upload failed: {:?}", answer);
+        };
+        assert_eq!(metadata.metadata_strip, None);
+        assert!(has_exif(&stored_plaintext(&node, "alice", "kept.jpg").await));
+
+        let answer = node.handle_client_request("r2", gps_upload(&token, "gone.jpg", Some(true)), &Progress::default()).await;
+        let ServerResponse::EncryptedImageData { metadata: Some(metadata), .. } = answer else {
+            panic!("upload failed: {:?}", answer);
+        };
+        assert_eq!(metadata.metadata_strip, Some(MetadataStrip::Stripped { bytes_removed: 90 }));
+        let stored = stored_plaintext(&node, "alice", "gone.jpg").await;
+        assert!(!has_exif(&stored));
+        assert!(image::load_from_memory(&stored).is_ok());
+        assert_eq!(metadata.content_id, Some(sha256_hex(include_bytes!("../testdata/metastrip/gps.jpg"))));
+
+        // The server's default applies unless the upload says otherwise
+        node.node.strip_metadata = true;
+        node.handle_client_request("r3", gps_upload(&token, "default.jpg", None), &Progress::default()).await;
+        assert!(!has_exif(&stored_plaintext(&node, "alice", "default.jpg").await));
+        node.handle_client_request("r4", gps_upload(&token, "opted_out.jpg", Some(false)), &Progress::default()).await;
+        assert!(has_exif(&stored_plaintext(&node, "alice", "opted_out.jpg").await));
+    }
+
+    #[tokio::test]
+    async fn unsupported_formats_are_stored_as_uploaded_with_a_warning() {
+        let (node, token) = serving_node().await;
+        let mut bmp = std::io::Cursor::new(Vec::new());
+        image::RgbImage::from_pixel(4, 4, image::Rgb([200, 30, 30]))
+            .write_to(&mut bmp, image::ImageOutputFormat::Bmp)
+            .unwrap();
+        let bmp = bmp.into_inner();
+        let mut upload = upload_of(&token, "cat.bmp", bmp.clone());
+        if let ClientRequest::UploadImage { strip_metadata, .. } = &mut upload {
+            *strip_metadata = Some(true);
+        }
+
+        let answer = node.handle_client_request("r1", upload, &Progress::default()).await;
+        let ServerResponse::EncryptedImageData { metadata: Some(metadata), .. } = answer else {
+            panic!("upload failed: {:?}", answer);
+        };
+        assert!(matches!(metadata.metadata_strip, Some(MetadataStrip::NotStripped { .. })), "{:?}", metadata.metadata_strip);
+        assert_eq!(stored_plaintext(&node, "alice", "cat.bmp").await, bmp);
+    }
+
+    fn compressed_upload_of(token: &str, filename: &str, image_data: Vec<u8>, compress: Option<bool>) -> ClientRequest {
+        let mut upload = upload_of(token, filename, image_data);
+        if let ClientRequest::UploadImage { compress: option, .. } = &mut upload {
+            *option = compress;
+        }
+        upload
+    }
+
+    fn test_bmp() -> Vec<u8> {
+        let mut bmp = std::io::Cursor::new(Vec::new());
+        image::RgbImage::from_pixel(256, 256, image::Rgb([200, 30, 30]))
+            .write_to(&mut bmp, image::ImageOutputFormat::Bmp)
+            .unwrap();
+        bmp.into_inner()
+    }
+
+    #[tokio::test]
+    async fn bitmaps_are_stored_compressed_and_read_back_whatever_the_setting() {
+        let (mut node, token) = serving_node().await;
+        node.node.compress_storage = true;
+        node.users.register("bob", "hunter3", 1).unwrap();
+        let (bob, _) = node.tokens.issue("bob", unix_now());
+        let bmp = test_bmp();
+
+        let answer = node.handle_client_request("r1", compressed_upload_of(&token, "raw.bmp", bmp.clone(), None), &Progress::default()).await;
+        let ServerResponse::EncryptedImageData { metadata: Some(metadata), data } = answer else {
+            panic!("upload failed: {:?}", answer);
+        };
+        assert!(matches!(metadata.storage_compression, Some(StorageCompression::Zstd { compressed_size }) if compressed_size < bmp.len() as u64 / 10));
+        assert!(is_compressed(&data) && data.len() < bmp.len() / 10);
+
+        // Stored images say how they were stored; the setting only applies
+        // to new uploads
+        node.node.compress_storage = false;
+        assert_eq!(stored_plaintext(&node, "alice", "raw.bmp").await, bmp);
+        let share = ClientRequest::ShareImage {
+            owner: "alice".to_string(),
+            token,
+            image: ImageSelector::Name("raw.bmp".to_string()),
+            with_user: "bob".to_string(),
+            allowed_views: 1,
+        };
+        node.handle_client_request("r2", share, &Progress::default()).await;
+        let view = ClientRequest::ViewSharedImage {
+            viewer: "bob".to_string(),
+            token: bob,
+            owner: "alice".to_string(),
+            filename: "raw.bmp".to_string(),
+        };
+        let ServerResponse::SharedImageData { data, .. } = node.handle_client_request("r3", view, &Progress::default()).await else {
+            panic!("view refused");
+        };
+        assert_eq!(open_image(&data, &node.user_keys("bob"), "bob", "raw.bmp").unwrap(), bmp);
+    }
+
+    #[tokio::test]
+    async fn pngs_are_stored_uncompressed_even_when_asked() {
+        let (node, token) = serving_node().await;
+        // A photo's worth of noise, which PNG's own deflate leaves nothing
+        // for zstd to take
+        let mut png = std::io::Cursor::new(Vec::new());
+        image::RgbImage::from_fn(64, 64, |_, _| image::Rgb(rand::random()))
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let png = png.into_inner();
+        let answer =
+            node.handle_client_request("r1", compressed_upload_of(&token, "cat.png", png.clone(), Some(true)), &Progress::default()).await;
+        let ServerResponse::EncryptedImageData { metadata: Some(metadata), data } = answer else {
+            panic!("upload failed: {:?}", answer);
+        };
+        assert!(matches!(metadata.storage_compression, Some(StorageCompression::Skipped { .. })), "{:?}", metadata.storage_compression);
+        assert!(!is_compressed(&data));
+        assert_eq!(stored_plaintext(&node, "alice", "cat.png").await, png);
+
+        let answer = node.handle_client_request("r2", compressed_upload_of(&token, "raw.bmp", test_bmp(), Some(false)), &Progress::default()).await;
+        let ServerResponse::EncryptedImageData { metadata: Some(metadata), data } = answer else {
+            panic!("upload failed: {:?}", answer);
+        };
+        assert_eq!(metadata.storage_compression, None);
+        assert!(!is_compressed(&data));
+    }
+
+    fn list_of(token: &str, sealed_names: bool) -> ClientRequest {
+        ClientRequest::ListImages {
+            username: "alice".to_string(),
+            token: token.to_string(),
+            offset: 0,
+            limit: 0,
+            sort_by: ImageSort::Name,
+            descending: false,
+            sealed_names,
+        }
+    }
+
+    #[tokio::test]
+    async fn listings_open_names_only_when_asked_to() {
+        let (node, token) = serving_node().await;
+        assert!(node.ensure_user_key("alice", "hunter2").await);
+        let upload = upload_of(&token, "passport_scan.png", test_png());
+        node.handle_client_request("r1", upload, &Progress::default()).await;
+
+        let ServerResponse::ImageList { images, .. } = node.handle_client_request("r2", list_of(&token, false), &Progress::default()).await
+        else {
+            panic!("listing failed");
+        };
+        assert_eq!(images[0].filename, "passport_scan.png");
+        assert!(images[0].sealed_entry.is_none());
+
+        let ServerResponse::ImageList { images, .. } = node.handle_client_request("r3", list_of(&token, true), &Progress::default()).await
+        else {
+            panic!("listing failed");
+        };
+        assert!(!images[0].filename.contains("passport"));
+        let sealed = images[0].sealed_entry.as_ref().unwrap();
+        let json = open_metadata(&sealed.sealed, &node.user_keys("alice"), "alice", sealed.generation).unwrap();
+        assert!(String::from_utf8(json).unwrap().contains("passport_scan.png"));
+
+        // Downloads by name still find it through its token
+        let download = download_of(&token, ImageSelector::Name("passport_scan.png".to_string()));
+        let answer = node.handle_client_request("r4", download, &Progress::default()).await;
+        assert!(matches!(&answer, ServerResponse::ImageData { filename, .. } if filename == "passport_scan.png"), "{:?}", answer);
+    }
+
+    /// A worker that answers every internal message with a processed image
+    /// after `delay`, counting the messages in `handled`
+    async fn slow_worker(delay: Duration, handled: Arc<AtomicUsize>) -> (String, tokio::task::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let task = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let handled = Arc::clone(&handled);
+                tokio::spawn(async move {
+                    if let Ok(Some(_)) = read_frame(&mut stream, MAX_FRAME_LEN).await {
+                        handled.fetch_add(1, Ordering::Relaxed);
+                        tokio::time::sleep(delay).await;
+                        let answer = InternalMessage::ImageData { metadata: None, data: vec![1, 2, 3] };
+                        let frame = encode(Format::Bincode, &answer).unwrap();
+                        let _ = write_frame(&mut stream, &frame, MAX_FRAME_LEN).await;
+                    }
+                });
+            }
+        });
+        (address, task)
+    }
+
+    #[tokio::test]
+    async fn least_loaded_balancing_hands_a_fast_worker_more_uploads() {
+        let mut leader = TestNode::new(1);
+        leader.node.balancing = Balancing::LeastLoaded;
+        let (slow, fast) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        // The slow worker has the lower id, so it wins every tie
+        let (slow_address, slow_task) = slow_worker(Duration::from_millis(300), Arc::clone(&slow)).await;
+        let (fast_address, fast_task) = slow_worker(Duration::from_millis(5), Arc::clone(&fast)).await;
+        let lb = LoadBalancer::new();
+        lb.register_server(2, slow_address).await;
+        lb.register_server(3, fast_address).await;
+        *leader.load_balancer.write().await = Some(lb);
+
+        let mut uploads = tokio::task::JoinSet::new();
+        for i in 0..30 {
+            let node = leader.clone_for_task();
+            uploads.spawn(async move {
+                tokio::time::sleep(Duration::from_millis(20 * i)).await;
+                let options = UploadOptions { strip_metadata: false, compress: false };
+                node.dispatch_upload("r", "alice".to_string(), format!("{}.png", i), test_png(), options, &Progress::default())
+                    .await
+            });
+        }
+        while let Some(answer) = uploads.join_next().await {
+            assert!(matches!(answer.unwrap(), ServerResponse::EncryptedImageData { .. }));
+        }
+        let (slow, fast) = (slow.load(Ordering::Relaxed), fast.load(Ordering::Relaxed));
+        assert_eq!(slow + fast, 30);
+        // Round-robin would give each 15
+        assert!(slow >= 1 && fast >= 4 * slow, "slow {} fast {}", slow, fast);
+        slow_task.abort();
+        fast_task.abort();
+    }
+
+    #[tokio::test]
+    async fn load_balancer_takes_loads_from_heartbeats() {
+        let leader = TestNode::new(1);
+        *leader.load_balancer.write().await = Some(LoadBalancer::new());
+        for id in [2, 3, 4] {
+            leader.add_peer(id, format!("127.0.0.1:{}", 9000 + id), 0).await;
+        }
+        leader.bully.load.store(2, Ordering::Relaxed);
+        for (from_id, load) in [(2, 7), (3, 0)] {
+            leader.bully.handle_message(bully::BullyMessage::Heartbeat { from_id, address: None, load }).await;
+        }
+        leader.sync_load_balancer().await;
+
+        let lb_guard = leader.load_balancer.read().await;
+        let lb = lb_guard.as_ref().unwrap();
+        let loads: HashMap<u32, usize> = lb.servers.read().await.values().map(|s| (s.server_id, s.current_load)).collect();
+        // Node 4 never reported, so it counts as fully loaded
+        assert_eq!(loads, HashMap::from([(1, 2), (2, 7), (3, 0), (4, usize::MAX)]));
+        assert_eq!(lb.get_least_loaded_server(&HashSet::new()).await.map(|(id, _)| id), Some(3));
+        assert_eq!(lb.get_least_loaded_server(&HashSet::from([3])).await.map(|(id, _)| id), Some(1));
+    }
+
+    #[tokio::test]
+    async fn forwarded_uploads_count_as_in_flight_while_processed() {
+        let worker = TestNode::new(2);
+        let signer = PeerSigner::new(SECRET.as_bytes());
+        let process = signer.sign(
+            &InternalMessage::ProcessImage {
+                username: "alice".to_string(),
+                image_data: vec![7; 8 << 20],
+                filename: "big.png".to_string(),
+                strip_metadata: false,
+                compress: false,
+            },
+            unix_now_ms(),
+        );
+        let node = worker.clone_for_task();
+        let processing = tokio::spawn(async move { answer_internal(&node, process).await });
+        let mut peak = 0;
+        while !processing.is_finished() {
+            peak = peak.max(worker.bully.load.load(Ordering::Relaxed));
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(matches!(processing.await.unwrap(), InternalMessage::ImageData { .. }));
+        assert_eq!(peak, 1);
+        assert_eq!(worker.bully.load.load(Ordering::Relaxed), 0);
+    }
 }
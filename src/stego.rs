@@ -0,0 +1,82 @@
+use image::{ImageOutputFormat, RgbImage};
+use std::io::Cursor;
+
+/// Marks a PNG that carries a payload
+const MAGIC: &[u8; 4] = b"DSTG";
+/// Magic marker, then the payload length as a big-endian u64
+const HEADER_LEN: usize = MAGIC.len() + 8;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StegoError {
+    #[error("cover image cannot be read or written: {0}")]
+    Image(#[from] image::ImageError),
+    #[error("cover holds {capacity} bytes, the payload needs {needed}")]
+    CapacityExceeded { needed: usize, capacity: usize },
+    #[error("image carries no hidden payload")]
+    NoPayload,
+    #[error("hidden payload is cut short")]
+    Truncated,
+}
+
+/// Hide `payload` in the least significant bits of the cover's red, green
+/// and blue values, giving a PNG that still shows the cover
+///
+/// Each channel value carries one bit, so a cover holds three bits per pixel
+/// less the 12-byte header. Alpha is dropped.
+pub fn embed(cover_png: &[u8], payload: &[u8]) -> Result<Vec<u8>, StegoError> {
+    let mut pixels = image::load_from_memory(cover_png)?.to_rgb8();
+    let capacity = capacity(&pixels);
+    if payload.len() > capacity {
+        return Err(StegoError::CapacityExceeded {
+            needed: payload.len(),
+            capacity,
+        });
+    }
+
+    let mut message = Vec::with_capacity(HEADER_LEN + payload.len());
+    message.extend_from_slice(MAGIC);
+    message.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    message.extend_from_slice(payload);
+    let bits = message.iter().flat_map(|byte| (0..8).rev().map(move |i| (byte >> i) & 1));
+    for (channel, bit) in pixels.iter_mut().zip(bits) {
+        *channel = (*channel & !1) | bit;
+    }
+
+    let mut png = Vec::new();
+    pixels.write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)?;
+    Ok(png)
+}
+
+/// The payload `embed` hid in a PNG
+pub fn extract(stego_png: &[u8]) -> Result<Vec<u8>, StegoError> {
+    let pixels = image::load_from_memory(stego_png)?.to_rgb8();
+    let mut bytes = pixels.as_raw().chunks_exact(8).map(|bits| bits.iter().fold(0u8, |byte, bit| (byte << 1) | (bit & 1)));
+
+    let header: Vec<u8> = bytes.by_ref().take(HEADER_LEN).collect();
+    if header.len() < HEADER_LEN || &header[..MAGIC.len()] != MAGIC {
+        return Err(StegoError::NoPayload);
+    }
+    let len = u64::from_be_bytes(header[MAGIC.len()..].try_into().unwrap());
+    if len > capacity(&pixels) as u64 {
+        return Err(StegoError::Truncated);
+    }
+    Ok(bytes.take(len as usize).collect())
+}
+
+/// A plain gradient PNG just large enough to hold `payload_len` bytes, for
+/// when no cover is configured
+pub fn default_cover(payload_len: usize) -> Result<Vec<u8>, StegoError> {
+    let pixels_needed = ((HEADER_LEN + payload_len) * 8).div_ceil(3).max(1);
+    let side = (pixels_needed as f64).sqrt().ceil() as u32;
+    let cover = RgbImage::from_fn(side, side, |x, y| {
+        image::Rgb([(x * 255 / side) as u8, (y * 255 / side) as u8, 160])
+    });
+    let mut png = Vec::new();
+    cover.write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)?;
+    Ok(png)
+}
+
+/// Payload bytes a cover of this size holds
+fn capacity(pixels: &RgbImage) -> usize {
+    (pixels.as_raw().len() / 8).saturating_sub(HEADER_LEN)
+}
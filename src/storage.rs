@@ -0,0 +1,1257 @@
+use crate::encryption::{
+    access_header_len, file_token, from_hex, lookup_token, open_metadata, read_header, seal_metadata, strip_header, to_hex, update_header,
+    CryptoError, SecretKey, UserKey,
+};
+use crate::protocol::{CatalogEntry, ImageEntry, ImageMetadata, SealedEntry, ViewNotice};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use zeroize::Zeroizing;
+
+/// How much of an assembled upload is read between progress reports
+const UPLOAD_READ_SLICE: usize = 1024 * 1024;
+/// Most view notifications kept per owner; older ones are dropped first
+const MAX_NOTIFICATIONS: usize = 500;
+
+/// Views of one image granted to another user
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AccessGrant {
+    pub allowed_views: u32,
+    pub used_views: u32,
+    /// The image's data key wrapped for the viewer, if they registered a
+    /// public key
+    #[serde(default)]
+    pub share_key: Option<ShareKey>,
+}
+
+/// An image's data key wrapped to a viewer's X25519 public key by
+/// `wrap_for_viewer`, so that nodes can hand them the image as stored
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShareKey {
+    pub public_key: [u8; 32],
+    pub wrapped_key: Vec<u8>,
+    /// `data_key_id` of the image when the key was wrapped; a different one
+    /// means it was sealed again since and the key must be wrapped again
+    pub data_key_id: String,
+}
+
+/// Who may view an image, kept in the authenticated header in front of it
+/// so it travels with the image
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AccessHeader {
+    owner: String,
+    /// Keyed by viewer
+    grants: HashMap<String, AccessGrant>,
+    created_at_unix: u64,
+}
+
+/// One image in a catalog bucket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CatalogRecord {
+    /// `file_token` of the image's name, which it and its sidecars are
+    /// stored under
+    file: String,
+    /// Generation of the owner's key `entry` is sealed under; `None` if it is
+    /// plain JSON, for owners without a key of their own
+    generation: Option<u32>,
+    /// Hex of the `CatalogEntry`, sealed by `seal_metadata` or plain
+    entry: String,
+}
+
+impl CatalogRecord {
+    /// The record of `entry`, sealed under `user_key` if there is one
+    fn new(file: String, entry: &CatalogEntry, user_key: Option<&UserKey>, username: &str) -> std::io::Result<Self> {
+        let json = Zeroizing::new(serde_json::to_vec(entry)?);
+        Ok(match user_key {
+            Some(user_key) => CatalogRecord {
+                file,
+                generation: Some(user_key.generation),
+                entry: to_hex(&seal_metadata(user_key, username, &json)),
+            },
+            None => CatalogRecord {
+                file,
+                generation: None,
+                entry: to_hex(&json),
+            },
+        })
+    }
+
+    fn open(&self, user_keys: &[UserKey], username: &str) -> Result<CatalogEntry, CryptoError> {
+        let bytes = from_hex(&self.entry).ok_or(CryptoError::Truncated)?;
+        let json = match self.generation {
+            Some(generation) => Zeroizing::new(open_metadata(&bytes, user_keys, username, generation)?),
+            None => Zeroizing::new(bytes),
+        };
+        serde_json::from_slice(&json).map_err(|_| CryptoError::Tampered)
+    }
+
+    /// The entry as sent to clients that open it themselves
+    fn sealed(&self) -> Option<SealedEntry> {
+        Some(SealedEntry {
+            generation: self.generation?,
+            sealed: from_hex(&self.entry)?,
+        })
+    }
+}
+
+/// Outcome of a viewer asking to see a shared image
+#[derive(Debug)]
+pub enum ViewCheck {
+    /// The image is not stored on this node
+    NotStored,
+    /// The owner never shared the image with the viewer
+    NotShared,
+    QuotaExhausted,
+    /// One view was used up; `remaining` are left
+    Granted {
+        data: Vec<u8>,
+        remaining: u32,
+        share_key: Option<ShareKey>,
+    },
+}
+
+/// Outcome of renaming an image or changing its tags or caption
+#[derive(Debug)]
+pub enum MetaUpdate {
+    /// The image is not stored on this node
+    NotStored,
+    /// The new name is already taken by another image of the user
+    NameTaken,
+    /// Tags or a caption were given for an image stored without metadata
+    NoMetadata,
+    /// The metadata as it now stands, if the image has any
+    Updated(Option<Box<ImageMetadata>>),
+}
+
+/// Encrypted images kept by one node, laid out as `<dir>/<username>/<file>`,
+/// `<file>` being the `file_token` of the image's name
+///
+/// Names, tags and captions are kept only in the user's catalog, sealed
+/// under their key: a bucket per `lookup_token` in
+/// `<dir>/<username>/.meta/<token>.json`, holding every image whose name is
+/// the same but for case. An image's sealed thumbnail, if one was made, is in
+/// `<dir>/<username>/.thumbs/<file>`.
+/// The views its owner granted are in an access header in front of the
+/// encrypted image, or for images shared before that in
+/// `<dir>/<username>/.access/<file>.json` until they next change. Views of
+/// a user's images not yet fetched are queued in
+/// `<dir>/<username>/.notifications/views.json`.
+#[derive(Debug, Clone)]
+pub struct ImageStore {
+    node_id: u32,
+    dir: PathBuf,
+    /// Authenticates access headers; the same on every node
+    header_key: SecretKey,
+    /// Keys the tokens images are stored under; the same on every node
+    catalog_key: SecretKey,
+    /// Serializes read-modify-write of access records so concurrent views
+    /// cannot spend the same remaining view twice
+    access_lock: Arc<Mutex<()>>,
+    /// Serializes read-modify-write of catalog buckets, which several
+    /// images can share
+    catalog_lock: Arc<Mutex<()>>,
+}
+
+impl ImageStore {
+    pub fn new(node_id: u32, dir: PathBuf, header_key: SecretKey, catalog_key: SecretKey) -> Self {
+        ImageStore {
+            node_id,
+            dir,
+            header_key,
+            catalog_key,
+            access_lock: Arc::new(Mutex::new(())),
+            catalog_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Where an image is stored, `None` if a name would escape the storage directory
+    fn path(&self, username: &str, filename: &str) -> Option<PathBuf> {
+        if !is_safe_name(username) || !is_safe_name(filename) {
+            return None;
+        }
+        Some(self.dir.join(username).join(file_token(&self.catalog_key, username, filename)))
+    }
+
+    /// The catalog bucket an image's entry is kept in
+    fn metadata_path(&self, username: &str, filename: &str) -> Option<PathBuf> {
+        if !is_safe_name(username) || !is_safe_name(filename) {
+            return None;
+        }
+        let token = lookup_token(&self.catalog_key, username, filename);
+        Some(self.dir.join(username).join(".meta").join(format!("{}.json", token)))
+    }
+
+    fn access_path(&self, username: &str, filename: &str) -> Option<PathBuf> {
+        self.sidecar_path(username, ".access", filename)
+    }
+
+    fn thumbnail_path(&self, username: &str, filename: &str) -> Option<PathBuf> {
+        if !is_safe_name(username) || !is_safe_name(filename) {
+            return None;
+        }
+        Some(self.dir.join(username).join(".thumbs").join(file_token(&self.catalog_key, username, filename)))
+    }
+
+    fn sidecar_path(&self, username: &str, kind: &str, filename: &str) -> Option<PathBuf> {
+        if !is_safe_name(username) || !is_safe_name(filename) {
+            return None;
+        }
+        let file = file_token(&self.catalog_key, username, filename);
+        Some(self.dir.join(username).join(kind).join(format!("{}.json", file)))
+    }
+
+    /// Store an encrypted image, keeping the views granted for whatever was
+    /// stored under its name before
+    pub async fn store(&self, username: &str, filename: &str, data: &[u8]) -> std::io::Result<PathBuf> {
+        let path = self
+            .path(username, filename)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid image name"))?;
+        let _guard = self.access_lock.lock().await;
+        let existing = tokio::fs::read(&path).await.unwrap_or_default();
+        match self.load_access(username, filename, &existing).await {
+            // A header that fails its check is dropped with the old image
+            Ok(access) if !access.grants.is_empty() => self.store_access(username, filename, data, &access).await?,
+            _ => self.write_atomic(username, filename, data).await?,
+        }
+        Ok(path)
+    }
+
+    /// Store `data` over an image only if it still holds `expected`, keeping
+    /// its views, returning whether it did
+    ///
+    /// Lets a rewrite of a stored image lose to an upload that replaced it
+    /// in the meantime.
+    pub async fn replace(&self, username: &str, filename: &str, expected: &[u8], data: &[u8]) -> std::io::Result<bool> {
+        let _guard = self.access_lock.lock().await;
+        let Some(existing) = self.load_stored(username, filename).await else {
+            return Ok(false);
+        };
+        if strip_header(&existing).ok() != Some(expected) {
+            return Ok(false);
+        }
+        match self.load_access(username, filename, &existing).await {
+            Ok(access) if !access.grants.is_empty() => self.store_access(username, filename, data, &access).await?,
+            _ => self.write_atomic(username, filename, data).await?,
+        }
+        Ok(true)
+    }
+
+    /// Replace an image's file through a temporary one, so it is never seen
+    /// half written
+    async fn write_atomic(&self, username: &str, filename: &str, data: &[u8]) -> std::io::Result<()> {
+        let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid image name");
+        let path = self.path(username, filename).ok_or_else(invalid)?;
+        let tmp = self.sidecar_path(username, ".tmp", filename).ok_or_else(invalid)?;
+        if let Some(dir) = tmp.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, &path).await
+    }
+
+    /// Keep an image's metadata in the catalog, sealed under the first of
+    /// `user_keys`, the owner's current key; in the clear if they have none
+    pub async fn store_metadata(&self, username: &str, metadata: &ImageMetadata, user_keys: &[UserKey]) -> std::io::Result<()> {
+        let entry = CatalogEntry {
+            filename: metadata.filename.clone(),
+            metadata: Some(metadata.clone()),
+        };
+        self.store_entry(username, &entry, user_keys.first()).await
+    }
+
+    /// `None` if the image has no metadata, e.g. it was stored before
+    /// metadata was recorded, or none of `user_keys` opens it
+    pub async fn load_metadata(&self, username: &str, filename: &str, user_keys: &[UserKey]) -> Option<ImageMetadata> {
+        self.load_entry(username, filename, user_keys).await?.metadata
+    }
+
+    /// Put an image's entry in its catalog bucket in place of any it had
+    async fn store_entry(&self, username: &str, entry: &CatalogEntry, user_key: Option<&UserKey>) -> std::io::Result<()> {
+        let path = self
+            .metadata_path(username, &entry.filename)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid image name"))?;
+        let file = file_token(&self.catalog_key, username, &entry.filename);
+        let record = CatalogRecord::new(file, entry, user_key, username)?;
+        let _guard = self.catalog_lock.lock().await;
+        let mut records = read_bucket(&path).await?;
+        records.retain(|r| r.file != record.file);
+        records.push(record);
+        write_bucket(&path, &records).await
+    }
+
+    /// The entry of the image named exactly `filename`, found through its
+    /// bucket without opening the others sharing it
+    async fn load_entry(&self, username: &str, filename: &str, user_keys: &[UserKey]) -> Option<CatalogEntry> {
+        let path = self.metadata_path(username, filename)?;
+        let file = file_token(&self.catalog_key, username, filename);
+        let records = read_bucket(&path).await.ok()?;
+        let entry = records.iter().find(|r| r.file == file)?.open(user_keys, username).ok()?;
+        // A record moved into another image's place opens, but names that image
+        Some(entry).filter(|entry| entry.filename == filename)
+    }
+
+    /// Take an image's entry out of its catalog bucket
+    async fn remove_entry(&self, username: &str, filename: &str) -> std::io::Result<()> {
+        let Some(path) = self.metadata_path(username, filename) else {
+            return Ok(());
+        };
+        let file = file_token(&self.catalog_key, username, filename);
+        let _guard = self.catalog_lock.lock().await;
+        let mut records = read_bucket(&path).await?;
+        let before = records.len();
+        records.retain(|r| r.file != file);
+        if records.len() == before {
+            return Ok(());
+        }
+        write_bucket(&path, &records).await
+    }
+
+    /// Seal every catalog entry of `username` under the first of
+    /// `user_keys` that is not under it already, returning how many could
+    /// not be opened to be
+    ///
+    /// Run once a user has a key, to seal entries kept in the clear before,
+    /// and after a rotation, so that earlier keys can be retired.
+    pub async fn seal_catalog(&self, username: &str, user_keys: &[UserKey]) -> std::io::Result<u32> {
+        let Some(current) = user_keys.first() else {
+            return Ok(0);
+        };
+        if !is_safe_name(username) {
+            return Ok(0);
+        }
+        let _guard = self.catalog_lock.lock().await;
+        let mut left = 0;
+        for path in bucket_paths(&self.dir.join(username)).await {
+            let mut records = read_bucket(&path).await?;
+            let mut changed = false;
+            for record in &mut records {
+                if record.generation == Some(current.generation) {
+                    continue;
+                }
+                match record.open(user_keys, username) {
+                    Ok(entry) => {
+                        *record = CatalogRecord::new(record.file.clone(), &entry, Some(current), username)?;
+                        changed = true;
+                    }
+                    Err(_) => left += 1,
+                }
+            }
+            if changed {
+                write_bucket(&path, &records).await?;
+            }
+        }
+        Ok(left)
+    }
+
+    /// The encrypted image, without its access header
+    pub async fn load(&self, username: &str, filename: &str) -> Option<Vec<u8>> {
+        let path = self.path(username, filename)?;
+        let mut data = tokio::fs::read(path).await.ok()?;
+        let header_len = access_header_len(&data).ok()?.min(data.len());
+        data.drain(..header_len);
+        Some(data)
+    }
+
+    /// Open a stored image for reading in slices, past its access header,
+    /// with the encrypted image's size in bytes
+    pub async fn open(&self, username: &str, filename: &str) -> Option<(tokio::fs::File, u64)> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        let path = self.path(username, filename)?;
+        let mut file = tokio::fs::File::open(path).await.ok()?;
+        let len = file.metadata().await.ok()?.len();
+        let mut start = [0u8; 5];
+        let read = file.read(&mut start).await.ok()?;
+        let header_len = (access_header_len(&start[..read]).ok()? as u64).min(len);
+        file.seek(std::io::SeekFrom::Start(header_len)).await.ok()?;
+        Some((file, len - header_len))
+    }
+
+    /// Store an image's sealed thumbnail, replacing any it had
+    pub async fn store_thumbnail(&self, username: &str, filename: &str, data: &[u8]) -> std::io::Result<()> {
+        let path = self
+            .thumbnail_path(username, filename)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid image name"))?;
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(&path, data).await
+    }
+
+    /// `None` if no thumbnail was made of the image
+    pub async fn load_thumbnail(&self, username: &str, filename: &str) -> Option<Vec<u8>> {
+        tokio::fs::read(self.thumbnail_path(username, filename)?).await.ok()
+    }
+
+    /// Remove an image's thumbnail, if it has one
+    pub async fn delete_thumbnail(&self, username: &str, filename: &str) -> std::io::Result<()> {
+        let Some(path) = self.thumbnail_path(username, filename) else {
+            return Ok(());
+        };
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the image is stored on this node
+    pub async fn contains(&self, username: &str, filename: &str) -> bool {
+        match self.path(username, filename) {
+            Some(path) => tokio::fs::metadata(path).await.map(|m| m.is_file()).unwrap_or(false),
+            None => false,
+        }
+    }
+
+    /// Grant `viewer` a fresh quota of `allowed_views` views of one of
+    /// `owner`'s images, with the image's data key wrapped for them if given
+    ///
+    /// Returns `false` without recording anything if the image is not stored here.
+    pub async fn grant_access(
+        &self,
+        owner: &str,
+        filename: &str,
+        viewer: &str,
+        allowed_views: u32,
+        share_key: Option<ShareKey>,
+    ) -> std::io::Result<bool> {
+        let _guard = self.access_lock.lock().await;
+        let Some(stored) = self.load_stored(owner, filename).await else {
+            return Ok(false);
+        };
+        let mut access = self.load_access(owner, filename, &stored).await?;
+        access.grants.insert(
+            viewer.to_string(),
+            AccessGrant {
+                allowed_views,
+                used_views: 0,
+                share_key,
+            },
+        );
+        self.store_access(owner, filename, &stored, &access).await?;
+        Ok(true)
+    }
+
+    /// Change how many views `viewer` has in total, keeping the views already
+    /// used and any data key wrapped for them
+    ///
+    /// Zero revokes the grant, wrapped key and all. Returns the grant as it
+    /// now stands, or `None` if the image is not stored here.
+    pub async fn update_access(
+        &self,
+        owner: &str,
+        filename: &str,
+        viewer: &str,
+        allowed_views: u32,
+    ) -> std::io::Result<Option<AccessGrant>> {
+        let _guard = self.access_lock.lock().await;
+        let Some(stored) = self.load_stored(owner, filename).await else {
+            return Ok(None);
+        };
+        let mut access = self.load_access(owner, filename, &stored).await?;
+        let mut grant = access.grants.remove(viewer).unwrap_or_default();
+        grant.allowed_views = allowed_views;
+        if allowed_views > 0 {
+            access.grants.insert(viewer.to_string(), grant.clone());
+        }
+        self.store_access(owner, filename, &stored, &access).await?;
+        Ok(Some(grant))
+    }
+
+    /// Spend one of `viewer`'s views of an image and return the encrypted image
+    ///
+    /// The spent view is written to the image's access header before the
+    /// image is handed out, so it survives a restart.
+    pub async fn consume_view(&self, owner: &str, filename: &str, viewer: &str) -> std::io::Result<ViewCheck> {
+        let _guard = self.access_lock.lock().await;
+        let Some(stored) = self.load_stored(owner, filename).await else {
+            return Ok(ViewCheck::NotStored);
+        };
+        let mut access = self.load_access(owner, filename, &stored).await?;
+        let Some(grant) = access.grants.get_mut(viewer) else {
+            return Ok(ViewCheck::NotShared);
+        };
+        if grant.used_views >= grant.allowed_views {
+            return Ok(ViewCheck::QuotaExhausted);
+        }
+        grant.used_views += 1;
+        let remaining = grant.allowed_views - grant.used_views;
+        let share_key = grant.share_key.clone();
+        self.store_access(owner, filename, &stored, &access).await?;
+        let header_len = access_header_len(&stored).unwrap_or(0).min(stored.len());
+        Ok(ViewCheck::Granted {
+            data: stored[header_len..].to_vec(),
+            remaining,
+            share_key,
+        })
+    }
+
+    /// Replace the data key wrapped for `viewer` in their grant, after the
+    /// image was sealed again or they registered another public key
+    ///
+    /// Does nothing if the image is gone or no longer shared with them.
+    pub async fn set_share_key(
+        &self,
+        owner: &str,
+        filename: &str,
+        viewer: &str,
+        share_key: Option<ShareKey>,
+    ) -> std::io::Result<()> {
+        let _guard = self.access_lock.lock().await;
+        let Some(stored) = self.load_stored(owner, filename).await else {
+            return Ok(());
+        };
+        let mut access = self.load_access(owner, filename, &stored).await?;
+        let Some(grant) = access.grants.get_mut(viewer) else {
+            return Ok(());
+        };
+        grant.share_key = share_key;
+        self.store_access(owner, filename, &stored, &access).await
+    }
+
+    /// An image as stored, with its access header if it has one
+    async fn load_stored(&self, owner: &str, filename: &str) -> Option<Vec<u8>> {
+        tokio::fs::read(self.path(owner, filename)?).await.ok()
+    }
+
+    /// Views granted for a stored image: from its access header, or for
+    /// images from before headers from its `.access` sidecar
+    ///
+    /// Fails if the header was changed by anything but this store.
+    async fn load_access(&self, owner: &str, filename: &str, stored: &[u8]) -> std::io::Result<AccessHeader> {
+        let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+        match read_header(stored, &self.header_key, owner) {
+            Ok(Some(contents)) => {
+                let access: AccessHeader = serde_json::from_slice(contents)?;
+                if access.owner != owner {
+                    return Err(invalid(format!("access header names {} as the owner", access.owner)));
+                }
+                Ok(access)
+            }
+            Ok(None) => {
+                let grants = match self.access_path(owner, filename) {
+                    Some(path) => match tokio::fs::read(path).await {
+                        Ok(json) => serde_json::from_slice(&json).unwrap_or_default(),
+                        Err(_) => HashMap::new(),
+                    },
+                    None => HashMap::new(),
+                };
+                Ok(AccessHeader {
+                    owner: owner.to_string(),
+                    grants,
+                    created_at_unix: unix_now(),
+                })
+            }
+            Err(e) => Err(invalid(format!("access header of {}: {}", filename, e))),
+        }
+    }
+
+    /// Write `access` into the header of a stored image, leaving the
+    /// encrypted image as it is, and drop any `.access` sidecar it replaces
+    async fn store_access(&self, owner: &str, filename: &str, stored: &[u8], access: &AccessHeader) -> std::io::Result<()> {
+        let contents = serde_json::to_vec(access)?;
+        let updated = update_header(stored, &contents, &self.header_key, owner)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        self.write_atomic(owner, filename, &updated).await?;
+        if let Some(sidecar) = self.access_path(owner, filename) {
+            match tokio::fs::remove_file(&sidecar).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn notifications_path(&self, owner: &str) -> Option<PathBuf> {
+        if !is_safe_name(owner) {
+            return None;
+        }
+        Some(self.dir.join(owner).join(".notifications").join("views.json"))
+    }
+
+    /// Queue a view for the owner, dropping the oldest past the cap
+    pub async fn push_notification(&self, owner: &str, notice: ViewNotice) -> std::io::Result<()> {
+        let path = self
+            .notifications_path(owner)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid username"))?;
+        let _guard = self.access_lock.lock().await;
+        let mut queue: Vec<ViewNotice> = match tokio::fs::read(&path).await {
+            Ok(json) => serde_json::from_slice(&json).unwrap_or_default(),
+            Err(_) => Vec::new(),
+        };
+        queue.push(notice);
+        if queue.len() > MAX_NOTIFICATIONS {
+            queue.drain(..queue.len() - MAX_NOTIFICATIONS);
+        }
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(&path, serde_json::to_vec_pretty(&queue)?).await
+    }
+
+    /// The owner's queued views after `after_seq`, leaving the queue as it is
+    pub async fn peek_notifications(&self, owner: &str, after_seq: u64) -> Vec<ViewNotice> {
+        let Some(path) = self.notifications_path(owner) else {
+            return Vec::new();
+        };
+        let _guard = self.access_lock.lock().await;
+        let queue: Vec<ViewNotice> = match tokio::fs::read(&path).await {
+            Ok(json) => serde_json::from_slice(&json).unwrap_or_default(),
+            Err(_) => Vec::new(),
+        };
+        queue.into_iter().filter(|notice| notice.seq > after_seq).collect()
+    }
+
+    /// Take the owner's queued views, leaving the queue empty
+    pub async fn take_notifications(&self, owner: &str) -> std::io::Result<Vec<ViewNotice>> {
+        let Some(path) = self.notifications_path(owner) else {
+            return Ok(Vec::new());
+        };
+        let _guard = self.access_lock.lock().await;
+        let json = match tokio::fs::read(&path).await {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        tokio::fs::remove_file(&path).await?;
+        Ok(serde_json::from_slice(&json).unwrap_or_default())
+    }
+
+    /// Temporary file a chunked upload is assembled in
+    fn upload_path(&self, upload_id: &str) -> Option<PathBuf> {
+        if !is_safe_name(upload_id) {
+            return None;
+        }
+        Some(self.dir.join(".uploads").join(upload_id))
+    }
+
+    /// Create the empty file a chunked upload is assembled in
+    pub async fn begin_upload(&self, upload_id: &str) -> std::io::Result<()> {
+        let path = self
+            .upload_path(upload_id)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid upload id"))?;
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::File::create(&path).await?;
+        Ok(())
+    }
+
+    /// Write one chunk of an upload at its offset; chunks may arrive in any order
+    pub async fn write_chunk(&self, upload_id: &str, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+        let path = self
+            .upload_path(upload_id)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid upload id"))?;
+        let mut file = tokio::fs::OpenOptions::new().write(true).open(&path).await?;
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+        file.write_all(data).await?;
+        file.flush().await
+    }
+
+    /// Where an upload is encrypted to before it is stored
+    fn sealed_upload_path(&self, upload_id: &str) -> Option<PathBuf> {
+        if !is_safe_name(upload_id) {
+            return None;
+        }
+        Some(self.dir.join(".uploads").join(format!("{}.sealed", upload_id)))
+    }
+
+    /// Hex SHA-256 and size of an assembled upload, read a slice at a time,
+    /// calling `on_progress` with the bytes read so far after every slice
+    pub async fn hash_upload(&self, upload_id: &str, mut on_progress: impl FnMut(u64)) -> std::io::Result<(String, u64)> {
+        use tokio::io::AsyncReadExt;
+
+        let mut file = self.open_upload(upload_id).await?;
+        let mut hasher = Sha256::new();
+        let mut read = 0;
+        let mut slice = vec![0u8; UPLOAD_READ_SLICE];
+        loop {
+            let n = file.read(&mut slice).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&slice[..n]);
+            read += n as u64;
+            on_progress(read);
+        }
+        Ok((to_hex(&hasher.finalize()), read))
+    }
+
+    /// Open an assembled upload for reading
+    pub async fn open_upload(&self, upload_id: &str) -> std::io::Result<tokio::fs::File> {
+        let path = self
+            .upload_path(upload_id)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid upload id"))?;
+        tokio::fs::File::open(&path).await
+    }
+
+    /// Create the file an upload is encrypted into, for
+    /// `store_sealed_upload` to move into place
+    pub async fn create_sealed_upload(&self, upload_id: &str) -> std::io::Result<tokio::fs::File> {
+        let path = self
+            .sealed_upload_path(upload_id)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid upload id"))?;
+        tokio::fs::File::create(&path).await
+    }
+
+    /// Store an upload encrypted into `create_sealed_upload`'s file as an
+    /// image, like `store`
+    ///
+    /// The file is moved into place rather than read. Only if the image it
+    /// replaces has an access header are both read, to check the header and
+    /// carry its views over.
+    pub async fn store_sealed_upload(&self, upload_id: &str, username: &str, filename: &str) -> std::io::Result<PathBuf> {
+        use tokio::io::AsyncReadExt;
+
+        let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid image name");
+        let path = self.path(username, filename).ok_or_else(invalid)?;
+        let sealed = self.sealed_upload_path(upload_id).ok_or_else(invalid)?;
+        let _guard = self.access_lock.lock().await;
+        let mut start = [0u8; 5];
+        let read = match tokio::fs::File::open(&path).await {
+            Ok(mut file) => file.read(&mut start).await.unwrap_or(0),
+            Err(_) => 0,
+        };
+        let existing = match access_header_len(&start[..read]) {
+            Ok(header_len) if header_len > 0 => tokio::fs::read(&path).await.unwrap_or_default(),
+            _ => Vec::new(),
+        };
+        match self.load_access(username, filename, &existing).await {
+            Ok(access) if !access.grants.is_empty() => {
+                let data = tokio::fs::read(&sealed).await?;
+                self.store_access(username, filename, &data, &access).await?;
+                tokio::fs::remove_file(&sealed).await?;
+            }
+            _ => {
+                if let Some(dir) = path.parent() {
+                    tokio::fs::create_dir_all(dir).await?;
+                }
+                tokio::fs::rename(&sealed, &path).await?;
+            }
+        }
+        Ok(path)
+    }
+
+    /// Remove an upload's temporary files, once stored or abandoned
+    pub async fn discard_upload(&self, upload_id: &str) {
+        for path in [self.upload_path(upload_id), self.sealed_upload_path(upload_id)].into_iter().flatten() {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+    }
+
+    /// Whether `filename` names a file inside `username`'s own directory
+    pub fn owns(&self, username: &str, filename: &str) -> bool {
+        self.path(username, filename).is_some()
+    }
+
+    /// Remove an image, its catalog entry, thumbnail and access grants,
+    /// returning whether the image was stored here
+    pub async fn delete(&self, username: &str, filename: &str) -> std::io::Result<bool> {
+        let Some(path) = self.path(username, filename) else {
+            return Ok(false);
+        };
+        let _guard = self.access_lock.lock().await;
+        self.remove_entry(username, filename).await?;
+        let sidecars = [self.access_path(username, filename), self.thumbnail_path(username, filename)];
+        for sidecar in sidecars.into_iter().flatten() {
+            match tokio::fs::remove_file(&sidecar).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Rename an image and/or replace its tags or caption
+    ///
+    /// The image is hard-linked under its new name before the old name is
+    /// removed, so it never disappears and an existing image is never
+    /// overwritten. Its catalog entry, sealed again under the first of
+    /// `user_keys`, thumbnail and access grants move with it.
+    pub async fn update_meta(
+        &self,
+        username: &str,
+        filename: &str,
+        new_filename: Option<&str>,
+        tags: Option<Vec<String>>,
+        caption: Option<String>,
+        user_keys: &[UserKey],
+    ) -> std::io::Result<MetaUpdate> {
+        let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid image name");
+        let path = self.path(username, filename).ok_or_else(invalid)?;
+        let _guard = self.access_lock.lock().await;
+        if !self.contains(username, filename).await {
+            return Ok(MetaUpdate::NotStored);
+        }
+        let mut metadata = self.load_metadata(username, filename, user_keys).await;
+        if metadata.is_none() && (tags.is_some() || caption.is_some()) {
+            return Ok(MetaUpdate::NoMetadata);
+        }
+
+        let new_filename = new_filename.filter(|&name| name != filename);
+        if let Some(new_filename) = new_filename {
+            let new_path = self.path(username, new_filename).ok_or_else(invalid)?;
+            match tokio::fs::hard_link(&path, &new_path).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Ok(MetaUpdate::NameTaken),
+                Err(e) => return Err(e),
+            }
+            tokio::fs::remove_file(&path).await?;
+
+            let sidecars = [
+                (self.access_path(username, filename), self.access_path(username, new_filename)),
+                (self.thumbnail_path(username, filename), self.thumbnail_path(username, new_filename)),
+            ];
+            for (from, to) in sidecars {
+                let (Some(from), Some(to)) = (from, to) else {
+                    continue;
+                };
+                match tokio::fs::rename(&from, &to).await {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        if let Some(metadata) = &mut metadata {
+            if let Some(new_filename) = new_filename {
+                metadata.filename = new_filename.to_string();
+            }
+            if let Some(tags) = tags {
+                metadata.tags = tags;
+            }
+            if let Some(caption) = caption {
+                metadata.caption = Some(caption).filter(|caption| !caption.is_empty());
+            }
+        }
+        if let Some(new_filename) = new_filename {
+            // Images without metadata still need their name in the catalog
+            let entry = CatalogEntry {
+                filename: new_filename.to_string(),
+                metadata: metadata.clone(),
+            };
+            self.store_entry(username, &entry, user_keys.first()).await?;
+            self.remove_entry(username, filename).await?;
+        } else if let Some(metadata) = &metadata {
+            self.store_metadata(username, metadata, user_keys).await?;
+        }
+        Ok(MetaUpdate::Updated(metadata.map(Box::new)))
+    }
+
+    /// Every image stored here for `username` that has a catalog entry,
+    /// sorted by filename
+    ///
+    /// Entries none of `user_keys` opens are listed sealed, under the token
+    /// the image is stored under.
+    pub async fn list(&self, username: &str, user_keys: &[UserKey]) -> Vec<ImageEntry> {
+        let mut entries = Vec::new();
+        if !is_safe_name(username) {
+            return entries;
+        }
+        let user_dir = self.dir.join(username);
+
+        for path in bucket_paths(&user_dir).await {
+            for record in read_bucket(&path).await.unwrap_or_default() {
+                if !is_token(&record.file) {
+                    continue;
+                }
+                let Ok(metadata) = tokio::fs::metadata(user_dir.join(&record.file)).await else {
+                    continue;
+                };
+                if !metadata.is_file() {
+                    continue;
+                }
+                let uploaded_at_unix = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0);
+                let has_thumbnail = tokio::fs::metadata(user_dir.join(".thumbs").join(&record.file)).await.is_ok();
+                let mut entry = ImageEntry {
+                    filename: record.file.clone(),
+                    size_bytes: metadata.len(),
+                    uploaded_at_unix,
+                    node_id: self.node_id,
+                    tags: Vec::new(),
+                    caption: None,
+                    content_id: None,
+                    has_thumbnail,
+                    sealed_entry: None,
+                };
+                match record.open(user_keys, username) {
+                    Ok(catalog) => {
+                        entry.filename = catalog.filename;
+                        if let Some(image) = catalog.metadata {
+                            (entry.tags, entry.caption, entry.content_id) = (image.tags, image.caption, image.content_id);
+                        }
+                    }
+                    Err(_) => entry.sealed_entry = record.sealed(),
+                }
+                entries.push(entry);
+            }
+        }
+
+        entries.sort_by(|a, b| a.filename.cmp(&b.filename));
+        entries
+    }
+
+    /// Move images stored under their own names, as before catalogs, to
+    /// their tokens, with their thumbnails and access sidecars, and put their
+    /// names and metadata in the catalog, returning how many were moved
+    ///
+    /// No user keys are known here, so the entries are kept in the clear
+    /// until `seal_catalog` seals them.
+    pub async fn migrate_names(&self) -> std::io::Result<u32> {
+        let mut moved = 0;
+        let Ok(mut users) = tokio::fs::read_dir(&self.dir).await else {
+            return Ok(0);
+        };
+        while let Some(user) = users.next_entry().await? {
+            let username = user.file_name().to_string_lossy().into_owned();
+            if username.starts_with('.') || !user.file_type().await?.is_dir() {
+                continue;
+            }
+            let mut images = tokio::fs::read_dir(user.path()).await?;
+            while let Some(image) = images.next_entry().await? {
+                let filename = image.file_name().to_string_lossy().into_owned();
+                if is_token(&filename) || !image.file_type().await?.is_file() {
+                    continue;
+                }
+                self.migrate_name(&username, &filename).await?;
+                moved += 1;
+            }
+        }
+        Ok(moved)
+    }
+
+    /// `migrate_names` for one image; run again after being cut short, it
+    /// finishes the move
+    async fn migrate_name(&self, username: &str, filename: &str) -> std::io::Result<()> {
+        let user_dir = self.dir.join(username);
+        let old_meta = user_dir.join(".meta").join(format!("{}.json", filename));
+        let metadata = match tokio::fs::read(&old_meta).await {
+            Ok(json) => serde_json::from_slice(&json).ok(),
+            Err(_) => None,
+        };
+        let entry = CatalogEntry {
+            filename: filename.to_string(),
+            metadata,
+        };
+        self.store_entry(username, &entry, None).await?;
+
+        let file = file_token(&self.catalog_key, username, filename);
+        let moves = [
+            (user_dir.join(".access").join(format!("{}.json", filename)), user_dir.join(".access").join(format!("{}.json", file))),
+            (user_dir.join(".thumbs").join(filename), user_dir.join(".thumbs").join(&file)),
+            (user_dir.join(filename), user_dir.join(&file)),
+        ];
+        for (from, to) in moves {
+            match tokio::fs::rename(&from, &to).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        match tokio::fs::remove_file(&old_meta).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The catalog buckets in a user's directory
+async fn bucket_paths(user_dir: &Path) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    let Ok(mut dir) = tokio::fs::read_dir(user_dir.join(".meta")).await else {
+        return paths;
+    };
+    while let Ok(Some(entry)) = dir.next_entry().await {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.strip_suffix(".json").is_some_and(is_token) {
+            paths.push(entry.path());
+        }
+    }
+    paths
+}
+
+/// The records of a catalog bucket, none if it does not exist
+async fn read_bucket(path: &Path) -> std::io::Result<Vec<CatalogRecord>> {
+    match tokio::fs::read(path).await {
+        Ok(json) => Ok(serde_json::from_slice(&json)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Replace a catalog bucket through a temporary file, removing it once empty
+async fn write_bucket(path: &Path, records: &[CatalogRecord]) -> std::io::Result<()> {
+    if records.is_empty() {
+        return match tokio::fs::remove_file(path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp, serde_json::to_vec_pretty(records)?).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// A single path component: no separators, no `.`/`..`
+fn is_safe_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\'])
+}
+
+/// Whether a name is a token as `file_token` and `lookup_token` give them;
+/// image names always have an extension, so never are
+fn is_token(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::{seal_image, EncryptionScheme};
+
+    /// An image store under a directory of its own, removed when the test ends
+    struct TestStore {
+        dir: PathBuf,
+    }
+
+    impl TestStore {
+        fn new() -> Self {
+            TestStore {
+                dir: std::env::temp_dir().join(format!("distinsta-store-{}", uuid::Uuid::new_v4())),
+            }
+        }
+
+        /// A store over the directory, as a node opens it when it starts
+        fn open(&self) -> ImageStore {
+            ImageStore::new(1, self.dir.clone(), SecretKey::new([9; 32]), SecretKey::new([7; 32]))
+        }
+    }
+
+    impl Drop for TestStore {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn sealed() -> Vec<u8> {
+        seal_image(EncryptionScheme::Aes256Gcm, None, b"a shared image", "alice", "cat.png")
+    }
+
+    fn remaining(check: ViewCheck) -> Option<u32> {
+        match check {
+            ViewCheck::Granted { remaining, .. } => Some(remaining),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn spent_views_survive_a_restart() {
+        let test = TestStore::new();
+        let store = test.open();
+        store.store("alice", "cat.png", &sealed()).await.unwrap();
+        assert!(store.grant_access("alice", "cat.png", "bob", 3, None).await.unwrap());
+        assert_eq!(remaining(store.consume_view("alice", "cat.png", "bob").await.unwrap()), Some(2));
+
+        let store = test.open();
+        assert_eq!(remaining(store.consume_view("alice", "cat.png", "bob").await.unwrap()), Some(1));
+        let store = test.open();
+        let ViewCheck::Granted { data, remaining: 0, .. } = store.consume_view("alice", "cat.png", "bob").await.unwrap() else {
+            panic!("last view refused");
+        };
+        assert_eq!(data, sealed_without_header(&store).await);
+        let store = test.open();
+        assert!(matches!(store.consume_view("alice", "cat.png", "bob").await.unwrap(), ViewCheck::QuotaExhausted));
+        assert!(matches!(store.consume_view("alice", "cat.png", "carol").await.unwrap(), ViewCheck::NotShared));
+    }
+
+    /// The sealed image of alice's cat.png as stored, without its header
+    async fn sealed_without_header(store: &ImageStore) -> Vec<u8> {
+        let stored = store.load_stored("alice", "cat.png").await.unwrap();
+        strip_header(&stored).unwrap().to_vec()
+    }
+
+    #[tokio::test]
+    async fn access_headers_changed_on_disk_are_refused() {
+        let test = TestStore::new();
+        let store = test.open();
+        let sealed = sealed();
+        store.store("alice", "cat.png", &sealed).await.unwrap();
+        store.grant_access("alice", "cat.png", "bob", 1, None).await.unwrap();
+
+        // Give bob more views by editing the header's JSON in place
+        let path = store.path("alice", "cat.png").unwrap();
+        let mut stored = std::fs::read(&path).unwrap();
+        let field = b"\"allowed_views\":1";
+        let at = stored.windows(field.len()).position(|window| window == field).unwrap();
+        stored[at + field.len() - 1] = b'9';
+        std::fs::write(&path, &stored).unwrap();
+
+        let refused = store.consume_view("alice", "cat.png", "bob").await.unwrap_err();
+        assert_eq!(refused.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(sealed_without_header(&store).await, sealed);
+    }
+
+
+    #[test]
+    fn stores_print_without_their_keys() {
+        let test = TestStore::new();
+        let printed = format!("{:?}", test.open());
+        assert!(printed.contains("header_key: SecretKey([REDACTED; 32])"), "{}", printed);
+        assert!(printed.contains("catalog_key: SecretKey([REDACTED; 32])"), "{}", printed);
+    }
+
+    fn key(byte: u8, generation: u32) -> UserKey {
+        UserKey {
+            key: SecretKey::new([byte; 32]),
+            generation,
+        }
+    }
+
+    fn metadata(filename: &str, caption: &str) -> ImageMetadata {
+        ImageMetadata {
+            filename: filename.to_string(),
+            original_size: 14,
+            encrypted_size: 14,
+            processed_by_node: 1,
+            uploaded_at_unix: 0,
+            content_sha256: String::new(),
+            tags: vec!["private".to_string()],
+            caption: Some(caption.to_string()),
+            content_id: None,
+            thumbnail_error: None,
+            metadata_strip: None,
+            storage_compression: None,
+        }
+    }
+
+    /// Every file under `dir` with its name and contents
+    fn files_under(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                files.extend(files_under(&path));
+            } else {
+                let data = std::fs::read(&path).unwrap();
+                files.push((path, data));
+            }
+        }
+        files
+    }
+
+    #[tokio::test]
+    async fn names_tags_and_captions_are_never_on_disk_in_the_clear() {
+        let test = TestStore::new();
+        let store = test.open();
+        let keys = [key(0x42, 0)];
+        store.store("alice", "passport_scan.png", &sealed()).await.unwrap();
+        store.store_metadata("alice", &metadata("passport_scan.png", "my passport"), &keys).await.unwrap();
+
+        let files = files_under(&test.dir);
+        assert!(!files.is_empty());
+        for (path, data) in files {
+            let path = path.to_string_lossy();
+            assert!(!path.contains("passport"), "{}", path);
+            for secret in [&b"passport"[..], b"private"] {
+                assert!(!data.windows(secret.len()).any(|window| window == secret), "{} holds {:?}", path, secret);
+            }
+        }
+        let listed = store.list("alice", &keys).await;
+        assert_eq!(listed[0].filename, "passport_scan.png");
+        assert_eq!(listed[0].caption.as_deref(), Some("my passport"));
+    }
+
+    #[tokio::test]
+    async fn names_differing_in_case_share_a_bucket_but_not_an_entry() {
+        let test = TestStore::new();
+        let store = test.open();
+        let keys = [key(0x42, 0)];
+        for (filename, caption) in [("Cat.png", "upper"), ("cat.png", "lower")] {
+            store.store("alice", filename, &sealed()).await.unwrap();
+            store.store_metadata("alice", &metadata(filename, caption), &keys).await.unwrap();
+        }
+        assert_eq!(store.metadata_path("alice", "Cat.png"), store.metadata_path("alice", "cat.png"));
+        assert_ne!(store.path("alice", "Cat.png"), store.path("alice", "cat.png"));
+        let bucket = store.metadata_path("alice", "cat.png").unwrap();
+        assert_eq!(read_bucket(&bucket).await.unwrap().len(), 2);
+
+        let caption = |metadata: Option<ImageMetadata>| metadata.and_then(|metadata| metadata.caption);
+        assert_eq!(caption(store.load_metadata("alice", "Cat.png", &keys).await).as_deref(), Some("upper"));
+        assert_eq!(caption(store.load_metadata("alice", "cat.png", &keys).await).as_deref(), Some("lower"));
+        assert!(store.load_metadata("alice", "CAT.png", &keys).await.is_none());
+
+        assert!(store.delete("alice", "Cat.png").await.unwrap());
+        assert_eq!(read_bucket(&bucket).await.unwrap().len(), 1);
+        assert_eq!(caption(store.load_metadata("alice", "cat.png", &keys).await).as_deref(), Some("lower"));
+    }
+
+    #[tokio::test]
+    async fn records_swapped_within_a_bucket_are_refused() {
+        let test = TestStore::new();
+        let store = test.open();
+        let keys = [key(0x42, 0)];
+        for filename in ["Cat.png", "cat.png"] {
+            store.store("alice", filename, &sealed()).await.unwrap();
+            store.store_metadata("alice", &metadata(filename, filename), &keys).await.unwrap();
+        }
+        // Point each record at the other's file
+        let bucket = store.metadata_path("alice", "cat.png").unwrap();
+        let mut records = read_bucket(&bucket).await.unwrap();
+        let (first, second) = (records[0].file.clone(), records[1].file.clone());
+        (records[0].file, records[1].file) = (second, first);
+        write_bucket(&bucket, &records).await.unwrap();
+
+        assert!(store.load_metadata("alice", "Cat.png", &keys).await.is_none());
+        assert!(store.load_metadata("alice", "cat.png", &keys).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn entries_under_another_key_stay_sealed() {
+        let test = TestStore::new();
+        let store = test.open();
+        store.store("alice", "cat.png", &sealed()).await.unwrap();
+        store.store_metadata("alice", &metadata("cat.png", "hello"), &[key(0x42, 3)]).await.unwrap();
+
+        for wrong in [[key(0x43, 3)], [key(0x42, 4)]] {
+            assert!(store.load_metadata("alice", "cat.png", &wrong).await.is_none());
+            let listed = store.list("alice", &wrong).await;
+            assert_eq!(listed.len(), 1);
+            assert_eq!(listed[0].filename, file_token(&store.catalog_key, "alice", "cat.png"));
+            assert_eq!(listed[0].caption, None);
+            let sealed_entry = listed[0].sealed_entry.as_ref().unwrap();
+            assert_eq!(sealed_entry.generation, 3);
+            let json = open_metadata(&sealed_entry.sealed, &[key(0x42, 3)], "alice", 3).unwrap();
+            let entry: CatalogEntry = serde_json::from_slice(&json).unwrap();
+            assert_eq!(entry.filename, "cat.png");
+        }
+        // Nor does another user's name open it
+        let sealed_entry = store.list("alice", &[]).await.remove(0).sealed_entry.unwrap();
+        assert!(matches!(open_metadata(&sealed_entry.sealed, &[key(0x42, 3)], "bob", 3), Err(CryptoError::Tampered)));
+    }
+}
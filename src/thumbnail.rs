@@ -0,0 +1,55 @@
+use image::io::{Limits, Reader};
+use image::ImageOutputFormat;
+use std::io::Cursor;
+
+/// Longest side of a thumbnail in pixels
+pub const MAX_SIDE: u32 = 256;
+/// Largest upload, in bytes, that a thumbnail is made of
+pub const MAX_SOURCE_LEN: u64 = 64 * 1024 * 1024;
+/// Widest or tallest image a thumbnail is made of; checked against the
+/// image's header before any pixels are decoded
+const MAX_SOURCE_SIDE: u32 = 16384;
+/// Most memory decoding one image may take, so that a small file claiming
+/// a huge image cannot exhaust the node's
+const MAX_DECODE_ALLOC: u64 = 512 * 1024 * 1024;
+const JPEG_QUALITY: u8 = 80;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ThumbnailError {
+    #[error("not an image format the server reads")]
+    UnknownFormat,
+    #[error("image is {0} bytes, too large to make a thumbnail of")]
+    TooLarge(u64),
+    #[error("image cannot be decoded: {0}")]
+    Image(#[from] image::ImageError),
+    #[error("image cannot be read: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// A JPEG of the image no larger than `MAX_SIDE` on either side, keeping
+/// its aspect ratio
+///
+/// Decoding is slow and refuses images over `MAX_SOURCE_SIDE` pixels on a
+/// side; run it off the runtime's threads.
+pub fn make(data: &[u8]) -> Result<Vec<u8>, ThumbnailError> {
+    let mut reader = Reader::new(Cursor::new(data)).with_guessed_format()?;
+    if reader.format().is_none() {
+        return Err(ThumbnailError::UnknownFormat);
+    }
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_SOURCE_SIDE);
+    limits.max_image_height = Some(MAX_SOURCE_SIDE);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+    reader.limits(limits);
+
+    // JPEG has no alpha channel
+    let thumbnail = reader.decode()?.thumbnail(MAX_SIDE, MAX_SIDE).to_rgb8();
+    let mut jpeg = Vec::new();
+    thumbnail.write_to(&mut Cursor::new(&mut jpeg), ImageOutputFormat::Jpeg(JPEG_QUALITY))?;
+    Ok(jpeg)
+}
+
+/// The name a thumbnail is sealed under, which no image can have
+pub fn sealed_name(filename: &str) -> String {
+    format!("{}/thumbnail", filename)
+}
@@ -0,0 +1,377 @@
+use crate::bully::{BullyError, BullyMessage};
+use crate::framing::{read_frame, write_frame};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::future::Future;
+use std::pin::Pin;
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+use tracing::warn;
+
+/// Future returned by `Transport` methods
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// How bully messages reach other nodes
+///
+/// `TcpTransport` is the real network; other implementations can route
+/// messages in memory to run elections without sockets.
+pub trait Transport: Send + Sync {
+    /// Deliver `msg` to the node at `address`, returning its reply if the message expects one
+    fn send<'a>(
+        &'a self,
+        address: &'a str,
+        msg: BullyMessage,
+    ) -> BoxFuture<'a, Result<Option<BullyMessage>, SendError>>;
+
+    /// Whether the node at `address` accepts connections within `probe_timeout`
+    fn probe<'a>(&'a self, address: &'a str, probe_timeout: Duration) -> BoxFuture<'a, bool>;
+}
+
+/// How a single exchange with a peer failed
+#[derive(Debug, thiserror::Error)]
+pub enum SendError {
+    /// Nothing accepted the connection: the peer's process is down
+    #[error("connection to {0} refused")]
+    ConnectRefused(String),
+    /// The connection attempt went unanswered: the host may be gone
+    #[error("connecting to {0} timed out")]
+    ConnectTimeout(String),
+    /// Connecting failed for another reason: a bad address, no route, ...
+    #[error("connecting to {address} failed: {reason}")]
+    ConnectFailed { address: String, reason: String },
+    /// Connected, but no reply in time: the peer is alive but slow
+    #[error("timed out waiting for a reply from {0}")]
+    ReadTimeout(String),
+    /// The exchange broke off or the reply could not be accepted
+    #[error("bad response from {address}: {reason}")]
+    BadResponse { address: String, reason: String },
+}
+
+impl SendError {
+    pub fn bad_response(address: &str, reason: impl std::fmt::Display) -> Self {
+        SendError::BadResponse {
+            address: address.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// Open a TCP connection to a peer, classifying the failure
+pub async fn connect_peer(address: &str, connect_timeout: Duration) -> Result<TcpStream, SendError> {
+    match timeout(connect_timeout, TcpStream::connect(address)).await {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+            Err(SendError::ConnectRefused(address.to_string()))
+        }
+        Ok(Err(e)) => Err(SendError::ConnectFailed {
+            address: address.to_string(),
+            reason: e.to_string(),
+        }),
+        Err(_) => Err(SendError::ConnectTimeout(address.to_string())),
+    }
+}
+
+/// Largest bully frame; election messages are small
+pub const MAX_FRAME_LEN: usize = 1 << 20;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Bully message signed with the shared cluster secret
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SignedEnvelope {
+    /// Serialized BullyMessage
+    payload: String,
+    /// HMAC-SHA256 of `payload`
+    hmac: Vec<u8>,
+}
+
+/// Turns bully messages into bytes and back, signing them when a cluster secret is set
+#[derive(Debug, Clone)]
+pub struct MessageCodec {
+    /// Only used to label log lines
+    pub node_id: u32,
+    pub cluster_secret: Option<String>,
+}
+
+impl MessageCodec {
+    /// Serialize a message for the wire, signing it if a cluster secret is set
+    pub fn encode(&self, msg: &BullyMessage) -> Result<String, BullyError> {
+        let payload = serde_json::to_string(msg)?;
+        match &self.cluster_secret {
+            Some(secret) => {
+                let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+                    .expect("HMAC accepts keys of any length");
+                mac.update(payload.as_bytes());
+                let hmac = mac.finalize().into_bytes().to_vec();
+                Ok(serde_json::to_string(&SignedEnvelope { payload, hmac })?)
+            }
+            None => Ok(payload),
+        }
+    }
+
+    /// Parse a message from the wire
+    ///
+    /// With a cluster secret configured, only envelopes carrying a valid HMAC
+    /// are accepted; without one, plain messages are accepted as before.
+    pub fn decode(&self, data: &[u8]) -> Result<BullyMessage, BullyError> {
+        let Some(secret) = &self.cluster_secret else {
+            return Ok(serde_json::from_slice(data)?);
+        };
+
+        let envelope = match serde_json::from_slice::<SignedEnvelope>(data) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                if serde_json::from_slice::<BullyMessage>(data).is_ok() {
+                    warn!("Node {}: Rejected unsigned bully message", self.node_id);
+                    return Err(BullyError::Unauthenticated);
+                }
+                return Err(e.into());
+            }
+        };
+
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(envelope.payload.as_bytes());
+        if mac.verify_slice(&envelope.hmac).is_err() {
+            warn!("Node {}: Rejected bully message with bad HMAC", self.node_id);
+            return Err(BullyError::Unauthenticated);
+        }
+
+        Ok(serde_json::from_str(&envelope.payload)?)
+    }
+}
+
+/// One framed TCP connection per message
+pub struct TcpTransport {
+    pub codec: MessageCodec,
+    /// Applied to connecting and to the exchange separately, so a peer that
+    /// is down can be told apart from one that is merely slow
+    pub timeout: Duration,
+}
+
+impl Transport for TcpTransport {
+    fn send<'a>(
+        &'a self,
+        address: &'a str,
+        msg: BullyMessage,
+    ) -> BoxFuture<'a, Result<Option<BullyMessage>, SendError>> {
+        Box::pin(async move {
+            let msg_json = self
+                .codec
+                .encode(&msg)
+                .map_err(|e| SendError::bad_response(address, e))?;
+            let mut stream = connect_peer(address, self.timeout).await?;
+
+            timeout(self.timeout, async {
+                write_frame(&mut stream, msg_json.as_bytes(), MAX_FRAME_LEN).await?;
+
+                // Wait for response if needed
+                match msg {
+                    BullyMessage::Election { .. }
+                    | BullyMessage::Coordinator { .. }
+                    | BullyMessage::Join { .. }
+                    | BullyMessage::Heartbeat { .. } => {
+                        match read_frame(&mut stream, MAX_FRAME_LEN).await? {
+                            Some(frame) => Ok::<_, BullyError>(Some(self.codec.decode(&frame)?)),
+                            None => Ok(None),
+                        }
+                    }
+                    _ => Ok(None),
+                }
+            })
+            .await
+            .map_err(|_| SendError::ReadTimeout(address.to_string()))?
+            .map_err(|e| SendError::bad_response(address, e))
+        })
+    }
+
+    fn probe<'a>(&'a self, address: &'a str, probe_timeout: Duration) -> BoxFuture<'a, bool> {
+        Box::pin(async move { connect_peer(address, probe_timeout).await.is_ok() })
+    }
+}
+
+#[cfg(test)]
+pub use channel::ChannelTransport;
+
+/// In-memory network for running several `BullyElection`s in one process
+#[cfg(test)]
+mod channel {
+    use super::{BoxFuture, SendError, Transport};
+    use crate::bully::{BullyElection, BullyMessage};
+    use rand::Rng;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Mutex;
+    use tokio::sync::{mpsc, oneshot};
+    use tokio::time::{sleep, timeout, Duration};
+
+    type Delivery = (BullyMessage, oneshot::Sender<Option<BullyMessage>>);
+    type DropRule = Box<dyn FnMut(&str, &BullyMessage) -> bool + Send>;
+
+    /// Routes messages between nodes over channels, with faults injected on demand
+    ///
+    /// A dropped message or one to a node that does not reply in time ends in
+    /// `ReadTimeout`, as over TCP; a node that is down refuses connections.
+    pub struct ChannelTransport {
+        nodes: Mutex<HashMap<String, mpsc::UnboundedSender<Delivery>>>,
+        down: Mutex<HashSet<String>>,
+        drop_rules: Mutex<Vec<DropRule>>,
+        /// Fixed delay, plus up to `jitter` more so concurrent messages can overtake each other
+        delay: Mutex<(Duration, Duration)>,
+        timeout: Duration,
+    }
+
+    impl ChannelTransport {
+        pub fn new(timeout: Duration) -> Self {
+            ChannelTransport {
+                nodes: Mutex::new(HashMap::new()),
+                down: Mutex::new(HashSet::new()),
+                drop_rules: Mutex::new(Vec::new()),
+                delay: Mutex::new((Duration::ZERO, Duration::ZERO)),
+                timeout,
+            }
+        }
+
+        /// Deliver messages sent to `node.node_address` to `node`
+        pub fn attach(&self, node: &BullyElection) {
+            let (tx, mut rx) = mpsc::unbounded_channel::<Delivery>();
+            self.nodes.lock().unwrap().insert(node.node_address.clone(), tx);
+            let node = node.clone();
+            tokio::spawn(async move {
+                while let Some((msg, reply)) = rx.recv().await {
+                    let node = node.clone();
+                    tokio::spawn(async move {
+                        let _ = reply.send(node.handle_message(msg).await);
+                    });
+                }
+            });
+        }
+
+        /// Take a node off the network, or bring it back
+        pub fn set_down(&self, address: &str, down: bool) {
+            let mut set = self.down.lock().unwrap();
+            if down {
+                set.insert(address.to_string());
+            } else {
+                set.remove(address);
+            }
+        }
+
+        /// Silently lose every message to an address for which `rule` returns true
+        pub fn drop_where(&self, rule: impl FnMut(&str, &BullyMessage) -> bool + Send + 'static) {
+            self.drop_rules.lock().unwrap().push(Box::new(rule));
+        }
+
+        /// Hold each message back for `delay` plus a random share of `jitter`
+        pub fn set_delay(&self, delay: Duration, jitter: Duration) {
+            *self.delay.lock().unwrap() = (delay, jitter);
+        }
+
+        fn is_up(&self, address: &str) -> bool {
+            self.nodes.lock().unwrap().contains_key(address) && !self.down.lock().unwrap().contains(address)
+        }
+    }
+
+    impl Transport for ChannelTransport {
+        fn send<'a>(
+            &'a self,
+            address: &'a str,
+            msg: BullyMessage,
+        ) -> BoxFuture<'a, Result<Option<BullyMessage>, SendError>> {
+            Box::pin(async move {
+                if !self.is_up(address) {
+                    return Err(SendError::ConnectRefused(address.to_string()));
+                }
+                let dropped = self.drop_rules.lock().unwrap().iter_mut().any(|rule| rule(address, &msg));
+                let (delay, jitter) = *self.delay.lock().unwrap();
+                let hold = delay + jitter.mul_f64(rand::thread_rng().gen::<f64>());
+                sleep(hold).await;
+
+                let (reply_tx, reply_rx) = oneshot::channel();
+                let sender = self.nodes.lock().unwrap().get(address).cloned();
+                if let (false, Some(sender)) = (dropped, sender) {
+                    let _ = sender.send((msg, reply_tx));
+                }
+                match timeout(self.timeout, reply_rx).await {
+                    Ok(Ok(reply)) => Ok(reply),
+                    _ => Err(SendError::ReadTimeout(address.to_string())),
+                }
+            })
+        }
+
+        fn probe<'a>(&'a self, address: &'a str, _probe_timeout: Duration) -> BoxFuture<'a, bool> {
+            Box::pin(async move { self.is_up(address) })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn closed_port_is_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        match connect_peer(&address, Duration::from_secs(1)).await {
+            Err(SendError::ConnectRefused(a)) => assert_eq!(a, address),
+            other => panic!("expected ConnectRefused, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn bad_address_is_not_refused() {
+        match connect_peer("no-port-here", Duration::from_secs(1)).await {
+            Err(SendError::ConnectFailed { address, .. }) => assert_eq!(address, "no-port-here"),
+            other => panic!("expected ConnectFailed, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn listening_peer_connects() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        assert!(connect_peer(&address, Duration::from_secs(1)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn replies_over_1024_bytes_arrive_whole() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let codec = MessageCodec {
+            node_id: 1,
+            cluster_secret: None,
+        };
+        let peers: Vec<(u32, String, u32)> = (0..100).map(|id| (id, format!("10.0.0.{}:8000", id), 0)).collect();
+        let reply = codec
+            .encode(&BullyMessage::JoinAck {
+                peers: peers.clone(),
+                leader: None,
+                term: 7,
+            })
+            .unwrap();
+        assert!(reply.len() > 1024);
+
+        let seed = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_frame(&mut stream, MAX_FRAME_LEN).await.unwrap().unwrap();
+            write_frame(&mut stream, reply.as_bytes(), MAX_FRAME_LEN).await.unwrap();
+        });
+        let transport = TcpTransport {
+            codec,
+            timeout: Duration::from_secs(1),
+        };
+        let join = BullyMessage::Join {
+            id: 200,
+            address: "127.0.0.1:1".into(),
+            priority: 0,
+        };
+        match transport.send(&address, join).await {
+            Ok(Some(BullyMessage::JoinAck { peers: got, term: 7, .. })) => assert_eq!(got, peers),
+            other => panic!("expected the JoinAck, got {:?}", other),
+        }
+        seed.await.unwrap();
+    }
+}
@@ -0,0 +1,297 @@
+use crate::protocol::{ClientRequest, ImageSelector};
+
+/// Longest username accepted
+pub const MAX_USERNAME_LEN: usize = 64;
+/// Longest filename accepted, in bytes; the usual file system limit
+pub const MAX_FILENAME_LEN: usize = 255;
+/// Extensions an image's filename may have, compared in lower case
+pub const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "bmp", "webp"];
+
+/// A field of a request that servers refuse, named as in the request
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ValidationError {
+    #[error("{0} must be 1 to 64 letters, digits, '_' or '-'")]
+    Username(&'static str),
+    #[error("{0} must be one file name of at most 255 bytes ending in .png, .jpg, .jpeg, .gif, .bmp or .webp")]
+    Filename(&'static str),
+    #[error("{0} must be a content id of 64 lowercase hex digits")]
+    ContentId(&'static str),
+    #[error("{0} is empty")]
+    EmptyImage(&'static str),
+    #[error("{field} is {len} bytes, over the {max} byte limit")]
+    ImageTooLarge { field: &'static str, len: usize, max: usize },
+}
+
+/// Letters, digits, `_` and `-` only, so a username is safe as a directory name
+pub fn validate_username(field: &'static str, username: &str) -> Result<(), ValidationError> {
+    let valid = !username.is_empty()
+        && username.len() <= MAX_USERNAME_LEN
+        && username.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-');
+    if !valid {
+        return Err(ValidationError::Username(field));
+    }
+    Ok(())
+}
+
+/// A single path component, not hidden, with one of `IMAGE_EXTENSIONS`
+pub fn validate_filename(field: &'static str, filename: &str) -> Result<(), ValidationError> {
+    let extension = filename.rsplit_once('.').map(|(stem, ext)| (stem, ext.to_ascii_lowercase()));
+    let valid = filename.len() <= MAX_FILENAME_LEN
+        && !filename.starts_with('.')
+        && !filename.contains(['/', '\\', '\0'])
+        && extension.is_some_and(|(stem, ext)| !stem.is_empty() && IMAGE_EXTENSIONS.contains(&ext.as_str()));
+    if !valid {
+        return Err(ValidationError::Filename(field));
+    }
+    Ok(())
+}
+
+/// A valid filename, or a content id as `sha256_hex` gives it
+pub fn validate_selector(field: &'static str, image: &ImageSelector) -> Result<(), ValidationError> {
+    match image {
+        ImageSelector::Name(filename) => validate_filename(field, filename),
+        ImageSelector::ContentId(content_id) => {
+            if content_id.len() != 64 || !content_id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+                return Err(ValidationError::ContentId(field));
+            }
+            Ok(())
+        }
+    }
+}
+
+pub fn validate_image_data(field: &'static str, len: usize, max_len: usize) -> Result<(), ValidationError> {
+    if len == 0 {
+        return Err(ValidationError::EmptyImage(field));
+    }
+    if len > max_len {
+        return Err(ValidationError::ImageTooLarge { field, len, max: max_len });
+    }
+    Ok(())
+}
+
+/// Check every username, filename and image in a request, with images
+/// limited to `max_image_len` bytes
+///
+/// Servers run this on each request as soon as it is parsed; clients run it
+/// before sending, so bad input is refused without a round trip.
+pub fn validate_request(request: &ClientRequest, max_image_len: usize) -> Result<(), ValidationError> {
+    if let Some((username, _)) = request.credentials() {
+        validate_username("username", username)?;
+    }
+    match request {
+        ClientRequest::Register { username, .. } | ClientRequest::Login { username, .. } => {
+            validate_username("username", username)
+        }
+        ClientRequest::UploadImage { image_data, filename, .. } => {
+            validate_filename("filename", filename)?;
+            validate_image_data("image_data", image_data.len(), max_image_len)
+        }
+        ClientRequest::DownloadImage { image, .. }
+        | ClientRequest::DeleteImage { image, .. }
+        | ClientRequest::GetThumbnail { image, .. } => validate_selector("image", image),
+        ClientRequest::StreamDownload { filename, .. } => validate_filename("filename", filename),
+        ClientRequest::ShareImage { image, with_user, .. } => {
+            validate_selector("image", image)?;
+            validate_username("with_user", with_user)
+        }
+        ClientRequest::UpdateAccess { filename, with_user, .. } => {
+            validate_filename("filename", filename)?;
+            validate_username("with_user", with_user)
+        }
+        ClientRequest::ViewSharedImage { owner, filename, .. } => {
+            validate_filename("filename", filename)?;
+            validate_username("owner", owner)
+        }
+        ClientRequest::BeginUpload { filename, total_size, .. } => {
+            validate_filename("filename", filename)?;
+            if *total_size == 0 {
+                return Err(ValidationError::EmptyImage("total_size"));
+            }
+            Ok(())
+        }
+        ClientRequest::UploadChunk { data, .. } => validate_image_data("data", data.len(), max_image_len),
+        ClientRequest::UploadBatch { images, .. } => {
+            for (filename, data) in images {
+                validate_filename("batch filename", filename)?;
+                validate_image_data("batch image", data.len(), max_image_len)?;
+            }
+            Ok(())
+        }
+        ClientRequest::UpdateImageMeta { filename, new_filename, .. } => {
+            validate_filename("filename", filename)?;
+            if let Some(new_filename) = new_filename {
+                validate_filename("new_filename", new_filename)?;
+            }
+            Ok(())
+        }
+        ClientRequest::ListImages { .. }
+        | ClientRequest::FinishUpload { .. }
+        | ClientRequest::FetchNotifications { .. }
+        | ClientRequest::Subscribe { .. }
+        | ClientRequest::GetQuota { .. }
+        | ClientRequest::ChangePassword { .. }
+        | ClientRequest::RegisterPublicKey { .. }
+        | ClientRequest::ClusterStatus => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload(username: &str, filename: &str, image_data: Vec<u8>) -> ClientRequest {
+        ClientRequest::UploadImage {
+            username: username.to_string(),
+            token: "token".to_string(),
+            image_data,
+            filename: filename.to_string(),
+            sha256: None,
+            strip_metadata: None,
+            compress: None,
+        }
+    }
+
+    #[test]
+    fn usernames() {
+        let long = "a".repeat(MAX_USERNAME_LEN);
+        let too_long = "a".repeat(MAX_USERNAME_LEN + 1);
+        let cases: &[(&str, bool)] = &[
+            ("alice", true),
+            ("Alice_99-x", true),
+            ("a", true),
+            (&long, true),
+            ("", false),
+            (&too_long, false),
+            ("../alice", false),
+            ("al ice", false),
+            ("alice/bob", false),
+            ("al\0ice", false),
+            ("ålice", false),
+        ];
+        for &(username, valid) in cases {
+            let result = validate_username("username", username);
+            assert_eq!(result.is_ok(), valid, "{:?}", username);
+            if !valid {
+                assert_eq!(result, Err(ValidationError::Username("username")));
+            }
+        }
+    }
+
+    #[test]
+    fn filenames() {
+        let long = format!("{}.png", "a".repeat(MAX_FILENAME_LEN - 4));
+        let too_long = format!("{}.png", "a".repeat(MAX_FILENAME_LEN - 3));
+        let cases: &[(&str, bool)] = &[
+            ("cat.png", true),
+            ("cat.JPG", true),
+            ("my cat.v2.jpeg", true),
+            ("cat.gif", true),
+            ("cat.bmp", true),
+            ("cat.webp", true),
+            (&long, true),
+            (&too_long, false),
+            ("", false),
+            ("cat", false),
+            ("cat.exe", false),
+            (".png", false),
+            (".hidden.png", false),
+            ("../../etc/passwd", false),
+            ("../cat.png", false),
+            ("dir/cat.png", false),
+            ("dir\\cat.png", false),
+            ("cat\0.png", false),
+        ];
+        for &(filename, valid) in cases {
+            let result = validate_filename("filename", filename);
+            assert_eq!(result.is_ok(), valid, "{:?}", filename);
+            if !valid {
+                assert_eq!(result, Err(ValidationError::Filename("filename")));
+            }
+        }
+    }
+
+    #[test]
+    fn image_sizes() {
+        let cases = [
+            (1, Ok(())),
+            (100, Ok(())),
+            (0, Err(ValidationError::EmptyImage("image_data"))),
+            (
+                101,
+                Err(ValidationError::ImageTooLarge {
+                    field: "image_data",
+                    len: 101,
+                    max: 100,
+                }),
+            ),
+        ];
+        for (len, expected) in cases {
+            assert_eq!(validate_image_data("image_data", len, 100), expected, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn requests_name_the_field_they_fail_on() {
+        let cases = [
+            (upload("alice", "cat.png", vec![1]), Ok(())),
+            (upload(&"a".repeat(10 * 1024), "cat.png", vec![1]), Err(ValidationError::Username("username"))),
+            (upload("alice", "../../etc/passwd", vec![1]), Err(ValidationError::Filename("filename"))),
+            (upload("alice", "cat.png", vec![]), Err(ValidationError::EmptyImage("image_data"))),
+            (
+                upload("alice", "cat.png", vec![0; 11]),
+                Err(ValidationError::ImageTooLarge {
+                    field: "image_data",
+                    len: 11,
+                    max: 10,
+                }),
+            ),
+            (
+                ClientRequest::Register {
+                    username: "../root".to_string(),
+                    password: "hunter2".to_string(),
+                },
+                Err(ValidationError::Username("username")),
+            ),
+        ];
+        for (request, expected) in cases {
+            assert_eq!(validate_request(&request, 10), expected, "{:?}", request);
+        }
+    }
+
+    #[test]
+    fn messages_name_the_field() {
+        assert!(ValidationError::Filename("new_filename").to_string().starts_with("new_filename must be"));
+        assert_eq!(
+            ValidationError::ImageTooLarge {
+                field: "data",
+                len: 11,
+                max: 10
+            }
+            .to_string(),
+            "data is 11 bytes, over the 10 byte limit"
+        );
+    }
+
+    #[test]
+    fn selectors() {
+        let id = "0123456789abcdef".repeat(4);
+        let cases: Vec<(ImageSelector, bool)> = vec![
+            (ImageSelector::Name("cat.png".to_string()), true),
+            (ImageSelector::Name("cat".to_string()), false),
+            (ImageSelector::ContentId(id.clone()), true),
+            (ImageSelector::ContentId(id[1..].to_string()), false),
+            (ImageSelector::ContentId(format!("{}0", id)), false),
+            (ImageSelector::ContentId(id.to_uppercase()), false),
+            (ImageSelector::ContentId(id.replace('a', "g")), false),
+            (ImageSelector::ContentId(String::new()), false),
+        ];
+        for (image, valid) in cases {
+            let result = validate_selector("image", &image);
+            assert_eq!(result.is_ok(), valid, "{:?}", image);
+        }
+        assert_eq!(
+            validate_selector("image", &ImageSelector::ContentId("x".to_string())),
+            Err(ValidationError::ContentId("image"))
+        );
+    }
+}
@@ -52,7 +52,7 @@ def run_stress_test(commands):
 
     # Run cargo with the commands
     process = subprocess.Popen(
-        ["cargo", "run", "--bin", "client", USERNAME],
+        ["cargo", "run", "--bin", "client", USERNAME, "--anonymous"],
         stdin=subprocess.PIPE,
         stdout=subprocess.PIPE,
         stderr=subprocess.STDOUT,
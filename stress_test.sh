@@ -51,7 +51,7 @@ echo "=========================================="
 echo ""
 
 OUTPUT_FILE=$(mktemp)
-cargo run --bin client "$USERNAME" < "$COMMANDS_FILE" 2>&1 | tee "$OUTPUT_FILE"
+cargo run --bin client "$USERNAME" --anonymous < "$COMMANDS_FILE" 2>&1 | tee "$OUTPUT_FILE"
 
 echo ""
 echo "=========================================="